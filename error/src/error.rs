@@ -130,6 +130,9 @@ pub enum ErrorKind {
 	/// WebsocketError
 	#[fail(display = "WebSocket Error: {}", _0)]
 	WebSocketError(String),
+	/// WriteQueueFull
+	#[fail(display = "Write Queue Full Error: {}", _0)]
+	WriteQueueFull(String),
 }
 
 impl Display for Error {
@@ -12,75 +12,88 @@
 // See the License for the specific language governing permissions and
 // limitations under the License
 
+use crate::metrics::{Metrics, ThreadMetrics};
+use crate::selector::{self, GenericEvent, GenericEventType, Selector};
+use crate::timer::{TimerHandle, TimerWheel};
 use errno::errno;
 use errno::Errno;
 use libc::{accept, c_int, c_void, EAGAIN};
+#[cfg(unix)]
+use libc::{sockaddr, sockaddr_storage, socklen_t, EINPROGRESS};
 use nioruntime_err::{Error, ErrorKind};
 use nioruntime_log::*;
 use rand::Rng;
+use rustls::server::{
+	AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientHello,
+	ResolvesServerCert,
+};
+use rustls::sign::{any_supported_type, CertifiedKey};
 use rustls::{ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection};
+use std::any::Any;
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::collections::LinkedList;
 use std::convert::TryInto;
 use std::fs::File;
+use std::hash::{Hash, Hasher};
 use std::io::BufReader;
+use std::io::IoSlice;
 use std::io::Read;
 use std::io::Write;
-use std::net::{TcpListener, TcpStream};
-use std::pin::Pin;
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
+use std::net::{
+	IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, TcpListener, TcpStream,
+	UdpSocket,
+};
 use std::sync::mpsc::sync_channel;
 use std::sync::mpsc::SyncSender;
-use std::sync::RwLockWriteGuard;
 use std::sync::{Arc, RwLock};
-use std::thread::spawn;
+use std::thread::{spawn, JoinHandle};
+use std::time::{Duration, Instant};
 
 pub type OnPanic = fn() -> Result<(), Error>;
-
-// linux deps
-#[cfg(target_os = "linux")]
-use nix::sys::epoll::{
-	epoll_create1, epoll_ctl, epoll_wait, EpollCreateFlags, EpollEvent, EpollFlags, EpollOp,
-};
-
-// macos/bsd deps
-#[cfg(any(target_os = "macos", dragonfly, freebsd, netbsd, openbsd))]
-use crate::duration_to_timespec;
-#[cfg(any(target_os = "macos", dragonfly, freebsd, netbsd, openbsd))]
-use kqueue_sys::EventFilter::{self, EVFILT_READ, EVFILT_WRITE};
-#[cfg(any(target_os = "macos", dragonfly, freebsd, netbsd, openbsd))]
-use kqueue_sys::{kevent, kqueue, EventFlag, FilterFlag};
-#[cfg(any(target_os = "macos", dragonfly, freebsd, netbsd, openbsd))]
-use libc::uintptr_t;
+type OnRead = dyn Fn(&[u8], usize, WriteHandle) -> Result<(), Error> + Send + Sync;
+type OnAccept = dyn Fn(u128, WriteHandle) -> Result<(), Error> + Send + Sync;
+type OnClose = dyn Fn(u128) -> Result<(), Error> + Send + Sync;
+// returns false if the end of the stream was not handled and the connection is to be closed
+type OnReadEof = dyn Fn(WriteHandle) -> Result<bool, Error> + Send + Sync;
+type OnWritable = dyn Fn(WriteHandle) -> Result<(), Error> + Send + Sync;
+type OnTimeout = dyn Fn(WriteHandle) -> Result<(), Error> + Send + Sync;
+type OnConnect = dyn Fn(u128, Result<WriteHandle, Error>) -> Result<(), Error> + Send + Sync;
+type OnReject = dyn Fn(Option<SocketAddr>, RejectReason) -> Result<(), Error> + Send + Sync;
+type OnDatagram =
+	dyn Fn(&[u8], usize, SocketAddr, DatagramHandle) -> Result<(), Error> + Send + Sync;
+type TimerCallback = Box<dyn FnOnce() -> Result<(), Error> + Send + Sync>;
+type Attachment = Arc<RwLock<Option<Arc<dyn Any + Send + Sync>>>>;
 
 // unix deps
 #[cfg(unix)]
 use libc::{close, fcntl, pipe, read, write};
 #[cfg(unix)]
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
 
 // windows deps
 #[cfg(windows)]
-use std::os::windows::io::AsRawSocket;
-#[cfg(windows)]
-use wepoll_sys::{
-	epoll_create, epoll_ctl, epoll_data_t, epoll_event, epoll_wait, EPOLLIN, EPOLLOUT, EPOLLRDHUP,
-	EPOLL_CTL_ADD, EPOLL_CTL_DEL, EPOLL_CTL_MOD,
-};
+use std::os::windows::io::{AsRawSocket, FromRawSocket};
 
 info!();
 
 const MAIN_LOG: &str = "mainlog";
 const BUFFER_SIZE: usize = 10 * 1024;
+const MAX_WRITE_VECTORS: usize = 64;
 const TLS_CHUNKS: usize = 32768;
-const MAX_EVENTS: i32 = 100;
+const MAX_DATAGRAM_SIZE: usize = 65_536;
 #[cfg(target_os = "windows")]
 const WINSOCK_BUF_SIZE: winapi::c_int = 100_000_000;
 
 #[cfg(unix)]
-type ConnectionHandle = i32;
+pub(crate) type ConnectionHandle = i32;
 #[cfg(target_os = "windows")]
-type ConnectionHandle = u64;
+pub(crate) type ConnectionHandle = u64;
 
 /// Internal macro used to log to the main log. Applications should use the default logger (or another
 /// user specified logger). See [`nioruntime_log`] for details on logging.
@@ -106,33 +119,58 @@ macro_rules! mainlogerror {
 
 }
 
-fn load_certs(filename: &str) -> Vec<rustls::Certificate> {
-	let certfile = File::open(filename).expect("cannot open certificate file");
+fn load_certs(filename: &str) -> Result<Vec<rustls::Certificate>, Error> {
+	let certfile = File::open(filename).map_err(|e| {
+		let error: Error =
+			ErrorKind::TLSError(format!("cannot open certificate file {}: {}", filename, e)).into();
+		error
+	})?;
 	let mut reader = BufReader::new(certfile);
-	rustls_pemfile::certs(&mut reader)
-		.unwrap()
+	let certs = rustls_pemfile::certs(&mut reader).map_err(|e| {
+		let error: Error =
+			ErrorKind::TLSError(format!("cannot parse certificate file {}: {}", filename, e))
+				.into();
+		error
+	})?;
+
+	if certs.is_empty() {
+		return Err(ErrorKind::TLSError(format!("no certificates found in {}", filename)).into());
+	}
+
+	Ok(certs
 		.iter()
 		.map(|v| rustls::Certificate(v.clone()))
-		.collect()
+		.collect())
 }
 
-fn load_private_key(filename: &str) -> rustls::PrivateKey {
-	let keyfile = File::open(filename).expect("cannot open private key file");
+fn load_private_key(filename: &str) -> Result<rustls::PrivateKey, Error> {
+	let keyfile = File::open(filename).map_err(|e| {
+		let error: Error =
+			ErrorKind::TLSError(format!("cannot open private key file {}: {}", filename, e)).into();
+		error
+	})?;
 	let mut reader = BufReader::new(keyfile);
 
 	loop {
-		match rustls_pemfile::read_one(&mut reader).expect("cannot parse private key .pem file") {
-			Some(rustls_pemfile::Item::RSAKey(key)) => return rustls::PrivateKey(key),
-			Some(rustls_pemfile::Item::PKCS8Key(key)) => return rustls::PrivateKey(key),
+		let item = rustls_pemfile::read_one(&mut reader).map_err(|e| {
+			let error: Error =
+				ErrorKind::TLSError(format!("cannot parse private key file {}: {}", filename, e))
+					.into();
+			error
+		})?;
+		match item {
+			Some(rustls_pemfile::Item::RSAKey(key)) => return Ok(rustls::PrivateKey(key)),
+			Some(rustls_pemfile::Item::PKCS8Key(key)) => return Ok(rustls::PrivateKey(key)),
 			None => break,
 			_ => {}
 		}
 	}
 
-	panic!(
-		"no keys found in {:?} (encrypted keys not supported)",
+	Err(ErrorKind::TLSError(format!(
+		"no keys found in {} (encrypted keys not supported)",
 		filename
-	);
+	))
+	.into())
 }
 
 #[derive(Eq, PartialEq, Debug)]
@@ -159,6 +197,9 @@ pub struct WriteHandle {
 	pub callback_state: Arc<RwLock<State>>,
 	tls_server: Option<Arc<RwLock<ServerConnection>>>,
 	tls_client: Option<Arc<RwLock<ClientConnection>>>,
+	peer_addr: Option<SocketAddr>,
+	local_addr: Option<SocketAddr>,
+	attachment: Attachment,
 }
 
 impl WriteHandle {
@@ -167,8 +208,6 @@ impl WriteHandle {
 		guarded_data: Arc<RwLock<GuardedData>>,
 		connection_id: u128,
 		_global_lock: Arc<RwLock<bool>>,
-		tls_client: Option<Arc<RwLock<ClientConnection>>>,
-		tls_server: Option<Arc<RwLock<ServerConnection>>>,
 	) -> Self {
 		let callback_state = {
 			let guarded_data = nioruntime_util::lockw!(guarded_data).unwrap();
@@ -180,8 +219,31 @@ impl WriteHandle {
 			connection_id,
 			_global_lock,
 			callback_state,
-			tls_server,
-			tls_client,
+			tls_server: None,
+			tls_client: None,
+			peer_addr: None,
+			local_addr: None,
+			attachment: Arc::new(RwLock::new(None)),
+		}
+	}
+
+	fn from_connection_info(
+		connection_info: &ConnectionInfo,
+		guarded_data: Arc<RwLock<GuardedData>>,
+		global_lock: Arc<RwLock<bool>>,
+	) -> Self {
+		WriteHandle {
+			tls_server: connection_info.tls_server.clone(),
+			tls_client: connection_info.tls_client.clone(),
+			peer_addr: connection_info.peer_addr,
+			local_addr: connection_info.local_addr,
+			attachment: connection_info.attachment.clone(),
+			..Self::new(
+				connection_info.handle,
+				guarded_data,
+				connection_info.connection_id,
+				global_lock,
+			)
 		}
 	}
 
@@ -190,135 +252,469 @@ impl WriteHandle {
 		self.connection_id
 	}
 
-	/// Close the connection associated with this write handle.
-	pub fn close(&self) -> Result<(), Error> {
-		let buf = [0u8; BUFFER_SIZE];
-		let mut guarded_data = nioruntime_util::lockw!(self.guarded_data)?;
-		let wbuffer = WriteBuffer {
-			buffer: buf.clone(),
-			offset: 0,
-			len: 0,
-			close: true,
-			connection_id: self.connection_id,
-		};
-		guarded_data.write_queue.push(wbuffer);
-		guarded_data.wakeup()?;
+	/// Get the address of the remote side of the connection associated with this write handle.
+	/// None is returned if the connection is not an IPv4 or IPv6 socket, for example a unix
+	/// domain socket, or if the address could not be determined.
+	pub fn peer_addr(&self) -> Option<SocketAddr> {
+		self.peer_addr
+	}
+
+	/// Get the local address of the connection associated with this write handle.
+	/// None is returned if the connection is not an IPv4 or IPv6 socket, for example a unix
+	/// domain socket, or if the address could not be determined.
+	pub fn local_addr(&self) -> Option<SocketAddr> {
+		self.local_addr
+	}
 
+	/// Attach a value to the connection associated with this write handle, replacing any
+	/// previous attachment. The attachment is shared by all write handles of the connection,
+	/// so it can be retrieved in later callbacks with [`WriteHandle::attachment`] instead of
+	/// looking it up in a map keyed by connection_id. The attachment is dropped when the
+	/// connection is closed, after the callback specified by [`EventHandler::set_on_close`]
+	/// has been executed.
+	pub fn set_attachment<T: Any + Send + Sync>(&self, attachment: T) -> Result<(), Error> {
+		let mut slot = nioruntime_util::lockw!(self.attachment)?;
+		*slot = Some(Arc::new(attachment));
 		Ok(())
 	}
 
-	pub fn async_recheck(&self) -> Result<(), Error> {
-		let mut guarded_data = nioruntime_util::lockw!(self.guarded_data)?;
-		let conn = ConnectionInfo {
-			handle: self.fd,
-			connection_id: self.connection_id,
-			ctype: ConnectionType::Inbound,
-			sender: None,
-			tls_server: None,
-			tls_client: None,
+	/// Get the value attached to the connection associated with this write handle with
+	/// [`WriteHandle::set_attachment`]. None is returned if nothing is attached or the
+	/// attachment is not of type `T`.
+	pub fn attachment<T: Any + Send + Sync>(&self) -> Result<Option<Arc<T>>, Error> {
+		let slot = nioruntime_util::lockr!(self.attachment)?;
+		let attachment = match &*slot {
+			Some(attachment) => attachment.clone().downcast::<T>().ok(),
+			None => None,
 		};
-		guarded_data.aconns.push(conn);
-		guarded_data.wakeup()?;
-
-		Ok(())
+		Ok(attachment)
 	}
 
-	/// Write the specifed data to the connection associated with this write handle.
-	///
-	/// * `data` - The data to write to this connection.
-	pub fn write(&self, data: &[u8]) -> Result<(), Error> {
-		match &self.tls_server {
+	/// Get the certificate chain presented by the remote side of the TLS connection associated
+	/// with this write handle. Each certificate is returned DER encoded, starting with the end
+	/// entity certificate. The chain has already been verified against the trusted certificates,
+	/// see [`TlsConfig::client_ca_file`] and [`EventHandler::add_tls_stream`]. None is returned
+	/// if the connection is not a TLS connection, the handshake has not completed yet, or the
+	/// client did not present a certificate.
+	pub fn peer_certificates(&self) -> Result<Option<Vec<Vec<u8>>>, Error> {
+		let certificates = match &self.tls_server {
 			Some(tls_conn) => {
-				let mut wbuf = vec![];
-				{
-					let mut tls_conn = nioruntime_util::lockw!(tls_conn)?;
-					let mut start = 0;
-					loop {
-						let mut end = data.len();
-						if end - start > TLS_CHUNKS {
-							end = start + TLS_CHUNKS;
-						}
-						tls_conn.writer().write_all(&data[start..end])?;
-						tls_conn.write_tls(&mut wbuf)?;
-						if end == data.len() {
-							break;
-						}
-						start += TLS_CHUNKS;
-					}
+				let tls_conn = nioruntime_util::lockr!(tls_conn)?;
+				tls_conn
+					.peer_certificates()
+					.map(|certificates| certificates.iter().map(|c| c.0.clone()).collect())
+			}
+			None => match &self.tls_client {
+				Some(tls_conn) => {
+					let tls_conn = nioruntime_util::lockr!(tls_conn)?;
+					tls_conn
+						.peer_certificates()
+						.map(|certificates| certificates.iter().map(|c| c.0.clone()).collect())
 				}
-				self.do_write(&wbuf)
+				None => None,
+			},
+		};
+		Ok(certificates)
+	}
+
+	/// Get the ALPN protocol agreed on for the TLS connection associated with this write handle.
+	/// See [`TlsConfig::alpn_protocols`] and [`TlsClientConfig::alpn_protocols`]. None is returned
+	/// if the connection is not a TLS connection, the handshake has not completed yet, or no
+	/// protocol was agreed on.
+	pub fn alpn_protocol(&self) -> Result<Option<String>, Error> {
+		let protocol = match &self.tls_server {
+			Some(tls_conn) => {
+				let tls_conn = nioruntime_util::lockr!(tls_conn)?;
+				tls_conn
+					.alpn_protocol()
+					.map(|protocol| String::from_utf8_lossy(protocol).to_string())
 			}
 			None => match &self.tls_client {
 				Some(tls_conn) => {
-					let mut wbuf = vec![];
-					{
-						let mut tls_conn = nioruntime_util::lockw!(tls_conn)?;
-						let mut start = 0;
-						loop {
-							let mut end = data.len();
-							if end - start > TLS_CHUNKS {
-								end = start + TLS_CHUNKS;
-							}
-							tls_conn.writer().write_all(&data[start..end])?;
-							tls_conn.write_tls(&mut wbuf)?;
-							if end == data.len() {
-								break;
-							}
-							start += TLS_CHUNKS;
-						}
-					}
-					self.do_write(&wbuf)
+					let tls_conn = nioruntime_util::lockr!(tls_conn)?;
+					tls_conn
+						.alpn_protocol()
+						.map(|protocol| String::from_utf8_lossy(protocol).to_string())
 				}
-				None => self.do_write(data),
+				None => None,
 			},
-		}
+		};
+		Ok(protocol)
+	}
+
+	/// Close the connection associated with this write handle.
+	pub fn close(&self) -> Result<(), Error> {
+		self.with_owner(|guarded_data| {
+			let wbuffer = WriteBuffer {
+				data: WriteData::Empty,
+				offset: 0,
+				close: true,
+				connection_id: self.connection_id,
+			};
+			guarded_data.write_queue.push(wbuffer);
+			guarded_data.wakeup()
+		})
+	}
+
+	/// Shut down the write side of the connection associated with this write handle.
+	///
+	/// The data queued before this call is written first, then the peer reads the end of the
+	/// stream, while data may still be read from it. For TLS connections, a close_notify alert is
+	/// sent before the socket is shut down. Data written afterwards can't be sent and closes the
+	/// connection. If the peer already shut down its side of the connection, see
+	/// [`EventHandler::set_on_read_eof`], the connection is closed once its write side is shut
+	/// down.
+	pub fn shutdown_write(&self) -> Result<(), Error> {
+		let tls = self.tls_server.is_some() || self.tls_client.is_some();
+		self.with_owner(|guarded_data| {
+			// the alert is encrypted by the event loop after the data queued before it
+			if tls {
+				guarded_data.write_queue.push(WriteBuffer {
+					data: WriteData::CloseNotify,
+					offset: 0,
+					close: false,
+					connection_id: self.connection_id,
+				});
+			}
+			let wbuffer = WriteBuffer {
+				data: WriteData::Shutdown,
+				offset: 0,
+				close: false,
+				connection_id: self.connection_id,
+			};
+			guarded_data.write_queue.push(wbuffer);
+			guarded_data.wakeup()
+		})
+	}
+
+	/// Set an idle timeout on the connection associated with this write handle.
+	///
+	/// If no data is read on the connection for `timeout`, the callback specified by
+	/// [`EventHandler::set_on_timeout`] is executed on the thread that owns the connection.
+	/// The timeout fires once. Calling this function again replaces any previous timeout.
+	pub fn set_timeout(&self, timeout: Duration) -> Result<(), Error> {
+		self.with_owner(|guarded_data| {
+			guarded_data
+				.timeouts
+				.push((self.connection_id, Some(timeout)));
+			guarded_data.wakeup()
+		})
+	}
+
+	/// Clear any idle timeout set with [`WriteHandle::set_timeout`].
+	pub fn clear_timeout(&self) -> Result<(), Error> {
+		self.with_owner(|guarded_data| {
+			guarded_data.timeouts.push((self.connection_id, None));
+			guarded_data.wakeup()
+		})
+	}
+
+	pub fn async_recheck(&self) -> Result<(), Error> {
+		self.with_owner(|guarded_data| {
+			let conn = ConnectionInfo {
+				handle: self.fd,
+				connection_id: self.connection_id,
+				ctype: ConnectionType::Inbound,
+				sender: None,
+				tls_server: None,
+				tls_client: None,
+				peer_addr: self.peer_addr,
+				local_addr: self.local_addr,
+				attachment: self.attachment.clone(),
+				migrated_from: vec![],
+				reuse_port: false,
+				read_eof: false,
+				write_shutdown: false,
+			};
+			guarded_data.aconns.push(conn);
+			guarded_data.wakeup()
+		})
+	}
+
+	/// Write the specifed data to the connection associated with this write handle.
+	///
+	/// If [`EventHandlerConfig::write_queue_high_water`] is set and the number of bytes
+	/// already queued for this connection is at or above it, no data is queued and
+	/// [`ErrorKind::WriteQueueFull`] is returned. The callback specified by
+	/// [`EventHandler::set_on_writable`] will be executed once the queue drains to
+	/// [`EventHandlerConfig::write_queue_low_water`] bytes or less. For TLS connections, the
+	/// data is encrypted by the event loop once the data queued before it has been encrypted.
+	///
+	/// * `data` - The data to write to this connection.
+	pub fn write(&self, data: &[u8]) -> Result<(), Error> {
+		self.write_vectored(&[IoSlice::new(data)])
+	}
+
+	/// Write the specified parts to the connection associated with this write handle.
+	///
+	/// All parts are queued at once, so no data written to the connection from other threads
+	/// is interleaved between them, and they are flushed to the socket with as few `writev`
+	/// calls as possible. This is useful for responses that consist of several parts, such as
+	/// the length line, data and line ending of an HTTP chunk. Otherwise, this function behaves
+	/// the same as [`WriteHandle::write`].
+	///
+	/// * `bufs` - The parts to write to this connection, in order.
+	pub fn write_vectored(&self, bufs: &[IoSlice]) -> Result<(), Error> {
+		self.check_write_queue()?;
+		self.do_write(bufs)
+	}
+
+	/// Write the specified data to the connection associated with this write handle, taking
+	/// ownership of it instead of copying it into the write queue.
+	///
+	/// `data` may be any type that owns its bytes, such as [`Vec<u8>`], [`String`] or
+	/// `bytes::Bytes`, and is dropped once it has been written. This avoids copying large
+	/// payloads. For TLS connections, the data is dropped once it has been encrypted. Otherwise,
+	/// this function behaves the same as [`WriteHandle::write`].
+	///
+	/// * `data` - The data to write to this connection.
+	pub fn write_owned<B>(&self, data: B) -> Result<(), Error>
+	where
+		B: AsRef<[u8]> + Send + Sync + 'static,
+	{
+		self.check_write_queue()?;
+		self.do_write_owned(Arc::new(data))
 	}
 
-	fn do_write(&self, data: &[u8]) -> Result<(), Error> {
-		let len = data.len();
+	/// Send `len` bytes of `file`, starting at `offset`, to the connection associated with this
+	/// write handle.
+	///
+	/// The file is queued behind any data already written and is transmitted from the event
+	/// loop without being read into memory. On Linux, plaintext connections use `sendfile`.
+	/// Other platforms read the file in small chunks as the connection becomes writable. For
+	/// TLS connections, each chunk is read and encrypted as the connection becomes writable.
+	/// If the file is shorter than `offset + len`, the connection is closed once the end of the
+	/// file is reached.
+	///
+	/// * `file` - The file to send. It is closed after it has been sent.
+	/// * `offset` - The position in the file to start sending from.
+	/// * `len` - The number of bytes to send.
+	pub fn send_file(&self, file: File, offset: u64, len: u64) -> Result<(), Error> {
 		if len == 0 {
 			// nothing to write
 			return Ok(());
 		}
-		let mut buf = [0u8; BUFFER_SIZE];
-		let mut guarded_data = nioruntime_util::lockw!(self.guarded_data)?;
-		let mut start = 0;
-		let len = data.len();
-		let mut end = if len > BUFFER_SIZE { BUFFER_SIZE } else { len };
-		let mut rem = len;
-		loop {
-			let len = if rem < BUFFER_SIZE { rem } else { BUFFER_SIZE };
-			buf[..len].clone_from_slice(&data[start..end]);
+		self.check_write_queue()?;
+		self.with_owner(|guarded_data| {
 			let wbuffer = WriteBuffer {
-				buffer: buf.clone(),
+				data: WriteData::File {
+					file: Arc::new(file),
+					start: offset,
+					len,
+				},
 				offset: 0,
-				len: len.try_into().unwrap_or(0),
 				close: false,
 				connection_id: self.connection_id,
 			};
 			guarded_data.write_queue.push(wbuffer);
-			if rem <= BUFFER_SIZE {
-				break;
+			let pending = guarded_data
+				.pending_bytes
+				.entry(self.connection_id)
+				.or_insert(0);
+			*pending = pending.saturating_add(len.try_into().unwrap_or(usize::MAX));
+			guarded_data.wakeup()
+		})
+	}
+
+	/// Returns true if the write queue for this connection is at or above the high-water
+	/// mark configured in [`EventHandlerConfig::write_queue_high_water`].
+	pub fn is_write_queue_full(&self) -> Result<bool, Error> {
+		self.with_owner(|guarded_data| Ok(guarded_data.is_write_queue_full(self.connection_id)))
+	}
+
+	// execute f with the data of the thread that currently owns the connection, which is not
+	// the thread this handle was created for if the connection was moved by EventHandler::migrate
+	fn with_owner<T, F>(&self, f: F) -> Result<T, Error>
+	where
+		F: FnOnce(&mut GuardedData) -> Result<T, Error>,
+	{
+		let mut owner = self.guarded_data.clone();
+		loop {
+			let next = {
+				let mut guarded_data = nioruntime_util::lockw!(owner)?;
+				match guarded_data.migrated.get(&self.connection_id) {
+					Some(next) => next.clone(),
+					None => return f(&mut guarded_data),
+				}
+			};
+			owner = next;
+		}
+	}
+
+	fn check_write_queue(&self) -> Result<(), Error> {
+		self.with_owner(|guarded_data| {
+			if guarded_data.is_write_queue_full(self.connection_id) {
+				guarded_data.paused.insert(self.connection_id);
+				return Err(ErrorKind::WriteQueueFull(format!(
+					"write queue for connection {} is full",
+					self.connection_id
+				))
+				.into());
+			}
+			Ok(())
+		})
+	}
+
+	fn do_write(&self, bufs: &[IoSlice]) -> Result<(), Error> {
+		let len: usize = bufs.iter().map(|data| data.len()).sum();
+		if len == 0 {
+			// nothing to write
+			return Ok(());
+		}
+		self.with_owner(|guarded_data| {
+			// pack the parts into as few buffers as possible
+			let mut buffer = guarded_data.get_buffer();
+			for data in bufs {
+				let mut data: &[u8] = data;
+				while !data.is_empty() {
+					let len = std::cmp::min(BUFFER_SIZE - buffer.len(), data.len());
+					buffer.extend_from_slice(&data[..len]);
+					data = &data[len..];
+					if buffer.len() == BUFFER_SIZE {
+						let wbuffer = WriteBuffer {
+							data: WriteData::Pooled(buffer),
+							offset: 0,
+							close: false,
+							connection_id: self.connection_id,
+						};
+						guarded_data.write_queue.push(wbuffer);
+						buffer = guarded_data.get_buffer();
+					}
+				}
 			}
-			rem -= BUFFER_SIZE;
-			start += BUFFER_SIZE;
-			end += BUFFER_SIZE;
-			if end > data.len() {
-				end = data.len();
+			match buffer.is_empty() {
+				true => guarded_data.recycle_buffer(buffer),
+				false => {
+					let wbuffer = WriteBuffer {
+						data: WriteData::Pooled(buffer),
+						offset: 0,
+						close: false,
+						connection_id: self.connection_id,
+					};
+					guarded_data.write_queue.push(wbuffer);
+				}
 			}
+			*guarded_data
+				.pending_bytes
+				.entry(self.connection_id)
+				.or_insert(0) += len;
+			guarded_data.wakeup()
+		})
+	}
+
+	// queues a request to send the tls records that were produced while reading, such as
+	// handshake messages, behind the data already queued
+	fn flush_tls(&self) -> Result<(), Error> {
+		self.with_owner(|guarded_data| {
+			let wbuffer = WriteBuffer {
+				data: WriteData::TlsFlush,
+				offset: 0,
+				close: false,
+				connection_id: self.connection_id,
+			};
+			guarded_data.write_queue.push(wbuffer);
+			guarded_data.wakeup()
+		})
+	}
+
+	fn do_write_owned(&self, data: Arc<dyn AsRef<[u8]> + Send + Sync>) -> Result<(), Error> {
+		let len = (*data).as_ref().len();
+		if len == 0 {
+			// nothing to write
+			return Ok(());
 		}
+		self.with_owner(|guarded_data| {
+			let wbuffer = WriteBuffer {
+				data: WriteData::Owned(data),
+				offset: 0,
+				close: false,
+				connection_id: self.connection_id,
+			};
+			guarded_data.write_queue.push(wbuffer);
+			*guarded_data
+				.pending_bytes
+				.entry(self.connection_id)
+				.or_insert(0) += len;
+			guarded_data.wakeup()
+		})
+	}
+}
+
+/// A handle used to send datagrams on a [`UdpSocket`] registered with
+/// [`EventHandler::add_udp_socket`].
+///
+/// A [`DatagramHandle`] is returned by [`EventHandler::add_udp_socket`] and is also passed to the
+/// callback specified by [`EventHandler::set_on_datagram`] so that the user may reply to the peer.
+#[derive(Clone)]
+pub struct DatagramHandle {
+	fd: ConnectionHandle,
+	connection_id: u128,
+	guarded_data: Arc<RwLock<GuardedData>>,
+	global_lock: Arc<RwLock<bool>>,
+}
+
+impl DatagramHandle {
+	/// Get the connection_id associated with this datagram handle.
+	pub fn get_connection_id(&self) -> u128 {
+		self.connection_id
+	}
+
+	/// Send the specified data to `addr`. Datagrams are sent immediately rather than being
+	/// queued. If the socket's send buffer is full, an i/o error of kind
+	/// [`std::io::ErrorKind::WouldBlock`] is returned and the datagram is not sent.
+	/// On success, the number of bytes sent is returned.
+	///
+	/// * `data` - The datagram to send.
+	/// * `addr` - The address of the peer to send the datagram to.
+	pub fn send_to(&self, data: &[u8], addr: SocketAddr) -> Result<usize, Error> {
+		let _lock = nioruntime_util::lockr!(self.global_lock)?;
+		let socket = udp_socket(self.fd);
+		Ok(socket.send_to(data, addr)?)
+	}
+
+	/// Stop handling datagrams for the socket associated with this handle. The callback
+	/// specified by [`EventHandler::set_on_close`] will be executed once it is removed. The
+	/// socket itself is not closed because it is owned by the caller of
+	/// [`EventHandler::add_udp_socket`]. It is closed when that [`UdpSocket`] is dropped.
+	pub fn close(&self) -> Result<(), Error> {
+		let mut guarded_data = nioruntime_util::lockw!(self.guarded_data)?;
+		let wbuffer = WriteBuffer {
+			data: WriteData::Empty,
+			offset: 0,
+			close: true,
+			connection_id: self.connection_id,
+		};
+		guarded_data.write_queue.push(wbuffer);
 		guarded_data.wakeup()?;
 
 		Ok(())
 	}
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct TlsConfig {
 	/// The location of the private_key file (privkey.pem).
 	pub private_key_file: String,
 	/// The location of the certificates file (fullchain.pem).
 	pub certificates_file: String,
+	/// Additional certificates which are selected based on the hostname that the client
+	/// requests via SNI. Clients that do not send a hostname, or request one that is not in
+	/// this list, are served the certificate specified above. The default value is an empty list.
+	pub sni_certificates: Vec<SniCertificate>,
+	/// The location of a file containing the certificates of the certificate authorities that
+	/// client certificates are verified against. If specified, clients are asked to present a
+	/// certificate and the verified chain is available via [`WriteHandle::peer_certificates`].
+	/// If not specified, client certificates are not requested. The default value is None.
+	pub client_ca_file: Option<String>,
+	/// If true, clients that do not present a valid certificate are rejected. Otherwise, clients
+	/// that do not present a certificate are accepted as well. This value is only used if
+	/// [`TlsConfig::client_ca_file`] is specified. The default value is false.
+	pub client_auth_required: bool,
+	/// The ALPN protocols supported by the server in order of preference, for example
+	/// "h2" and "http/1.1". The protocol agreed on with a client is available via
+	/// [`WriteHandle::alpn_protocol`]. Clients that don't share a protocol with the server
+	/// are rejected. If empty, ALPN is not used. The default value is an empty list.
+	pub alpn_protocols: Vec<String>,
 }
 
 impl TlsConfig {
@@ -326,64 +722,522 @@ impl TlsConfig {
 		TlsConfig {
 			private_key_file,
 			certificates_file,
+			sni_certificates: vec![],
+			client_ca_file: None,
+			client_auth_required: false,
+			alpn_protocols: vec![],
 		}
 	}
 }
 
-fn make_config(
-	trusted_cert_full_chain_file: Option<&str>,
-) -> Result<Arc<rustls::ClientConfig>, Error> {
-	let mut root_store = RootCertStore::empty();
-	//root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0);
-	match trusted_cert_full_chain_file {
-		Some(trusted_cert_full_chain_file) => {
-			let full_chain_certs = load_certs(trusted_cert_full_chain_file);
-			for i in 0..full_chain_certs.len() {
-				root_store.add(&full_chain_certs[i]).map_err(|e| {
-					let error: Error = ErrorKind::SetupError(format!(
-						"adding certificate to root store generated error: {}",
-						e.to_string()
-					))
-					.into();
-					error
-				})?;
-			}
+/// A certificate and private key that are presented to the server when a TLS connection is
+/// established. See [`TlsClientConfig::client_certificate`].
+#[derive(Clone, Debug)]
+pub struct ClientCertificate {
+	/// The location of the private_key file (privkey.pem).
+	pub private_key_file: String,
+	/// The location of the certificates file (fullchain.pem).
+	pub certificates_file: String,
+}
+
+impl ClientCertificate {
+	pub fn new(private_key_file: String, certificates_file: String) -> Self {
+		ClientCertificate {
+			private_key_file,
+			certificates_file,
 		}
-		None => {}
 	}
+}
 
-	let config = ClientConfig::builder()
-		.with_safe_default_cipher_suites()
-		.with_safe_default_kx_groups()
-		.with_safe_default_protocol_versions()
-		.unwrap()
-		.with_root_certificates(root_store)
-		.with_no_client_auth();
-
-	Ok(Arc::new(config))
+/// The configuration of a TLS client connection. See [`EventHandler::add_tls_stream_with_config`].
+#[derive(Clone, Debug, Default)]
+pub struct TlsClientConfig {
+	/// The location of a file containing the certificates that the server's certificate is
+	/// verified against. The default value is None.
+	pub trusted_certificate: Option<String>,
+	/// The certificate presented to servers that request one, see [`TlsConfig::client_ca_file`].
+	/// The default value is None.
+	pub client_certificate: Option<ClientCertificate>,
+	/// The ALPN protocols offered to the server in order of preference. The protocol selected
+	/// by the server is available via [`WriteHandle::alpn_protocol`]. If empty, ALPN is not
+	/// used. The default value is an empty list.
+	pub alpn_protocols: Vec<String>,
 }
 
+/// A certificate served to clients that request `hostname` via SNI.
+/// See [`TlsConfig::sni_certificates`].
 #[derive(Clone, Debug)]
-pub struct EventHandlerConfig {
-	/// Number of threads for handling read/write operations. The default value is 6.
-	pub thread_count: usize,
-	/// The optional TLS config. If not specified, the server will run in non-ssl
-	/// mode.
-	pub tls_config: Option<TlsConfig>,
+pub struct SniCertificate {
+	/// The hostname that this certificate is served for.
+	pub hostname: String,
+	/// The location of the private_key file (privkey.pem).
+	pub private_key_file: String,
+	/// The location of the certificates file (fullchain.pem).
+	pub certificates_file: String,
 }
 
-impl Default for EventHandlerConfig {
-	fn default() -> EventHandlerConfig {
-		EventHandlerConfig {
-			thread_count: 6,
-			tls_config: None,
+impl SniCertificate {
+	pub fn new(hostname: String, private_key_file: String, certificates_file: String) -> Self {
+		SniCertificate {
+			hostname,
+			private_key_file,
+			certificates_file,
 		}
 	}
 }
 
-/// EventHandler struct.
-///
-/// The EventHandler provides a simple interface for registering [`TcpStream`]'s and [`TcpListener`]'s such
+/// The TLS config of a listener and the [`ServerConfig`] built from it. The [`ServerConfig`] is
+/// replaced by [`EventHandler::reload_tls`].
+struct ListenerTls {
+	tls_config: TlsConfig,
+	server_config: Arc<ServerConfig>,
+}
+
+/// Selects a certificate based on the SNI hostname sent by the client.
+struct SniResolver {
+	default: Arc<CertifiedKey>,
+	certificates: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for SniResolver {
+	fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+		let certificate = match client_hello.server_name() {
+			Some(server_name) => self.certificates.get(&server_name.to_lowercase()),
+			None => None,
+		};
+		match certificate {
+			Some(certificate) => Some(certificate.clone()),
+			None => Some(self.default.clone()),
+		}
+	}
+}
+
+fn certified_key(
+	private_key_file: &str,
+	certificates_file: &str,
+) -> Result<Arc<CertifiedKey>, Error> {
+	let key = any_supported_type(&load_private_key(private_key_file)?).map_err(|e| {
+		let error: Error = ErrorKind::TLSError(format!(
+			"unsupported private key in {}: {}",
+			private_key_file, e
+		))
+		.into();
+		error
+	})?;
+	Ok(Arc::new(CertifiedKey::new(
+		load_certs(certificates_file)?,
+		key,
+	)))
+}
+
+fn make_root_store(certificates_file: &str) -> Result<RootCertStore, Error> {
+	let mut root_store = RootCertStore::empty();
+	let full_chain_certs = load_certs(certificates_file)?;
+	for certificate in &full_chain_certs {
+		root_store.add(certificate).map_err(|e| {
+			let error: Error = ErrorKind::SetupError(format!(
+				"adding certificate to root store generated error: {}",
+				e
+			))
+			.into();
+			error
+		})?;
+	}
+	Ok(root_store)
+}
+
+fn make_server_config(tls_config: &TlsConfig) -> Result<ServerConfig, Error> {
+	let builder = ServerConfig::builder().with_safe_defaults();
+	let builder = match &tls_config.client_ca_file {
+		Some(client_ca_file) => {
+			let root_store = make_root_store(client_ca_file)?;
+			match tls_config.client_auth_required {
+				true => {
+					builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(root_store))
+				}
+				false => builder.with_client_cert_verifier(
+					AllowAnyAnonymousOrAuthenticatedClient::new(root_store),
+				),
+			}
+		}
+		None => builder.with_no_client_auth(),
+	};
+
+	let mut config = match tls_config.sni_certificates.is_empty() {
+		true => builder
+			.with_single_cert(
+				load_certs(&tls_config.certificates_file)?,
+				load_private_key(&tls_config.private_key_file)?,
+			)
+			.map_err(|e| {
+				let error: Error =
+					ErrorKind::TLSError(format!("Error loading tls configurations: {}", e)).into();
+				error
+			})?,
+		false => {
+			let mut certificates = HashMap::new();
+			for sni_certificate in &tls_config.sni_certificates {
+				certificates.insert(
+					sni_certificate.hostname.to_lowercase(),
+					certified_key(
+						&sni_certificate.private_key_file,
+						&sni_certificate.certificates_file,
+					)?,
+				);
+			}
+			let resolver = SniResolver {
+				default: certified_key(
+					&tls_config.private_key_file,
+					&tls_config.certificates_file,
+				)?,
+				certificates,
+			};
+			builder.with_cert_resolver(Arc::new(resolver))
+		}
+	};
+	config.alpn_protocols = alpn_protocols(&tls_config.alpn_protocols);
+
+	Ok(config)
+}
+
+fn alpn_protocols(protocols: &[String]) -> Vec<Vec<u8>> {
+	protocols.iter().map(|p| p.as_bytes().to_vec()).collect()
+}
+
+fn make_config(tls_client_config: &TlsClientConfig) -> Result<Arc<rustls::ClientConfig>, Error> {
+	//root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0);
+	let root_store = match &tls_client_config.trusted_certificate {
+		Some(trusted_cert_full_chain_file) => make_root_store(trusted_cert_full_chain_file)?,
+		None => RootCertStore::empty(),
+	};
+
+	let builder = ClientConfig::builder()
+		.with_safe_default_cipher_suites()
+		.with_safe_default_kx_groups()
+		.with_safe_default_protocol_versions()
+		.unwrap()
+		.with_root_certificates(root_store);
+
+	let mut config = match &tls_client_config.client_certificate {
+		Some(client_certificate) => builder
+			.with_single_cert(
+				load_certs(&client_certificate.certificates_file)?,
+				load_private_key(&client_certificate.private_key_file)?,
+			)
+			.map_err(|e| {
+				let error: Error =
+					ErrorKind::TLSError(format!("Error loading client certificate: {}", e)).into();
+				error
+			})?,
+		None => builder.with_no_client_auth(),
+	};
+	config.alpn_protocols = alpn_protocols(&tls_client_config.alpn_protocols);
+
+	Ok(Arc::new(config))
+}
+
+/// A handler for the events of the connections of an [`EventHandler`].
+///
+/// This trait is an alternative to setting each callback individually with
+/// [`EventHandler::set_on_read`], [`EventHandler::set_on_accept`], etc. All methods have a
+/// default implementation that does nothing, so only the events of interest need to be
+/// implemented. A handler is registered with [`EventHandler::set_handler`] or
+/// [`EventHandler::with_handler`].
+///
+/// # Examples
+/// ```
+/// use nioruntime_evh::{ConnectionHandler, EventHandler, EventHandlerConfig, WriteHandle};
+/// use nioruntime_err::Error;
+/// use std::sync::Arc;
+///
+/// struct Echo {}
+///
+/// impl ConnectionHandler for Echo {
+///     fn on_read(&self, buf: &[u8], len: usize, wh: WriteHandle) -> Result<(), Error> {
+///         wh.write(&buf[0..len])
+///     }
+/// }
+///
+/// fn main() -> Result<(), Error> {
+///     let mut eh = EventHandler::with_handler(EventHandlerConfig::default(), Arc::new(Echo {}))?;
+///     eh.start()?;
+///     Ok(())
+/// }
+/// ```
+pub trait ConnectionHandler: Send + Sync {
+	/// See [`EventHandler::set_on_accept`].
+	fn on_accept(&self, _connection_id: u128, _wh: WriteHandle) -> Result<(), Error> {
+		Ok(())
+	}
+
+	/// See [`EventHandler::set_on_read`].
+	fn on_read(&self, _buf: &[u8], _len: usize, _wh: WriteHandle) -> Result<(), Error> {
+		Ok(())
+	}
+
+	/// See [`EventHandler::set_on_close`].
+	fn on_close(&self, _connection_id: u128) -> Result<(), Error> {
+		Ok(())
+	}
+
+	/// See [`EventHandler::set_on_client_read`].
+	fn on_client_read(&self, _buf: &[u8], _len: usize, _wh: WriteHandle) -> Result<(), Error> {
+		Ok(())
+	}
+
+	/// See [`EventHandler::set_on_writable`].
+	fn on_writable(&self, _wh: WriteHandle) -> Result<(), Error> {
+		Ok(())
+	}
+
+	/// See [`EventHandler::set_on_timeout`].
+	fn on_timeout(&self, _wh: WriteHandle) -> Result<(), Error> {
+		Ok(())
+	}
+
+	/// See [`EventHandler::set_on_read_eof`]. Unlike the other methods, this one returns
+	/// whether the end of the stream was handled, since a handler can't be registered for only
+	/// some of the events. Returning `Ok(false)` closes the connection as is done when no
+	/// on_read_eof callback is set, which is what the default implementation does.
+	fn on_read_eof(&self, _wh: WriteHandle) -> Result<bool, Error> {
+		Ok(false)
+	}
+
+	/// See [`EventHandler::set_on_datagram`].
+	fn on_datagram(
+		&self,
+		_buf: &[u8],
+		_len: usize,
+		_peer: SocketAddr,
+		_dh: DatagramHandle,
+	) -> Result<(), Error> {
+		Ok(())
+	}
+
+	/// See [`EventHandler::set_on_connect`].
+	fn on_connect(
+		&self,
+		_connection_id: u128,
+		_wh: Result<WriteHandle, Error>,
+	) -> Result<(), Error> {
+		Ok(())
+	}
+
+	/// See [`EventHandler::set_on_reject`].
+	fn on_reject(
+		&self,
+		_peer_addr: Option<SocketAddr>,
+		_reason: RejectReason,
+	) -> Result<(), Error> {
+		Ok(())
+	}
+}
+
+impl
+	EventHandler<
+		fn(&[u8], usize, WriteHandle) -> Result<(), Error>,
+		fn(u128, WriteHandle) -> Result<(), Error>,
+		fn(u128) -> Result<(), Error>,
+		fn(&[u8], usize, WriteHandle) -> Result<(), Error>,
+	>
+{
+	/// Create a new instance of the [`EventHandler`] with all callbacks handled by `handler`.
+	/// See [`ConnectionHandler`] and [`EventHandler::set_handler`].
+	pub fn with_handler(
+		config: EventHandlerConfig,
+		handler: Arc<dyn ConnectionHandler>,
+	) -> Result<Self, Error> {
+		let mut eh = Self::new(config);
+		eh.set_handler(handler)?;
+		Ok(eh)
+	}
+}
+
+/// A summary of the connections that could not be shut down cleanly by
+/// [`EventHandler::stop_graceful`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ShutdownSummary {
+	/// The connection_ids of the connections that still had data queued for writing when the
+	/// deadline passed. These connections were closed without writing the remaining data. Also
+	/// includes the connections that were closed without executing on_close because the thread
+	/// that executes it was unresponsive.
+	pub force_closed: Vec<u128>,
+	/// The total number of bytes that were queued for writing on the force closed connections.
+	pub unsent_bytes: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct EventHandlerConfig {
+	/// Number of threads for handling read/write operations. The default value is 6.
+	pub thread_count: usize,
+	/// The optional TLS config. If not specified, the server will run in non-ssl
+	/// mode. This config is used by listeners added with [`EventHandler::add_tcp_listener`].
+	/// Use [`EventHandler::add_tcp_listener_with_tls`] to specify a different config per listener.
+	pub tls_config: Option<TlsConfig>,
+	/// The maximum number of bytes that may be queued for writing on a single connection
+	/// before [`WriteHandle::write`] returns [`ErrorKind::WriteQueueFull`]. If not specified,
+	/// the write queue is unbounded. The default value is None.
+	pub write_queue_high_water: Option<usize>,
+	/// Once a connection's write queue has hit the high-water mark, the callback specified by
+	/// [`EventHandler::set_on_writable`] is executed when the number of queued bytes drops to
+	/// this value or less. The default value is 0.
+	pub write_queue_low_water: usize,
+	/// The maximum number of write buffers that each thread keeps for reuse. Data written with
+	/// [`WriteHandle::write`] is copied into buffers of 10 KB taken from this pool, which are
+	/// returned to it once the data has been written. The default value is 128.
+	pub buffer_pool_size: usize,
+	/// The size of the buffer used for each read from a connection. This is the maximum number
+	/// of bytes passed to a single on_read callback for plaintext connections. The default value
+	/// is 10,240.
+	pub read_buffer_size: usize,
+	/// The maximum number of events returned each time a thread polls for events. The default
+	/// value is 100.
+	pub max_events: usize,
+	/// The [`TriggerMode`] used to register for read events on connections. The default value
+	/// is [`TriggerMode::Edge`].
+	pub read_trigger_mode: TriggerMode,
+	/// The maximum number of bytes read from a single connection each time its thread polls for
+	/// events. Once it is reached, the events of other connections are handled before more data
+	/// is read from the connection, so that one busy connection can't starve the others. If not
+	/// specified, each connection is read until no more data is available. The default value is
+	/// None.
+	pub max_read_per_poll: Option<usize>,
+	/// The [`Backend`] used to wait for events on sockets. The default value is
+	/// [`Backend::Native`].
+	pub backend: Backend,
+	/// The maximum number of connections accepted by the listeners that may be open at the same
+	/// time. Connections accepted beyond this limit are closed immediately and reported to the
+	/// callback specified by [`EventHandler::set_on_reject`]. If not specified, the number of
+	/// connections is unbounded. The default value is None.
+	pub max_connections: Option<usize>,
+	/// The maximum number of connections from a single IP address that may be open at the same
+	/// time. Connections beyond this limit are rejected as with
+	/// [`EventHandlerConfig::max_connections`]. The default value is None.
+	pub max_connections_per_ip: Option<usize>,
+	/// The maximum number of connections accepted per second. Once it is reached, the listeners
+	/// stop accepting until the next second and new connections wait in the listen backlog.
+	/// Connections rejected because of the other limits don't count towards the rate. The
+	/// default value is None.
+	pub max_accept_rate: Option<usize>,
+	/// The [`PlacementStrategy`] used to assign new connections to the r/w threads. The default
+	/// value is [`PlacementStrategy::RoundRobin`].
+	pub placement_strategy: PlacementStrategy,
+	/// The [`SocketOptions`] applied to the tcp listeners, the connections accepted on them and
+	/// the outbound streams. Use [`EventHandler::add_tcp_stream_with_options`] or
+	/// [`EventHandler::connect_with_options`] to specify different options for a single stream.
+	/// The default value leaves all options at the system defaults.
+	pub socket_options: SocketOptions,
+}
+
+impl Default for EventHandlerConfig {
+	fn default() -> EventHandlerConfig {
+		EventHandlerConfig {
+			thread_count: 6,
+			tls_config: None,
+			write_queue_high_water: None,
+			write_queue_low_water: 0,
+			buffer_pool_size: 128,
+			read_buffer_size: 10 * 1024,
+			max_events: 100,
+			read_trigger_mode: TriggerMode::Edge,
+			max_read_per_poll: None,
+			backend: Backend::Native,
+			max_connections: None,
+			max_connections_per_ip: None,
+			max_accept_rate: None,
+			placement_strategy: PlacementStrategy::RoundRobin,
+			socket_options: SocketOptions::default(),
+		}
+	}
+}
+
+/// The socket options applied to the tcp sockets of the [`EventHandler`]. See
+/// [`EventHandlerConfig::socket_options`]. Options that are None are left at the system default.
+/// On windows, the keepalive idle time, interval and count and tcp fast open are not supported
+/// and are ignored, and the send buffer size defaults to 100,000,000 bytes.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SocketOptions {
+	/// Enables or disables Nagle's algorithm (TCP_NODELAY). Some(true) disables it so that small
+	/// writes are sent immediately.
+	pub tcp_nodelay: Option<bool>,
+	/// Enables or disables keepalive probes (SO_KEEPALIVE).
+	pub keepalive: Option<bool>,
+	/// The time a connection must be idle before the first keepalive probe is sent
+	/// (TCP_KEEPIDLE, or TCP_KEEPALIVE on macos). It is rounded up to whole seconds and is at
+	/// least one second.
+	pub keepalive_idle: Option<Duration>,
+	/// The time between keepalive probes (TCP_KEEPINTVL). It is rounded up to whole seconds
+	/// and is at least one second.
+	pub keepalive_interval: Option<Duration>,
+	/// The number of unanswered keepalive probes after which the connection is dropped
+	/// (TCP_KEEPCNT).
+	pub keepalive_count: Option<u32>,
+	/// The size of the socket's send buffer in bytes (SO_SNDBUF).
+	pub send_buffer_size: Option<usize>,
+	/// The size of the socket's receive buffer in bytes (SO_RCVBUF).
+	pub recv_buffer_size: Option<usize>,
+	/// If specified, closing the socket blocks until the queued data has been sent or the
+	/// duration has elapsed (SO_LINGER). The duration is rounded up to whole seconds and a
+	/// duration of 0 resets the connection on close. Note that on linux the close blocks even
+	/// though the socket is non-blocking, so the r/w thread closing the connection stalls for
+	/// up to this duration and no other connection on that thread is served meanwhile.
+	pub linger: Option<Duration>,
+	/// Enables tcp fast open (TCP_FASTOPEN). For listeners, this is the maximum number of
+	/// pending fast open requests. For the connections opened with [`EventHandler::connect`] on
+	/// linux, any value enables TCP_FASTOPEN_CONNECT. It has no effect on other sockets.
+	pub tcp_fastopen: Option<u32>,
+}
+
+/// The mechanism used by the [`EventHandler`]'s threads to wait for events on their sockets.
+/// See [`EventHandlerConfig::backend`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+	/// epoll on linux, kqueues on BSD variants and wepoll on windows.
+	Native,
+	/// io_uring, which is only available on linux 5.6 or later. Each thread submits the
+	/// reads, writes and accepts of its tcp connections and listeners to its own ring and
+	/// handles their completions instead of waiting for readiness and making a system call for
+	/// each of them. [`EventHandler::start`] returns an error on other platforms.
+	IoUring,
+}
+
+/// How readiness to read is reported for a connection. See [`EventHandlerConfig::read_trigger_mode`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TriggerMode {
+	/// A read event is only reported when new data arrives. This requires fewer system calls.
+	Edge,
+	/// A read event is reported for as long as there is data to read.
+	Level,
+}
+
+/// How new connections are assigned to the r/w threads. See
+/// [`EventHandlerConfig::placement_strategy`]. Connections may also be moved to another thread
+/// after they were assigned with [`EventHandler::migrate`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PlacementStrategy {
+	/// Each new connection is assigned to the next thread in turn.
+	RoundRobin,
+	/// Each new connection is assigned to the thread with the fewest connections.
+	LeastConnections,
+	/// Connections are assigned by a hash of the peer's IP address, so that all connections from
+	/// the same host are handled by the same thread. Connections without a known peer address,
+	/// such as unix domain sockets, are assigned as with [`PlacementStrategy::RoundRobin`].
+	PeerAddressHash,
+}
+
+/// The reason a connection was rejected by a listener. See [`EventHandler::set_on_reject`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RejectReason {
+	/// [`EventHandlerConfig::max_connections`] connections were already open.
+	MaxConnections,
+	/// [`EventHandlerConfig::max_connections_per_ip`] connections were already open from the
+	/// peer's IP address.
+	MaxConnectionsPerIp,
+}
+
+/// EventHandler struct.
+///
+/// The EventHandler provides a simple interface for registering [`TcpStream`]'s and [`TcpListener`]'s such
 /// that when data is available on any of the sockets, a user defined callback will be executed. Data
 /// may also be written back to the sockets inband or out of band. The interface uses
 /// [epoll](https://man7.org/linux/man-pages/man7/epoll.7.html) on linux,
@@ -466,12 +1320,18 @@ impl Default for EventHandlerConfig {
 pub struct EventHandler<F, G, H, K> {
 	config: EventHandlerConfig,
 	guarded_data: Vec<Arc<RwLock<GuardedData>>>,
-	callbacks: Arc<RwLock<Callbacks<F, G, H, K>>>,
+	callbacks: Arc<RwLock<Callbacks>>,
 	global_lock: Arc<RwLock<bool>>,
 	on_panic: Option<OnPanic>,
-	tls_server_config: Option<ServerConfig>,
+	listener_tls: Arc<RwLock<HashMap<ConnectionHandle, ListenerTls>>>,
+	thread_handles: Vec<JoinHandle<()>>,
+	// the selector of the listener thread, set once the EventHandler is started
+	listener_selector: Option<Arc<dyn Selector>>,
+	next_index: usize,
+	reuse_port_listeners: Vec<TcpListener>,
 	_pipe_listener: Vec<Option<TcpListener>>,
 	_pipe_stream: Vec<Option<TcpStream>>,
+	_phantom_data: PhantomData<(F, G, H, K)>,
 }
 
 impl<F, G, H, K> EventHandler<F, G, H, K>
@@ -507,6 +1367,43 @@ where
 		stream: &TcpStream,
 		server_name: &str,
 		trusted_certificate: Option<&str>,
+	) -> Result<WriteHandle, Error> {
+		self.add_tls_stream_with_config(
+			stream,
+			server_name,
+			TlsClientConfig {
+				trusted_certificate: trusted_certificate.map(|t| t.to_string()),
+				..TlsClientConfig::default()
+			},
+		)
+	}
+
+	/// Add a [`TcpStream`] to this EventHandler as a TLS client using the specified
+	/// [`TlsClientConfig`], for example to present a client certificate to servers that require
+	/// mutual TLS authentication or to negotiate an ALPN protocol. Otherwise, this function
+	/// behaves the same as [`EventHandler::add_tls_stream`].
+	pub fn add_tls_stream_with_config(
+		&mut self,
+		stream: &TcpStream,
+		server_name: &str,
+		tls_client_config: TlsClientConfig,
+	) -> Result<WriteHandle, Error> {
+		let socket_options = self.config.socket_options.clone();
+		self.add_tls_stream_with_options(stream, server_name, tls_client_config, &socket_options)
+	}
+
+	/// Add a [`TcpStream`] to this EventHandler as a TLS client with its own socket options.
+	///
+	/// This function behaves exactly like [`EventHandler::add_tls_stream_with_config`] except
+	/// that `socket_options` are applied to the stream instead of
+	/// [`EventHandlerConfig::socket_options`]. This function will result in an error if one of
+	/// the options could not be set.
+	pub fn add_tls_stream_with_options(
+		&mut self,
+		stream: &TcpStream,
+		server_name: &str,
+		tls_client_config: TlsClientConfig,
+		socket_options: &SocketOptions,
 	) -> Result<WriteHandle, Error> {
 		// make sure we have a client on_read handler configured
 		{
@@ -524,49 +1421,30 @@ where
 		}
 
 		stream.set_nonblocking(true)?;
-
+		#[cfg(unix)]
+		apply_socket_options(stream.as_raw_fd(), socket_options, false)?;
 		#[cfg(target_os = "windows")]
-		{
-			let fd = stream.as_raw_socket();
-
-			let sockoptres = unsafe {
-				ws2_32::setsockopt(
-					fd,
-					winapi::SOL_SOCKET,
-					winapi::SO_SNDBUF,
-					&WINSOCK_BUF_SIZE as *const _ as *const i8,
-					std::mem::size_of_val(&WINSOCK_BUF_SIZE) as winapi::c_int,
-				)
-			};
-
-			if sockoptres != 0 {
-				log_multi!(
-					ERROR,
-					MAIN_LOG,
-					"setsockopt resulted in error: {}",
-					errno().to_string()
-				);
-			}
-		}
+		apply_socket_options(stream.as_raw_socket().into(), socket_options, false)?;
 
-		let config = make_config(trusted_certificate)?;
+		let config = make_config(&tls_client_config)?;
 		let tls_client = Some(Arc::new(RwLock::new(
 			ClientConnection::new(config, server_name.try_into().unwrap()).unwrap(),
 		)));
 
-		let mut rng = rand::thread_rng();
-		let gd_index: usize = rng.gen();
-		let gd_index = (gd_index % (self.guarded_data.len() - 1)) + 1;
+		#[cfg(unix)]
+		let gd_index = self.next_thread(socket_addr(stream.as_raw_fd(), true))?;
+		#[cfg(target_os = "windows")]
+		let gd_index = self.next_thread(socket_addr(stream.as_raw_socket().into(), true))?;
 
 		#[cfg(unix)]
-		let (fd, connection_id) = self.add(
+		let conn = self.add(
 			stream.as_raw_fd(),
 			ActionType::AddTlsStream,
 			tls_client.clone(),
 			gd_index,
 		)?;
 		#[cfg(target_os = "windows")]
-		let (fd, connection_id) = self.add(
+		let conn = self.add(
 			stream.as_raw_socket().into(),
 			ActionType::AddTlsStream,
 			tls_client.clone(),
@@ -575,13 +1453,16 @@ where
 
 		let callback_state = Arc::new(RwLock::new(State::Init));
 		Ok(WriteHandle {
-			fd,
-			connection_id,
+			fd: conn.handle,
+			connection_id: conn.connection_id,
 			guarded_data: self.guarded_data[gd_index].clone(),
 			_global_lock: self.global_lock.clone(),
 			callback_state,
 			tls_server: None,
 			tls_client,
+			peer_addr: conn.peer_addr,
+			local_addr: conn.local_addr,
+			attachment: conn.attachment,
 		})
 	}
 
@@ -607,51 +1488,26 @@ where
 	/// This function will result in an error if an i/o error occurs while trying to configure the stream
 	/// or the [`EventHandler`] has not been configured using the [`EventHandler::set_on_client_read`] function.
 	pub fn add_tcp_stream(&mut self, stream: &TcpStream) -> Result<WriteHandle, Error> {
-		// make sure we have a client on_read handler configured
-		{
-			let callbacks = nioruntime_util::lockr!(self.callbacks)?;
+		let socket_options = self.config.socket_options.clone();
+		self.add_tcp_stream_with_options(stream, &socket_options)
+	}
 
-			match callbacks.on_client_read {
-				Some(_) => {}
-				None => {
-					return Err(ErrorKind::SetupError(
-						"on_client_read callback must be registered first".to_string(),
-					)
-					.into());
-				}
-			}
-		}
+	/// Add a [`TcpStream`] to this EventHandler with its own socket options.
+	///
+	/// This function behaves exactly like [`EventHandler::add_tcp_stream`] except that
+	/// `socket_options` are applied to the stream instead of
+	/// [`EventHandlerConfig::socket_options`]. This function will result in an error if one of
+	/// the options could not be set.
+	pub fn add_tcp_stream_with_options(
+		&mut self,
+		stream: &TcpStream,
+		socket_options: &SocketOptions,
+	) -> Result<WriteHandle, Error> {
+		// make sure we have a client on_read handler configured
+		self.check_on_client_read()?;
 
 		stream.set_nonblocking(true)?;
 
-		#[cfg(target_os = "windows")]
-		{
-			let fd = stream.as_raw_socket();
-
-			let sockoptres = unsafe {
-				ws2_32::setsockopt(
-					fd,
-					winapi::SOL_SOCKET,
-					winapi::SO_SNDBUF,
-					&WINSOCK_BUF_SIZE as *const _ as *const i8,
-					std::mem::size_of_val(&WINSOCK_BUF_SIZE) as winapi::c_int,
-				)
-			};
-
-			if sockoptres != 0 {
-				log_multi!(
-					ERROR,
-					MAIN_LOG,
-					"setsockopt resulted in error: {}",
-					errno().to_string()
-				);
-			}
-		}
-
-		let mut rng = rand::thread_rng();
-		let gd_index: usize = rng.gen();
-		let gd_index = (gd_index % (self.guarded_data.len() - 1)) + 1;
-
 		#[cfg(any(
 			target_os = "linux",
 			target_os = "macos",
@@ -660,24 +1516,62 @@ where
 			netbsd,
 			openbsd
 		))]
-		let (fd, connection_id) = self.add(stream.as_raw_fd(), ActionType::AddStream, None, gd_index)?;
+		let handle = stream.as_raw_fd();
 		#[cfg(target_os = "windows")]
-		let (fd, connection_id) = self.add(
-			stream.as_raw_socket().into(),
-			ActionType::AddStream,
-			None,
-			gd_index,
-		)?;
+		let handle = stream.as_raw_socket().into();
+
+		apply_socket_options(handle, socket_options, false)?;
+		self.add_client_stream(handle)
+	}
+
+	/// Add a [`UnixStream`] to this EventHandler.
+	///
+	/// This function behaves exactly like [`EventHandler::add_tcp_stream`] except that it operates on a
+	/// unix domain socket. Data read on the stream is passed to the callback specified by
+	/// [`EventHandler::set_on_client_read`] and the callback specified by [`EventHandler::set_on_close`]
+	/// is executed when the stream closes. As with [`TcpStream`], the caller must keep the
+	/// [`UnixStream`] in scope and must not read or write to it directly once it is registered.
+	/// This function returns the [`WriteHandle`] that may be used to write to the socket.
+	/// This function will result in an error if an i/o error occurs while trying to configure the stream
+	/// or the [`EventHandler`] has not been configured using the [`EventHandler::set_on_client_read`] function.
+	#[cfg(unix)]
+	pub fn add_unix_stream(&mut self, stream: &UnixStream) -> Result<WriteHandle, Error> {
+		// make sure we have a client on_read handler configured
+		self.check_on_client_read()?;
+
+		stream.set_nonblocking(true)?;
+		self.add_client_stream(stream.as_raw_fd())
+	}
+
+	fn check_on_client_read(&self) -> Result<(), Error> {
+		let callbacks = nioruntime_util::lockr!(self.callbacks)?;
+
+		match callbacks.on_client_read {
+			Some(_) => Ok(()),
+			None => Err(ErrorKind::SetupError(
+				"on_client_read callback must be registered first".to_string(),
+			)
+			.into()),
+		}
+	}
+
+	fn add_client_stream(&mut self, handle: ConnectionHandle) -> Result<WriteHandle, Error> {
+		let gd_index = self.next_thread(socket_addr(handle, true))?;
+
+		let conn = self.add(handle, ActionType::AddStream, None, gd_index)?;
 
 		let callback_state = Arc::new(RwLock::new(State::Init));
 		Ok(WriteHandle {
-			fd,
-			connection_id,
+			fd: conn.handle,
+			connection_id: conn.connection_id,
 			guarded_data: self.guarded_data[gd_index].clone(),
 			_global_lock: self.global_lock.clone(),
 			callback_state,
 			tls_server: None,
 			tls_client: None,
+			peer_addr: conn.peer_addr,
+			local_addr: conn.local_addr,
+			attachment: conn.attachment,
 		})
 	}
 
@@ -700,36 +1594,331 @@ where
 	/// [`TcpListener`] itself. Calling any functions in [`TcpListener`] will result in undefined behavior.
 	/// This function will result in an error if an i/o error occurs while trying to configure the listener
 	/// or the [`EventHandler`] has not been started by calling the [`EventHandler::start`] function.
+	/// The TLS config specified in [`EventHandlerConfig::tls_config`] is used for connections
+	/// accepted on this listener.
 	pub fn add_tcp_listener(&mut self, listener: &TcpListener) -> Result<(), Error> {
+		let tls_config = self.config.tls_config.clone();
+		self.add_tcp_listener_with_tls(listener, tls_config)
+	}
+
+	/// Add a [`TcpListener`] to this EventHandler with its own TLS config.
+	///
+	/// This function behaves exactly like [`EventHandler::add_tcp_listener`] except that
+	/// connections accepted on this listener use `tls_config` instead of
+	/// [`EventHandlerConfig::tls_config`]. If `tls_config` is None, connections are accepted in
+	/// non-ssl mode. This allows a single [`EventHandler`] to serve plaintext and several TLS
+	/// configurations at the same time. This function will result in an error if the certificates
+	/// or private keys in `tls_config` cannot be loaded.
+	pub fn add_tcp_listener_with_tls(
+		&mut self,
+		listener: &TcpListener,
+		tls_config: Option<TlsConfig>,
+	) -> Result<(), Error> {
 		// must be nonblocking
 		listener.set_nonblocking(true)?;
 		#[cfg(unix)]
-		self.add(listener.as_raw_fd(), ActionType::AddListener, None, 0)?;
+		let handle = listener.as_raw_fd();
 		#[cfg(target_os = "windows")]
-		self.add(
-			listener.as_raw_socket().try_into().unwrap_or(0),
-			ActionType::AddListener,
-			None,
-			0,
-		)?;
-		Ok(())
+		let handle = listener.as_raw_socket().try_into().unwrap_or(0);
+		apply_socket_options(handle, &self.config.socket_options, true)?;
+		self.add_listener(handle, tls_config)
 	}
 
-	/// This sets the on_read callback for this [`EventHandler`].
+	/// Add a [`UnixListener`] to this EventHandler.
 	///
-	/// As described in [`EventHandler::add_tcp_listener`], this callback is executed when data is available
-	/// on a connection that has been accepted by a [`TcpListener`] that was registered with this [`EventHandler`].
-	/// # Examples
-	/// ```
-	/// use nioruntime_evh::{EventHandler, EventHandlerConfig};
-	/// use nioruntime_err::Error;
+	/// This function behaves exactly like [`EventHandler::add_tcp_listener`] except that it accepts
+	/// connections on a unix domain socket. Accepted connections are handled with the same
+	/// callbacks: [`EventHandler::set_on_accept`], [`EventHandler::set_on_read`] and
+	/// [`EventHandler::set_on_close`]. The caller must keep the [`UnixListener`] in scope and is
+	/// responsible for removing the socket file when it is no longer needed.
+	/// This function will result in an error if an i/o error occurs while trying to configure the listener
+	/// or the [`EventHandler`] has not been started by calling the [`EventHandler::start`] function.
+	#[cfg(unix)]
+	pub fn add_unix_listener(&mut self, listener: &UnixListener) -> Result<(), Error> {
+		// must be nonblocking
+		listener.set_nonblocking(true)?;
+		let tls_config = self.config.tls_config.clone();
+		self.add_listener(listener.as_raw_fd(), tls_config)
+	}
+
+	/// Bind one listener per r/w thread to `addr` with SO_REUSEPORT and add them to this
+	/// EventHandler.
 	///
-	/// fn main() -> Result<(), Error> {
-	///     let mut eh = EventHandler::new(EventHandlerConfig::default());
-	///     // set the on_read callback to simply echo back what is written to it
-	///     eh.set_on_read(|buf, len, wh| {
-	///         let _ = wh.write(&buf[0..len]);
-	///         Ok(())
+	/// Unlike the listeners added with [`EventHandler::add_tcp_listener`], which are all served
+	/// by a single listener thread, each r/w thread accepts the connections of its own listener
+	/// without taking the lock that is shared by all threads. The kernel spreads the incoming
+	/// connections between the listeners, so [`EventHandlerConfig::placement_strategy`] does not
+	/// apply to them. This increases the accept throughput for workloads with many short lived
+	/// connections. The callback specified by [`EventHandler::set_on_accept`] is executed on the
+	/// r/w thread that accepted the connection, while the connection limits and the tls config
+	/// of [`EventHandlerConfig`] apply as with the other listeners. The listeners are owned by
+	/// the [`EventHandler`] and are closed when it is dropped.
+	/// This function returns the address the listeners are bound to, which is useful if the port
+	/// of `addr` is 0. It will result in an error if the sockets could not be created or the
+	/// [`EventHandler`] has not been started by calling the [`EventHandler::start`] function.
+	#[cfg(unix)]
+	pub fn add_tcp_listener_reuse_port(&mut self, addr: SocketAddr) -> Result<SocketAddr, Error> {
+		let tls_config = self.config.tls_config.clone();
+		let socket_options = &self.config.socket_options;
+		let first = reuse_port_listener(&addr, socket_options)?;
+		// the others are bound to the port that was picked for the first one
+		let addr = first.local_addr()?;
+		let mut listeners = vec![first];
+		for _ in 1..self.config.thread_count {
+			listeners.push(reuse_port_listener(&addr, socket_options)?);
+		}
+		for (i, listener) in listeners.iter().enumerate() {
+			let handle = listener.as_raw_fd();
+			self.set_listener_tls(handle, tls_config.clone())?;
+			self.add(handle, ActionType::AddReusePortListener, None, i + 1)?;
+		}
+		self.reuse_port_listeners.append(&mut listeners);
+		Ok(addr)
+	}
+
+	fn add_listener(
+		&mut self,
+		handle: ConnectionHandle,
+		tls_config: Option<TlsConfig>,
+	) -> Result<(), Error> {
+		self.set_listener_tls(handle, tls_config)?;
+		self.add(handle, ActionType::AddListener, None, 0)?;
+		Ok(())
+	}
+
+	fn set_listener_tls(
+		&mut self,
+		handle: ConnectionHandle,
+		tls_config: Option<TlsConfig>,
+	) -> Result<(), Error> {
+		// build the tls config first so that errors are reported before the listener is added
+		let tls = match tls_config {
+			Some(tls_config) => Some(ListenerTls {
+				server_config: Arc::new(make_server_config(&tls_config)?),
+				tls_config,
+			}),
+			None => None,
+		};
+		let mut listener_tls = nioruntime_util::lockw!(self.listener_tls)?;
+		match tls {
+			Some(tls) => {
+				listener_tls.insert(handle, tls);
+			}
+			None => {
+				listener_tls.remove(&handle);
+			}
+		}
+		Ok(())
+	}
+
+	/// Add a [`UdpSocket`] to this EventHandler.
+	///
+	/// This function adds the specified [`UdpSocket`] to this [`EventHandler`]. When a datagram is
+	/// received on the socket, the callback specified by [`EventHandler::set_on_datagram`] will be
+	/// executed with the datagram, the address of the peer that sent it and a [`DatagramHandle`] which
+	/// may be used to reply. Datagrams may also be sent out of band with the returned [`DatagramHandle`].
+	/// As with [`TcpStream`], the caller must keep the [`UdpSocket`] in scope and, once registered,
+	/// should not read from it directly. Reading from the [`UdpSocket`] will result in undefined
+	/// behavior. If [`DatagramHandle::close`] is called, the callback specified by
+	/// [`EventHandler::set_on_close`] will be executed to notify the user. The [`EventHandler`]
+	/// never closes the socket, it is closed when the caller drops the [`UdpSocket`].
+	/// This function will result in an error if an i/o error occurs while trying to configure the socket
+	/// or the [`EventHandler`] has not been configured using the [`EventHandler::set_on_datagram`] function.
+	pub fn add_udp_socket(&mut self, socket: &UdpSocket) -> Result<DatagramHandle, Error> {
+		// make sure we have a datagram handler configured
+		{
+			let callbacks = nioruntime_util::lockr!(self.callbacks)?;
+
+			match callbacks.on_datagram {
+				Some(_) => {}
+				None => {
+					return Err(ErrorKind::SetupError(
+						"on_datagram callback must be registered first".to_string(),
+					)
+					.into());
+				}
+			}
+		}
+
+		socket.set_nonblocking(true)?;
+
+		let gd_index = self.next_thread(None)?;
+
+		#[cfg(unix)]
+		let handle = socket.as_raw_fd();
+		#[cfg(target_os = "windows")]
+		let handle = socket.as_raw_socket().into();
+
+		let conn = self.add(handle, ActionType::AddDatagram, None, gd_index)?;
+
+		Ok(DatagramHandle {
+			fd: conn.handle,
+			connection_id: conn.connection_id,
+			guarded_data: self.guarded_data[gd_index].clone(),
+			global_lock: self.global_lock.clone(),
+		})
+	}
+
+	/// Open an outbound tcp connection to `addr` without blocking the caller.
+	///
+	/// This function starts a non-blocking connect and registers the socket with this
+	/// [`EventHandler`]. When the connection is established, the callback specified by
+	/// [`EventHandler::set_on_connect`] is executed with a [`WriteHandle`] for the new connection.
+	/// If the connection fails or is not established within `timeout`, the callback is executed
+	/// with the error instead and the socket is closed. Data read on an established connection is
+	/// passed to the callback specified by [`EventHandler::set_on_client_read`] and the callback
+	/// specified by [`EventHandler::set_on_close`] is executed when it closes. Unlike
+	/// [`EventHandler::add_tcp_stream`], the socket is owned by the [`EventHandler`] and it is
+	/// closed by the [`EventHandler`].
+	/// This function returns the connection_id that will be passed to the on_connect callback.
+	/// It will result in an error if the socket could not be created or the [`EventHandler`] has not
+	/// been configured using the [`EventHandler::set_on_connect`] and
+	/// [`EventHandler::set_on_client_read`] functions.
+	/// This function is only available on unix platforms. On windows, connect a [`TcpStream`] and
+	/// register it with [`EventHandler::add_tcp_stream`] instead.
+	#[cfg(unix)]
+	pub fn connect(&mut self, addr: SocketAddr, timeout: Duration) -> Result<u128, Error> {
+		let socket_options = self.config.socket_options.clone();
+		self.connect_with_options(addr, timeout, &socket_options)
+	}
+
+	/// Open an outbound tcp connection to `addr` with its own socket options.
+	///
+	/// This function behaves exactly like [`EventHandler::connect`] except that `socket_options`
+	/// are applied to the socket instead of [`EventHandlerConfig::socket_options`]. The options
+	/// are set before connecting, so that [`SocketOptions::tcp_fastopen`] takes effect. This
+	/// function will result in an error if one of the options could not be set. As with
+	/// [`EventHandler::connect`], it is only available on unix platforms.
+	#[cfg(unix)]
+	pub fn connect_with_options(
+		&mut self,
+		addr: SocketAddr,
+		timeout: Duration,
+		socket_options: &SocketOptions,
+	) -> Result<u128, Error> {
+		// make sure we have the handlers configured
+		self.check_on_client_read()?;
+		{
+			let callbacks = nioruntime_util::lockr!(self.callbacks)?;
+
+			match callbacks.on_connect {
+				Some(_) => {}
+				None => {
+					return Err(ErrorKind::SetupError(
+						"on_connect callback must be registered first".to_string(),
+					)
+					.into());
+				}
+			}
+		}
+
+		let domain = match addr {
+			SocketAddr::V4(_) => libc::AF_INET,
+			SocketAddr::V6(_) => libc::AF_INET6,
+		};
+		let fd = unsafe { libc::socket(domain, libc::SOCK_STREAM, 0) };
+		if fd < 0 {
+			return Err(std::io::Error::last_os_error().into());
+		}
+
+		match apply_connect_options(fd, socket_options) {
+			Ok(_) => {}
+			Err(e) => {
+				unsafe {
+					close(fd);
+				}
+				return Err(e);
+			}
+		}
+
+		let fcntl_res = unsafe { fcntl(fd, libc::F_SETFL, libc::O_NONBLOCK) };
+		let res = if fcntl_res < 0 {
+			fcntl_res
+		} else {
+			let (storage, len) = raw_socket_addr(&addr);
+			unsafe { libc::connect(fd, &storage as *const _ as *const sockaddr, len) }
+		};
+		if res < 0 {
+			let e = std::io::Error::last_os_error();
+			if e.raw_os_error() != Some(EINPROGRESS) {
+				unsafe {
+					close(fd);
+				}
+				return Err(e.into());
+			}
+		}
+
+		let gd_index = self.next_thread(Some(addr))?;
+
+		let connection_id = self
+			.add(fd, ActionType::AddConnect, None, gd_index)?
+			.connection_id;
+
+		{
+			let mut guarded_data = nioruntime_util::lockw!(self.guarded_data[gd_index])?;
+			guarded_data.connect_timeouts.push((connection_id, timeout));
+			guarded_data.wakeup()?;
+		}
+
+		Ok(connection_id)
+	}
+
+	/// This sets all of the callbacks handled by [`ConnectionHandler`] to the methods of
+	/// `handler`, replacing any callbacks that were previously set for them. This may be used
+	/// instead of setting the callbacks one by one. Callbacks set after this call replace the
+	/// corresponding method of `handler`. See [`EventHandler::with_handler`] to create an
+	/// [`EventHandler`] without naming the types of the individual callbacks.
+	pub fn set_handler(&mut self, handler: Arc<dyn ConnectionHandler>) -> Result<(), Error> {
+		let mut callbacks = nioruntime_util::lockw!(self.callbacks)?;
+
+		let h = handler.clone();
+		callbacks.on_read = Some(Arc::new(move |buf: &[u8], len, wh| h.on_read(buf, len, wh)));
+		let h = handler.clone();
+		callbacks.on_accept = Some(Arc::new(move |connection_id, wh| {
+			h.on_accept(connection_id, wh)
+		}));
+		let h = handler.clone();
+		callbacks.on_close = Some(Arc::new(move |connection_id| h.on_close(connection_id)));
+		let h = handler.clone();
+		callbacks.on_client_read = Some(Arc::new(move |buf: &[u8], len, wh| {
+			h.on_client_read(buf, len, wh)
+		}));
+		let h = handler.clone();
+		callbacks.on_writable = Some(Arc::new(move |wh| h.on_writable(wh)));
+		let h = handler.clone();
+		callbacks.on_timeout = Some(Arc::new(move |wh| h.on_timeout(wh)));
+		let h = handler.clone();
+		callbacks.on_read_eof = Some(Arc::new(move |wh| h.on_read_eof(wh)));
+		let h = handler.clone();
+		callbacks.on_datagram = Some(Arc::new(move |buf: &[u8], len, peer, dh| {
+			h.on_datagram(buf, len, peer, dh)
+		}));
+		let h = handler.clone();
+		callbacks.on_connect = Some(Arc::new(move |connection_id, wh| {
+			h.on_connect(connection_id, wh)
+		}));
+		callbacks.on_reject = Some(Arc::new(move |peer_addr, reason| {
+			handler.on_reject(peer_addr, reason)
+		}));
+
+		Ok(())
+	}
+
+	/// This sets the on_read callback for this [`EventHandler`].
+	///
+	/// As described in [`EventHandler::add_tcp_listener`], this callback is executed when data is available
+	/// on a connection that has been accepted by a [`TcpListener`] that was registered with this [`EventHandler`].
+	/// # Examples
+	/// ```
+	/// use nioruntime_evh::{EventHandler, EventHandlerConfig};
+	/// use nioruntime_err::Error;
+	///
+	/// fn main() -> Result<(), Error> {
+	///     let mut eh = EventHandler::new(EventHandlerConfig::default());
+	///     // set the on_read callback to simply echo back what is written to it
+	///     eh.set_on_read(|buf, len, wh| {
+	///         let _ = wh.write(&buf[0..len]);
+	///         Ok(())
 	///     })?;
 	///     eh.set_on_accept(|_,_| Ok(()))?;
 	///     eh.set_on_client_read(|_,_,_| Ok(()))?;
@@ -740,7 +1929,7 @@ where
 	pub fn set_on_read(&mut self, on_read: F) -> Result<(), Error> {
 		let mut callbacks = nioruntime_util::lockw!(self.callbacks)?;
 
-		callbacks.on_read = Some(Box::pin(on_read));
+		callbacks.on_read = Some(Arc::new(on_read));
 
 		Ok(())
 	}
@@ -780,7 +1969,7 @@ where
 	pub fn set_on_accept(&mut self, on_accept: G) -> Result<(), Error> {
 		let mut callbacks = nioruntime_util::lockw!(self.callbacks)?;
 
-		callbacks.on_accept = Some(Box::pin(on_accept));
+		callbacks.on_accept = Some(Arc::new(on_accept));
 
 		Ok(())
 	}
@@ -817,7 +2006,7 @@ where
 	pub fn set_on_close(&mut self, on_close: H) -> Result<(), Error> {
 		let mut callbacks = nioruntime_util::lockw!(self.callbacks)?;
 
-		callbacks.on_close = Some(Box::pin(on_close));
+		callbacks.on_close = Some(Arc::new(on_close));
 
 		Ok(())
 	}
@@ -855,241 +2044,812 @@ where
 	pub fn set_on_client_read(&mut self, on_client_read: K) -> Result<(), Error> {
 		let mut callbacks = nioruntime_util::lockw!(self.callbacks)?;
 
-		callbacks.on_client_read = Some(Box::pin(on_client_read));
+		callbacks.on_client_read = Some(Arc::new(on_client_read));
 
 		Ok(())
 	}
 
-	/// Create a new instance of the [`EventHandler`]. Note that all callbacks must be registered
-	/// and [`EventHandler::start`] must be called before handleing events. See the example in the
-	/// [`EventHandler`] section of the documentation for a full details.
-	pub fn new(config: EventHandlerConfig) -> Self {
-		let callbacks = Callbacks {
-			on_read: None,
-			on_accept: None,
-			on_close: None,
-			on_client_read: None,
-		};
-		let callbacks = Arc::new(RwLock::new(callbacks));
+	/// This sets the on_writable callback for this [`EventHandler`].
+	///
+	/// This callback is optional. When [`EventHandlerConfig::write_queue_high_water`] is set and
+	/// a call to [`WriteHandle::write`] has returned [`ErrorKind::WriteQueueFull`], this callback
+	/// is executed with a [`WriteHandle`] for the connection once its write queue drops to
+	/// [`EventHandlerConfig::write_queue_low_water`] bytes or less. Producers can use it to resume
+	/// writing. The callback is executed on the thread that owns the connection.
+	///
+	/// # Examples
+	/// ```
+	/// use nioruntime_evh::{EventHandler, EventHandlerConfig};
+	/// use nioruntime_err::Error;
+	/// use nioruntime_log::*;
+	///
+	/// // set log level to info
+	/// info!();
+	///
+	/// fn main() -> Result<(), Error> {
+	///     let mut eh = EventHandler::new(EventHandlerConfig {
+	///         write_queue_high_water: Some(1024 * 1024),
+	///         ..EventHandlerConfig::default()
+	///     });
+	///     eh.set_on_writable(|wh| {
+	///         info!("connection {} is writable again", wh.get_connection_id());
+	///         Ok(())
+	///     })?;
+	///     eh.set_on_read(|_,_,_| Ok(()))?;
+	///     eh.set_on_client_read(|_,_,_| Ok(()))?;
+	///     eh.set_on_accept(|_,_| Ok(()))?;
+	///     eh.set_on_close(|_| Ok(()))?;
+	///     Ok(())
+	/// }
+	/// ```
+	pub fn set_on_writable<W>(&mut self, on_writable: W) -> Result<(), Error>
+	where
+		W: Fn(WriteHandle) -> Result<(), Error> + Send + Sync + 'static,
+	{
+		let mut callbacks = nioruntime_util::lockw!(self.callbacks)?;
 
-		let mut guarded_data = vec![];
-		for _ in 0..config.thread_count + 1 {
-			guarded_data.push(Arc::new(RwLock::new(GuardedData {
-				nconns: vec![],
-				write_queue: vec![],
-				aconns: vec![],
-				cconns: vec![],
-				wakeup_tx: 0,
-				wakeup_rx: 0,
-				wakeup_scheduled: false,
-				stop: false,
-				callback_state: Arc::new(RwLock::new(State::Init)),
-			})));
-		}
+		callbacks.on_writable = Some(Arc::new(on_writable));
 
-		let global_lock = Arc::new(RwLock::new(true));
+		Ok(())
+	}
 
-		let tls_server_config = if config.tls_config.is_some() {
-			let tls_config = config.tls_config.as_ref().unwrap();
-			match ServerConfig::builder()
-				.with_safe_defaults()
-				.with_no_client_auth()
-				.with_single_cert(
-					load_certs(&tls_config.certificates_file),
-					load_private_key(&tls_config.private_key_file),
-				) {
-				Ok(tls_server_config) => Some(tls_server_config),
-				Err(e) => {
-					panic!("Error loading tls configurations: {}", e.to_string());
-				}
-			}
-		} else {
-			None
-		};
+	/// This sets the on_timeout callback for this [`EventHandler`].
+	///
+	/// This callback is optional. It is executed with a [`WriteHandle`] for the connection when
+	/// an idle timeout set by [`WriteHandle::set_timeout`] expires. The callback is executed on
+	/// the thread that owns the connection, so it may be used to close idle connections or send
+	/// keepalive messages.
+	///
+	/// # Examples
+	/// ```
+	/// use nioruntime_evh::{EventHandler, EventHandlerConfig};
+	/// use nioruntime_err::Error;
+	///
+	/// fn main() -> Result<(), Error> {
+	///     let mut eh = EventHandler::new(EventHandlerConfig::default());
+	///     // close connections that have been idle for 30 seconds
+	///     eh.set_on_accept(|_, wh| {
+	///         wh.set_timeout(std::time::Duration::from_secs(30))?;
+	///         Ok(())
+	///     })?;
+	///     eh.set_on_timeout(|wh| {
+	///         wh.close()?;
+	///         Ok(())
+	///     })?;
+	///     eh.set_on_read(|_,_,_| Ok(()))?;
+	///     eh.set_on_client_read(|_,_,_| Ok(()))?;
+	///     eh.set_on_close(|_| Ok(()))?;
+	///     Ok(())
+	/// }
+	/// ```
+	pub fn set_on_timeout<T>(&mut self, on_timeout: T) -> Result<(), Error>
+	where
+		T: Fn(WriteHandle) -> Result<(), Error> + Send + Sync + 'static,
+	{
+		let mut callbacks = nioruntime_util::lockw!(self.callbacks)?;
 
-		EventHandler {
-			config,
-			guarded_data,
-			callbacks,
-			global_lock,
-			on_panic: None,
-			tls_server_config,
-			_pipe_listener: vec![],
-			_pipe_stream: vec![],
-		}
+		callbacks.on_timeout = Some(Arc::new(on_timeout));
+
+		Ok(())
 	}
 
-	/// Start the event handler.
-	pub fn start(&mut self) -> Result<(), Error> {
-		for _ in 0..self.guarded_data.len() {
-			self._pipe_listener.push(None);
-			self._pipe_stream.push(None);
-		}
-		for i in 0..self.guarded_data.len() {
-			let (rx, tx) = self.build_pipe(i)?;
-			let mut guarded_data = nioruntime_util::lockw!(self.guarded_data[i])?;
-			guarded_data.wakeup_tx = tx;
-			guarded_data.wakeup_rx = rx;
-		}
-		self.do_start()?;
+	/// This sets the on_read_eof callback for this [`EventHandler`].
+	///
+	/// This callback is optional. By default, a connection is closed as soon as the peer shuts
+	/// down its side of it, and the callback specified by [`EventHandler::set_on_close`] is
+	/// executed. If this callback is set, it is executed with a [`WriteHandle`] for the
+	/// connection instead and the connection stays open, so that data may still be written to
+	/// it. This is useful for protocols in which the peer signals the end of a request by
+	/// shutting down its write side. The connection must be closed with [`WriteHandle::close`]
+	/// or [`WriteHandle::shutdown_write`] once the response has been written. Connections whose
+	/// write side was already shut down are closed without executing this callback.
+	///
+	/// # Examples
+	/// ```
+	/// use nioruntime_evh::{EventHandler, EventHandlerConfig};
+	/// use nioruntime_err::Error;
+	///
+	/// fn main() -> Result<(), Error> {
+	///     let mut eh = EventHandler::new(EventHandlerConfig::default());
+	///     // respond once the whole request has been received
+	///     eh.set_on_read_eof(|wh| {
+	///         wh.write(b"done")?;
+	///         wh.shutdown_write()?;
+	///         Ok(())
+	///     })?;
+	///     eh.set_on_read(|_,_,_| Ok(()))?;
+	///     eh.set_on_accept(|_,_| Ok(()))?;
+	///     eh.set_on_client_read(|_,_,_| Ok(()))?;
+	///     eh.set_on_close(|_| Ok(()))?;
+	///     Ok(())
+	/// }
+	/// ```
+	pub fn set_on_read_eof<E>(&mut self, on_read_eof: E) -> Result<(), Error>
+	where
+		E: Fn(WriteHandle) -> Result<(), Error> + Send + Sync + 'static,
+	{
+		let mut callbacks = nioruntime_util::lockw!(self.callbacks)?;
+
+		callbacks.on_read_eof = Some(Arc::new(move |wh| on_read_eof(wh).map(|_| true)));
 
 		Ok(())
 	}
 
-	/// Stop the event handler and free any internal resources associated with it. Note: this
-	/// does not close any registered sockets. That is the responsibility of the user.
-	pub fn stop(&self) -> Result<(), Error> {
-		for i in 0..self.guarded_data.len() {
-			let mut guarded_data = nioruntime_util::lockw!(self.guarded_data[i])?;
-			guarded_data.stop = true;
-			guarded_data.wakeup()?;
-		}
+	/// This sets the on_datagram callback for this [`EventHandler`].
+	///
+	/// As described in [`EventHandler::add_udp_socket`], this callback is executed when a datagram
+	/// is received on a [`UdpSocket`] that has been registered with this [`EventHandler`]. The
+	/// parameters are the buffer, the length of the datagram, the address of the peer that sent it
+	/// and a [`DatagramHandle`] that may be used to reply.
+	///
+	/// # Examples
+	/// ```
+	/// use nioruntime_evh::{EventHandler, EventHandlerConfig};
+	/// use nioruntime_err::Error;
+	///
+	/// fn main() -> Result<(), Error> {
+	///     let mut eh = EventHandler::new(EventHandlerConfig::default());
+	///     // echo each datagram back to the peer that sent it
+	///     eh.set_on_datagram(|buf, len, peer, dh| {
+	///         dh.send_to(&buf[0..len], peer)?;
+	///         Ok(())
+	///     })?;
+	///     eh.set_on_read(|_,_,_| Ok(()))?;
+	///     eh.set_on_accept(|_,_| Ok(()))?;
+	///     eh.set_on_client_read(|_,_,_| Ok(()))?;
+	///     eh.set_on_close(|_| Ok(()))?;
+	///     Ok(())
+	/// }
+	/// ```
+	pub fn set_on_datagram<D>(&mut self, on_datagram: D) -> Result<(), Error>
+	where
+		D: Fn(&[u8], usize, SocketAddr, DatagramHandle) -> Result<(), Error>
+			+ Send
+			+ Sync
+			+ 'static,
+	{
+		let mut callbacks = nioruntime_util::lockw!(self.callbacks)?;
+
+		callbacks.on_datagram = Some(Arc::new(on_datagram));
 
 		Ok(())
 	}
 
-	fn build_pipe(&mut self, _i: usize) -> Result<(ConnectionHandle, ConnectionHandle), Error> {
-		#[cfg(target_os = "windows")]
-		{
-			let mut retfds = [0i32; 2];
-			let fds: *mut c_int = &mut retfds as *mut _ as *mut c_int;
-			let res = Self::socket_pipe(fds);
-			match res {
-				Ok((listener, stream)) => {
-					self._pipe_listener[_i] = Some(listener);
-					self._pipe_stream[_i] = Some(stream);
-				}
-				Err(e) => {
-					mainlogerror!("Error creating socket_pipe on windows, {}", e.to_string());
-				}
-			}
-			Ok((
-				retfds[0].try_into().unwrap_or(0),
-				retfds[1].try_into().unwrap_or(0),
-			))
+	/// This sets the on_connect callback for this [`EventHandler`].
+	///
+	/// As described in [`EventHandler::connect`], this callback is executed when an outbound
+	/// connection started by [`EventHandler::connect`] is established or fails. The parameters are
+	/// the connection_id returned by [`EventHandler::connect`] and either the [`WriteHandle`] for the
+	/// connection or the error that caused it to fail. Since [`EventHandler::connect`] is only
+	/// available on unix platforms, this callback is never executed on windows.
+	///
+	/// # Examples
+	/// ```
+	/// use nioruntime_evh::{EventHandler, EventHandlerConfig};
+	/// use nioruntime_err::Error;
+	/// use nioruntime_log::*;
+	///
+	/// // set log level to info
+	/// info!();
+	///
+	/// fn main() -> Result<(), Error> {
+	///     let mut eh = EventHandler::new(EventHandlerConfig::default());
+	///     // send a greeting as soon as the connection is established
+	///     eh.set_on_connect(|connection_id, wh| {
+	///         match wh {
+	///             Ok(wh) => wh.write(b"hello")?,
+	///             Err(e) => info!("connect {} failed: {}", connection_id, e),
+	///         }
+	///         Ok(())
+	///     })?;
+	///     eh.set_on_read(|_,_,_| Ok(()))?;
+	///     eh.set_on_accept(|_,_| Ok(()))?;
+	///     eh.set_on_client_read(|_,_,_| Ok(()))?;
+	///     eh.set_on_close(|_| Ok(()))?;
+	///     Ok(())
+	/// }
+	/// ```
+	pub fn set_on_connect<C>(&mut self, on_connect: C) -> Result<(), Error>
+	where
+		C: Fn(u128, Result<WriteHandle, Error>) -> Result<(), Error> + Send + Sync + 'static,
+	{
+		let mut callbacks = nioruntime_util::lockw!(self.callbacks)?;
+
+		callbacks.on_connect = Some(Arc::new(on_connect));
+
+		Ok(())
+	}
+
+	/// This sets the on_reject callback for this [`EventHandler`].
+	///
+	/// This callback is optional. It is executed when a connection accepted by a listener is
+	/// closed immediately because of [`EventHandlerConfig::max_connections`] or
+	/// [`EventHandlerConfig::max_connections_per_ip`]. The parameters are the address of the peer,
+	/// if known, and the [`RejectReason`]. The callback specified by [`EventHandler::set_on_accept`]
+	/// is not executed for rejected connections.
+	///
+	/// # Examples
+	/// ```
+	/// use nioruntime_evh::{EventHandler, EventHandlerConfig};
+	/// use nioruntime_err::Error;
+	/// use nioruntime_log::*;
+	///
+	/// // set log level to info
+	/// info!();
+	///
+	/// fn main() -> Result<(), Error> {
+	///     let mut eh = EventHandler::new(EventHandlerConfig {
+	///         max_connections: Some(10_000),
+	///         max_connections_per_ip: Some(100),
+	///         ..EventHandlerConfig::default()
+	///     });
+	///     eh.set_on_reject(|peer_addr, reason| {
+	///         info!("rejected connection from {:?}: {:?}", peer_addr, reason);
+	///         Ok(())
+	///     })?;
+	///     eh.set_on_read(|_,_,_| Ok(()))?;
+	///     eh.set_on_accept(|_,_| Ok(()))?;
+	///     eh.set_on_client_read(|_,_,_| Ok(()))?;
+	///     eh.set_on_close(|_| Ok(()))?;
+	///     Ok(())
+	/// }
+	/// ```
+	pub fn set_on_reject<R>(&mut self, on_reject: R) -> Result<(), Error>
+	where
+		R: Fn(Option<SocketAddr>, RejectReason) -> Result<(), Error> + Send + Sync + 'static,
+	{
+		let mut callbacks = nioruntime_util::lockw!(self.callbacks)?;
+
+		callbacks.on_reject = Some(Arc::new(on_reject));
+
+		Ok(())
+	}
+
+	/// Schedule `callback` to be executed after `delay` has elapsed.
+	///
+	/// The callback is executed on one of the [`EventHandler`]'s read/write threads. The returned
+	/// [`TimerHandle`] may be used to cancel the callback before it is executed. The
+	/// [`EventHandler`] must be started with [`EventHandler::start`] before timers are scheduled.
+	///
+	/// # Examples
+	/// ```
+	/// use nioruntime_evh::{EventHandler, EventHandlerConfig};
+	/// use nioruntime_err::Error;
+	/// use nioruntime_log::*;
+	///
+	/// // set log level to info
+	/// info!();
+	///
+	/// fn main() -> Result<(), Error> {
+	///     let mut eh = EventHandler::new(EventHandlerConfig::default());
+	///     eh.set_on_read(|_,_,_| Ok(()))?;
+	///     eh.set_on_client_read(|_,_,_| Ok(()))?;
+	///     eh.set_on_accept(|_,_| Ok(()))?;
+	///     eh.set_on_close(|_| Ok(()))?;
+	///     eh.start()?;
+	///
+	///     let timer = eh.schedule(std::time::Duration::from_secs(1), || {
+	///         info!("timer fired");
+	///         Ok(())
+	///     })?;
+	///     // changed our mind
+	///     timer.cancel()?;
+	///     Ok(())
+	/// }
+	/// ```
+	pub fn schedule<T>(&self, delay: Duration, callback: T) -> Result<TimerHandle, Error>
+	where
+		T: FnOnce() -> Result<(), Error> + Send + Sync + 'static,
+	{
+		let handle = TimerHandle::new();
+		let mut rng = rand::thread_rng();
+		let gd_index: usize = rng.gen();
+		let gd_index = (gd_index % (self.guarded_data.len() - 1)) + 1;
+
+		let mut guarded_data = nioruntime_util::lockw!(self.guarded_data[gd_index])?;
+		guarded_data.timers.push(ScheduledTimer {
+			deadline: Instant::now() + delay,
+			handle: handle.clone(),
+			callback: Box::new(callback),
+		});
+		guarded_data.wakeup()?;
+
+		Ok(handle)
+	}
+
+	/// Create a new instance of the [`EventHandler`]. Note that all callbacks must be registered
+	/// and [`EventHandler::start`] must be called before handleing events. See the example in the
+	/// [`EventHandler`] section of the documentation for a full details.
+	pub fn new(config: EventHandlerConfig) -> Self {
+		let callbacks = Callbacks {
+			on_read: None,
+			on_accept: None,
+			on_close: None,
+			on_client_read: None,
+			on_writable: None,
+			on_read_eof: None,
+			on_timeout: None,
+			on_datagram: None,
+			on_connect: None,
+			on_reject: None,
+		};
+		let callbacks = Arc::new(RwLock::new(callbacks));
+
+		let mut guarded_data = vec![];
+		for i in 0..config.thread_count + 1 {
+			guarded_data.push(Arc::new(RwLock::new(GuardedData {
+				nconns: vec![],
+				write_queue: vec![],
+				aconns: vec![],
+				cconns: vec![],
+				wakeup_tx: 0,
+				wakeup_rx: 0,
+				wakeup_scheduled: false,
+				stop: false,
+				callback_state: Arc::new(RwLock::new(State::Init)),
+				write_queue_high_water: config.write_queue_high_water,
+				write_queue_low_water: config.write_queue_low_water,
+				pending_bytes: HashMap::new(),
+				paused: HashSet::new(),
+				timers: vec![],
+				timeouts: vec![],
+				connect_timeouts: vec![],
+				stop_accepting: None,
+				close_on_stop: false,
+				buffer_pool: vec![],
+				buffer_pool_size: config.buffer_pool_size,
+				read_config: ReadConfig {
+					buffer_size: std::cmp::max(config.read_buffer_size, 1),
+					max_events: std::cmp::max(config.max_events, 1),
+					trigger_mode: config.read_trigger_mode,
+					max_read_per_poll: config.max_read_per_poll,
+				},
+				metrics: Arc::new(Metrics::new()),
+				thread_index: i,
+				migrated: HashMap::new(),
+				migration_requests: vec![],
+				migrated_conns: vec![],
+			})));
 		}
-		#[cfg(unix)]
-		{
-			let mut retfds = [0i32; 2];
-			let fds: *mut c_int = &mut retfds as *mut _ as *mut c_int;
-			unsafe { pipe(fds) };
-			Ok((retfds[0], retfds[1]))
+
+		let global_lock = Arc::new(RwLock::new(true));
+
+		EventHandler {
+			config,
+			guarded_data,
+			callbacks,
+			global_lock,
+			on_panic: None,
+			listener_tls: Arc::new(RwLock::new(HashMap::new())),
+			thread_handles: vec![],
+			listener_selector: None,
+			next_index: 0,
+			reuse_port_listeners: vec![],
+			_pipe_listener: vec![],
+			_pipe_stream: vec![],
+			_phantom_data: PhantomData,
 		}
 	}
 
-	#[cfg(target_os = "linux")]
-	fn do_start(&mut self) -> Result<(), Error> {
+	/// Start the event handler.
+	pub fn start(&mut self) -> Result<(), Error> {
 		let mut selectors = vec![];
-		for _ in 0..self.config.thread_count + 1 {
-			selectors.push(epoll_create1(EpollCreateFlags::empty()).unwrap());
+		for _ in 0..self.guarded_data.len() {
+			selectors.push(selector::create(self.config.backend)?);
 		}
-		self.start_generic(selectors)?;
-		Ok(())
+		self.start_with_selectors(selectors)
 	}
 
-	#[cfg(any(target_os = "macos", dragonfly, freebsd, netbsd, openbsd))]
-	fn do_start(&mut self) -> Result<(), Error> {
-		let mut selectors = vec![];
-		for _ in 0..self.config.thread_count + 1 {
-			selectors.push(unsafe { kqueue() });
+	// start the threads with the specified selectors. The first one is used by the listener and
+	// the others by the r/w threads.
+	fn start_with_selectors(&mut self, selectors: Vec<Arc<dyn Selector>>) -> Result<(), Error> {
+		for _ in 0..self.guarded_data.len() {
+			self._pipe_listener.push(None);
+			self._pipe_stream.push(None);
+		}
+		for i in 0..self.guarded_data.len() {
+			let (rx, tx) = self.build_pipe(i)?;
+			let mut guarded_data = nioruntime_util::lockw!(self.guarded_data[i])?;
+			guarded_data.wakeup_tx = tx;
+			guarded_data.wakeup_rx = rx;
 		}
 		self.start_generic(selectors)?;
+
 		Ok(())
 	}
 
-	#[cfg(target_os = "windows")]
-	fn do_start(&mut self) -> Result<(), Error> {
-		let mut selectors = vec![];
-		for _ in 0..self.config.thread_count + 1 {
-			selectors.push(unsafe { epoll_create(1) });
+	/// Stop the event handler and free any internal resources associated with it. Note: this
+	/// does not close any registered sockets. That is the responsibility of the user. See
+	/// [`EventHandler::stop_graceful`] to have the [`EventHandler`] close them.
+	pub fn stop(&self) -> Result<(), Error> {
+		for i in 0..self.guarded_data.len() {
+			let mut guarded_data = nioruntime_util::lockw!(self.guarded_data[i])?;
+			guarded_data.stop = true;
+			guarded_data.wakeup()?;
 		}
-		self.start_generic(selectors)?;
+
 		Ok(())
 	}
 
-	fn ensure_handlers(&self) -> Result<(), Error> {
-		let callbacks = nioruntime_util::lockr!(self.callbacks)?;
+	/// Stop the event handler after draining all connections.
+	///
+	/// First, no more connections are accepted on any listener. Then, this function waits
+	/// until all data queued for writing has been written or `timeout` has passed. After that,
+	/// all connections are closed, the callback specified by [`EventHandler::set_on_close`] is
+	/// executed for each of them, and connections in progress are failed through the callback
+	/// specified by [`EventHandler::set_on_connect`]. As with other closes, sockets accepted
+	/// or connected by the [`EventHandler`] are closed, while sockets registered by the user,
+	/// including the listeners, remain the responsibility of the user. This function blocks
+	/// until all threads of the [`EventHandler`] have exited and returns a [`ShutdownSummary`]
+	/// of the connections that still had data queued for writing when `timeout` passed.
+	///
+	/// A thread that hasn't confirmed that it stopped accepting when `timeout` passes, because
+	/// it exited after a callback returned an error or is stuck in a callback, is not waited
+	/// for. If that thread is the one that executes on_close, the connections are closed
+	/// without executing on_close and are reported in [`ShutdownSummary::force_closed`].
+	pub fn stop_graceful(&mut self, timeout: Duration) -> Result<ShutdownSummary, Error> {
+		if self.thread_handles.is_empty() {
+			return Err(ErrorKind::SetupError("EventHandler is not started".to_string()).into());
+		}
+		let deadline = Instant::now() + timeout;
 
-		match callbacks.on_read {
-			Some(_) => {}
-			None => {
-				return Err(ErrorKind::SetupError(
-					"on_read callback must be registered first".to_string(),
-				)
-				.into());
+		// stop accepting and wait for the threads to confirm. The r/w threads accept on the
+		// listeners added with add_tcp_listener_reuse_port.
+		let mut receivers = vec![];
+		for i in 0..self.guarded_data.len() {
+			let (tx, rx) = sync_channel(1);
+			let mut guarded_data = nioruntime_util::lockw!(self.guarded_data[i])?;
+			guarded_data.stop_accepting = Some(tx);
+			guarded_data.wakeup()?;
+			receivers.push(rx);
+		}
+		let mut responsive = vec![];
+		for rx in receivers {
+			let wait = deadline.saturating_duration_since(Instant::now());
+			responsive.push(rx.recv_timeout(wait).is_ok());
+		}
+
+		// wait for the write queues to drain
+		loop {
+			let mut drained = true;
+			for i in 1..self.guarded_data.len() {
+				let guarded_data = nioruntime_util::lockr!(self.guarded_data[i])?;
+				if !guarded_data.pending_bytes.is_empty() {
+					drained = false;
+					break;
+				}
 			}
+			if drained || Instant::now() >= deadline {
+				break;
+			}
+			std::thread::sleep(Duration::from_millis(10));
 		}
 
-		match callbacks.on_accept {
-			Some(_) => {}
-			None => {
-				return Err(ErrorKind::SetupError(
-					"on_accept callback must be registered first".to_string(),
-				)
-				.into());
+		// the r/w threads hand their connections to the listener thread to be closed, so they
+		// must exit before the listener thread is stopped.
+		for i in 1..self.guarded_data.len() {
+			let mut guarded_data = nioruntime_util::lockw!(self.guarded_data[i])?;
+			guarded_data.stop = true;
+			guarded_data.close_on_stop = true;
+			guarded_data.wakeup()?;
+		}
+		let mut thread_handles = self.thread_handles.drain(..).collect::<Vec<_>>();
+		let listener_handle = thread_handles.remove(0);
+		for (i, thread_handle) in thread_handles.into_iter().enumerate() {
+			if responsive[i + 1] {
+				let _ = thread_handle.join();
 			}
 		}
+		{
+			let mut guarded_data = nioruntime_util::lockw!(self.guarded_data[0])?;
+			guarded_data.stop = true;
+			guarded_data.wakeup()?;
+		}
 
-		match callbacks.on_close {
-			Some(_) => {}
-			None => {
-				return Err(ErrorKind::SetupError(
-					"on_close callback must be registered first".to_string(),
-				)
-				.into());
+		let mut summary = ShutdownSummary::default();
+		if responsive[0] {
+			let _ = listener_handle.join();
+		} else {
+			summary.force_closed = self.close_handed_over()?;
+		}
+		for i in 1..self.guarded_data.len() {
+			let guarded_data = nioruntime_util::lockr!(self.guarded_data[i])?;
+			for (connection_id, pending) in &guarded_data.pending_bytes {
+				if !summary.force_closed.contains(connection_id) {
+					summary.force_closed.push(*connection_id);
+				}
+				summary.unsent_bytes += pending;
 			}
 		}
 
-		Ok(())
+		Ok(summary)
 	}
 
-	fn add(
-		&mut self,
-		handle: ConnectionHandle,
-		atype: ActionType,
-		tls_client: Option<Arc<RwLock<ClientConnection>>>,
-		gd_index: usize,
-	) -> Result<(ConnectionHandle, u128), Error> {
-		let mut rng = rand::thread_rng();
-		let connection_id = rng.gen();
-
-		let (tx, rx) = sync_channel(5);
-
-		let conn = ConnectionInfo {
-			handle,
-			connection_id,
-			ctype: if atype == ActionType::AddListener {
-				ConnectionType::Listener
-			} else {
-				ConnectionType::Outbound
-			},
-			sender: Some(tx.clone()),
-			tls_server: None,
-			tls_client,
+	// closes the connections that the r/w threads handed to an unresponsive listener thread
+	// without executing on_close. Returns their connection_ids.
+	fn close_handed_over(&self) -> Result<Vec<u128>, Error> {
+		let cconns = {
+			let mut guarded_data = nioruntime_util::lockw!(self.guarded_data[0])?;
+			std::mem::take(&mut guarded_data.cconns)
 		};
-
-		{
-			match atype {
-				ActionType::AddListener => {
-					let mut guarded_data = nioruntime_util::lockw!(self.guarded_data[0])?;
-					guarded_data.nconns.push(conn);
-					guarded_data.wakeup()?;
-				}
-				ActionType::AddStream | ActionType::AddTlsStream => {
-					let mut guarded_data = nioruntime_util::lockw!(self.guarded_data[gd_index])?;
-					guarded_data.nconns.push(conn);
-					guarded_data.wakeup()?;
+		let mut closed = vec![];
+		let _lock = nioruntime_util::lockw!(self.global_lock)?;
+		for conn in cconns {
+			{
+				let mut attachment = nioruntime_util::lockw!(conn.attachment)?;
+				*attachment = None;
+			}
+			// as in the listener thread, only the sockets owned by the EventHandler are closed
+			let owned = matches!(
+				conn.ctype,
+				ConnectionType::Inbound | ConnectionType::Connected
+			);
+			if let (true, Some(selector)) = (owned, &self.listener_selector) {
+				if selector.close_handle(conn.handle)? != 0 {
+					mainlogerror!("error closing socket: {}", errno().to_string());
 				}
 			}
+			closed.push(conn.connection_id);
 		}
+		Ok(closed)
+	}
 
-		rx.recv().map_err(|e| {
-			let error: Error =
-				ErrorKind::InternalError(format!("recv error: {}", e.to_string())).into();
-			error
-		})?;
-
-		Ok((handle, connection_id))
+	/// Returns a snapshot of the counters of each of the [`EventHandler`]'s threads. The first
+	/// entry is the listener thread, which accepts connections and executes on_accept and
+	/// on_close. The others are the r/w threads, in the order of their creation. Comparing the
+	/// entries of the r/w threads shows how the load is spread between them, and a thread whose
+	/// [`ThreadMetrics::since_last_poll`] keeps growing is stuck in a callback.
+	pub fn metrics(&self) -> Result<Vec<ThreadMetrics>, Error> {
+		let mut ret = vec![];
+		for guarded_data in &self.guarded_data {
+			let guarded_data = nioruntime_util::lockr!(guarded_data)?;
+			let mut metrics = guarded_data.metrics.snapshot();
+			metrics.write_queue_connections = guarded_data.pending_bytes.len() as u64;
+			metrics.write_queue_bytes = guarded_data
+				.pending_bytes
+				.values()
+				.map(|pending| *pending as u64)
+				.sum();
+			ret.push(metrics);
+		}
+		Ok(ret)
+	}
+
+	/// Move the connection with the specified connection_id to another r/w thread. `thread` is the
+	/// index of the r/w thread in the vector returned by [`EventHandler::metrics`], so the first
+	/// r/w thread is 1. This may be used to rebalance long-lived connections, such as websocket
+	/// clients, when a thread becomes overloaded.
+	///
+	/// The connection is moved asynchronously by the thread that owns it. Data that is queued for
+	/// writing is written by the new thread, idle timeouts are kept and [`WriteHandle`]s obtained
+	/// before the move keep working. Unknown or closed connections, datagram sockets and outbound
+	/// connections that are still connecting are left where they are. An error is returned if
+	/// there is no r/w thread with the specified index.
+	pub fn migrate(&self, connection_id: u128, thread: usize) -> Result<(), Error> {
+		if thread == 0 || thread >= self.guarded_data.len() {
+			return Err(
+				ErrorKind::ArrayIndexOutofBounds(format!("no r/w thread {}", thread)).into(),
+			);
+		}
+		for i in 1..self.guarded_data.len() {
+			if i == thread {
+				continue;
+			}
+			// only the thread that owns the connection acts on the request
+			let mut guarded_data = nioruntime_util::lockw!(self.guarded_data[i])?;
+			guarded_data.migration_requests.push(MigrationRequest {
+				connection_id,
+				thread,
+				guarded_data: self.guarded_data[thread].clone(),
+			});
+			guarded_data.wakeup()?;
+		}
+		Ok(())
+	}
+
+	/// Reload the certificates and private keys of all TLS listeners.
+	///
+	/// The files specified in the [`TlsConfig`] of each listener are read again and, if all of
+	/// them are valid, the new certificates are used for all connections accepted from then on.
+	/// Connections that were already accepted keep using the certificates they were accepted
+	/// with. If any file cannot be loaded, an error is returned and no listener is changed. This
+	/// may be used to pick up renewed certificates without restarting the [`EventHandler`].
+	pub fn reload_tls(&self) -> Result<(), Error> {
+		let mut listener_tls = nioruntime_util::lockw!(self.listener_tls)?;
+
+		// validate everything before swapping anything
+		let mut server_configs = vec![];
+		for (handle, tls) in listener_tls.iter() {
+			server_configs.push((*handle, Arc::new(make_server_config(&tls.tls_config)?)));
+		}
+
+		for (handle, server_config) in server_configs {
+			if let Some(tls) = listener_tls.get_mut(&handle) {
+				tls.server_config = server_config;
+			}
+		}
+
+		Ok(())
+	}
+
+	fn build_pipe(&mut self, _i: usize) -> Result<(ConnectionHandle, ConnectionHandle), Error> {
+		#[cfg(target_os = "windows")]
+		{
+			let mut retfds = [0i32; 2];
+			let fds: *mut c_int = &mut retfds as *mut _ as *mut c_int;
+			let res = Self::socket_pipe(fds);
+			match res {
+				Ok((listener, stream)) => {
+					self._pipe_listener[_i] = Some(listener);
+					self._pipe_stream[_i] = Some(stream);
+				}
+				Err(e) => {
+					mainlogerror!("Error creating socket_pipe on windows, {}", e.to_string());
+				}
+			}
+			Ok((
+				retfds[0].try_into().unwrap_or(0),
+				retfds[1].try_into().unwrap_or(0),
+			))
+		}
+		#[cfg(unix)]
+		{
+			let mut retfds = [0i32; 2];
+			let fds: *mut c_int = &mut retfds as *mut _ as *mut c_int;
+			unsafe { pipe(fds) };
+			Ok((retfds[0], retfds[1]))
+		}
+	}
+
+	fn ensure_handlers(&self) -> Result<(), Error> {
+		let callbacks = nioruntime_util::lockr!(self.callbacks)?;
+
+		match callbacks.on_read {
+			Some(_) => {}
+			None => {
+				return Err(ErrorKind::SetupError(
+					"on_read callback must be registered first".to_string(),
+				)
+				.into());
+			}
+		}
+
+		match callbacks.on_accept {
+			Some(_) => {}
+			None => {
+				return Err(ErrorKind::SetupError(
+					"on_accept callback must be registered first".to_string(),
+				)
+				.into());
+			}
+		}
+
+		match callbacks.on_close {
+			Some(_) => {}
+			None => {
+				return Err(ErrorKind::SetupError(
+					"on_close callback must be registered first".to_string(),
+				)
+				.into());
+			}
+		}
+
+		Ok(())
+	}
+
+	// returns the index in self.guarded_data of the r/w thread for a new client connection
+	fn next_thread(&mut self, peer_addr: Option<SocketAddr>) -> Result<usize, Error> {
+		let index = Self::select_thread(
+			&self.guarded_data[1..],
+			self.config.placement_strategy,
+			&mut self.next_index,
+			peer_addr,
+		)?;
+		Ok(index + 1)
+	}
+
+	// returns the index in guarded_data of the r/w thread that a new connection is assigned to
+	fn select_thread(
+		guarded_data: &[Arc<RwLock<GuardedData>>],
+		strategy: PlacementStrategy,
+		next_index: &mut usize,
+		peer_addr: Option<SocketAddr>,
+	) -> Result<usize, Error> {
+		match (strategy, peer_addr) {
+			(PlacementStrategy::LeastConnections, _) => {
+				let mut index = 0;
+				let mut least = u64::MAX;
+				for i in 0..guarded_data.len() {
+					let guarded_data = nioruntime_util::lockr!(guarded_data[i])?;
+					// connections that the thread has not picked up yet count too
+					let connections = Metrics::get(&guarded_data.metrics.active_connections)
+						+ guarded_data.nconns.len() as u64
+						+ guarded_data.migrated_conns.len() as u64;
+					if connections < least {
+						least = connections;
+						index = i;
+					}
+				}
+				Ok(index)
+			}
+			(PlacementStrategy::PeerAddressHash, Some(peer_addr)) => {
+				let mut hasher = DefaultHasher::new();
+				peer_addr.ip().hash(&mut hasher);
+				Ok((hasher.finish() % guarded_data.len() as u64) as usize)
+			}
+			_ => {
+				let index = *next_index % guarded_data.len();
+				*next_index += 1;
+				if *next_index == usize::MAX {
+					*next_index = 0;
+				}
+				Ok(index)
+			}
+		}
+	}
+
+	fn add(
+		&mut self,
+		handle: ConnectionHandle,
+		atype: ActionType,
+		tls_client: Option<Arc<RwLock<ClientConnection>>>,
+		gd_index: usize,
+	) -> Result<ConnectionInfo, Error> {
+		let mut rng = rand::thread_rng();
+		let connection_id = rng.gen();
+
+		let (tx, rx) = sync_channel(5);
+
+		let conn = ConnectionInfo {
+			handle,
+			connection_id,
+			ctype: match atype {
+				ActionType::AddListener | ActionType::AddReusePortListener => {
+					ConnectionType::Listener
+				}
+				ActionType::AddDatagram => ConnectionType::Datagram,
+				ActionType::AddConnect => ConnectionType::Connecting,
+				_ => ConnectionType::Outbound,
+			},
+			sender: Some(tx.clone()),
+			tls_server: None,
+			tls_client,
+			peer_addr: socket_addr(handle, true),
+			local_addr: socket_addr(handle, false),
+			attachment: Arc::new(RwLock::new(None)),
+			migrated_from: vec![],
+			reuse_port: false,
+			read_eof: false,
+			write_shutdown: false,
+		};
+		let ret = conn.clone();
+
+		{
+			match atype {
+				ActionType::AddListener => {
+					let mut guarded_data = nioruntime_util::lockw!(self.guarded_data[0])?;
+					guarded_data.nconns.push(conn);
+					guarded_data.wakeup()?;
+				}
+				ActionType::AddStream
+				| ActionType::AddTlsStream
+				| ActionType::AddDatagram
+				| ActionType::AddConnect
+				| ActionType::AddReusePortListener => {
+					let mut guarded_data = nioruntime_util::lockw!(self.guarded_data[gd_index])?;
+					guarded_data.nconns.push(conn);
+					guarded_data.wakeup()?;
+				}
+			}
+		}
+
+		rx.recv().map_err(|e| {
+			let error: Error =
+				ErrorKind::InternalError(format!("recv error: {}", e.to_string())).into();
+			error
+		})?;
+
+		Ok(ret)
 	}
 
 	#[cfg(target_os = "windows")]
@@ -1115,133 +2875,97 @@ where
 		Ok((listener, stream))
 	}
 
-	fn start_generic(&mut self, selectors: Vec<SelectorHandle>) -> Result<(), Error> {
+	fn start_generic(&mut self, selectors: Vec<Arc<dyn Selector>>) -> Result<(), Error> {
 		self.ensure_handlers()?;
 
 		let on_panic = self.on_panic.clone();
-		let global_lock = &self.global_lock;
-		let global_lock_clone = global_lock.clone();
-		#[cfg(unix)]
-		let selectors_clone = selectors[0];
-		#[cfg(windows)]
-		let selectors_clone = selectors[0] as u64;
-		let guarded_data = self.guarded_data[0].clone();
 		let callbacks = nioruntime_util::lockr!(self.callbacks)?;
-		let mut guarded_data_vec = vec![];
-		for i in 1..self.guarded_data.len() {
-			guarded_data_vec.push(self.guarded_data[i].clone());
-		}
-
-		let on_accept = callbacks.on_accept.as_ref().unwrap().clone();
-		let on_close = callbacks.on_close.as_ref().unwrap().clone();
-		let tls_server_config = self.tls_server_config.clone();
-		spawn(move || {
-			#[cfg(windows)]
-			let selectors_clone = selectors_clone as *mut c_void;
-			match Self::listener(
-				selectors_clone,
-				guarded_data,
-				&mut guarded_data_vec,
-				on_accept.clone(),
-				on_close.clone(),
-				global_lock_clone.clone(),
-				tls_server_config,
-			) {
-				Ok(_) => {}
-				Err(e) => {
-					log_multi!(
-						ERROR,
-						MAIN_LOG,
-						"listener generated error: {}",
-						e.to_string()
-					);
-				}
+		let acceptor = Acceptor {
+			on_accept: callbacks.on_accept.as_ref().unwrap().clone(),
+			on_reject: callbacks.on_reject.clone(),
+			limits: SharedConnectionLimits::new(&self.config),
+			listener_tls: self.listener_tls.clone(),
+			socket_options: self.config.socket_options.clone(),
+		};
+		let ctx = {
+			let guarded_data = nioruntime_util::lockr!(self.guarded_data[0])?;
+			ListenerContext {
+				selector: selectors[0].clone(),
+				guarded_data: self.guarded_data[0].clone(),
+				guarded_data_vec: self.guarded_data[1..].to_vec(),
+				on_close: callbacks.on_close.as_ref().unwrap().clone(),
+				global_lock: self.global_lock.clone(),
+				acceptor: acceptor.clone(),
+				placement_strategy: self.config.placement_strategy,
+				metrics: guarded_data.metrics.clone(),
+				max_events: guarded_data.read_config.max_events,
+				wakeup_fd: guarded_data.wakeup_rx,
+			}
+		};
+		self.listener_selector = Some(ctx.selector.clone());
+		let listener_handle = spawn(move || match Self::listener(&ctx) {
+			Ok(_) => {}
+			Err(e) => {
+				log_multi!(
+					ERROR,
+					MAIN_LOG,
+					"listener generated error: {}",
+					e.to_string()
+				);
 			}
 		});
+		self.thread_handles.push(listener_handle);
 
 		// start r/w threads
 		for i in 0..self.config.thread_count {
-			#[cfg(unix)]
-			let selectors_clone = selectors[i + 1] as i32;
-			#[cfg(windows)]
-			let selectors_clone = selectors[i + 1] as u64;
-			let listener_guarded_data = self.guarded_data[0].clone();
 			let guarded_data = self.guarded_data[i + 1].clone();
-			let on_read = callbacks.on_read.as_ref().unwrap().clone();
-			let on_client_read = callbacks.on_client_read.as_ref().unwrap().clone();
-			let global_lock = global_lock.clone();
-			let connection_id_map = Arc::new(RwLock::new(HashMap::new()));
-			let connection_info_map = Arc::new(RwLock::new(HashMap::new()));
-			let hash_set = Arc::new(RwLock::new(HashSet::new()));
-			let write_buffers = Arc::new(RwLock::new(HashMap::new()));
-			let input_events = Arc::new(RwLock::new(Vec::new()));
-			let output_events = Arc::new(RwLock::new(Vec::new()));
+			let ctx = {
+				let guarded_data = nioruntime_util::lockr!(guarded_data)?;
+				RwContext {
+					selector: selectors[i + 1].clone(),
+					listener_guarded_data: self.guarded_data[0].clone(),
+					guarded_data: self.guarded_data[i + 1].clone(),
+					global_lock: self.global_lock.clone(),
+					callbacks: RwCallbacks {
+						on_read: callbacks.on_read.as_ref().unwrap().clone(),
+						on_client_read: callbacks.on_client_read.as_ref().unwrap().clone(),
+						on_writable: callbacks.on_writable.clone(),
+						on_timeout: callbacks.on_timeout.clone(),
+						on_read_eof: callbacks.on_read_eof.clone(),
+						on_datagram: callbacks.on_datagram.clone(),
+						on_connect: callbacks.on_connect.clone(),
+					},
+					acceptor: acceptor.clone(),
+					read_config: guarded_data.read_config,
+					metrics: guarded_data.metrics.clone(),
+					wakeup_fd: guarded_data.wakeup_rx,
+				}
+			};
+			// kept when the thread is restarted after a panic
+			let state = Arc::new(RwLock::new(RwState::new(ctx.wakeup_fd)));
 			let on_panic = on_panic.clone();
-			let counter = Arc::new(RwLock::new(0));
-			let res = Arc::new(RwLock::new(0));
-
-			let wakeup_fd;
 
-			// add wakeup fd
-			{
-				let guarded_data = nioruntime_util::lockw!(guarded_data)?;
-				let mut input_events = nioruntime_util::lockw!(input_events)?;
-
-				input_events.push(GenericEvent {
-					fd: guarded_data.wakeup_rx,
-					etype: GenericEventType::AddReadLT,
+			let thread_handle = spawn(move || loop {
+				let ctx_clone = ctx.clone();
+				let state = state.clone();
+				let jh = spawn(move || match Self::rwthread(&ctx_clone, &state) {
+					Ok(_) => {}
+					Err(e) => {
+						log_multi!(
+							ERROR,
+							MAIN_LOG,
+							"rwthread generated error: {}",
+							e.to_string()
+						);
+					}
 				});
 
-				wakeup_fd = guarded_data.wakeup_rx;
-			}
-
-			spawn(move || loop {
-				let listener_guarded_data = listener_guarded_data.clone();
-				let guarded_data = guarded_data.clone();
-				let guarded_data_clone = guarded_data.clone();
-				let on_read = on_read.clone();
-				let on_client_read = on_client_read.clone();
-				let on_panic = on_panic.clone();
-				let global_lock = global_lock.clone();
-				let connection_id_map = connection_id_map.clone();
-				let connection_info_map = connection_info_map.clone();
-				let write_buffers = write_buffers.clone();
-				let hash_set = hash_set.clone();
-				let input_events = input_events.clone();
-				let output_events = output_events.clone();
-				let counter = counter.clone();
-				let res = res.clone();
-				let jh = spawn(move || {
-					#[cfg(windows)]
-					let selectors_clone = selectors_clone as *mut c_void;
-					match Self::rwthread(
-						selectors_clone,
-						listener_guarded_data,
-						guarded_data_clone,
-						on_read,
-						on_client_read,
-						global_lock,
-						connection_info_map,
-						connection_id_map,
-						hash_set,
-						write_buffers,
-						wakeup_fd,
-						input_events,
-						output_events,
-						counter,
-						res,
-					) {
-						Ok(_) => {}
-						Err(e) => {
-							log_multi!(
-								ERROR,
-								MAIN_LOG,
-								"rwthread generated error: {}",
-								e.to_string()
-							);
-						}
+				match jh.join() {
+					Ok(_) => {}
+					Err(_e) => {
+						log_multi!(ERROR, MAIN_LOG, "thread panic!");
 					}
-				});
+				}
 
 				{
 					let guarded_data = nioruntime_util::lockw!(guarded_data).unwrap();
@@ -1250,13 +2974,6 @@ where
 					}
 				}
 
-				match jh.join() {
-					Ok(_) => {}
-					Err(_e) => {
-						log_multi!(ERROR, MAIN_LOG, "thread panic!");
-					}
-				}
-
 				match on_panic {
 					Some(on_panic) => match (on_panic)() {
 						Ok(_) => {}
@@ -1267,22 +2984,23 @@ where
 					None => {}
 				}
 			});
+			self.thread_handles.push(thread_handle);
 		}
 		Ok(())
 	}
 
 	fn update_listener_input_events(
-		guarded_data: &Arc<RwLock<GuardedData>>,
+		ctx: &ListenerContext,
 		input_events: &mut Vec<GenericEvent>,
-		global_lock: Arc<RwLock<bool>>,
-		on_close: Pin<Box<H>>,
 		cid_map: &mut HashMap<ConnectionHandle, u128>,
+		listener_handles: &mut HashSet<ConnectionHandle>,
 	) -> Result<bool, Error> {
+		let metrics = &ctx.metrics;
 		let stop;
 		let nconns;
 		let cconns;
 		{
-			let mut guarded_data = nioruntime_util::lockw!(guarded_data)?;
+			let mut guarded_data = nioruntime_util::lockw!(ctx.guarded_data)?;
 			stop = guarded_data.stop;
 			nconns = guarded_data.nconns.clone();
 			cconns = guarded_data.cconns.clone();
@@ -1296,32 +3014,46 @@ where
 				etype: GenericEventType::AddReadLT,
 			};
 			input_events.push(ge);
+			listener_handles.insert(conn.handle);
 
 			if conn.sender.is_some() {
 				let _ = conn.sender.unwrap().send(());
 			}
 		}
 
-		let _lock = nioruntime_util::lockw!(global_lock)?;
+		let _lock = nioruntime_util::lockw!(ctx.global_lock)?;
 		for conn in cconns {
 			let connection_id = conn.connection_id;
 			let fd = conn.handle;
-			(on_close)(connection_id)?;
-
-			let lookup = cid_map.remove(&fd);
-			if lookup.is_none() {
-				continue;
+			metrics.on_close.time(|| (ctx.on_close)(connection_id))?;
+			Metrics::inc(&metrics.closes, 1);
+			ctx.acceptor.limits.remove(connection_id)?;
+			{
+				let mut attachment = nioruntime_util::lockw!(conn.attachment)?;
+				*attachment = None;
 			}
-			let lookup = lookup.unwrap();
-			if lookup != connection_id {
-				continue;
+			// the threads the connection was moved from no longer need to forward its writes
+			for thread_index in &conn.migrated_from {
+				let mut guarded_data =
+					nioruntime_util::lockw!(ctx.guarded_data_vec[thread_index - 1])?;
+				guarded_data.migrated.remove(&connection_id);
+			}
+
+			// sockets opened by EventHandler::connect or accepted by the r/w threads are always
+			// owned by the EventHandler
+			if conn.ctype != ConnectionType::Connected && !conn.reuse_port {
+				let lookup = cid_map.remove(&fd);
+				if lookup.is_none() {
+					continue;
+				}
+				let lookup = lookup.unwrap();
+				if lookup != connection_id {
+					continue;
+				}
 			}
 
 			{
-				#[cfg(unix)]
-				let res = unsafe { close(fd) };
-				#[cfg(target_os = "windows")]
-				let res = unsafe { ws2_32::closesocket(fd.try_into().unwrap_or(0)) };
+				let res = ctx.selector.close_handle(fd)?;
 				if res != 0 {
 					let e = errno();
 					mainlogerror!("error closing socket: {}", e.to_string());
@@ -1334,200 +3066,165 @@ where
 	}
 
 	fn process_listener_events(
-		count: usize,
-		events: &Vec<GenericEvent>,
+		ctx: &ListenerContext,
+		events: &[GenericEvent],
 		next_index: &mut usize,
-		guarded_data: &mut Vec<Arc<RwLock<GuardedData>>>,
-		on_accept: Pin<Box<G>>,
-		global_lock: Arc<RwLock<bool>>,
-		wakeup_fd: ConnectionHandle,
 		cid_map: &mut HashMap<ConnectionHandle, u128>,
-		tls_server_config: Option<ServerConfig>,
 	) -> Result<(), Error> {
-		for i in 0..count {
-			let event = &events[i];
-			if event.fd == wakeup_fd {
+		let selector = &ctx.selector;
+		let global_lock = &ctx.global_lock;
+		let limits = &ctx.acceptor.limits;
+		let metrics = &ctx.metrics;
+		let guarded_data = &ctx.guarded_data_vec;
+		for event in events {
+			if event.fd == ctx.wakeup_fd {
 				// don't process here. Process in the main loop
 				continue;
 			}
 
-			let res = {
-				let _lock = nioruntime_util::lockw!(global_lock)?;
-				#[cfg(unix)]
-				let res = unsafe {
-					accept(
-						event.fd,
-						&mut libc::sockaddr {
-							..std::mem::zeroed()
-						},
-						&mut (std::mem::size_of::<libc::sockaddr>() as u32)
-							.try_into()
-							.unwrap_or(0),
-					)
-				};
-				#[cfg(target_os = "windows")]
-				let res = unsafe {
-					ws2_32::accept(
-						event.fd.try_into().unwrap_or(0),
-						&mut winapi::ws2def::SOCKADDR {
-							..std::mem::zeroed()
-						},
-						&mut (std::mem::size_of::<winapi::ws2def::SOCKADDR>() as u32)
-							.try_into()
-							.unwrap_or(0),
-					)
-				};
+			if limits.paused_until(Instant::now())?.is_some() {
+				// the accept rate limit was reached, new connections wait in the backlog
+				continue;
+			}
 
-				res
+			let (handle, peer_addr) = {
+				let _lock = nioruntime_util::lockw!(global_lock)?;
+				match selector.accept(event.fd, &ctx.acceptor.socket_options)? {
+					Some(accepted) => accepted,
+					None => continue,
+				}
 			};
 
-			if res > 0 {
-				// set non-blocking
-				#[cfg(unix)]
-				{
-					let fcntl_res = unsafe { fcntl(res, libc::F_SETFL, libc::O_NONBLOCK) };
-					if fcntl_res < 0 {
-						let e = errno().to_string();
-						return Err(ErrorKind::InternalError(format!("fcntl error: {}", e)).into());
-					}
-				}
-				#[cfg(target_os = "windows")]
+			let mut rng = rand::thread_rng();
+			let connection_id = rng.gen();
+			let peer_ip = peer_addr.map(|peer_addr| peer_addr.ip());
+			if let Some(reason) = limits.admit(connection_id, peer_ip)? {
+				Metrics::inc(&metrics.rejects, 1);
 				{
-					let fionbio = 0x8004667eu32;
-					let ioctl_res = unsafe { ws2_32::ioctlsocket(res, fionbio as c_int, &mut 1) };
-
-					if ioctl_res != 0 {
-						mainlogerror!("complete fion with error: {}", errno().to_string());
-					}
-					let sockoptres = unsafe {
-						ws2_32::setsockopt(
-							res,
-							winapi::SOL_SOCKET,
-							winapi::SO_SNDBUF,
-							&WINSOCK_BUF_SIZE as *const _ as *const i8,
-							std::mem::size_of_val(&WINSOCK_BUF_SIZE) as winapi::c_int,
-						)
-					};
-
-					if sockoptres != 0 {
-						mainlogerror!("setsockopt resulted in error: {}", errno().to_string());
+					let _lock = nioruntime_util::lockw!(global_lock)?;
+					if selector.close_handle(handle)? != 0 {
+						mainlogerror!("error closing rejected socket: {}", errno().to_string());
 					}
 				}
-				let index = *next_index % guarded_data.len();
-				*next_index += 1;
-				if *next_index == usize::MAX {
-					*next_index = 0;
+				if let Some(on_reject) = &ctx.acceptor.on_reject {
+					(on_reject)(peer_addr, reason)?;
 				}
-				let guarded_data_next = &guarded_data[index];
-				let mut rng = rand::thread_rng();
-				let connection_id = rng.gen();
-
-				let tls_conn = match tls_server_config.clone() {
-					Some(tls_server_config) => {
-						//let tls_config =
-						//	Arc::new(ServerConfig::clone(config.tls_config.as_ref().unwrap()));
-						let tls_config = Arc::new(tls_server_config);
-
-						match ServerConnection::new(tls_config) {
-							Ok(tls_conn) => Some(Arc::new(RwLock::new(tls_conn))),
-							Err(e) => {
-								mainlogerror!("Error building tls_connection: {}", e.to_string());
-								None
-							}
-						}
-					}
-					None => None,
-				};
-
-				let wh = WriteHandle::new(
-					res.into(),
-					guarded_data[index].clone(),
-					connection_id,
-					global_lock.clone(),
-					None,
-					tls_conn.clone(),
-				);
-				(on_accept)(connection_id, wh)?;
-				cid_map.insert(res.into(), connection_id);
-				{
-					let mut guarded_data_next = nioruntime_util::lockw!(guarded_data_next)?;
+				continue;
+			}
 
-					guarded_data_next.nconns.push(ConnectionInfo {
-						handle: res.into(),
-						connection_id,
-						ctype: ConnectionType::Inbound,
-						sender: None,
-						tls_server: tls_conn,
-						tls_client: None,
-					});
-					guarded_data_next.wakeup()?;
-				}
+			let index =
+				Self::select_thread(guarded_data, ctx.placement_strategy, next_index, peer_addr)?;
+			let guarded_data_next = &guarded_data[index];
+
+			let tls_conn = Self::tls_server_connection(&ctx.acceptor.listener_tls, event.fd)?;
+
+			let conn = ConnectionInfo {
+				handle,
+				connection_id,
+				ctype: ConnectionType::Inbound,
+				sender: None,
+				tls_server: tls_conn,
+				tls_client: None,
+				peer_addr,
+				local_addr: socket_addr(handle, false),
+				attachment: Arc::new(RwLock::new(None)),
+				migrated_from: vec![],
+				reuse_port: false,
+				read_eof: false,
+				write_shutdown: false,
+			};
+			let wh = WriteHandle::from_connection_info(
+				&conn,
+				guarded_data_next.clone(),
+				global_lock.clone(),
+			);
+			metrics
+				.on_accept
+				.time(|| (ctx.acceptor.on_accept)(connection_id, wh))?;
+			Metrics::inc(&metrics.accepts, 1);
+			cid_map.insert(handle, connection_id);
+			{
+				let mut guarded_data_next = nioruntime_util::lockw!(guarded_data_next)?;
+				guarded_data_next.nconns.push(conn);
+				guarded_data_next.wakeup()?;
 			}
 		}
 
 		Ok(())
 	}
 
-	fn listener(
-		selector: SelectorHandle,
-		guarded_data: Arc<RwLock<GuardedData>>,
-		guarded_data_vec: &mut Vec<Arc<RwLock<GuardedData>>>,
-		on_accept: Pin<Box<G>>,
-		on_close: Pin<Box<H>>,
-		global_lock: Arc<RwLock<bool>>,
-		tls_server_config: Option<ServerConfig>,
-	) -> Result<(), Error> {
+	fn listener(ctx: &ListenerContext) -> Result<(), Error> {
+		let selector = &ctx.selector;
+		let guarded_data = &ctx.guarded_data;
+		let metrics = &ctx.metrics;
 		let mut cid_map = HashMap::new();
 		let mut hash_set = HashSet::new();
-		let mut input_events = vec![];
+		let mut input_events = vec![GenericEvent::new(
+			ctx.wakeup_fd,
+			GenericEventType::AddReadLT,
+		)];
 		let mut output_events = vec![];
 		let mut next_index = 0;
-		let wakeup_fd;
-
-		// add wakeup fd
-		{
-			let guarded_data = nioruntime_util::lockw!(guarded_data)?;
-			input_events.push(GenericEvent {
-				fd: guarded_data.wakeup_rx,
-				etype: GenericEventType::AddReadLT,
-			});
-			wakeup_fd = guarded_data.wakeup_rx;
-		}
+		let mut listener_handles = HashSet::new();
+		let mut paused_until: Option<Instant> = None;
 
 		let mut wakeup = false;
 
 		loop {
 			// get new handles
 			let stop = Self::update_listener_input_events(
-				&guarded_data,
+				ctx,
 				&mut input_events,
-				global_lock.clone(),
-				on_close.clone(),
 				&mut cid_map,
+				&mut listener_handles,
 			)?;
 
 			if stop {
-				#[cfg(unix)]
-				let _ = unsafe { close(selector) };
-				#[cfg(target_os = "windows")]
-				let _ = unsafe { ws2_32::closesocket((selector as u64).try_into().unwrap_or(0)) };
+				selector.close()?;
 				break;
 			}
 
+			let stop_accepting = {
+				let mut guarded_data = nioruntime_util::lockw!(guarded_data)?;
+				guarded_data.stop_accepting.take()
+			};
+			if let Some(stop_accepting) = stop_accepting {
+				input_events.retain(|evt| !listener_handles.contains(&evt.fd));
+				for handle in listener_handles.drain() {
+					selector.remove_handle(handle, &mut hash_set)?;
+				}
+				let _ = stop_accepting.send(());
+			}
+
+			// resume accepting once the accept rate allows it
+			if let Some(until) = paused_until {
+				if Instant::now() >= until {
+					paused_until = None;
+					for handle in &listener_handles {
+						input_events.push(GenericEvent::new(*handle, GenericEventType::AddReadLT));
+					}
+				} else {
+					input_events.retain(|evt| !listener_handles.contains(&evt.fd));
+				}
+			}
+
 			output_events.clear();
-			let count = Self::get_events(
-				selector,
+			let count = selector.get_events(
 				input_events.clone(),
 				&mut output_events,
 				&mut hash_set,
 				wakeup,
+				paused_until.map(|until| until.saturating_duration_since(Instant::now())),
+				ctx.max_events,
 			)?;
+			metrics.polled();
 			{
 				let mut guarded_data = nioruntime_util::lockw!(guarded_data)?;
 				if guarded_data.wakeup_scheduled {
 					wakeup = true;
 					guarded_data.wakeup_scheduled = false;
-					let res = do_read_bytes(wakeup_fd, &mut [0u8; 1])?;
+					Metrics::inc(&metrics.wakeups, 1);
+					let res = do_read_bytes(ctx.wakeup_fd, &mut [0u8; 1])?;
 					if res <= 0 {
 						log_multi!(ERROR, MAIN_LOG, "read error on wakeupfd");
 					}
@@ -1539,67 +3236,110 @@ where
 			input_events.clear();
 
 			Self::process_listener_events(
-				count,
-				&output_events,
+				ctx,
+				&output_events[..count],
 				&mut next_index,
-				guarded_data_vec,
-				on_accept.clone(),
-				global_lock.clone(),
-				wakeup_fd,
 				&mut cid_map,
-				tls_server_config.clone(),
 			)?;
+
+			// stop accepting if the accept rate limit was reached
+			if paused_until.is_none() {
+				paused_until = ctx.acceptor.limits.paused_until(Instant::now())?;
+				if paused_until.is_some() {
+					for handle in &listener_handles {
+						selector.remove_handle(*handle, &mut hash_set)?;
+					}
+				}
+			}
 		}
 		Ok(())
 	}
 
-	fn update_rw_input_events(
-		guarded_data: Arc<RwLock<GuardedData>>,
-		input_events: &mut Vec<GenericEvent>,
-		connection_info_map: &mut HashMap<ConnectionHandle, ConnectionInfo>,
-		connection_id_map: &mut HashMap<u128, ConnectionInfo>,
-		hash_set: &mut HashSet<ConnectionHandle>,
-		global_lock: Arc<RwLock<bool>>,
-		on_read: Pin<Box<F>>,
-	) -> Result<bool, Error> {
+	fn update_rw_input_events(ctx: &RwContext, state: &mut RwState) -> Result<bool, Error> {
+		let selector = &ctx.selector;
+		let guarded_data = &ctx.guarded_data;
+		let metrics = &ctx.metrics;
 		let nconns;
 		let stop;
 		let aconns;
+		let read_event_type;
+		let stop_accepting;
 		{
 			let mut guarded_data = nioruntime_util::lockw!(guarded_data)?;
 			stop = guarded_data.stop;
+			read_event_type = guarded_data.read_config.read_event_type();
 			nconns = guarded_data.nconns.clone();
 			aconns = guarded_data.aconns.clone();
 			guarded_data.aconns.clear();
 			guarded_data.nconns.clear();
+			stop_accepting = guarded_data.stop_accepting.take();
+			// counted right away so that they are not missed by PlacementStrategy::LeastConnections
+			let count = nconns
+				.iter()
+				.filter(|conn| conn.ctype != ConnectionType::Listener)
+				.count();
+			Metrics::inc(&metrics.active_connections, count as u64);
+		}
+
+		if let Some(stop_accepting) = stop_accepting {
+			let listeners: Vec<ConnectionHandle> = state
+				.connection_info_map
+				.values()
+				.filter(|conn| conn.ctype == ConnectionType::Listener)
+				.map(|conn| conn.handle)
+				.collect();
+			for handle in listeners {
+				state.connection_info_map.remove(&handle);
+				state.input_events.retain(|event| event.fd != handle);
+				selector.remove_handle(handle, &mut state.filter_set)?;
+			}
+			let _ = stop_accepting.send(());
 		}
 
 		for conn in aconns {
-			match connection_id_map.get(&conn.connection_id) {
-				Some(_) => {
-					let wh = WriteHandle::new(
-						conn.handle,
-						guarded_data.clone(),
-						conn.connection_id,
-						global_lock.clone(),
-						conn.tls_client,
-						conn.tls_server,
-					);
-					(on_read)(&[0u8; 0], 0, wh)?;
-				}
-				None => {} // already closed
+			// skipped if already closed
+			if state.connection_id_map.contains_key(&conn.connection_id) {
+				let wh = WriteHandle::from_connection_info(
+					&conn,
+					guarded_data.clone(),
+					ctx.global_lock.clone(),
+				);
+				(ctx.callbacks.on_read)(&[0u8; 0], 0, wh)?;
 			}
 		}
 
 		for conn in nconns {
-			hash_set.remove(&conn.handle);
-			connection_info_map.insert(conn.handle, conn.clone());
-			connection_id_map.insert(conn.connection_id, conn.clone());
+			if conn.ctype == ConnectionType::Listener {
+				// a listener added with add_tcp_listener_reuse_port. It is edge triggered so
+				// that it does not need to be removed while accepting is paused.
+				state.filter_set.remove(&conn.handle);
+				state.connection_info_map.insert(conn.handle, conn.clone());
+				state
+					.input_events
+					.push(GenericEvent::new(conn.handle, GenericEventType::AddReadET));
+				if let Some(sender) = conn.sender {
+					let _ = sender.send(());
+				}
+				continue;
+			}
+			if conn.ctype == ConnectionType::Inbound {
+				Metrics::inc(&metrics.accepts, 1);
+			}
+			state.filter_set.remove(&conn.handle);
+			state.connection_info_map.insert(conn.handle, conn.clone());
+			state
+				.connection_id_map
+				.insert(conn.connection_id, conn.clone());
+			// a connecting socket becomes writable once the connection completes or fails
 			let ge = GenericEvent {
 				fd: conn.handle,
-				etype: GenericEventType::AddReadET,
+				etype: if conn.ctype == ConnectionType::Connecting {
+					GenericEventType::AddWriteET
+				} else {
+					read_event_type.clone()
+				},
 			};
-			input_events.push(ge);
+			state.input_events.push(ge);
 
 			if conn.sender.is_some() {
 				let _ = conn.sender.unwrap().send(());
@@ -1609,65 +3349,222 @@ where
 		Ok(stop)
 	}
 
+	// writes as many of the buffers at the front of the list as possible with a single call.
+	// For tls connections, only the records encrypted by encrypt_front are written.
+	// returns the number of bytes written, whether to disconnect and whether to stop writing.
 	fn do_write(
+		ctx: &RwContext,
 		fd: ConnectionHandle,
-		write_buffer: &mut WriteBuffer,
-		global_lock: Arc<RwLock<bool>>,
-	) -> Result<(bool, bool, bool), Error> {
-		if write_buffer.len == 0 {
-			return Ok((false, write_buffer.close, true));
-		}
+		list: &mut LinkedList<WriteBuffer>,
+		recycled: &mut Vec<Vec<u8>>,
+		tls: bool,
+	) -> Result<(usize, bool, bool), Error> {
+		let selector = &ctx.selector;
+		let metrics = &ctx.metrics;
+		let len = {
+			let mut bufs = vec![];
+			for write_buffer in list.iter() {
+				let remaining = write_buffer.remaining();
+				if remaining.is_empty() || bufs.len() == MAX_WRITE_VECTORS {
+					break;
+				}
+				match write_buffer.data {
+					WriteData::Encrypted(_) => {}
+					_ if tls => break,
+					_ => {}
+				}
+				bufs.push(IoSlice::new(remaining));
+			}
 
-		let len = write_data(
-			fd,
-			&mut write_buffer.buffer[(write_buffer.offset as usize)
-				..(write_buffer.offset as usize + write_buffer.len as usize)],
-			&global_lock,
-		)?;
+			match bufs.is_empty() {
+				true => match list.front() {
+					Some(WriteBuffer {
+						data: WriteData::File { file, start, len },
+						offset,
+						..
+					}) => {
+						let position = start + *offset as u64;
+						let len: usize = (*len).try_into().unwrap_or(usize::MAX);
+						let _lock = nioruntime_util::lockr!(ctx.global_lock)?;
+						let len = selector.send_file(fd, file, position, len - offset)?;
+						if len == 0 {
+							// the file is shorter than expected, there is nothing more to send.
+							mainlogerror!("send_file: unexpected end of file at {}", position);
+							return Ok((0, true, true));
+						}
+						len
+					}
+					// nothing to write, the front buffer is a close request
+					Some(write_buffer) => return Ok((0, write_buffer.close, true)),
+					None => return Ok((0, false, true)),
+				},
+				false => {
+					let _lock = nioruntime_util::lockr!(ctx.global_lock)?;
+					selector.write(fd, &bufs)?
+				}
+			}
+		};
 
 		if len >= 0 {
-			if len == (write_buffer.len as i32).try_into().unwrap_or(0) {
-				// we're done
-				write_buffer.offset += len as u16;
-				write_buffer.len -= len as u16;
-				return Ok((true, write_buffer.close, false));
-			} else {
-				// update values and write again
-				write_buffer.offset += len as u16;
-				write_buffer.len -= len as u16;
-				return Ok((false, false, false));
+			// pop what was written completely and update the offset of the rest
+			let written = len as usize;
+			Metrics::inc(&metrics.bytes_written, written as u64);
+			let mut len = written;
+			while len > 0 {
+				match list.front_mut() {
+					Some(write_buffer) => {
+						let remaining = write_buffer.remaining_len();
+						if len < remaining {
+							write_buffer.offset += len;
+							break;
+						}
+						len -= remaining;
+					}
+					None => break,
+				}
+				match list.pop_front() {
+					Some(WriteBuffer {
+						data: WriteData::Pooled(buffer),
+						..
+					})
+					| Some(WriteBuffer {
+						data: WriteData::Encrypted(buffer),
+						..
+					}) => recycled.push(buffer),
+					_ => {}
+				}
 			}
+			// write again
+			Ok((written, false, false))
 		} else {
 			let error = errno();
 			if errno().0 == EAGAIN {
 				errno::set_errno(Errno(0));
+				Metrics::inc(&metrics.write_eagain, 1);
 				// break because we're edge triggered.
 				// a new event occurs.
-				return Ok((false, false, true));
+				Ok((0, false, true))
 			} else {
 				// this is an actual write error.
 				// close the connection.
 				mainlogerror!("write error: {}", error.to_string());
-				return Ok((true, true, true));
+				Ok((0, true, true))
+			}
+		}
+	}
+
+	// encrypts the data at the front of the list of a tls connection. The data passed to the
+	// WriteHandle is encrypted here rather than when it is queued so that the records are
+	// produced in the order they are sent, which allows files to be read one chunk at a time.
+	// Returns the number of bytes the queue grew by and whether to disconnect.
+	fn encrypt_front(
+		tls: &TlsConnection,
+		list: &mut LinkedList<WriteBuffer>,
+		recycled: &mut Vec<Vec<u8>>,
+	) -> (isize, bool) {
+		let mut encrypted = LinkedList::new();
+		let mut growth: isize = 0;
+		let mut disconnect = false;
+		while encrypted.len() < MAX_WRITE_VECTORS {
+			let mut write_buffer = match list.pop_front() {
+				Some(write_buffer) => write_buffer,
+				None => break,
+			};
+			let mut records = vec![];
+			// returns the number of plaintext bytes that were encrypted
+			let res = match &write_buffer.data {
+				WriteData::Encrypted(_) => {
+					encrypted.push_back(write_buffer);
+					continue;
+				}
+				WriteData::Empty | WriteData::Shutdown => {
+					list.push_front(write_buffer);
+					break;
+				}
+				WriteData::Pooled(_) | WriteData::Owned(_) => {
+					let plaintext = write_buffer.remaining();
+					tls.encrypt(plaintext, false, &mut records)
+						.map(|_| plaintext.len())
+				}
+				WriteData::TlsFlush => tls.encrypt(&[], false, &mut records).map(|_| 0),
+				WriteData::CloseNotify => tls.encrypt(&[], true, &mut records).map(|_| 0),
+				WriteData::File { file, start, .. } => {
+					let mut chunk = [0u8; TLS_CHUNKS];
+					let position = start + write_buffer.offset as u64;
+					let len = std::cmp::min(write_buffer.remaining_len(), TLS_CHUNKS);
+					let amt = match read_file_at(file, &mut chunk[..len], position) {
+						Ok(amt) => amt,
+						Err(e) => {
+							mainlogerror!("send_file: error reading file: {}", e);
+							0
+						}
+					};
+					if amt == 0 {
+						// the file is shorter than expected, there is nothing more to send.
+						mainlogerror!("send_file: unexpected end of file at {}", position);
+						disconnect = true;
+						break;
+					}
+					tls.encrypt(&chunk[..amt], false, &mut records).map(|_| amt)
+				}
+			};
+			let encrypted_len = match res {
+				Ok(encrypted_len) => encrypted_len,
+				Err(e) => {
+					mainlogerror!("error encrypting data: {}", e);
+					disconnect = true;
+					break;
+				}
+			};
+			growth += records.len() as isize - encrypted_len as isize;
+
+			let connection_id = write_buffer.connection_id;
+			match write_buffer.data {
+				WriteData::File { .. } => {
+					// the rest of the file is read once these records have been written
+					write_buffer.offset += encrypted_len;
+					if !records.is_empty() {
+						encrypted.push_back(WriteBuffer::encrypted(records, connection_id));
+					}
+					if write_buffer.remaining_len() > 0 {
+						list.push_front(write_buffer);
+					}
+					break;
+				}
+				WriteData::Pooled(buffer) => recycled.push(buffer),
+				_ => {}
+			}
+			// nothing is produced for data written before the handshake completes, it is sent
+			// with the handshake
+			if !records.is_empty() {
+				encrypted.push_back(WriteBuffer::encrypted(records, connection_id));
 			}
 		}
+		encrypted.append(list);
+		*list = encrypted;
+		(growth, disconnect)
 	}
 
 	fn process_write_event(
-		selector: SelectorHandle,
-		event: &GenericEvent,
+		ctx: &RwContext,
+		state: &mut RwState,
+		fd: ConnectionHandle,
 		connection_id: u128,
-		write_buffers: &mut HashMap<u128, LinkedList<WriteBuffer>>,
-		global_lock: Arc<RwLock<bool>>,
-		connection_id_map: &mut HashMap<u128, ConnectionInfo>,
-		connection_info_map: &mut HashMap<ConnectionHandle, ConnectionInfo>,
-		listener_guarded_data: Arc<RwLock<GuardedData>>,
-		filter_set: &mut HashSet<ConnectionHandle>,
-		_input_events: &mut Vec<GenericEvent>,
 	) -> Result<(), Error> {
+		let selector = &ctx.selector;
+		let guarded_data = &ctx.guarded_data;
 		let mut disconnect = false;
-		let list = write_buffers.get_mut(&connection_id);
+		let list = state.write_buffers.get_mut(&connection_id);
 		let mut break_received = false;
+		let mut written = 0;
+		let mut recycled = vec![];
+		let mut shutdown = false;
+		let tls = match state.connection_id_map.get(&connection_id) {
+			Some(connection_info) => TlsConnection::from_connection_info(connection_info),
+			None => None,
+		};
+		// the number of bytes the queue grew by when its plaintext was encrypted
+		let mut encrypted_growth: isize = 0;
 		let empty = match list {
 			Some(list) => {
 				loop {
@@ -1675,61 +3572,329 @@ where
 						break;
 					}
 
-					loop {
-						let front = list.front_mut();
-						match front {
-							Some(mut front) => {
-								let (pop, disc, br) =
-									Self::do_write(event.fd, &mut front, global_lock.clone())?;
-								if disc {
-									break_received = true;
-									disconnect = true;
-									break;
-								}
-								if pop {
-									// if all was written, pop
-									list.pop_front();
-								}
-								if br {
-									break_received = true;
-									break;
-								}
-							}
-							None => {
-								break;
-							}
+					// the data queued before the shutdown request has been written
+					if let Some(WriteBuffer {
+						data: WriteData::Shutdown,
+						..
+					}) = list.front()
+					{
+						list.pop_front();
+						let res = {
+							let _lock = nioruntime_util::lockr!(ctx.global_lock)?;
+							selector.shutdown_write(fd)?
+						};
+						if res != 0 {
+							mainlogerror!("shutdown error: {}", errno().to_string());
+							disconnect = true;
+							break;
+						}
+						shutdown = true;
+						continue;
+					}
+
+					if let Some(tls) = &tls {
+						let (growth, disc) = Self::encrypt_front(tls, list, &mut recycled);
+						encrypted_growth += growth;
+						if disc {
+							disconnect = true;
+							break;
 						}
 					}
+
+					let (len, disc, br) =
+						Self::do_write(ctx, fd, list, &mut recycled, tls.is_some())?;
+					written += len;
+					if disc {
+						break_received = true;
+						disconnect = true;
+					}
+					if br {
+						break_received = true;
+					}
 				}
 				list.is_empty()
 			}
 			None => true,
 		};
 
+		if !recycled.is_empty() || encrypted_growth != 0 {
+			let mut guarded_data = nioruntime_util::lockw!(guarded_data)?;
+			for buffer in recycled {
+				guarded_data.recycle_buffer(buffer);
+			}
+			guarded_data.grow_pending(connection_id, encrypted_growth);
+		}
+
+		if shutdown && !disconnect {
+			let read_eof = match state.connection_id_map.get(&connection_id) {
+				Some(connection_info) => connection_info.read_eof,
+				None => false,
+			};
+			match read_eof {
+				// both sides are shut down
+				true => disconnect = true,
+				false => state.set_write_shutdown(connection_id, fd),
+			}
+		}
+
+		if written > 0 && !disconnect {
+			Self::update_pending(ctx, state, connection_id, written)?;
+		}
+
 		// since windows is edge triggered, if list is empty, we need to add a DelWrite event to
 		// avoid infinite loop
 		if empty {
 			#[cfg(windows)]
-			Self::disable_write(_input_events, event.fd)?;
+			match state.connection_info_map.get(&fd) {
+				// re-registering reads would report the end of the stream continuously
+				Some(connection_info) if connection_info.read_eof && !disconnect => {
+					selector.remove_handle(fd, &mut state.filter_set)?
+				}
+				_ => Self::disable_write(&mut state.input_events, fd)?,
+			}
+			// writes are registered edge triggered, so restore level triggered reads unless the
+			// end of the stream was already read
+			#[cfg(not(windows))]
+			if !disconnect && ctx.read_config.trigger_mode == TriggerMode::Level {
+				let read_eof = match state.connection_info_map.get(&fd) {
+					Some(connection_info) => connection_info.read_eof,
+					None => false,
+				};
+				if !read_eof {
+					state
+						.input_events
+						.push(GenericEvent::new(fd, GenericEventType::AddReadLT));
+				}
+			}
+		} else if !disconnect && selector.one_shot_writes() {
+			// register for the next write event, the selector reports it only once
+			let etype = match state.connection_info_map.get(&fd) {
+				Some(connection_info) => connection_info.write_event_type(),
+				None => GenericEventType::AddWriteET,
+			};
+			state.input_events.push(GenericEvent::new(fd, etype));
 		}
 
 		if disconnect {
-			let fd = event.fd;
-			if connection_id_map.remove(&connection_id).is_some() {
-				connection_info_map.remove(&fd);
-				write_buffers.remove(&connection_id);
-				Self::remove_handle(selector, fd, filter_set)?;
-
-				let mut listener_guarded_data = nioruntime_util::lockw!(listener_guarded_data)?;
-				listener_guarded_data.cconns.push(ConnectionInfo {
-					handle: fd,
-					connection_id,
-					ctype: ConnectionType::Inbound,
-					sender: None,
-					tls_client: None,
-					tls_server: None,
-				});
-				listener_guarded_data.wakeup()?;
+			Self::close_connection(ctx, state, fd, connection_id)?;
+			let mut guarded_data = nioruntime_util::lockw!(guarded_data)?;
+			guarded_data.clear_pending(connection_id);
+		}
+
+		Ok(())
+	}
+
+	// removes the connection from this thread and reports it closed to the listener thread which
+	// executes on_close. The bytes that are still pending are kept for EventHandler::stop_graceful
+	// and cleared by the caller otherwise.
+	fn close_connection(
+		ctx: &RwContext,
+		state: &mut RwState,
+		fd: ConnectionHandle,
+		connection_id: u128,
+	) -> Result<(), Error> {
+		if let Some(removed) = state.connection_id_map.remove(&connection_id) {
+			state.connection_info_map.remove(&fd);
+			state.write_buffers.remove(&connection_id);
+			ctx.selector.remove_handle(fd, &mut state.filter_set)?;
+
+			Metrics::inc(&ctx.metrics.closes, 1);
+			let mut listener_guarded_data = nioruntime_util::lockw!(ctx.listener_guarded_data)?;
+			listener_guarded_data.cconns.push(ConnectionInfo {
+				handle: fd,
+				connection_id,
+				ctype: removed.ctype,
+				sender: None,
+				tls_client: None,
+				tls_server: None,
+				peer_addr: None,
+				local_addr: None,
+				attachment: removed.attachment,
+				migrated_from: removed.migrated_from,
+				reuse_port: removed.reuse_port,
+				read_eof: false,
+				write_shutdown: false,
+			});
+			listener_guarded_data.wakeup()?;
+		}
+		Ok(())
+	}
+
+	fn update_timers(ctx: &RwContext, state: &mut RwState) -> Result<(), Error> {
+		let timer_wheel = &mut state.timer_wheel;
+		let idle_timeouts = &mut state.idle_timeouts;
+		let (timers, timeouts, connect_timeouts) = {
+			let mut guarded_data = nioruntime_util::lockw!(ctx.guarded_data)?;
+			(
+				std::mem::take(&mut guarded_data.timers),
+				std::mem::take(&mut guarded_data.timeouts),
+				std::mem::take(&mut guarded_data.connect_timeouts),
+			)
+		};
+
+		for timer in timers {
+			timer_wheel.insert(timer.deadline, TimerEvent::Scheduled(timer));
+		}
+
+		let now = Instant::now();
+		for (connection_id, timeout) in connect_timeouts {
+			timer_wheel.insert(now + timeout, TimerEvent::ConnectTimeout(connection_id));
+		}
+
+		for (connection_id, timeout) in timeouts {
+			match timeout {
+				Some(timeout) => {
+					let generation = match idle_timeouts.get(&connection_id) {
+						Some(idle_timeout) => idle_timeout.generation + 1,
+						None => 0,
+					};
+					idle_timeouts.insert(
+						connection_id,
+						IdleTimeout {
+							timeout,
+							last_activity: now,
+							generation,
+						},
+					);
+					timer_wheel.insert(
+						now + timeout,
+						TimerEvent::IdleTimeout(connection_id, generation),
+					);
+				}
+				None => {
+					idle_timeouts.remove(&connection_id);
+				}
+			}
+		}
+
+		Ok(())
+	}
+
+	fn process_timers(ctx: &RwContext, state: &mut RwState) -> Result<(), Error> {
+		let guarded_data = &ctx.guarded_data;
+		let metrics = &ctx.metrics;
+		if state.timer_wheel.len() == 0 {
+			return Ok(());
+		}
+
+		let now = Instant::now();
+		for event in state.timer_wheel.expire(now) {
+			match event {
+				TimerEvent::Scheduled(timer) => {
+					if !timer.handle.is_cancelled()? {
+						match (timer.callback)() {
+							Ok(_) => {}
+							Err(e) => {
+								mainlogerror!("timer callback generated error: {}", e.to_string());
+							}
+						}
+					}
+				}
+				TimerEvent::IdleTimeout(connection_id, generation) => {
+					let idle_timeout = match state.idle_timeouts.get(&connection_id) {
+						Some(idle_timeout) => idle_timeout,
+						None => continue,
+					};
+					if idle_timeout.generation != generation {
+						// replaced by a later call to set_timeout
+						continue;
+					}
+					let connection_info = match state.connection_id_map.get(&connection_id) {
+						Some(connection_info) => connection_info,
+						None => {
+							// connection already closed
+							state.idle_timeouts.remove(&connection_id);
+							continue;
+						}
+					};
+
+					let deadline = idle_timeout.last_activity + idle_timeout.timeout;
+					if deadline > now {
+						// there was activity, check again later
+						state
+							.timer_wheel
+							.insert(deadline, TimerEvent::IdleTimeout(connection_id, generation));
+						continue;
+					}
+
+					state.idle_timeouts.remove(&connection_id);
+					if let Some(on_timeout) = &ctx.callbacks.on_timeout {
+						let wh = WriteHandle::from_connection_info(
+							connection_info,
+							guarded_data.clone(),
+							ctx.global_lock.clone(),
+						);
+						metrics.on_timeout.time(|| (on_timeout)(wh))?;
+					}
+				}
+				TimerEvent::ConnectTimeout(connection_id) => {
+					let handle = match state.connection_id_map.get(&connection_id) {
+						Some(connection_info) => {
+							if connection_info.ctype != ConnectionType::Connecting {
+								// already connected
+								continue;
+							}
+							connection_info.handle
+						}
+						None => continue,
+					};
+					Self::fail_connect(
+						ctx,
+						state,
+						handle,
+						connection_id,
+						ErrorKind::Timeout(format!("connection {} timed out", connection_id))
+							.into(),
+					)?;
+				}
+				TimerEvent::ResumeAccept(handle) => {
+					if state.connection_info_map.contains_key(&handle) {
+						// registering the listener again reports the pending connections
+						state
+							.input_events
+							.push(GenericEvent::new(handle, GenericEventType::AddReadET));
+					}
+				}
+			}
+		}
+
+		Ok(())
+	}
+
+	fn update_pending(
+		ctx: &RwContext,
+		state: &RwState,
+		connection_id: u128,
+		written: usize,
+	) -> Result<(), Error> {
+		let guarded_data = &ctx.guarded_data;
+		let resume = {
+			let mut guarded_data = nioruntime_util::lockw!(guarded_data)?;
+			let low_water = guarded_data.write_queue_low_water;
+			let pending = match guarded_data.pending_bytes.get_mut(&connection_id) {
+				Some(pending) => {
+					*pending = pending.saturating_sub(written);
+					*pending
+				}
+				None => 0,
+			};
+			if pending == 0 {
+				guarded_data.pending_bytes.remove(&connection_id);
+			}
+			pending <= low_water && guarded_data.paused.remove(&connection_id)
+		};
+
+		if resume {
+			if let (Some(on_writable), Some(connection_info)) = (
+				&ctx.callbacks.on_writable,
+				state.connection_id_map.get(&connection_id),
+			) {
+				let wh = WriteHandle::from_connection_info(
+					connection_info,
+					guarded_data.clone(),
+					ctx.global_lock.clone(),
+				);
+				ctx.metrics.on_writable.time(|| (on_writable)(wh))?;
 			}
 		}
 
@@ -1750,38 +3915,32 @@ where
 	}
 
 	fn process_read_result(
-		selector: SelectorHandle,
+		ctx: &RwContext,
+		state: &mut RwState,
 		fd: ConnectionHandle,
-		listener_guarded_data: Arc<RwLock<GuardedData>>,
-		guarded_data: Arc<RwLock<GuardedData>>,
+		connection_id: u128,
 		buf: &[u8],
 		len: isize,
-		connection_id: u128,
-		on_read: Pin<Box<F>>,
-		on_client_read: Pin<Box<K>>,
-		connection_id_map: &mut HashMap<u128, ConnectionInfo>,
-		connection_info_map: &mut HashMap<ConnectionHandle, ConnectionInfo>,
-		write_buffers: &mut HashMap<u128, LinkedList<WriteBuffer>>,
-		global_lock: Arc<RwLock<bool>>,
-		filter_set: &mut HashSet<ConnectionHandle>,
 	) -> Result<bool, Error> {
-		let connection_info = connection_id_map.get(&connection_id);
+		let metrics = &ctx.metrics;
+		let connection_info = state.connection_id_map.get(&connection_id);
 		if connection_info.is_some() {
 			let connection_info = connection_info.unwrap();
 			if len > 0 {
-				let wh = WriteHandle::new(
-					fd,
-					guarded_data,
-					connection_id,
-					global_lock,
-					connection_info.tls_client.clone(),
-					connection_info.tls_server.clone(),
+				let wh = WriteHandle::from_connection_info(
+					connection_info,
+					ctx.guarded_data.clone(),
+					ctx.global_lock.clone(),
 				);
+				Metrics::inc(&metrics.bytes_read, len as u64);
+				let len = len.try_into().unwrap_or(0);
 				match connection_info.ctype {
-					ConnectionType::Inbound => (on_read)(buf, len.try_into().unwrap_or(0), wh)?,
-					ConnectionType::Outbound => {
-						(on_client_read)(buf, len.try_into().unwrap_or(0), wh)?
-					}
+					ConnectionType::Inbound => metrics
+						.on_read
+						.time(|| (ctx.callbacks.on_read)(buf, len, wh))?,
+					ConnectionType::Outbound | ConnectionType::Connected => metrics
+						.on_read
+						.time(|| (ctx.callbacks.on_client_read)(buf, len, wh))?,
 					_ => {} // not expected
 				}
 				Ok(true)
@@ -1792,28 +3951,41 @@ where
 					if e.0 == EAGAIN || len == -2 {
 						// -2 is windows would block
 						errno::set_errno(Errno(0));
+						Metrics::inc(&metrics.read_eagain, 1);
 						do_close = false;
 					}
+				} else if connection_info.read_eof {
+					// the end of the stream was already reported, wait for the connection to
+					// be closed by the user
+					do_close = false;
+				} else if !connection_info.write_shutdown {
+					if let Some(on_read_eof) = &ctx.callbacks.on_read_eof {
+						let wh = WriteHandle::from_connection_info(
+							connection_info,
+							ctx.guarded_data.clone(),
+							ctx.global_lock.clone(),
+						);
+						if (on_read_eof)(wh)? {
+							do_close = false;
+							state.set_read_eof(connection_id, fd);
+							// the socket stays readable, so read interest is removed to
+							// avoid reporting it continuously. Writes that are still queued
+							// are registered again without it.
+							ctx.selector.remove_handle(fd, &mut state.filter_set)?;
+							match state.write_buffers.get(&connection_id) {
+								Some(list) if !list.is_empty() => state
+									.input_events
+									.push(GenericEvent::new(fd, GenericEventType::AddWriteOnlyET)),
+								_ => {}
+							}
+						}
+					}
 				}
 
 				if do_close {
-					if connection_id_map.remove(&connection_id).is_some() {
-						connection_info_map.remove(&fd);
-						write_buffers.remove(&connection_id);
-						Self::remove_handle(selector, fd, filter_set)?;
-
-						let mut listener_guarded_data =
-							nioruntime_util::lockw!(listener_guarded_data)?;
-						listener_guarded_data.cconns.push(ConnectionInfo {
-							handle: fd,
-							connection_id,
-							ctype: ConnectionType::Inbound,
-							sender: None,
-							tls_client: None,
-							tls_server: None,
-						});
-						listener_guarded_data.wakeup()?;
-					}
+					Self::close_connection(ctx, state, fd, connection_id)?;
+					let mut guarded_data = nioruntime_util::lockw!(ctx.guarded_data)?;
+					guarded_data.clear_pending(connection_id);
 				}
 				Ok(false)
 			}
@@ -1830,24 +4002,59 @@ where
 		}
 	}
 
-	fn process_writes(
-		guarded_data: Arc<RwLock<GuardedData>>,
-		write_buffers: &mut HashMap<u128, LinkedList<WriteBuffer>>,
-		input_events: &mut Vec<GenericEvent>,
-		connection_id_map: &mut HashMap<u128, ConnectionInfo>,
-	) -> Result<(), Error> {
-		let write_queue = {
+	fn process_writes(ctx: &RwContext, state: &mut RwState) -> Result<(), Error> {
+		let guarded_data = &ctx.guarded_data;
+		let metrics = &ctx.metrics;
+		// the connections moved to this thread are taken with the write queue so that they are
+		// known before the writes that were queued for them after the move are processed
+		let (write_queue, migrated_conns) = {
 			let mut guarded_data = nioruntime_util::lockw!(guarded_data)?;
-			let ret = guarded_data.write_queue.clone();
-			guarded_data.write_queue.clear();
-			ret
+			(
+				std::mem::take(&mut guarded_data.write_queue),
+				std::mem::take(&mut guarded_data.migrated_conns),
+			)
 		};
 
 		let mut hash_set = HashSet::new();
+		let mut closed = vec![];
+
+		let mut recheck = vec![];
+		Metrics::inc(&metrics.active_connections, migrated_conns.len() as u64);
+		for migration in migrated_conns {
+			let conn = migration.conn;
+			state.filter_set.remove(&conn.handle);
+			ctx.selector.attach(conn.handle, migration.received)?;
+			state.connection_info_map.insert(conn.handle, conn.clone());
+			state
+				.connection_id_map
+				.insert(conn.connection_id, conn.clone());
+			if !conn.read_eof {
+				state.input_events.push(GenericEvent::new(
+					conn.handle,
+					ctx.read_config.read_event_type(),
+				));
+			}
+			if !migration.write_buffers.is_empty() {
+				state
+					.write_buffers
+					.insert(conn.connection_id, migration.write_buffers);
+				hash_set.insert(conn.handle);
+				state
+					.input_events
+					.push(GenericEvent::new(conn.handle, conn.write_event_type()));
+			}
+			if migration.recheck {
+				recheck.push(conn);
+			}
+		}
+		if !recheck.is_empty() {
+			let mut guarded_data = nioruntime_util::lockw!(guarded_data)?;
+			guarded_data.aconns.append(&mut recheck);
+		}
 
 		for write_buffer in write_queue {
-			let list = write_buffers.get_mut(&write_buffer.connection_id);
-			let connection_info = connection_id_map.get(&write_buffer.connection_id);
+			let list = state.write_buffers.get_mut(&write_buffer.connection_id);
+			let connection_info = state.connection_id_map.get(&write_buffer.connection_id);
 
 			match connection_info {
 				Some(connection_info) => {
@@ -1858,129 +4065,318 @@ where
 						None => {
 							let mut list = LinkedList::new();
 							list.push_back(write_buffer);
-							write_buffers.insert(connection_info.connection_id, list);
+							state
+								.write_buffers
+								.insert(connection_info.connection_id, list);
 						}
 					}
 					let ge = GenericEvent {
 						fd: connection_info.handle,
-						etype: GenericEventType::AddWriteET,
+						etype: connection_info.write_event_type(),
 					};
 					if hash_set.get(&connection_info.handle).is_none() {
 						hash_set.insert(connection_info.handle);
-						input_events.push(ge);
+						state.input_events.push(ge);
 					}
 				}
 				None => {
 					// the connection already closed
+					closed.push(write_buffer.connection_id);
 					log_multi!(
 						DEBUG,
 						MAIN_LOG,
 						"connection not found write_buffer: '{}', map='{:?}'",
 						write_buffer.connection_id,
-						connection_id_map
+						state.connection_id_map
 					);
 				}
 			}
 		}
 
+		if !closed.is_empty() {
+			let mut guarded_data = nioruntime_util::lockw!(guarded_data)?;
+			for connection_id in closed {
+				guarded_data.clear_pending(connection_id);
+			}
+		}
+
 		Ok(())
 	}
 
-	fn rwthread(
-		selector: SelectorHandle,
-		listener_guarded_data: Arc<RwLock<GuardedData>>,
-		guarded_data: Arc<RwLock<GuardedData>>,
-		on_read: Pin<Box<F>>,
-		on_client_read: Pin<Box<K>>,
-		global_lock: Arc<RwLock<bool>>,
-		connection_info_map: Arc<RwLock<HashMap<ConnectionHandle, ConnectionInfo>>>,
-		connection_id_map: Arc<RwLock<HashMap<u128, ConnectionInfo>>>,
-		hash_set: Arc<RwLock<HashSet<ConnectionHandle>>>,
-		write_buffers: Arc<RwLock<HashMap<u128, LinkedList<WriteBuffer>>>>,
-		wakeup_fd: ConnectionHandle,
-		input_events: Arc<RwLock<Vec<GenericEvent>>>,
-		output_events: Arc<RwLock<Vec<GenericEvent>>>,
-		counter: Arc<RwLock<usize>>,
-		res: Arc<RwLock<usize>>,
+	// accept the connections on a listener added with EventHandler::add_tcp_listener_reuse_port.
+	// Unlike the listener thread, the r/w threads accept without the global lock.
+	fn process_accept(
+		ctx: &RwContext,
+		state: &mut RwState,
+		listener: ConnectionHandle,
 	) -> Result<(), Error> {
-		let mut connection_info_map = nioruntime_util::lockwp!(connection_info_map);
-		let mut connection_id_map = nioruntime_util::lockwp!(connection_id_map);
-		let mut hash_set = nioruntime_util::lockwp!(hash_set);
-		let mut write_buffers = nioruntime_util::lockwp!(write_buffers);
-		let mut input_events = nioruntime_util::lockwp!(input_events);
-		let mut output_events = nioruntime_util::lockwp!(output_events);
-		let mut counter = nioruntime_util::lockwp!(counter);
-		let mut res = nioruntime_util::lockwp!(res);
+		let selector = &ctx.selector;
+		let global_lock = &ctx.global_lock;
+		let acceptor = &ctx.acceptor;
+		let metrics = &ctx.metrics;
+		loop {
+			if let Some(paused_until) = acceptor.limits.paused_until(Instant::now())? {
+				// the accept rate limit was reached, new connections wait in the backlog
+				state
+					.timer_wheel
+					.insert(paused_until, TimerEvent::ResumeAccept(listener));
+				return Ok(());
+			}
 
-		// handle panic in progress events here
-		*counter += 1;
-		Self::process_events(
-			selector,
-			&mut counter,
-			&mut res,
-			&output_events,
-			wakeup_fd,
-			listener_guarded_data.clone(),
-			&mut connection_info_map,
-			&mut connection_id_map,
-			&mut write_buffers,
-			global_lock.clone(),
-			on_read.clone(),
-			on_client_read.clone(),
-			guarded_data.clone(),
-			&mut hash_set,
-			&mut input_events,
-		)?;
+			let (handle, peer_addr) = match selector.accept(listener, &acceptor.socket_options)? {
+				Some(accepted) => accepted,
+				None => return Ok(()),
+			};
 
-		if *res != 0 {
-			input_events.clear();
+			let mut rng = rand::thread_rng();
+			let connection_id = rng.gen();
+			let peer_ip = peer_addr.map(|peer_addr| peer_addr.ip());
+			if let Some(reason) = acceptor.limits.admit(connection_id, peer_ip)? {
+				Metrics::inc(&metrics.rejects, 1);
+				{
+					let _lock = nioruntime_util::lockw!(global_lock)?;
+					if selector.close_handle(handle)? != 0 {
+						mainlogerror!("error closing rejected socket: {}", errno().to_string());
+					}
+				}
+				if let Some(on_reject) = &acceptor.on_reject {
+					(on_reject)(peer_addr, reason)?;
+				}
+				continue;
+			}
+
+			let conn = ConnectionInfo {
+				handle,
+				connection_id,
+				ctype: ConnectionType::Inbound,
+				sender: None,
+				tls_server: Self::tls_server_connection(&acceptor.listener_tls, listener)?,
+				tls_client: None,
+				peer_addr,
+				local_addr: socket_addr(handle, false),
+				attachment: Arc::new(RwLock::new(None)),
+				migrated_from: vec![],
+				reuse_port: true,
+				read_eof: false,
+				write_shutdown: false,
+			};
+			let wh = WriteHandle::from_connection_info(
+				&conn,
+				ctx.guarded_data.clone(),
+				global_lock.clone(),
+			);
+			metrics
+				.on_accept
+				.time(|| (acceptor.on_accept)(connection_id, wh))?;
+			Metrics::inc(&metrics.accepts, 1);
+			Metrics::inc(&metrics.active_connections, 1);
+
+			state.connection_info_map.insert(handle, conn.clone());
+			state.connection_id_map.insert(connection_id, conn);
+			state
+				.input_events
+				.push(GenericEvent::new(handle, ctx.read_config.read_event_type()));
 		}
-		output_events.clear();
+	}
 
-		let mut wakeup = false;
+	// returns the tls connection for a connection accepted on `listener` if it is a tls listener
+	fn tls_server_connection(
+		listener_tls: &Arc<RwLock<HashMap<ConnectionHandle, ListenerTls>>>,
+		listener: ConnectionHandle,
+	) -> Result<Option<Arc<RwLock<ServerConnection>>>, Error> {
+		let tls_server_config = {
+			let listener_tls = nioruntime_util::lockr!(listener_tls)?;
+			listener_tls
+				.get(&listener)
+				.map(|tls| tls.server_config.clone())
+		};
+		Ok(match tls_server_config {
+			Some(tls_server_config) => match ServerConnection::new(tls_server_config) {
+				Ok(tls_conn) => Some(Arc::new(RwLock::new(tls_conn))),
+				Err(e) => {
+					mainlogerror!("Error building tls_connection: {}", e.to_string());
+					None
+				}
+			},
+			None => None,
+		})
+	}
 
-		loop {
-			// see if there's any new write buffers to process
-			Self::process_writes(
-				guarded_data.clone(),
-				&mut write_buffers,
-				&mut input_events,
-				&mut connection_id_map,
-			)?;
+	// move the connections requested with EventHandler::migrate that are owned by this thread
+	fn process_migrations(ctx: &RwContext, state: &mut RwState) -> Result<(), Error> {
+		let guarded_data = &ctx.guarded_data;
+		let (requests, thread_index) = {
+			let mut guarded_data = nioruntime_util::lockw!(guarded_data)?;
+			(
+				std::mem::take(&mut guarded_data.migration_requests),
+				guarded_data.thread_index,
+			)
+		};
 
-			// get new handles
-			let stop = Self::update_rw_input_events(
-				guarded_data.clone(),
-				&mut input_events,
-				&mut connection_info_map,
-				&mut connection_id_map,
-				&mut hash_set,
-				global_lock.clone(),
-				on_read.clone(),
-			)?;
+		for request in requests {
+			let connection_id = request.connection_id;
+			let mut conn = match state.connection_id_map.get(&connection_id) {
+				Some(conn) => match conn.ctype {
+					ConnectionType::Inbound
+					| ConnectionType::Outbound
+					| ConnectionType::Connected => conn.clone(),
+					_ => continue,
+				},
+				None => continue, // owned by another thread or already closed
+			};
+
+			state.connection_id_map.remove(&connection_id);
+			state.connection_info_map.remove(&conn.handle);
+			// the selector may hold data that was received but not read or written but not sent
+			let (received, unsent) = ctx.selector.detach(conn.handle, &mut state.filter_set)?;
+			state.input_events.retain(|event| event.fd != conn.handle);
+			let mut list = state
+				.write_buffers
+				.remove(&connection_id)
+				.unwrap_or_default();
+			let unsent_len = unsent.len();
+			if unsent_len > 0 {
+				let data = match TlsConnection::from_connection_info(&conn) {
+					Some(_) => WriteData::Encrypted(unsent),
+					None => WriteData::Pooled(unsent),
+				};
+				list.push_front(WriteBuffer {
+					data,
+					offset: 0,
+					close: false,
+					connection_id,
+				});
+			}
+			let timeout = state
+				.idle_timeouts
+				.remove(&connection_id)
+				.map(|idle_timeout| idle_timeout.timeout);
+			conn.migrated_from.push(thread_index);
+
+			// both threads are locked in the order of their index so that concurrent migrations
+			// in opposite directions can't deadlock
+			let (mut source, mut target) = if thread_index < request.thread {
+				let source = nioruntime_util::lockw!(guarded_data)?;
+				let target = nioruntime_util::lockw!(request.guarded_data)?;
+				(source, target)
+			} else {
+				let target = nioruntime_util::lockw!(request.guarded_data)?;
+				let source = nioruntime_util::lockw!(guarded_data)?;
+				(source, target)
+			};
+
+			// the writes queued since the last call to process_writes follow the buffers that
+			// were already being written
+			for write_buffer in std::mem::take(&mut source.write_queue) {
+				if write_buffer.connection_id == connection_id {
+					list.push_back(write_buffer);
+				} else {
+					source.write_queue.push(write_buffer);
+				}
+			}
+			if let Some(timeout) = timeout {
+				target.timeouts.push((connection_id, Some(timeout)));
+			}
+			for timeout in std::mem::take(&mut source.timeouts) {
+				if timeout.0 == connection_id {
+					target.timeouts.push(timeout);
+				} else {
+					source.timeouts.push(timeout);
+				}
+			}
+			let aconns_len = source.aconns.len();
+			source
+				.aconns
+				.retain(|aconn| aconn.connection_id != connection_id);
+			let recheck = source.aconns.len() != aconns_len;
+			if let Some(pending) = source.pending_bytes.remove(&connection_id) {
+				target.pending_bytes.insert(connection_id, pending);
+			}
+			if unsent_len > 0 {
+				target.grow_pending(connection_id, unsent_len as isize);
+			}
+			if source.paused.remove(&connection_id) {
+				target.paused.insert(connection_id);
+			}
+
+			source
+				.migrated
+				.insert(connection_id, request.guarded_data.clone());
+			target.migrated_conns.push(Migration {
+				conn,
+				write_buffers: list,
+				received,
+				recheck,
+			});
+			target.wakeup()?;
+		}
+
+		Ok(())
+	}
+
+	fn rwthread(ctx: &RwContext, state: &Arc<RwLock<RwState>>) -> Result<(), Error> {
+		let mut state = nioruntime_util::lockwp!(state);
+		let state = &mut *state;
+		let selector = &ctx.selector;
+		let guarded_data = &ctx.guarded_data;
+		let metrics = &ctx.metrics;
+		let mut read_buf = vec![0u8; ctx.read_config.buffer_size];
+
+		// handle panic in progress events here
+		state.counter += 1;
+		Self::process_events(ctx, state, &mut read_buf)?;
+
+		if state.res != 0 {
+			state.input_events.clear();
+		}
+		state.output_events.clear();
+
+		let mut wakeup = false;
+
+		loop {
+			// see if there's any new write buffers to process
+			Self::process_migrations(ctx, state)?;
+
+			Self::process_writes(ctx, state)?;
+
+			// get new handles
+			let stop = Self::update_rw_input_events(ctx, state)?;
 
 			if stop {
-				#[cfg(unix)]
-				let _ = unsafe { close(selector) };
-				#[cfg(target_os = "windows")]
-				let _ = unsafe { ws2_32::closesocket((selector as u64).try_into().unwrap_or(0)) };
+				let close_on_stop = {
+					let guarded_data = nioruntime_util::lockr!(guarded_data)?;
+					guarded_data.close_on_stop
+				};
+				if close_on_stop {
+					Self::close_all(ctx, state)?;
+				}
+				selector.close()?;
 				break;
 			}
 
-			*res = Self::get_events(
-				selector,
-				input_events.clone(),
-				&mut output_events,
-				&mut hash_set,
-				wakeup,
+			Self::update_timers(ctx, state)?;
+
+			// connections that reached the read limit are read again without waiting
+			let pending = !state.pending_reads.is_empty();
+			state.output_events.append(&mut state.pending_reads);
+
+			state.res = selector.get_events(
+				state.input_events.clone(),
+				&mut state.output_events,
+				&mut state.filter_set,
+				wakeup || pending,
+				state.timer_wheel.next_timeout(Instant::now()),
+				ctx.read_config.max_events,
 			)?;
+			metrics.polled();
 
 			{
 				let mut guarded_data = nioruntime_util::lockw!(guarded_data)?;
 				if guarded_data.wakeup_scheduled {
 					wakeup = true;
 					guarded_data.wakeup_scheduled = false;
-					let res = do_read_bytes(wakeup_fd, &mut [0u8; 1])?;
+					Metrics::inc(&metrics.wakeups, 1);
+					let res = do_read_bytes(ctx.wakeup_fd, &mut [0u8; 1])?;
 					if res <= 0 {
 						log_multi!(ERROR, MAIN_LOG, "read error on wakeupfd");
 					}
@@ -1989,193 +4385,48 @@ where
 				}
 			}
 
-			input_events.clear();
-			*counter = 0;
-			Self::process_events(
-				selector,
-				&mut counter,
-				&mut res,
-				&output_events,
-				wakeup_fd,
-				listener_guarded_data.clone(),
-				&mut connection_info_map,
-				&mut connection_id_map,
-				&mut write_buffers,
-				global_lock.clone(),
-				on_read.clone(),
-				on_client_read.clone(),
-				guarded_data.clone(),
-				&mut hash_set,
-				&mut input_events,
-			)?;
+			state.input_events.clear();
+			state.counter = 0;
+			Self::process_events(ctx, state, &mut read_buf)?;
 
-			output_events.clear();
+			state.output_events.clear();
+
+			Self::process_timers(ctx, state)?;
+
+			Metrics::set(
+				&metrics.active_connections,
+				state.connection_id_map.len() as u64,
+			);
 		}
 		Ok(())
 	}
 
+	// processes the output events from state.counter on. The counter is kept in the state so that
+	// the thread that replaces one that panicked continues with the next event.
 	fn process_events(
-		selector: SelectorHandle,
-		counter: &mut RwLockWriteGuard<usize>,
-		res: &mut RwLockWriteGuard<usize>,
-		output_events: &Vec<GenericEvent>,
-		wakeup_fd: ConnectionHandle,
-		listener_guarded_data: Arc<RwLock<GuardedData>>,
-		connection_info_map: &mut HashMap<ConnectionHandle, ConnectionInfo>,
-		connection_id_map: &mut HashMap<u128, ConnectionInfo>,
-		write_buffers: &mut HashMap<u128, LinkedList<WriteBuffer>>,
-		global_lock: Arc<RwLock<bool>>,
-		on_read: Pin<Box<F>>,
-		on_client_read: Pin<Box<K>>,
-		guarded_data: Arc<RwLock<GuardedData>>,
-		filter_set: &mut HashSet<ConnectionHandle>,
-		input_events: &mut Vec<GenericEvent>,
+		ctx: &RwContext,
+		state: &mut RwState,
+		read_buf: &mut [u8],
 	) -> Result<(), Error> {
-		for i in **counter..**res {
-			match output_events[i].etype {
-				GenericEventType::AddReadET | GenericEventType::AddReadLT => {
-					if output_events[i].fd == wakeup_fd {
-						// ignore wakeupfd here process in main loop.
-					} else {
-						let conn_info = connection_info_map.get(&output_events[i].fd);
-						match conn_info {
-							Some(conn_info) => {
-								let handle = conn_info.handle;
-								let connection_id = conn_info.connection_id;
-								match conn_info.tls_server.clone() {
-									Some(mut tls_conn) => loop {
-										let mut buf = vec![];
-										let (raw_len, tls_len) = Self::do_tls_read(
-											handle,
-											connection_id,
-											guarded_data.clone(),
-											&mut buf,
-											global_lock.clone(),
-											&mut tls_conn,
-										)?;
-										if raw_len <= 0 || tls_len > 0 {
-											let len = if tls_len > 0 {
-												tls_len.try_into().unwrap_or(0)
-											} else {
-												raw_len
-											};
-
-											if !Self::process_read_result(
-												selector,
-												handle,
-												listener_guarded_data.clone(),
-												guarded_data.clone(),
-												&buf,
-												len,
-												connection_id,
-												on_read.clone(),
-												on_client_read.clone(),
-												connection_id_map,
-												connection_info_map,
-												write_buffers,
-												global_lock.clone(),
-												filter_set,
-											)? {
-												break;
-											}
-										}
-									},
-									None => match conn_info.tls_client.clone() {
-										Some(mut tls_conn) => loop {
-											let mut buf = vec![];
-											let (raw_len, tls_len) = Self::do_tls_client_read(
-												handle,
-												connection_id,
-												guarded_data.clone(),
-												&mut buf,
-												global_lock.clone(),
-												&mut tls_conn,
-											)?;
-											if raw_len <= 0 || tls_len > 0 {
-												let len = if tls_len > 0 {
-													tls_len.try_into().unwrap_or(0)
-												} else {
-													raw_len
-												};
-
-												if !Self::process_read_result(
-													selector,
-													handle,
-													listener_guarded_data.clone(),
-													guarded_data.clone(),
-													&buf,
-													len,
-													connection_id,
-													on_read.clone(),
-													on_client_read.clone(),
-													connection_id_map,
-													connection_info_map,
-													write_buffers,
-													global_lock.clone(),
-													filter_set,
-												)? {
-													break;
-												}
-											}
-										},
-										None => loop {
-											let mut buf = [0u8; BUFFER_SIZE];
-											let len = Self::do_read(
-												handle,
-												&mut buf,
-												global_lock.clone(),
-											)?;
-											if !Self::process_read_result(
-												selector,
-												handle,
-												listener_guarded_data.clone(),
-												guarded_data.clone(),
-												&buf,
-												len,
-												connection_id,
-												on_read.clone(),
-												on_client_read.clone(),
-												connection_id_map,
-												connection_info_map,
-												write_buffers,
-												global_lock.clone(),
-												filter_set,
-											)? {
-												break;
-											}
-										},
-									},
-								}
-							}
-							None => {
-								// looks to be spurious. connection already disconnected
-								log_multi!(
-									DEBUG,
-									MAIN_LOG,
-									"connection not found (add read): {}",
-									output_events[i].fd
-								);
-							}
-						}
-					}
+		while state.counter < state.res {
+			let event = state.output_events[state.counter].clone();
+			match event.etype {
+				// ignore wakeupfd here process in main loop.
+				GenericEventType::AddReadET | GenericEventType::AddReadLT
+					if event.fd != ctx.wakeup_fd =>
+				{
+					Self::process_read_event(ctx, state, event.fd, read_buf)?;
 				}
 				GenericEventType::AddWriteET => {
-					let conn_info = connection_info_map.get(&output_events[i].fd);
-
-					match conn_info {
+					match state.connection_info_map.get(&event.fd) {
 						Some(conn_info) => {
-							Self::process_write_event(
-								selector,
-								&output_events[i],
-								conn_info.connection_id,
-								write_buffers,
-								global_lock.clone(),
-								connection_id_map,
-								connection_info_map,
-								listener_guarded_data.clone(),
-								filter_set,
-								input_events,
-							)?;
+							let handle = conn_info.handle;
+							let connection_id = conn_info.connection_id;
+							if conn_info.ctype == ConnectionType::Connecting {
+								Self::process_connect_event(ctx, state, handle, connection_id)?;
+							} else {
+								Self::process_write_event(ctx, state, handle, connection_id)?;
+							}
 						}
 						None => {
 							// looks to be spurious. connection already disconnected
@@ -2183,31 +4434,271 @@ where
 								DEBUG,
 								MAIN_LOG,
 								"connection not found (add write): {}",
-								output_events[i].fd
+								event.fd
 							);
 						}
 					}
 				}
-				#[cfg(windows)]
+				// only used to register events
 				_ => {}
 			}
-			**counter += 1;
+			state.counter += 1;
+		}
+		Ok(())
+	}
+
+	fn process_read_event(
+		ctx: &RwContext,
+		state: &mut RwState,
+		fd: ConnectionHandle,
+		read_buf: &mut [u8],
+	) -> Result<(), Error> {
+		let (handle, connection_id, ctype, tls_server, tls_client) =
+			match state.connection_info_map.get(&fd) {
+				Some(conn_info) => (
+					conn_info.handle,
+					conn_info.connection_id,
+					conn_info.ctype.clone(),
+					conn_info.tls_server.clone(),
+					conn_info.tls_client.clone(),
+				),
+				None => {
+					// looks to be spurious. connection already disconnected
+					log_multi!(DEBUG, MAIN_LOG, "connection not found (add read): {}", fd);
+					return Ok(());
+				}
+			};
+
+		match ctype {
+			ConnectionType::Listener => return Self::process_accept(ctx, state, handle),
+			ConnectionType::Connecting => {
+				return Self::process_connect_event(ctx, state, handle, connection_id)
+			}
+			_ => {}
+		}
+		if let Some(idle_timeout) = state.idle_timeouts.get_mut(&connection_id) {
+			idle_timeout.last_activity = Instant::now();
+		}
+		if ctype == ConnectionType::Datagram {
+			return Self::process_datagram_event(ctx, handle, connection_id);
+		}
+
+		let mut total = 0;
+		match (tls_server, tls_client) {
+			(Some(mut tls_conn), _) => loop {
+				let mut buf = vec![];
+				let (raw_len, tls_len) =
+					Self::do_tls_read(ctx, handle, connection_id, &mut buf, &mut tls_conn)?;
+				if raw_len <= 0 || tls_len > 0 {
+					let len = if tls_len > 0 {
+						tls_len.try_into().unwrap_or(0)
+					} else {
+						raw_len
+					};
+					if !Self::process_read_result(ctx, state, handle, connection_id, &buf, len)? {
+						break;
+					}
+				}
+				if Self::read_limit_reached(ctx, state, handle, &mut total, raw_len) {
+					break;
+				}
+			},
+			(None, Some(mut tls_conn)) => loop {
+				let mut buf = vec![];
+				let (raw_len, tls_len) =
+					Self::do_tls_client_read(ctx, handle, connection_id, &mut buf, &mut tls_conn)?;
+				if raw_len <= 0 || tls_len > 0 {
+					let len = if tls_len > 0 {
+						tls_len.try_into().unwrap_or(0)
+					} else {
+						raw_len
+					};
+					if !Self::process_read_result(ctx, state, handle, connection_id, &buf, len)? {
+						break;
+					}
+				}
+				if Self::read_limit_reached(ctx, state, handle, &mut total, raw_len) {
+					break;
+				}
+			},
+			(None, None) => loop {
+				let len = Self::do_read(ctx, handle, read_buf)?;
+				if !Self::process_read_result(ctx, state, handle, connection_id, read_buf, len)? {
+					break;
+				}
+				if Self::read_limit_reached(ctx, state, handle, &mut total, len) {
+					break;
+				}
+			},
+		}
+		Ok(())
+	}
+
+	fn process_connect_event(
+		ctx: &RwContext,
+		state: &mut RwState,
+		handle: ConnectionHandle,
+		connection_id: u128,
+	) -> Result<(), Error> {
+		match socket_error(handle) {
+			Ok(_) => {
+				let peer_addr = socket_addr(handle, true);
+				let local_addr = socket_addr(handle, false);
+				let wh = match state.connection_id_map.get_mut(&connection_id) {
+					Some(connection_info) => {
+						connection_info.ctype = ConnectionType::Connected;
+						connection_info.peer_addr = peer_addr;
+						connection_info.local_addr = local_addr;
+						WriteHandle::from_connection_info(
+							connection_info,
+							ctx.guarded_data.clone(),
+							ctx.global_lock.clone(),
+						)
+					}
+					None => WriteHandle::new(
+						handle,
+						ctx.guarded_data.clone(),
+						connection_id,
+						ctx.global_lock.clone(),
+					),
+				};
+				if let Some(connection_info) = state.connection_info_map.get_mut(&handle) {
+					connection_info.ctype = ConnectionType::Connected;
+					connection_info.peer_addr = peer_addr;
+					connection_info.local_addr = local_addr;
+				}
+				state.input_events.push(GenericEvent {
+					fd: handle,
+					etype: ctx.read_config.read_event_type(),
+				});
+
+				if let Some(on_connect) = &ctx.callbacks.on_connect {
+					(on_connect)(connection_id, Ok(wh))?;
+				}
+				Ok(())
+			}
+			Err(e) => Self::fail_connect(ctx, state, handle, connection_id, e),
+		}
+	}
+
+	fn fail_connect(
+		ctx: &RwContext,
+		state: &mut RwState,
+		handle: ConnectionHandle,
+		connection_id: u128,
+		error: Error,
+	) -> Result<(), Error> {
+		let selector = &ctx.selector;
+		state.connection_id_map.remove(&connection_id);
+		state.connection_info_map.remove(&handle);
+		selector.remove_handle(handle, &mut state.filter_set)?;
+		{
+			let _lock = nioruntime_util::lockw!(ctx.global_lock)?;
+			let res = selector.close_handle(handle)?;
+			if res != 0 {
+				mainlogerror!("error closing socket: {}", errno().to_string());
+			}
+		}
+
+		if let Some(on_connect) = &ctx.callbacks.on_connect {
+			(on_connect)(connection_id, Err(error))?;
+		}
+		Ok(())
+	}
+
+	fn close_all(ctx: &RwContext, state: &mut RwState) -> Result<(), Error> {
+		let conns: Vec<ConnectionInfo> = state.connection_id_map.values().cloned().collect();
+		for conn in conns {
+			match conn.ctype {
+				ConnectionType::Connecting => Self::fail_connect(
+					ctx,
+					state,
+					conn.handle,
+					conn.connection_id,
+					ErrorKind::ConnectionCloseError("EventHandler stopped".to_string()).into(),
+				)?,
+				_ => Self::close_connection(ctx, state, conn.handle, conn.connection_id)?,
+			}
+		}
+		Ok(())
+	}
+
+	fn process_datagram_event(
+		ctx: &RwContext,
+		handle: ConnectionHandle,
+		connection_id: u128,
+	) -> Result<(), Error> {
+		let mut buf = [0u8; MAX_DATAGRAM_SIZE];
+		loop {
+			let res = {
+				let _lock = nioruntime_util::lockr!(ctx.global_lock)?;
+				let socket = udp_socket(handle);
+				socket.recv_from(&mut buf)
+			};
+			match res {
+				Ok((len, peer)) => {
+					if let Some(on_datagram) = &ctx.callbacks.on_datagram {
+						let dh = DatagramHandle {
+							fd: handle,
+							connection_id,
+							guarded_data: ctx.guarded_data.clone(),
+							global_lock: ctx.global_lock.clone(),
+						};
+						(on_datagram)(&buf, len, peer, dh)?;
+					}
+				}
+				Err(e) => match e.kind() {
+					std::io::ErrorKind::WouldBlock => break,
+					// reported for an earlier send, more datagrams may be queued
+					std::io::ErrorKind::ConnectionRefused | std::io::ErrorKind::ConnectionReset => {
+						log_multi!(DEBUG, MAIN_LOG, "recv_from error: {}", e);
+					}
+					_ => {
+						mainlogerror!("recv_from error on fd = {}: {}", handle, e);
+						break;
+					}
+				},
+			}
 		}
 		Ok(())
 	}
 
+	// adds len to the number of bytes read from the connection in this poll and returns true if
+	// the limit has been reached. The connection is then read again on the next poll.
+	fn read_limit_reached(
+		ctx: &RwContext,
+		state: &mut RwState,
+		handle: ConnectionHandle,
+		total: &mut usize,
+		len: isize,
+	) -> bool {
+		match ctx.read_config.max_read_per_poll {
+			Some(max_read_per_poll) => {
+				*total += len.try_into().unwrap_or(0);
+				if *total >= max_read_per_poll {
+					state
+						.pending_reads
+						.push(GenericEvent::new(handle, ctx.read_config.read_event_type()));
+					true
+				} else {
+					false
+				}
+			}
+			None => false,
+		}
+	}
+
 	fn do_tls_client_read(
+		ctx: &RwContext,
 		handle: ConnectionHandle,
 		connection_id: u128,
-		guarded_data: Arc<RwLock<GuardedData>>,
 		buf: &mut Vec<u8>,
-		global_lock: Arc<RwLock<bool>>,
 		tls_conn: &mut Arc<RwLock<ClientConnection>>,
 	) -> Result<(isize, usize), Error> {
 		let pt_len;
-		buf.resize(BUFFER_SIZE, 0u8);
-		let len = Self::do_read(handle, buf, global_lock.clone())?;
-		let mut wbuf = vec![];
+		buf.resize(ctx.read_config.buffer_size, 0u8);
+		let len = Self::do_read(ctx, handle, buf)?;
+		let flush;
 		{
 			let mut tls_conn = nioruntime_util::lockw!(tls_conn)?;
 
@@ -2230,33 +4721,32 @@ where
 					return Ok((-1, 0)); // invalid text received. Close conn.
 				}
 			}
-			tls_conn.write_tls(&mut wbuf)?;
+			flush = tls_conn.wants_write();
+		}
+		if flush {
+			let wh = WriteHandle::new(
+				handle,
+				ctx.guarded_data.clone(),
+				connection_id,
+				ctx.global_lock.clone(),
+			);
+			wh.flush_tls()?;
 		}
-		let wh = WriteHandle::new(
-			handle,
-			guarded_data.clone(),
-			connection_id,
-			global_lock.clone(),
-			None,
-			None,
-		);
-		wh.write(&wbuf)?;
 
 		Ok((len, pt_len))
 	}
 
 	fn do_tls_read(
+		ctx: &RwContext,
 		handle: ConnectionHandle,
 		connection_id: u128,
-		guarded_data: Arc<RwLock<GuardedData>>,
 		buf: &mut Vec<u8>,
-		global_lock: Arc<RwLock<bool>>,
 		tls_conn: &mut Arc<RwLock<ServerConnection>>,
 	) -> Result<(isize, usize), Error> {
 		let pt_len;
-		buf.resize(BUFFER_SIZE, 0u8);
-		let len = Self::do_read(handle, buf, global_lock.clone())?;
-		let mut wbuf = vec![];
+		buf.resize(ctx.read_config.buffer_size, 0u8);
+		let len = Self::do_read(ctx, handle, buf)?;
+		let flush;
 		{
 			let mut tls_conn = nioruntime_util::lockw!(tls_conn)?;
 
@@ -2279,1015 +4769,3898 @@ where
 					return Ok((-1, 0)); // invalid text received. Close conn.
 				}
 			}
-			tls_conn.write_tls(&mut wbuf)?;
+			flush = tls_conn.wants_write();
+		}
+		if flush {
+			let wh = WriteHandle::new(
+				handle,
+				ctx.guarded_data.clone(),
+				connection_id,
+				ctx.global_lock.clone(),
+			);
+			wh.flush_tls()?;
 		}
 
-		let wh = WriteHandle::new(
-			handle,
-			guarded_data.clone(),
-			connection_id,
-			global_lock.clone(),
-			None,
-			None,
-		);
+		Ok((len, pt_len))
+	}
 
-		wh.write(&wbuf)?;
+	fn do_read(ctx: &RwContext, handle: ConnectionHandle, buf: &mut [u8]) -> Result<isize, Error> {
+		let _lock = nioruntime_util::lockr!(ctx.global_lock)?;
+		ctx.selector.read(handle, buf)
+	}
+}
 
-		Ok((len, pt_len))
+#[derive(Clone, Debug, PartialEq)]
+enum ConnectionType {
+	Outbound,
+	Inbound,
+	Listener,
+	Datagram,
+	Connecting,
+	Connected,
+}
+
+#[derive(Clone, Debug)]
+struct ConnectionInfo {
+	handle: ConnectionHandle,
+	connection_id: u128,
+	ctype: ConnectionType,
+	sender: Option<SyncSender<()>>,
+	tls_server: Option<Arc<RwLock<ServerConnection>>>,
+	tls_client: Option<Arc<RwLock<ClientConnection>>>,
+	peer_addr: Option<SocketAddr>,
+	local_addr: Option<SocketAddr>,
+	attachment: Attachment,
+	// the indexes of the threads the connection was moved from
+	migrated_from: Vec<usize>,
+	// accepted by a r/w thread on a listener added with EventHandler::add_tcp_listener_reuse_port
+	reuse_port: bool,
+	// the peer shut down its side and on_read_eof was executed
+	read_eof: bool,
+	// the write side was shut down with WriteHandle::shutdown_write
+	write_shutdown: bool,
+}
+
+impl ConnectionInfo {
+	// read interest is removed once the end of the stream was read
+	fn write_event_type(&self) -> GenericEventType {
+		match self.read_eof {
+			true => GenericEventType::AddWriteOnlyET,
+			false => GenericEventType::AddWriteET,
+		}
 	}
+}
 
-	fn do_read(
-		handle: ConnectionHandle,
-		buf: &mut [u8],
-		global_lock: Arc<RwLock<bool>>,
-	) -> Result<isize, Error> {
-		let _lock = nioruntime_util::lockr!(global_lock)?;
-		#[cfg(unix)]
-		let len = {
-			let cbuf: *mut c_void = buf as *mut _ as *mut c_void;
-			unsafe { read(handle, cbuf, BUFFER_SIZE) }
-		};
-		#[cfg(target_os = "windows")]
-		let len = {
-			let cbuf: *mut i8 = buf as *mut _ as *mut i8;
-			errno::set_errno(Errno(0));
-			let mut len = unsafe {
-				ws2_32::recv(
-					handle.try_into().unwrap_or(0),
-					cbuf,
-					BUFFER_SIZE.try_into().unwrap_or(0),
-					0,
-				)
-			};
-			if errno().0 == 10035 {
-				// would block
-				len = -2;
-			}
-			len
-		};
-		Ok(len.try_into().unwrap_or(-1))
+struct GuardedData {
+	nconns: Vec<ConnectionInfo>,
+	cconns: Vec<ConnectionInfo>,
+	aconns: Vec<ConnectionInfo>,
+	write_queue: Vec<WriteBuffer>,
+	wakeup_tx: ConnectionHandle,
+	wakeup_rx: ConnectionHandle,
+	wakeup_scheduled: bool,
+	stop: bool,
+	callback_state: Arc<RwLock<State>>,
+	write_queue_high_water: Option<usize>,
+	write_queue_low_water: usize,
+	pending_bytes: HashMap<u128, usize>,
+	paused: HashSet<u128>,
+	timers: Vec<ScheduledTimer>,
+	timeouts: Vec<(u128, Option<Duration>)>,
+	connect_timeouts: Vec<(u128, Duration)>,
+	stop_accepting: Option<SyncSender<()>>,
+	close_on_stop: bool,
+	buffer_pool: Vec<Vec<u8>>,
+	buffer_pool_size: usize,
+	read_config: ReadConfig,
+	metrics: Arc<Metrics>,
+	// the index of the thread in EventHandler::guarded_data
+	thread_index: usize,
+	// connections moved to another thread, the writes for them are queued on that thread
+	migrated: HashMap<u128, Arc<RwLock<GuardedData>>>,
+	migration_requests: Vec<MigrationRequest>,
+	migrated_conns: Vec<Migration>,
+}
+
+// a request to move a connection to another thread. See EventHandler::migrate.
+struct MigrationRequest {
+	connection_id: u128,
+	thread: usize,
+	guarded_data: Arc<RwLock<GuardedData>>,
+}
+
+// a connection moved from another thread, with the buffers that were not written yet and the
+// data that the selector of the other thread received but that was not read yet
+struct Migration {
+	conn: ConnectionInfo,
+	write_buffers: LinkedList<WriteBuffer>,
+	received: Vec<u8>,
+	recheck: bool,
+}
+
+#[derive(Clone, Copy)]
+struct ReadConfig {
+	buffer_size: usize,
+	max_events: usize,
+	trigger_mode: TriggerMode,
+	max_read_per_poll: Option<usize>,
+}
+
+impl ReadConfig {
+	fn read_event_type(&self) -> GenericEventType {
+		match self.trigger_mode {
+			TriggerMode::Edge => GenericEventType::AddReadET,
+			TriggerMode::Level => GenericEventType::AddReadLT,
+		}
+	}
+}
+
+struct ScheduledTimer {
+	deadline: Instant,
+	handle: TimerHandle,
+	callback: TimerCallback,
+}
+
+enum TimerEvent {
+	Scheduled(ScheduledTimer),
+	IdleTimeout(u128, u64),
+	ConnectTimeout(u128),
+	// accepting on a reuse port listener may resume after the accept rate limit was reached
+	ResumeAccept(ConnectionHandle),
+}
+
+struct IdleTimeout {
+	timeout: Duration,
+	last_activity: Instant,
+	generation: u64,
+}
+
+// The state needed by the r/w threads to accept connections on the listeners added with
+// EventHandler::add_tcp_listener_reuse_port. The limits are shared with the listener thread.
+#[derive(Clone)]
+struct Acceptor {
+	on_accept: Arc<OnAccept>,
+	on_reject: Option<Arc<OnReject>>,
+	limits: SharedConnectionLimits,
+	listener_tls: Arc<RwLock<HashMap<ConnectionHandle, ListenerTls>>>,
+	socket_options: SocketOptions,
+}
+
+// The callbacks executed by the r/w threads.
+#[derive(Clone)]
+struct RwCallbacks {
+	on_read: Arc<OnRead>,
+	on_client_read: Arc<OnRead>,
+	on_writable: Option<Arc<OnWritable>>,
+	on_timeout: Option<Arc<OnTimeout>>,
+	on_read_eof: Option<Arc<OnReadEof>>,
+	on_datagram: Option<Arc<OnDatagram>>,
+	on_connect: Option<Arc<OnConnect>>,
+}
+
+// What a r/w thread shares with the rest of the EventHandler. It doesn't change once the thread
+// is started.
+#[derive(Clone)]
+struct RwContext {
+	selector: Arc<dyn Selector>,
+	listener_guarded_data: Arc<RwLock<GuardedData>>,
+	guarded_data: Arc<RwLock<GuardedData>>,
+	global_lock: Arc<RwLock<bool>>,
+	callbacks: RwCallbacks,
+	acceptor: Acceptor,
+	read_config: ReadConfig,
+	metrics: Arc<Metrics>,
+	wakeup_fd: ConnectionHandle,
+}
+
+// The state of a r/w thread. It outlives the thread when a callback panics, so that the thread
+// that replaces it skips the event in progress and continues with the next one.
+struct RwState {
+	connection_info_map: HashMap<ConnectionHandle, ConnectionInfo>,
+	connection_id_map: HashMap<u128, ConnectionInfo>,
+	filter_set: HashSet<ConnectionHandle>,
+	write_buffers: HashMap<u128, LinkedList<WriteBuffer>>,
+	input_events: Vec<GenericEvent>,
+	output_events: Vec<GenericEvent>,
+	// the index of the output event in progress and the number of output events
+	counter: usize,
+	res: usize,
+	timer_wheel: TimerWheel<TimerEvent>,
+	idle_timeouts: HashMap<u128, IdleTimeout>,
+	// connections that reached max_read_per_poll, read again without waiting
+	pending_reads: Vec<GenericEvent>,
+}
+
+impl RwState {
+	fn new(wakeup_fd: ConnectionHandle) -> Self {
+		RwState {
+			connection_info_map: HashMap::new(),
+			connection_id_map: HashMap::new(),
+			filter_set: HashSet::new(),
+			write_buffers: HashMap::new(),
+			input_events: vec![GenericEvent::new(wakeup_fd, GenericEventType::AddReadLT)],
+			output_events: vec![],
+			counter: 0,
+			res: 0,
+			timer_wheel: TimerWheel::new(),
+			idle_timeouts: HashMap::new(),
+			pending_reads: vec![],
+		}
+	}
+
+	fn set_read_eof(&mut self, connection_id: u128, handle: ConnectionHandle) {
+		if let Some(connection_info) = self.connection_id_map.get_mut(&connection_id) {
+			connection_info.read_eof = true;
+		}
+		if let Some(connection_info) = self.connection_info_map.get_mut(&handle) {
+			connection_info.read_eof = true;
+		}
+	}
+
+	fn set_write_shutdown(&mut self, connection_id: u128, handle: ConnectionHandle) {
+		if let Some(connection_info) = self.connection_id_map.get_mut(&connection_id) {
+			connection_info.write_shutdown = true;
+		}
+		if let Some(connection_info) = self.connection_info_map.get_mut(&handle) {
+			connection_info.write_shutdown = true;
+		}
+	}
+}
+
+// What the listener thread shares with the rest of the EventHandler. It doesn't change once the
+// thread is started.
+struct ListenerContext {
+	selector: Arc<dyn Selector>,
+	guarded_data: Arc<RwLock<GuardedData>>,
+	// the guarded data of the r/w threads
+	guarded_data_vec: Vec<Arc<RwLock<GuardedData>>>,
+	on_close: Arc<OnClose>,
+	global_lock: Arc<RwLock<bool>>,
+	acceptor: Acceptor,
+	placement_strategy: PlacementStrategy,
+	metrics: Arc<Metrics>,
+	max_events: usize,
+	wakeup_fd: ConnectionHandle,
+}
+
+// The limits on the connections accepted by the listeners. See EventHandlerConfig::max_connections.
+struct ConnectionLimits {
+	max_connections: Option<usize>,
+	max_connections_per_ip: Option<usize>,
+	max_accept_rate: Option<usize>,
+	// the peer ip of each open connection accepted by the listener
+	connections: HashMap<u128, Option<IpAddr>>,
+	connections_per_ip: HashMap<IpAddr, usize>,
+	window_start: Instant,
+	window_accepts: usize,
+}
+
+impl ConnectionLimits {
+	fn new(config: &EventHandlerConfig) -> Self {
+		ConnectionLimits {
+			max_connections: config.max_connections,
+			max_connections_per_ip: config.max_connections_per_ip,
+			max_accept_rate: config.max_accept_rate,
+			connections: HashMap::new(),
+			connections_per_ip: HashMap::new(),
+			window_start: Instant::now(),
+			window_accepts: 0,
+		}
+	}
+
+	// returns the time at which accepting may resume if the accept rate limit has been reached
+	fn paused_until(&mut self, now: Instant) -> Option<Instant> {
+		match self.max_accept_rate {
+			Some(max_accept_rate) => {
+				let window_end = self.window_start + Duration::from_secs(1);
+				if now >= window_end {
+					self.window_start = now;
+					self.window_accepts = 0;
+					None
+				} else if self.window_accepts >= max_accept_rate {
+					Some(window_end)
+				} else {
+					None
+				}
+			}
+			None => None,
+		}
+	}
+
+	// returns the reason a new connection must be rejected, if any
+	fn check(&self, peer_ip: Option<IpAddr>) -> Option<RejectReason> {
+		if let Some(max_connections) = self.max_connections {
+			if self.connections.len() >= max_connections {
+				return Some(RejectReason::MaxConnections);
+			}
+		}
+		if let (Some(max_connections_per_ip), Some(peer_ip)) =
+			(self.max_connections_per_ip, peer_ip)
+		{
+			let count = self.connections_per_ip.get(&peer_ip).unwrap_or(&0);
+			if *count >= max_connections_per_ip {
+				return Some(RejectReason::MaxConnectionsPerIp);
+			}
+		}
+		None
+	}
+
+	// counts an accepted connection. Rejected connections don't count towards the accept rate.
+	fn add(&mut self, connection_id: u128, peer_ip: Option<IpAddr>) {
+		self.window_accepts += 1;
+		self.connections.insert(connection_id, peer_ip);
+		if let Some(peer_ip) = peer_ip {
+			*self.connections_per_ip.entry(peer_ip).or_insert(0) += 1;
+		}
+	}
+
+	fn remove(&mut self, connection_id: u128) {
+		if let Some(Some(peer_ip)) = self.connections.remove(&connection_id) {
+			match self.connections_per_ip.get_mut(&peer_ip) {
+				Some(count) if *count > 1 => *count -= 1,
+				_ => {
+					self.connections_per_ip.remove(&peer_ip);
+				}
+			}
+		}
+	}
+}
+
+// The connection limits shared by the listener thread and the r/w threads. None if no limit is
+// configured, so that accepting doesn't take the lock.
+#[derive(Clone)]
+struct SharedConnectionLimits {
+	limits: Option<Arc<RwLock<ConnectionLimits>>>,
+}
+
+impl SharedConnectionLimits {
+	fn new(config: &EventHandlerConfig) -> Self {
+		let limited = config.max_connections.is_some()
+			|| config.max_connections_per_ip.is_some()
+			|| config.max_accept_rate.is_some();
+		SharedConnectionLimits {
+			limits: match limited {
+				true => Some(Arc::new(RwLock::new(ConnectionLimits::new(config)))),
+				false => None,
+			},
+		}
+	}
+
+	fn paused_until(&self, now: Instant) -> Result<Option<Instant>, Error> {
+		match &self.limits {
+			Some(limits) => Ok(nioruntime_util::lockw!(limits)?.paused_until(now)),
+			None => Ok(None),
+		}
+	}
+
+	// counts the connection if it may be accepted, otherwise returns the reason it must be
+	// rejected
+	fn admit(
+		&self,
+		connection_id: u128,
+		peer_ip: Option<IpAddr>,
+	) -> Result<Option<RejectReason>, Error> {
+		match &self.limits {
+			Some(limits) => {
+				let mut limits = nioruntime_util::lockw!(limits)?;
+				let reason = limits.check(peer_ip);
+				match reason {
+					Some(_) => {}
+					None => limits.add(connection_id, peer_ip),
+				}
+				Ok(reason)
+			}
+			None => Ok(None),
+		}
+	}
+
+	fn remove(&self, connection_id: u128) -> Result<(), Error> {
+		if let Some(limits) = &self.limits {
+			nioruntime_util::lockw!(limits)?.remove(connection_id);
+		}
+		Ok(())
+	}
+}
+
+impl GuardedData {
+	fn is_write_queue_full(&self, connection_id: u128) -> bool {
+		match self.write_queue_high_water {
+			Some(high_water) => match self.pending_bytes.get(&connection_id) {
+				Some(pending) => *pending >= high_water,
+				None => false,
+			},
+			None => false,
+		}
+	}
+
+	fn get_buffer(&mut self) -> Vec<u8> {
+		match self.buffer_pool.pop() {
+			Some(buffer) => buffer,
+			None => Vec::with_capacity(BUFFER_SIZE),
+		}
+	}
+
+	fn recycle_buffer(&mut self, mut buffer: Vec<u8>) {
+		if self.buffer_pool.len() < self.buffer_pool_size {
+			buffer.clear();
+			self.buffer_pool.push(buffer);
+		}
+	}
+
+	// adjusts the pending bytes of a tls connection for the difference between the plaintext
+	// that was queued and the records it was encrypted to
+	fn grow_pending(&mut self, connection_id: u128, growth: isize) {
+		let pending = self.pending_bytes.entry(connection_id).or_insert(0);
+		match growth >= 0 {
+			true => *pending = pending.saturating_add(growth as usize),
+			false => *pending = pending.saturating_sub(growth.unsigned_abs()),
+		}
+	}
+
+	fn clear_pending(&mut self, connection_id: u128) {
+		self.pending_bytes.remove(&connection_id);
+		self.paused.remove(&connection_id);
+	}
+
+	fn wakeup(&mut self) -> Result<(), Error> {
+		if !self.wakeup_scheduled {
+			let res = write_bytes(self.wakeup_tx, &[0u8; 1])?;
+			if res <= 0 {
+				log_multi!(
+					ERROR,
+					MAIN_LOG,
+					"Error writing to wakup tx {}, error={}, wakeup={}",
+					res,
+					errno().to_string(),
+					self.wakeup_tx,
+				);
+			}
+			self.wakeup_scheduled = true;
+		}
+		Ok(())
+	}
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct WriteBuffer {
+	data: WriteData,
+	offset: usize,
+	close: bool,
+	connection_id: u128,
+}
+
+impl WriteBuffer {
+	fn encrypted(records: Vec<u8>, connection_id: u128) -> Self {
+		WriteBuffer {
+			data: WriteData::Encrypted(records),
+			offset: 0,
+			close: false,
+			connection_id,
+		}
+	}
+
+	fn remaining(&self) -> &[u8] {
+		match &self.data {
+			WriteData::Empty
+			| WriteData::Shutdown
+			| WriteData::CloseNotify
+			| WriteData::TlsFlush
+			| WriteData::File { .. } => &[],
+			WriteData::Encrypted(buffer) | WriteData::Pooled(buffer) => &buffer[self.offset..],
+			WriteData::Owned(data) => &(**data).as_ref()[self.offset..],
+		}
+	}
+
+	fn remaining_len(&self) -> usize {
+		match &self.data {
+			WriteData::File { len, .. } => {
+				let len: usize = (*len).try_into().unwrap_or(usize::MAX);
+				len - self.offset
+			}
+			_ => self.remaining().len(),
+		}
+	}
+}
+
+// the tls connection of a connection whose queued data is encrypted by the write loop
+enum TlsConnection {
+	Server(Arc<RwLock<ServerConnection>>),
+	Client(Arc<RwLock<ClientConnection>>),
+}
+
+impl TlsConnection {
+	fn from_connection_info(connection_info: &ConnectionInfo) -> Option<Self> {
+		match (&connection_info.tls_server, &connection_info.tls_client) {
+			(Some(tls_conn), _) => Some(TlsConnection::Server(tls_conn.clone())),
+			(None, Some(tls_conn)) => Some(TlsConnection::Client(tls_conn.clone())),
+			(None, None) => None,
+		}
+	}
+
+	// encrypts `data`, followed by a close_notify alert if requested, and appends the records
+	// to `records` along with any records produced while reading
+	fn encrypt(&self, data: &[u8], close_notify: bool, records: &mut Vec<u8>) -> Result<(), Error> {
+		match self {
+			TlsConnection::Server(tls_conn) => {
+				let mut tls_conn = nioruntime_util::lockw!(tls_conn)?;
+				for chunk in data.chunks(TLS_CHUNKS) {
+					tls_conn.writer().write_all(chunk)?;
+					tls_conn.write_tls(records)?;
+				}
+				if close_notify {
+					tls_conn.send_close_notify();
+				}
+				while tls_conn.wants_write() {
+					tls_conn.write_tls(records)?;
+				}
+			}
+			TlsConnection::Client(tls_conn) => {
+				let mut tls_conn = nioruntime_util::lockw!(tls_conn)?;
+				for chunk in data.chunks(TLS_CHUNKS) {
+					tls_conn.writer().write_all(chunk)?;
+					tls_conn.write_tls(records)?;
+				}
+				if close_notify {
+					tls_conn.send_close_notify();
+				}
+				while tls_conn.wants_write() {
+					tls_conn.write_tls(records)?;
+				}
+			}
+		}
+		Ok(())
+	}
+}
+
+#[derive(Clone)]
+enum WriteData {
+	// a close request, no data to write
+	Empty,
+	// a request to shut down the write side once the data queued before it is written
+	Shutdown,
+	// a request to send a tls close_notify alert, queued by WriteHandle::shutdown_write
+	CloseNotify,
+	// a request to send the tls records produced while reading, such as handshake messages
+	TlsFlush,
+	// tls records encrypted by the write loop, returned to the pool once written
+	Encrypted(Vec<u8>),
+	// a copy of the written data in a buffer that is returned to the pool once written
+	Pooled(Vec<u8>),
+	// data passed to WriteHandle::write_owned
+	Owned(Arc<dyn AsRef<[u8]> + Send + Sync>),
+	// a region of a file passed to WriteHandle::send_file
+	File {
+		file: Arc<File>,
+		start: u64,
+		len: u64,
+	},
+}
+
+impl std::fmt::Debug for WriteData {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			WriteData::Empty => write!(f, "Empty"),
+			WriteData::Shutdown => write!(f, "Shutdown"),
+			WriteData::CloseNotify => write!(f, "CloseNotify"),
+			WriteData::TlsFlush => write!(f, "TlsFlush"),
+			WriteData::Encrypted(buffer) => write!(f, "Encrypted({} bytes)", buffer.len()),
+			WriteData::Pooled(buffer) => write!(f, "Pooled({} bytes)", buffer.len()),
+			WriteData::Owned(data) => write!(f, "Owned({} bytes)", (**data).as_ref().len()),
+			WriteData::File { start, len, .. } => write!(f, "File({} bytes at {})", len, start),
+		}
+	}
+}
+
+struct Callbacks {
+	on_read: Option<Arc<OnRead>>,
+	on_accept: Option<Arc<OnAccept>>,
+	on_close: Option<Arc<OnClose>>,
+	on_client_read: Option<Arc<OnRead>>,
+	on_writable: Option<Arc<OnWritable>>,
+	on_timeout: Option<Arc<OnTimeout>>,
+	on_datagram: Option<Arc<OnDatagram>>,
+	on_connect: Option<Arc<OnConnect>>,
+	on_reject: Option<Arc<OnReject>>,
+	on_read_eof: Option<Arc<OnReadEof>>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct StreamWriteBuffer {
+	_fd: ConnectionHandle,
+	_write_buffer: WriteBuffer,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum ActionType {
+	AddStream,
+	AddListener,
+	AddReusePortListener,
+	AddTlsStream,
+	AddDatagram,
+	AddConnect,
+}
+
+/// Creates a non-blocking listener bound to `addr` with SO_REUSEADDR and SO_REUSEPORT set, so that
+/// several listeners may be bound to the same address. `socket_options` are applied before the
+/// socket starts listening.
+#[cfg(unix)]
+fn reuse_port_listener(
+	addr: &SocketAddr,
+	socket_options: &SocketOptions,
+) -> Result<TcpListener, Error> {
+	let domain = match addr {
+		SocketAddr::V4(_) => libc::AF_INET,
+		SocketAddr::V6(_) => libc::AF_INET6,
+	};
+	let fd = unsafe { libc::socket(domain, libc::SOCK_STREAM, 0) };
+	if fd < 0 {
+		return Err(std::io::Error::last_os_error().into());
+	}
+	// owned from here on so that the socket is closed on error
+	let listener = unsafe { TcpListener::from_raw_fd(fd) };
+
+	let on: c_int = 1;
+	for option in &[libc::SO_REUSEADDR, libc::SO_REUSEPORT] {
+		let res = unsafe {
+			libc::setsockopt(
+				fd,
+				libc::SOL_SOCKET,
+				*option,
+				&on as *const _ as *const c_void,
+				std::mem::size_of::<c_int>() as socklen_t,
+			)
+		};
+		if res < 0 {
+			return Err(std::io::Error::last_os_error().into());
+		}
+	}
+
+	apply_socket_options(fd, socket_options, true)?;
+
+	let (storage, len) = raw_socket_addr(addr);
+	if unsafe { libc::bind(fd, &storage as *const _ as *const sockaddr, len) } < 0 {
+		return Err(std::io::Error::last_os_error().into());
+	}
+	if unsafe { libc::listen(fd, libc::SOMAXCONN) } < 0 {
+		return Err(std::io::Error::last_os_error().into());
+	}
+	listener.set_nonblocking(true)?;
+	Ok(listener)
+}
+
+/// Applies `socket_options` to a tcp socket. Tcp fast open is only applied to listeners.
+#[cfg(unix)]
+fn apply_socket_options(
+	handle: ConnectionHandle,
+	socket_options: &SocketOptions,
+	listener: bool,
+) -> Result<(), Error> {
+	if let Some(nodelay) = socket_options.tcp_nodelay {
+		set_socket_option(
+			handle,
+			libc::IPPROTO_TCP,
+			libc::TCP_NODELAY,
+			nodelay as c_int,
+		)?;
+	}
+	if let Some(keepalive) = socket_options.keepalive {
+		set_socket_option(
+			handle,
+			libc::SOL_SOCKET,
+			libc::SO_KEEPALIVE,
+			keepalive as c_int,
+		)?;
+	}
+	match socket_options.keepalive_idle {
+		#[cfg(target_os = "macos")]
+		Some(idle) => set_socket_option(
+			handle,
+			libc::IPPROTO_TCP,
+			libc::TCP_KEEPALIVE,
+			keepalive_secs(idle) as c_int,
+		)?,
+		#[cfg(not(target_os = "macos"))]
+		Some(idle) => set_socket_option(
+			handle,
+			libc::IPPROTO_TCP,
+			libc::TCP_KEEPIDLE,
+			keepalive_secs(idle) as c_int,
+		)?,
+		None => {}
+	}
+	if let Some(interval) = socket_options.keepalive_interval {
+		set_socket_option(
+			handle,
+			libc::IPPROTO_TCP,
+			libc::TCP_KEEPINTVL,
+			keepalive_secs(interval) as c_int,
+		)?;
+	}
+	if let Some(count) = socket_options.keepalive_count {
+		set_socket_option(handle, libc::IPPROTO_TCP, libc::TCP_KEEPCNT, count as c_int)?;
+	}
+	if let Some(size) = socket_options.send_buffer_size {
+		set_socket_option(handle, libc::SOL_SOCKET, libc::SO_SNDBUF, size as c_int)?;
+	}
+	if let Some(size) = socket_options.recv_buffer_size {
+		set_socket_option(handle, libc::SOL_SOCKET, libc::SO_RCVBUF, size as c_int)?;
+	}
+	if let Some(linger) = socket_options.linger {
+		set_socket_option(
+			handle,
+			libc::SOL_SOCKET,
+			libc::SO_LINGER,
+			libc::linger {
+				l_onoff: 1,
+				l_linger: std::cmp::min(socket_option_secs(linger), c_int::MAX as u64) as c_int,
+			},
+		)?;
+	}
+	#[cfg(any(target_os = "linux", target_os = "macos", target_os = "freebsd"))]
+	if listener {
+		if let Some(queue_len) = socket_options.tcp_fastopen {
+			set_socket_option(
+				handle,
+				libc::IPPROTO_TCP,
+				libc::TCP_FASTOPEN,
+				queue_len as c_int,
+			)?;
+		}
+	}
+	#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "freebsd")))]
+	let _ = listener;
+	Ok(())
+}
+
+// Converts `duration` to the whole seconds taken by the socket options, rounding up so that a
+// sub-second duration isn't truncated to 0.
+fn socket_option_secs(duration: Duration) -> u64 {
+	match duration.subsec_nanos() {
+		0 => duration.as_secs(),
+		_ => duration.as_secs() + 1,
+	}
+}
+
+// The keepalive times are rejected with EINVAL if they are 0.
+#[cfg(unix)]
+fn keepalive_secs(duration: Duration) -> u64 {
+	std::cmp::min(
+		std::cmp::max(socket_option_secs(duration), 1),
+		c_int::MAX as u64,
+	)
+}
+
+/// Applies `socket_options` to a socket that is about to connect.
+#[cfg(unix)]
+fn apply_connect_options(
+	handle: ConnectionHandle,
+	socket_options: &SocketOptions,
+) -> Result<(), Error> {
+	apply_socket_options(handle, socket_options, false)?;
+	#[cfg(target_os = "linux")]
+	if socket_options.tcp_fastopen.is_some() {
+		set_socket_option(handle, libc::IPPROTO_TCP, libc::TCP_FASTOPEN_CONNECT, 1)?;
+	}
+	Ok(())
+}
+
+#[cfg(unix)]
+fn set_socket_option<T>(
+	handle: ConnectionHandle,
+	level: c_int,
+	name: c_int,
+	value: T,
+) -> Result<(), Error> {
+	let res = unsafe {
+		libc::setsockopt(
+			handle,
+			level,
+			name,
+			&value as *const _ as *const c_void,
+			std::mem::size_of::<T>() as socklen_t,
+		)
+	};
+	if res < 0 {
+		return Err(std::io::Error::last_os_error().into());
+	}
+	Ok(())
+}
+
+/// Applies `socket_options` to a tcp socket. The keepalive idle time, interval and count and
+/// tcp fast open are not supported.
+#[cfg(target_os = "windows")]
+fn apply_socket_options(
+	handle: ConnectionHandle,
+	socket_options: &SocketOptions,
+	_listener: bool,
+) -> Result<(), Error> {
+	let tcp = winapi::IPPROTO_TCP.0 as winapi::c_int;
+	match socket_options.tcp_nodelay {
+		Some(nodelay) => {
+			set_socket_option(handle, tcp, winapi::TCP_NODELAY, nodelay as winapi::c_int)?
+		}
+		None => {}
+	}
+	match socket_options.keepalive {
+		Some(keepalive) => set_socket_option(
+			handle,
+			winapi::SOL_SOCKET,
+			winapi::SO_KEEPALIVE,
+			keepalive as winapi::c_int,
+		)?,
+		None => {}
+	}
+	let send_buffer_size = match socket_options.send_buffer_size {
+		Some(size) => size as winapi::c_int,
+		None => WINSOCK_BUF_SIZE,
+	};
+	set_socket_option(
+		handle,
+		winapi::SOL_SOCKET,
+		winapi::SO_SNDBUF,
+		send_buffer_size,
+	)?;
+	match socket_options.recv_buffer_size {
+		Some(size) => set_socket_option(
+			handle,
+			winapi::SOL_SOCKET,
+			winapi::SO_RCVBUF,
+			size as winapi::c_int,
+		)?,
+		None => {}
+	}
+	match socket_options.linger {
+		Some(linger) => set_socket_option(
+			handle,
+			winapi::SOL_SOCKET,
+			winapi::SO_LINGER,
+			winapi::linger {
+				l_onoff: 1,
+				l_linger: std::cmp::min(socket_option_secs(linger), u16::MAX as u64)
+					as winapi::u_short,
+			},
+		)?,
+		None => {}
+	}
+	Ok(())
+}
+
+#[cfg(target_os = "windows")]
+fn set_socket_option<T>(
+	handle: ConnectionHandle,
+	level: winapi::c_int,
+	name: winapi::c_int,
+	value: T,
+) -> Result<(), Error> {
+	let res = unsafe {
+		ws2_32::setsockopt(
+			handle.try_into().unwrap_or(0),
+			level,
+			name,
+			&value as *const _ as *const i8,
+			std::mem::size_of::<T>() as winapi::c_int,
+		)
+	};
+	if res != 0 {
+		return Err(std::io::Error::last_os_error().into());
+	}
+	Ok(())
+}
+
+/// Returns the pending error on the socket, if any. Used to find the result of a non-blocking connect.
+fn socket_error(handle: ConnectionHandle) -> Result<(), Error> {
+	let mut err: c_int = 0;
+	#[cfg(unix)]
+	let res = {
+		let mut len = std::mem::size_of::<c_int>() as socklen_t;
+		unsafe {
+			libc::getsockopt(
+				handle,
+				libc::SOL_SOCKET,
+				libc::SO_ERROR,
+				&mut err as *mut _ as *mut c_void,
+				&mut len,
+			)
+		}
+	};
+	#[cfg(target_os = "windows")]
+	let res = {
+		let mut len = std::mem::size_of::<c_int>() as winapi::c_int;
+		unsafe {
+			ws2_32::getsockopt(
+				handle.try_into().unwrap_or(0),
+				winapi::SOL_SOCKET,
+				winapi::SO_ERROR,
+				&mut err as *mut _ as *mut i8,
+				&mut len,
+			)
+		}
+	};
+	if res != 0 {
+		return Err(std::io::Error::last_os_error().into());
+	}
+	if err != 0 {
+		return Err(std::io::Error::from_raw_os_error(err).into());
+	}
+	Ok(())
+}
+
+/// Returns the peer (if `peer` is true) or local address of the socket behind `handle`.
+fn socket_addr(handle: ConnectionHandle, peer: bool) -> Option<SocketAddr> {
+	let mut storage: SockAddrStorage = unsafe { std::mem::zeroed() };
+	#[cfg(unix)]
+	let res = {
+		let mut len = std::mem::size_of::<SockAddrStorage>() as socklen_t;
+		let addr = &mut storage as *mut _ as *mut sockaddr;
+		match peer {
+			true => unsafe { libc::getpeername(handle, addr, &mut len) },
+			false => unsafe { libc::getsockname(handle, addr, &mut len) },
+		}
+	};
+	#[cfg(target_os = "windows")]
+	let res = {
+		let mut len = std::mem::size_of::<SockAddrStorage>() as c_int;
+		let addr = &mut storage as *mut _ as *mut winapi::ws2def::SOCKADDR;
+		let handle = handle.try_into().unwrap_or(0);
+		match peer {
+			true => unsafe { ws2_32::getpeername(handle, addr, &mut len) },
+			false => unsafe { ws2_32::getsockname(handle, addr, &mut len) },
+		}
+	};
+	if res != 0 {
+		return None;
+	}
+	socket_addr_from_raw(&storage)
+}
+
+/// Convert a raw socket address to a [`SocketAddr`]. None is returned for address families
+/// other than IPv4 and IPv6.
+#[cfg(unix)]
+fn socket_addr_from_raw(storage: &SockAddrStorage) -> Option<SocketAddr> {
+	match storage.ss_family as c_int {
+		libc::AF_INET => {
+			let sin = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+			Some(SocketAddr::V4(SocketAddrV4::new(
+				Ipv4Addr::from(sin.sin_addr.s_addr.to_ne_bytes()),
+				u16::from_be(sin.sin_port),
+			)))
+		}
+		libc::AF_INET6 => {
+			let sin6 = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+			Some(SocketAddr::V6(SocketAddrV6::new(
+				Ipv6Addr::from(sin6.sin6_addr.s6_addr),
+				u16::from_be(sin6.sin6_port),
+				sin6.sin6_flowinfo,
+				sin6.sin6_scope_id,
+			)))
+		}
+		_ => None,
+	}
+}
+
+/// Convert a raw socket address to a [`SocketAddr`]. None is returned for address families
+/// other than IPv4 and IPv6.
+#[cfg(target_os = "windows")]
+fn socket_addr_from_raw(storage: &SockAddrStorage) -> Option<SocketAddr> {
+	// SOCKADDR_IN and SOCKADDR_IN6 share the layout family, port, then address data
+	let bytes = unsafe {
+		std::slice::from_raw_parts(
+			storage as *const _ as *const u8,
+			std::mem::size_of::<SockAddrStorage>(),
+		)
+	};
+	let port = u16::from_be_bytes([bytes[2], bytes[3]]);
+	match storage.ss_family as c_int {
+		winapi::AF_INET => Some(SocketAddr::V4(SocketAddrV4::new(
+			Ipv4Addr::new(bytes[4], bytes[5], bytes[6], bytes[7]),
+			port,
+		))),
+		winapi::AF_INET6 => {
+			let mut octets = [0u8; 16];
+			octets.clone_from_slice(&bytes[8..24]);
+			Some(SocketAddr::V6(SocketAddrV6::new(
+				Ipv6Addr::from(octets),
+				port,
+				u32::from_ne_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+				u32::from_ne_bytes([bytes[24], bytes[25], bytes[26], bytes[27]]),
+			)))
+		}
+		_ => None,
+	}
+}
+
+/// Convert `addr` to a `sockaddr_storage` that can be passed to libc.
+#[cfg(unix)]
+fn raw_socket_addr(addr: &SocketAddr) -> (sockaddr_storage, socklen_t) {
+	let mut storage: sockaddr_storage = unsafe { std::mem::zeroed() };
+	let len = match addr {
+		SocketAddr::V4(addr) => {
+			let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+			sin.sin_family = libc::AF_INET as libc::sa_family_t;
+			sin.sin_port = addr.port().to_be();
+			sin.sin_addr = libc::in_addr {
+				s_addr: u32::from_ne_bytes(addr.ip().octets()),
+			};
+			#[cfg(any(
+				target_os = "macos",
+				target_os = "dragonfly",
+				target_os = "freebsd",
+				target_os = "netbsd",
+				target_os = "openbsd"
+			))]
+			{
+				sin.sin_len = std::mem::size_of::<libc::sockaddr_in>() as u8;
+			}
+			std::mem::size_of::<libc::sockaddr_in>()
+		}
+		SocketAddr::V6(addr) => {
+			let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+			sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+			sin6.sin6_port = addr.port().to_be();
+			sin6.sin6_addr = libc::in6_addr {
+				s6_addr: addr.ip().octets(),
+			};
+			sin6.sin6_flowinfo = addr.flowinfo();
+			sin6.sin6_scope_id = addr.scope_id();
+			#[cfg(any(
+				target_os = "macos",
+				target_os = "dragonfly",
+				target_os = "freebsd",
+				target_os = "netbsd",
+				target_os = "openbsd"
+			))]
+			{
+				sin6.sin6_len = std::mem::size_of::<libc::sockaddr_in6>() as u8;
+			}
+			std::mem::size_of::<libc::sockaddr_in6>()
+		}
+	};
+	(storage, len as socklen_t)
+}
+
+/// Borrow the socket behind `handle` as a [`UdpSocket`] without taking ownership of it.
+fn udp_socket(handle: ConnectionHandle) -> ManuallyDrop<UdpSocket> {
+	#[cfg(unix)]
+	let socket = unsafe { UdpSocket::from_raw_fd(handle) };
+	#[cfg(target_os = "windows")]
+	let socket = unsafe { UdpSocket::from_raw_socket(handle) };
+	ManuallyDrop::new(socket)
+}
+
+fn do_read_bytes(handle: ConnectionHandle, buf: &mut [u8]) -> Result<i32, Error> {
+	#[cfg(unix)]
+	let res = {
+		let cbuf: *mut c_void = buf as *mut _ as *mut c_void;
+		unsafe { read(handle, cbuf, buf.len()) }
+	};
+	#[cfg(target_os = "windows")]
+	let res = {
+		let cbuf: *mut i8 = buf as *mut _ as *mut i8;
+		unsafe {
+			ws2_32::recv(
+				handle.try_into().unwrap_or(0),
+				cbuf,
+				buf.len().try_into().unwrap_or(0),
+				0,
+			)
+		}
+	};
+
+	Ok(res.try_into().unwrap_or(-1))
+}
+
+// sends up to len bytes of file starting at position. Returns the number of bytes sent, 0 at the
+// end of the file and -1 on error, with errno set.
+pub(crate) fn send_file_data(
+	handle: ConnectionHandle,
+	file: &File,
+	position: u64,
+	len: usize,
+) -> Result<isize, Error> {
+	#[cfg(any(target_os = "linux", target_os = "android"))]
+	{
+		let mut offset: libc::off_t = position.try_into().unwrap_or(libc::off_t::MAX);
+		let res = unsafe { libc::sendfile(handle, file.as_raw_fd(), &mut offset, len) };
+		Ok(res)
+	}
+	#[cfg(not(any(target_os = "linux", target_os = "android")))]
+	{
+		let mut buf = [0u8; BUFFER_SIZE];
+		let len = std::cmp::min(len, BUFFER_SIZE);
+		let amt = match read_file_at(file, &mut buf[..len], position) {
+			Ok(amt) => amt,
+			Err(e) => {
+				mainlogerror!("send_file: error reading file: {}", e);
+				0
+			}
+		};
+		match amt {
+			0 => Ok(0),
+			_ => write_bytes(handle, &buf[..amt]),
+		}
+	}
+}
+
+fn read_file_at(file: &File, buf: &mut [u8], position: u64) -> Result<usize, Error> {
+	#[cfg(unix)]
+	let amt = std::os::unix::fs::FileExt::read_at(file, buf, position)?;
+	#[cfg(target_os = "windows")]
+	let amt = std::os::windows::fs::FileExt::seek_read(file, buf, position)?;
+	Ok(amt)
+}
+
+pub(crate) fn write_vectored_data(
+	handle: ConnectionHandle,
+	bufs: &[IoSlice],
+) -> Result<isize, Error> {
+	#[cfg(unix)]
+	let len = {
+		// IoSlice is guaranteed to be ABI compatible with iovec on unix
+		let iov = bufs.as_ptr() as *const libc::iovec;
+		unsafe { libc::writev(handle, iov, bufs.len() as c_int) }
+	};
+	#[cfg(target_os = "windows")]
+	let len = {
+		let mut wsabufs: Vec<winapi::WSABUF> = bufs
+			.iter()
+			.map(|buf| winapi::WSABUF {
+				len: buf.len() as winapi::ULONG,
+				buf: buf.as_ptr() as *mut winapi::CHAR,
+			})
+			.collect();
+		let mut sent: winapi::DWORD = 0;
+		let res = unsafe {
+			ws2_32::WSASend(
+				handle.try_into().unwrap_or(0),
+				wsabufs.as_mut_ptr(),
+				wsabufs.len() as winapi::DWORD,
+				&mut sent,
+				0,
+				std::ptr::null_mut(),
+				None,
+			)
+		};
+		match res {
+			0 => sent as isize,
+			_ => -1,
+		}
+	};
+	Ok(len)
+}
+
+fn write_bytes(handle: ConnectionHandle, buf: &[u8]) -> Result<isize, Error> {
+	#[cfg(unix)]
+	let len = {
+		let cbuf: *const c_void = buf as *const _ as *const c_void;
+		unsafe { write(handle, cbuf, buf.len().into()) }
+	};
+	#[cfg(target_os = "windows")]
+	let len = {
+		let cbuf: *const i8 = buf as *const _ as *const i8;
+		unsafe {
+			ws2_32::send(
+				handle.try_into().unwrap_or(0),
+				cbuf,
+				(buf.len()).try_into().unwrap_or(0),
+				0,
+			)
+		}
+	};
+	Ok(len.try_into().unwrap_or(0))
+}
+
+pub(crate) fn read_handle(handle: ConnectionHandle, buf: &mut [u8]) -> Result<isize, Error> {
+	#[cfg(unix)]
+	let len = {
+		let cbuf: *mut c_void = buf as *mut _ as *mut c_void;
+		unsafe { read(handle, cbuf, buf.len()) }
+	};
+	#[cfg(target_os = "windows")]
+	let len = {
+		let cbuf: *mut i8 = buf as *mut _ as *mut i8;
+		errno::set_errno(Errno(0));
+		let mut len = unsafe {
+			ws2_32::recv(
+				handle.try_into().unwrap_or(0),
+				cbuf,
+				buf.len().try_into().unwrap_or(0),
+				0,
+			)
+		};
+		if errno().0 == 10035 {
+			// would block
+			len = -2;
+		}
+		len as isize
+	};
+	Ok(len)
+}
+
+// accepts a connection on listener, makes it non-blocking and applies socket_options to it if
+// it is a tcp connection
+pub(crate) fn accept_handle(
+	listener: ConnectionHandle,
+	socket_options: &SocketOptions,
+) -> Result<Option<(ConnectionHandle, Option<SocketAddr>)>, Error> {
+	let mut storage: SockAddrStorage = unsafe { std::mem::zeroed() };
+	#[cfg(unix)]
+	let res = unsafe {
+		accept(
+			listener,
+			&mut storage as *mut _ as *mut sockaddr,
+			&mut (std::mem::size_of::<SockAddrStorage>() as libc::socklen_t),
+		)
+	};
+	#[cfg(target_os = "windows")]
+	let res = unsafe {
+		ws2_32::accept(
+			listener.try_into().unwrap_or(0),
+			&mut storage as *mut _ as *mut winapi::ws2def::SOCKADDR,
+			&mut (std::mem::size_of::<SockAddrStorage>() as u32)
+				.try_into()
+				.unwrap_or(0),
+		)
+	};
+
+	if res <= 0 {
+		return Ok(None);
+	}
+
+	// set non-blocking
+	#[cfg(unix)]
+	{
+		let fcntl_res = unsafe { fcntl(res, libc::F_SETFL, libc::O_NONBLOCK) };
+		if fcntl_res < 0 {
+			let e = errno().to_string();
+			return Err(ErrorKind::InternalError(format!("fcntl error: {}", e)).into());
+		}
+	}
+	#[cfg(target_os = "windows")]
+	{
+		let fionbio = 0x8004667eu32;
+		let ioctl_res = unsafe { ws2_32::ioctlsocket(res, fionbio as c_int, &mut 1) };
+
+		if ioctl_res != 0 {
+			mainlogerror!("complete fion with error: {}", errno().to_string());
+		}
+	}
+
+	let peer_addr = accepted_peer_addr(res, &storage, socket_options);
+	Ok(Some((res, peer_addr)))
+}
+
+// returns the address of the peer of the accepted connection handle and applies socket_options
+// to it if it is a tcp connection
+pub(crate) fn accepted_peer_addr(
+	handle: ConnectionHandle,
+	storage: &SockAddrStorage,
+	socket_options: &SocketOptions,
+) -> Option<SocketAddr> {
+	let peer_addr = socket_addr_from_raw(storage);
+	// unix domain sockets have no tcp options
+	if peer_addr.is_some() {
+		match apply_socket_options(handle, socket_options, false) {
+			Ok(_) => {}
+			Err(e) => mainlogerror!("setsockopt resulted in error: {}", e.to_string()),
+		}
+	}
+	peer_addr
+}
+
+// shuts down the write side of a socket
+pub(crate) fn shutdown_write_handle(handle: ConnectionHandle) -> Result<i32, Error> {
+	#[cfg(unix)]
+	let res = unsafe { libc::shutdown(handle, libc::SHUT_WR) };
+	// 1 is SD_SEND
+	#[cfg(target_os = "windows")]
+	let res = unsafe { ws2_32::shutdown(handle.try_into().unwrap_or(0), 1) };
+	Ok(res)
+}
+
+// closes a socket
+pub(crate) fn close_handle(handle: ConnectionHandle) -> Result<i32, Error> {
+	#[cfg(unix)]
+	let res = unsafe { close(handle) };
+	#[cfg(target_os = "windows")]
+	let res = unsafe { ws2_32::closesocket(handle.try_into().unwrap_or(0)) };
+	Ok(res)
+}
+
+#[cfg(unix)]
+type SockAddrStorage = sockaddr_storage;
+#[cfg(target_os = "windows")]
+type SockAddrStorage = winapi::ws2def::SOCKADDR_STORAGE;
+
+#[test]
+fn test_echo() -> Result<(), Error> {
+	echo(Backend::Native, 9981)
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_echo_io_uring() -> Result<(), Error> {
+	echo(Backend::IoUring, 9503)
+}
+
+#[cfg(test)]
+fn echo(backend: Backend, port: u16) -> Result<(), Error> {
+	use std::net::TcpListener;
+	use std::net::TcpStream;
+	use std::sync::Mutex;
+
+	let x = Arc::new(Mutex::new(0));
+	let x_clone = x.clone();
+
+	let listener = TcpListener::bind(format!("127.0.0.1:{}", port))?;
+	let stream = TcpStream::connect(format!("127.0.0.1:{}", port))?;
+	let mut eh = EventHandler::new(EventHandlerConfig {
+		backend,
+		..EventHandlerConfig::default()
+	});
+
+	// echo
+	eh.set_on_read(|buf, len, wh| {
+		info!("server received: {} bytes", len);
+		let _ = wh.write(&buf[0..len]);
+		Ok(())
+	})?;
+
+	eh.set_on_accept(|_, _| Ok(()))?;
+	eh.set_on_close(|_| Ok(()))?;
+	let client_buf = Arc::new(Mutex::new(vec![]));
+	eh.set_on_client_read(move |buf, len, _wh| {
+		info!("client received: {} bytes", len);
+		let mut client_buf = client_buf.lock().unwrap();
+		for i in 0..len {
+			client_buf.push(buf[i]);
+		}
+		let len = client_buf.len();
+		if len == 5 {
+			assert_eq!(len, 5);
+			assert_eq!(client_buf[0], 1);
+			assert_eq!(client_buf[1], 2);
+			assert_eq!(client_buf[2], 3);
+			assert_eq!(client_buf[3], 4);
+			assert_eq!(client_buf[4], 5);
+			let mut x = x.lock().unwrap();
+			(*x) += 5;
+		}
+		Ok(())
+	})?;
+	eh.start()?;
+
+	eh.add_tcp_listener(&listener)?;
+	let wh = eh.add_tcp_stream(&stream)?;
+	// TODO: would be nice to eliminate this sleep
+	wh.write(&[1, 2, 3, 4, 5])?;
+	loop {
+		{
+			let x = x_clone.lock().unwrap();
+			if *x != 0 {
+				assert_eq!(*x, 5);
+				break;
+			}
+		}
+		std::thread::sleep(std::time::Duration::from_millis(10));
+	}
+	Ok(())
+}
+
+#[test]
+fn test_close() -> Result<(), Error> {
+	use std::io::Read;
+	use std::io::Write;
+	use std::net::TcpListener;
+	use std::net::TcpStream;
+	use std::sync::Mutex;
+
+	let listener = TcpListener::bind("127.0.0.1:9982")?;
+	let mut stream = TcpStream::connect("127.0.0.1:9982")?;
+	let mut eh = EventHandler::new(EventHandlerConfig::default());
+
+	let read_buf = Arc::new(Mutex::new(vec![]));
+	eh.set_on_read(move |buf, len, wh| {
+		let mut read_buf = read_buf.lock().unwrap();
+		for i in 0..len {
+			read_buf.push(buf[i]);
+		}
+
+		if read_buf.len() < 5 {
+			return Ok(());
+		}
+		info!("read = {:?}", read_buf);
+
+		let len = read_buf.len();
+
+		for i in 0..len {
+			if read_buf[i] == 7 {
+				wh.close()?;
+				return Ok(());
+			}
+		}
+		for i in 0..len {
+			if read_buf[i] == 8 {
+				wh.write(&[0])?;
+				wh.close()?;
+				read_buf.clear();
+				return Ok(());
+			}
+		}
+		wh.write(&[1])?;
+		read_buf.clear();
+		Ok(())
+	})?;
+
+	eh.set_on_accept(|_, _| Ok(()))?;
+	eh.set_on_close(|_| Ok(()))?;
+	eh.set_on_client_read(move |_buf, _len, _wh| Ok(()))?;
+
+	eh.start()?;
+	eh.add_tcp_listener(&listener)?;
+	stream.write(&[1, 2, 3, 4, 5])?;
+	let mut buf = [0u8; 1000];
+	let len = stream.read(&mut buf)?;
+	assert_eq!(len, 1);
+	assert_eq!(buf[0], 1);
+	stream.write(&[8, 8, 8, 8, 8])?;
+	let len = stream.read(&mut buf)?;
+	assert_eq!(len, 1);
+	assert_eq!(buf[0], 0);
+	let len = stream.read(&mut buf)?;
+	assert_eq!(len, 0); // means connection closed
+	let mut stream2 = TcpStream::connect("127.0.0.1:9982")?;
+	stream2.write(&[7, 7, 7, 7, 7])?;
+	let len = stream2.read(&mut buf)?;
+	assert_eq!(len, 0); // means connection closed
+
+	Ok(())
+}
+
+#[test]
+fn test_client() -> Result<(), Error> {
+	use std::io::Write;
+	use std::net::TcpListener;
+	use std::net::TcpStream;
+
+	let listener = TcpListener::bind("127.0.0.1:9983")?;
+	let mut stream = TcpStream::connect("127.0.0.1:9983")?;
+	let mut eh = EventHandler::new(EventHandlerConfig::default());
+
+	// echo
+	eh.set_on_read(|buf, len, wh| {
+		match len {
+			// just close the connection with no response
+			7 => {
+				let _ = wh.close();
+			}
+			// close if len == 5, otherwise keep open
+			_ => {
+				let _ = wh.write(&buf[0..len])?;
+				if len == 5 {
+					wh.close()?;
+				}
+			}
+		}
+		Ok(())
+	})?;
+
+	eh.set_on_accept(|_, _| Ok(()))?;
+	eh.set_on_close(|_| Ok(()))?;
+	eh.set_on_client_read(move |buf, len, _wh| {
+		info!("client_read={:?}", &buf[0..len]);
+		assert_eq!(&buf[0..len], [1, 2, 3, 4, 5, 6]);
+		Ok(())
+	})?;
+
+	eh.start()?;
+	eh.add_tcp_listener(&listener)?;
+	eh.add_tcp_stream(&stream)?;
+	std::thread::sleep(std::time::Duration::from_millis(1000));
+	stream.write(&[1, 2, 3, 4, 5, 6])?;
+	std::thread::sleep(std::time::Duration::from_millis(1000));
+	Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn test_unix_socket() -> Result<(), Error> {
+	use std::io::Write;
+	use std::os::unix::net::{UnixListener, UnixStream};
+	use std::sync::Mutex;
+
+	let path = std::env::temp_dir().join(format!("nioruntime_evh_{}.sock", std::process::id()));
+	let _ = std::fs::remove_file(&path);
+	let listener = UnixListener::bind(&path)?;
+	let mut stream = UnixStream::connect(&path)?;
+	let mut eh = EventHandler::new(EventHandlerConfig::default());
+	let client_read = Arc::new(Mutex::new(vec![]));
+	let client_read_clone = client_read.clone();
+
+	// echo
+	eh.set_on_read(|buf, len, wh| {
+		wh.write(&buf[0..len])?;
+		Ok(())
+	})?;
+	eh.set_on_accept(|_, _| Ok(()))?;
+	eh.set_on_close(|_| Ok(()))?;
+	eh.set_on_client_read(move |buf, len, _wh| {
+		let mut client_read = client_read_clone.lock().unwrap();
+		client_read.extend_from_slice(&buf[0..len]);
+		Ok(())
+	})?;
+
+	eh.start()?;
+	eh.add_unix_listener(&listener)?;
+	eh.add_unix_stream(&stream)?;
+	stream.write_all(&[1, 2, 3, 4])?;
+	std::thread::sleep(std::time::Duration::from_millis(1000));
+	assert_eq!(*client_read.lock().unwrap(), vec![1, 2, 3, 4]);
+	let _ = std::fs::remove_file(&path);
+	Ok(())
+}
+
+#[test]
+fn test_udp() -> Result<(), Error> {
+	use std::net::UdpSocket;
+
+	let server = UdpSocket::bind("127.0.0.1:9487")?;
+	let client = UdpSocket::bind("127.0.0.1:0")?;
+	client.set_read_timeout(Some(std::time::Duration::from_millis(5000)))?;
+	let mut eh = EventHandler::new(EventHandlerConfig::default());
+
+	// echo each datagram back to its sender
+	eh.set_on_datagram(|buf, len, peer, dh| {
+		dh.send_to(&buf[0..len], peer)?;
+		Ok(())
+	})?;
+	eh.set_on_read(|_, _, _| Ok(()))?;
+	eh.set_on_accept(|_, _| Ok(()))?;
+	eh.set_on_close(|_| Ok(()))?;
+	eh.set_on_client_read(|_, _, _| Ok(()))?;
+
+	eh.start()?;
+	eh.add_udp_socket(&server)?;
+
+	let mut buf = [0u8; 100];
+	for i in 0..3 {
+		client.send_to(&[i, 2, 3], "127.0.0.1:9487")?;
+		let (len, peer) = client.recv_from(&mut buf)?;
+		assert_eq!(&buf[0..len], [i, 2, 3]);
+		assert_eq!(peer, server.local_addr()?);
+	}
+	Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn test_connect() -> Result<(), Error> {
+	use std::net::TcpListener;
+	use std::sync::Mutex;
+
+	let listener = TcpListener::bind("127.0.0.1:9488")?;
+	// find a port with nothing listening on it
+	let refused_addr = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+	let mut eh = EventHandler::new(EventHandlerConfig::default());
+	let connected = Arc::new(Mutex::new(0));
+	let failed = Arc::new(Mutex::new(0));
+	let client_read = Arc::new(Mutex::new(vec![]));
+	let connected_clone = connected.clone();
+	let failed_clone = failed.clone();
+	let client_read_clone = client_read.clone();
+
+	// echo
+	eh.set_on_read(|buf, len, wh| {
+		wh.write(&buf[0..len])?;
+		Ok(())
+	})?;
+	eh.set_on_accept(|_, _| Ok(()))?;
+	eh.set_on_close(|_| Ok(()))?;
+	eh.set_on_client_read(move |buf, len, _wh| {
+		let mut client_read = client_read_clone.lock().unwrap();
+		client_read.extend_from_slice(&buf[0..len]);
+		Ok(())
+	})?;
+	eh.set_on_connect(move |_, wh| {
+		match wh {
+			Ok(wh) => {
+				*connected_clone.lock().unwrap() += 1;
+				wh.write(&[1, 2, 3])?;
+			}
+			Err(_) => *failed_clone.lock().unwrap() += 1,
+		}
+		Ok(())
+	})?;
+
+	eh.start()?;
+	eh.add_tcp_listener(&listener)?;
+	eh.connect(
+		listener.local_addr()?,
+		std::time::Duration::from_millis(5000),
+	)?;
+	eh.connect(refused_addr, std::time::Duration::from_millis(5000))?;
+	std::thread::sleep(std::time::Duration::from_millis(1000));
+	assert_eq!(*connected.lock().unwrap(), 1);
+	assert_eq!(*failed.lock().unwrap(), 1);
+	assert_eq!(*client_read.lock().unwrap(), vec![1, 2, 3]);
+	Ok(())
+}
+
+#[test]
+fn test_addresses() -> Result<(), Error> {
+	use std::net::TcpListener;
+	use std::net::TcpStream;
+	use std::sync::Mutex;
+
+	let listener = TcpListener::bind("127.0.0.1:9489")?;
+	let stream = TcpStream::connect("127.0.0.1:9489")?;
+	// ipv6 may not be available in all environments
+	let listener6 = TcpListener::bind("[::1]:9489").ok();
+	let stream6 = match listener6 {
+		Some(_) => Some(TcpStream::connect("[::1]:9489")?),
+		None => None,
+	};
+	let mut eh = EventHandler::new(EventHandlerConfig::default());
+	let accepted = Arc::new(Mutex::new(vec![]));
+	let accepted_clone = accepted.clone();
+
+	eh.set_on_read(|_, _, _| Ok(()))?;
+	eh.set_on_accept(move |_, wh| {
+		let mut accepted = accepted_clone.lock().unwrap();
+		accepted.push((wh.peer_addr(), wh.local_addr()));
+		Ok(())
+	})?;
+	eh.set_on_close(|_| Ok(()))?;
+	eh.set_on_client_read(|_, _, _| Ok(()))?;
+
+	eh.start()?;
+	eh.add_tcp_listener(&listener)?;
+	if let Some(listener6) = listener6.as_ref() {
+		eh.add_tcp_listener(listener6)?;
+	}
+	let wh = eh.add_tcp_stream(&stream)?;
+	assert_eq!(wh.peer_addr(), Some(listener.local_addr()?));
+	assert_eq!(wh.local_addr(), Some(stream.local_addr()?));
+	std::thread::sleep(std::time::Duration::from_millis(1000));
+
+	let accepted = accepted.lock().unwrap();
+	assert!(accepted.contains(&(Some(stream.local_addr()?), Some(listener.local_addr()?))));
+	match (listener6, stream6) {
+		(Some(listener6), Some(stream6)) => {
+			assert_eq!(accepted.len(), 2);
+			assert!(
+				accepted.contains(&(Some(stream6.local_addr()?), Some(listener6.local_addr()?)))
+			);
+		}
+		_ => assert_eq!(accepted.len(), 1),
+	}
+	Ok(())
+}
+
+#[test]
+fn test_stop() -> Result<(), Error> {
+	use std::io::Write;
+	use std::net::TcpListener;
+	use std::net::TcpStream;
+	use std::sync::Mutex;
+
+	let listener = TcpListener::bind("127.0.0.1:9984")?;
+	let mut stream = TcpStream::connect("127.0.0.1:9984")?;
+	let mut eh = EventHandler::new(EventHandlerConfig::default());
+	let x = Arc::new(Mutex::new(0));
+	let xclone = x.clone();
+
+	// echo
+	eh.set_on_read(move |_, _, _| {
+		let mut x = xclone.lock().unwrap();
+		*x += 1;
+		Ok(())
+	})?;
+
+	eh.set_on_accept(|_, _| Ok(()))?;
+	eh.set_on_close(|_| Ok(()))?;
+	eh.set_on_client_read(move |_, _, _| Ok(()))?;
+
+	eh.start()?;
+	eh.add_tcp_listener(&listener)?;
+	eh.add_tcp_stream(&stream)?;
+	stream.write(&[1])?;
+	loop {
+		{
+			let x = x.lock().unwrap();
+			if *x == 1 {
+				break;
+			}
+		}
+		std::thread::sleep(std::time::Duration::from_millis(10));
+	}
+	eh.stop()?;
+	std::thread::sleep(std::time::Duration::from_millis(3000));
+	stream.write(&[1])?;
+	std::thread::sleep(std::time::Duration::from_millis(3000));
+	let x = x.lock().unwrap();
+	assert_eq!(*x, 1);
+	Ok(())
+}
+
+#[test]
+fn test_large_messages() -> Result<(), Error> {
+	large_messages(Backend::Native, 9933)
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_large_messages_io_uring() -> Result<(), Error> {
+	large_messages(Backend::IoUring, 9504)
+}
+
+#[cfg(test)]
+fn large_messages(backend: Backend, port: u16) -> Result<(), Error> {
+	use std::net::TcpListener;
+	use std::net::TcpStream;
+	use std::sync::Mutex;
+
+	let listener = TcpListener::bind(format!("127.0.0.1:{}", port))?;
+	let stream = TcpStream::connect(format!("127.0.0.1:{}", port))?;
+	let mut eh = EventHandler::new(EventHandlerConfig {
+		backend,
+		..EventHandlerConfig::default()
+	});
+
+	eh.set_on_read(move |buf, len, wh| {
+		match len {
+			// just close the connection with no response
+			7 => {
+				let _ = wh.close();
+			}
+			// close if len == 5, otherwise keep open
+			_ => {
+				let _ = wh.write(&buf[0..len])?;
+				if len == 5 {
+					wh.close()?;
+				}
+			}
+		}
+		Ok(())
+	})?;
+
+	let mut msgbuf = vec![];
+	for i in 0..3_000_000 {
+		msgbuf.push((i % 123) as u8);
+	}
+	msgbuf.push(128);
+	let cloned_msgbuf = msgbuf.clone();
+
+	eh.set_on_accept(|_, _| Ok(()))?;
+	eh.set_on_close(|_| Ok(()))?;
+
+	let client_accumulator = Arc::new(Mutex::new(vec![]));
+	let complete = Arc::new(Mutex::new(false));
+	let complete_clone = complete.clone();
+
+	eh.set_on_client_read(move |buf, len, _wh| {
+		let mut client_accumulator = client_accumulator.lock().unwrap();
+		for i in 0..len {
+			client_accumulator.push(buf[i]);
+			if buf[i] == 128 {
+				assert_eq!(client_accumulator.len(), cloned_msgbuf.len());
+				assert_eq!(*client_accumulator, cloned_msgbuf);
+				let mut complete = complete_clone.lock().unwrap();
+				*complete = true;
+			}
+		}
+		Ok(())
+	})?;
+
+	eh.start()?;
+	eh.add_tcp_listener(&listener)?;
+	let wh = eh.add_tcp_stream(&stream)?;
+	wh.write(&msgbuf[0..msgbuf.len()])?;
+	loop {
+		{
+			let complete = complete.lock().unwrap();
+			if *complete {
+				break;
+			}
+		}
+		std::thread::sleep(std::time::Duration::from_millis(10));
+	}
+	Ok(())
+}
+
+#[test]
+fn test_ssl() -> Result<(), Error> {
+	ssl(Backend::Native, 9483)
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_ssl_io_uring() -> Result<(), Error> {
+	ssl(Backend::IoUring, 9505)
+}
+
+#[cfg(test)]
+fn ssl(backend: Backend, port: u16) -> Result<(), Error> {
+	use std::net::TcpListener;
+	use std::net::TcpStream;
+
+	let listener = TcpListener::bind(format!("127.0.0.1:{}", port))?;
+	let stream = TcpStream::connect(format!("127.0.0.1:{}", port))?;
+	let mut eh = EventHandler::new(EventHandlerConfig {
+		tls_config: Some(TlsConfig {
+			certificates_file: "./src/resources/cert.pem".to_string(),
+			private_key_file: "./src/resources/key.pem".to_string(),
+			..TlsConfig::default()
+		}),
+		backend,
+		..EventHandlerConfig::default()
+	});
+
+	// echo
+	eh.set_on_read(|buf, len, wh| {
+		info!("server read {}", len);
+		match len {
+			// just close the connection with no response
+			7 => {
+				let _ = wh.close();
+			}
+			// close if len == 5, otherwise keep open
+			_ => {
+				let _ = wh.write(&buf[0..len])?;
+				if len == 5 {
+					wh.close()?;
+				}
+			}
+		}
+		Ok(())
+	})?;
+
+	eh.set_on_accept(|_, _| Ok(()))?;
+	eh.set_on_close(|_| Ok(()))?;
+	let complete = Arc::new(RwLock::new(false));
+	let complete_clone = complete.clone();
+	eh.set_on_client_read(move |buf, len, _wh| {
+		info!("client_read={:?}", &buf[0..len]);
+		assert_eq!(&buf[0..len], [1, 2, 3, 4, 5, 6]);
+		let mut complete = complete_clone.write().unwrap();
+		*complete = true;
+		Ok(())
+	})?;
+
+	eh.start()?;
+	eh.add_tcp_listener(&listener)?;
+	let wh = eh.add_tls_stream(&stream, "localhost", Some("./src/resources/cert.pem"))?;
+	wh.write(&[1, 2, 3, 4, 5, 6])?;
+	loop {
+		std::thread::sleep(std::time::Duration::from_millis(10));
+		let complete = complete.write().unwrap();
+		if *complete {
+			break;
+		}
+	}
+
+	Ok(())
+}
+
+#[test]
+fn test_tls_per_listener() -> Result<(), Error> {
+	use std::net::TcpListener;
+	use std::net::TcpStream;
+	use std::sync::Mutex;
+
+	let plain_listener = TcpListener::bind("127.0.0.1:9490")?;
+	let tls_listener = TcpListener::bind("127.0.0.1:9491")?;
+	let plain_stream = TcpStream::connect("127.0.0.1:9490")?;
+	let default_stream = TcpStream::connect("127.0.0.1:9491")?;
+	let sni_stream = TcpStream::connect("127.0.0.1:9491")?;
+	let mut eh = EventHandler::new(EventHandlerConfig::default());
+	let client_reads = Arc::new(Mutex::new(HashMap::new()));
+	let client_reads_clone = client_reads.clone();
+
+	// echo
+	eh.set_on_read(|buf, len, wh| {
+		wh.write(&buf[0..len])?;
+		Ok(())
+	})?;
+	eh.set_on_accept(|_, _| Ok(()))?;
+	eh.set_on_close(|_| Ok(()))?;
+	eh.set_on_client_read(move |buf, len, wh| {
+		let mut client_reads = client_reads_clone.lock().unwrap();
+		client_reads
+			.entry(wh.get_connection_id())
+			.or_insert(vec![])
+			.extend_from_slice(&buf[0..len]);
+		Ok(())
+	})?;
+
+	eh.start()?;
+	eh.add_tcp_listener(&plain_listener)?;
+	eh.add_tcp_listener_with_tls(
+		&tls_listener,
+		Some(TlsConfig {
+			certificates_file: "./src/resources/cert.pem".to_string(),
+			private_key_file: "./src/resources/key.pem".to_string(),
+			sni_certificates: vec![SniCertificate::new(
+				"example.com".to_string(),
+				"./src/resources/key2.pem".to_string(),
+				"./src/resources/cert2.pem".to_string(),
+			)],
+			..TlsConfig::default()
+		}),
+	)?;
+
+	let plain_wh = eh.add_tcp_stream(&plain_stream)?;
+	let default_wh = eh.add_tls_stream(
+		&default_stream,
+		"localhost",
+		Some("./src/resources/cert.pem"),
+	)?;
+	// only trusts the certificate served for example.com
+	let sni_wh = eh.add_tls_stream(
+		&sni_stream,
+		"example.com",
+		Some("./src/resources/cert2.pem"),
+	)?;
+	plain_wh.write(&[1])?;
+	default_wh.write(&[2])?;
+	sni_wh.write(&[3])?;
+	std::thread::sleep(std::time::Duration::from_millis(1000));
+
+	let client_reads = client_reads.lock().unwrap();
+	assert_eq!(
+		client_reads.get(&plain_wh.get_connection_id()),
+		Some(&vec![1])
+	);
+	assert_eq!(
+		client_reads.get(&default_wh.get_connection_id()),
+		Some(&vec![2])
+	);
+	assert_eq!(
+		client_reads.get(&sni_wh.get_connection_id()),
+		Some(&vec![3])
+	);
+	Ok(())
+}
+
+#[test]
+fn test_write_queue_high_water() -> Result<(), Error> {
+	use std::io::Read;
+	use std::io::Write;
+	use std::net::TcpListener;
+	use std::net::TcpStream;
+	use std::sync::Mutex;
+
+	let listener = TcpListener::bind("127.0.0.1:9485")?;
+	let mut stream = TcpStream::connect("127.0.0.1:9485")?;
+	let mut eh = EventHandler::new(EventHandlerConfig {
+		write_queue_high_water: Some(100 * 1024),
+		write_queue_low_water: 10 * 1024,
+		..EventHandlerConfig::default()
+	});
+
+	let server_wh = Arc::new(Mutex::new(None));
+	let server_wh_clone = server_wh.clone();
+	let writable = Arc::new(Mutex::new(false));
+	let writable_clone = writable.clone();
+
+	eh.set_on_read(move |_, _, wh| {
+		let mut server_wh = server_wh_clone.lock().unwrap();
+		*server_wh = Some(wh);
+		Ok(())
+	})?;
+	eh.set_on_accept(|_, _| Ok(()))?;
+	eh.set_on_close(|_| Ok(()))?;
+	eh.set_on_client_read(|_, _, _| Ok(()))?;
+	eh.set_on_writable(move |_| {
+		let mut writable = writable_clone.lock().unwrap();
+		*writable = true;
+		Ok(())
+	})?;
+
+	eh.start()?;
+	eh.add_tcp_listener(&listener)?;
+	stream.write_all(&[1])?;
+
+	let wh = loop {
+		{
+			let server_wh = server_wh.lock().unwrap();
+			if server_wh.is_some() {
+				break server_wh.as_ref().unwrap().clone();
+			}
+		}
+		std::thread::sleep(std::time::Duration::from_millis(10));
+	};
+
+	// the client never reads, so eventually the queue must fill up
+	let mut total = 0;
+	loop {
+		match wh.write(&[1u8; 10 * 1024]) {
+			Ok(_) => total += 10 * 1024,
+			Err(e) => {
+				assert_eq!(
+					e.kind(),
+					ErrorKind::WriteQueueFull(format!(
+						"write queue for connection {} is full",
+						wh.get_connection_id()
+					))
+				);
+				break;
+			}
+		}
+	}
+	assert!(wh.is_write_queue_full()?);
+	assert!(!*writable.lock().unwrap());
+
+	// now drain everything that was queued
+	let mut buf = [0u8; 100 * 1024];
+	let mut read = 0;
+	while read < total {
+		read += stream.read(&mut buf)?;
+	}
+	loop {
+		{
+			if *writable.lock().unwrap() {
+				break;
+			}
+		}
+		std::thread::sleep(std::time::Duration::from_millis(10));
+	}
+	assert!(!wh.is_write_queue_full()?);
+	wh.write(&[1u8; 10])?;
+
+	Ok(())
+}
+
+#[test]
+fn test_timers() -> Result<(), Error> {
+	use std::io::Read;
+	use std::net::TcpListener;
+	use std::net::TcpStream;
+	use std::sync::Mutex;
+
+	let listener = TcpListener::bind("127.0.0.1:9486")?;
+	let mut stream = TcpStream::connect("127.0.0.1:9486")?;
+	let mut eh = EventHandler::new(EventHandlerConfig::default());
+
+	let timed_out = Arc::new(Mutex::new(0));
+	let timed_out_clone = timed_out.clone();
+
+	eh.set_on_read(|_, _, _| Ok(()))?;
+	eh.set_on_accept(|_, wh| {
+		wh.set_timeout(Duration::from_millis(200))?;
+		Ok(())
+	})?;
+	eh.set_on_close(|_| Ok(()))?;
+	eh.set_on_client_read(|_, _, _| Ok(()))?;
+	eh.set_on_timeout(move |wh| {
+		let mut timed_out = timed_out_clone.lock().unwrap();
+		*timed_out += 1;
+		wh.close()?;
+		Ok(())
+	})?;
+
+	eh.start()?;
+	eh.add_tcp_listener(&listener)?;
+
+	let fired = Arc::new(Mutex::new(false));
+	let fired_clone = fired.clone();
+	let cancelled = Arc::new(Mutex::new(false));
+	let cancelled_clone = cancelled.clone();
+	eh.schedule(Duration::from_millis(100), move || {
+		let mut fired = fired_clone.lock().unwrap();
+		*fired = true;
+		Ok(())
+	})?;
+	let handle = eh.schedule(Duration::from_millis(100), move || {
+		let mut cancelled = cancelled_clone.lock().unwrap();
+		*cancelled = true;
+		Ok(())
+	})?;
+	handle.cancel()?;
+
+	// the idle timeout closes the connection
+	let mut buf = [0u8; 100];
+	let len = stream.read(&mut buf)?;
+	assert_eq!(len, 0);
+	assert_eq!(*timed_out.lock().unwrap(), 1);
+
+	std::thread::sleep(Duration::from_millis(100));
+	assert!(*fired.lock().unwrap());
+	assert!(!*cancelled.lock().unwrap());
+
+	Ok(())
+}
+
+#[test]
+fn test_tls_reload() -> Result<(), Error> {
+	use std::net::TcpListener;
+	use std::net::TcpStream;
+	use std::sync::Mutex;
+
+	let dir = std::env::temp_dir();
+	let cert_file = dir.join(format!("nioruntime_evh_{}_cert.pem", std::process::id()));
+	let key_file = dir.join(format!("nioruntime_evh_{}_key.pem", std::process::id()));
+	std::fs::copy("./src/resources/cert.pem", &cert_file)?;
+	std::fs::copy("./src/resources/key.pem", &key_file)?;
+
+	let listener = TcpListener::bind("127.0.0.1:9492")?;
+	let old_stream = TcpStream::connect("127.0.0.1:9492")?;
+	let mut eh = EventHandler::new(EventHandlerConfig::default());
+	let client_reads = Arc::new(Mutex::new(HashMap::new()));
+	let client_reads_clone = client_reads.clone();
+
+	// echo
+	eh.set_on_read(|buf, len, wh| {
+		wh.write(&buf[0..len])?;
+		Ok(())
+	})?;
+	eh.set_on_accept(|_, _| Ok(()))?;
+	eh.set_on_close(|_| Ok(()))?;
+	eh.set_on_client_read(move |buf, len, wh| {
+		let mut client_reads = client_reads_clone.lock().unwrap();
+		client_reads
+			.entry(wh.get_connection_id())
+			.or_insert(vec![])
+			.extend_from_slice(&buf[0..len]);
+		Ok(())
+	})?;
+
+	eh.start()?;
+	eh.add_tcp_listener_with_tls(
+		&listener,
+		Some(TlsConfig {
+			certificates_file: cert_file.to_str().unwrap().to_string(),
+			private_key_file: key_file.to_str().unwrap().to_string(),
+			..TlsConfig::default()
+		}),
+	)?;
+
+	let old_wh = eh.add_tls_stream(&old_stream, "localhost", Some("./src/resources/cert.pem"))?;
+	old_wh.write(&[1])?;
+	std::thread::sleep(std::time::Duration::from_millis(500));
+
+	// an invalid certificate file is rejected and the current certificates are kept
+	std::fs::write(&cert_file, "invalid")?;
+	assert!(eh.reload_tls().is_err());
+
+	// renewed certificates are used for new connections only
+	std::fs::copy("./src/resources/cert2.pem", &cert_file)?;
+	std::fs::copy("./src/resources/key2.pem", &key_file)?;
+	eh.reload_tls()?;
+
+	let new_stream = TcpStream::connect("127.0.0.1:9492")?;
+	let new_wh = eh.add_tls_stream(
+		&new_stream,
+		"example.com",
+		Some("./src/resources/cert2.pem"),
+	)?;
+	new_wh.write(&[2])?;
+	old_wh.write(&[3])?;
+	std::thread::sleep(std::time::Duration::from_millis(1000));
+
+	let client_reads = client_reads.lock().unwrap();
+	assert_eq!(
+		client_reads.get(&old_wh.get_connection_id()),
+		Some(&vec![1, 3])
+	);
+	assert_eq!(
+		client_reads.get(&new_wh.get_connection_id()),
+		Some(&vec![2])
+	);
+
+	std::fs::remove_file(&cert_file)?;
+	std::fs::remove_file(&key_file)?;
+	Ok(())
+}
+
+#[test]
+fn test_mutual_tls() -> Result<(), Error> {
+	use std::net::TcpListener;
+	use std::net::TcpStream;
+	use std::sync::Mutex;
+
+	let listener = TcpListener::bind("127.0.0.1:9493")?;
+	let auth_stream = TcpStream::connect("127.0.0.1:9493")?;
+	let anon_stream = TcpStream::connect("127.0.0.1:9493")?;
+	let mut eh = EventHandler::new(EventHandlerConfig::default());
+	let client_reads = Arc::new(Mutex::new(HashMap::new()));
+	let client_reads_clone = client_reads.clone();
+	let server_peer_certificates = Arc::new(Mutex::new(vec![]));
+	let server_peer_certificates_clone = server_peer_certificates.clone();
+
+	// echo
+	eh.set_on_read(move |buf, len, wh| {
+		let mut server_peer_certificates = server_peer_certificates_clone.lock().unwrap();
+		server_peer_certificates.push(wh.peer_certificates()?);
+		wh.write(&buf[0..len])?;
+		Ok(())
+	})?;
+	eh.set_on_accept(|_, _| Ok(()))?;
+	eh.set_on_close(|_| Ok(()))?;
+	eh.set_on_client_read(move |buf, len, wh| {
+		let mut client_reads = client_reads_clone.lock().unwrap();
+		client_reads
+			.entry(wh.get_connection_id())
+			.or_insert(vec![])
+			.extend_from_slice(&buf[0..len]);
+		Ok(())
+	})?;
+
+	eh.start()?;
+	eh.add_tcp_listener_with_tls(
+		&listener,
+		Some(TlsConfig {
+			certificates_file: "./src/resources/cert.pem".to_string(),
+			private_key_file: "./src/resources/key.pem".to_string(),
+			client_ca_file: Some("./src/resources/client_cert.pem".to_string()),
+			client_auth_required: true,
+			..TlsConfig::default()
+		}),
+	)?;
+
+	let auth_wh = eh.add_tls_stream_with_config(
+		&auth_stream,
+		"localhost",
+		TlsClientConfig {
+			trusted_certificate: Some("./src/resources/cert.pem".to_string()),
+			client_certificate: Some(ClientCertificate::new(
+				"./src/resources/client_key.pem".to_string(),
+				"./src/resources/client_cert.pem".to_string(),
+			)),
+			..TlsClientConfig::default()
+		},
+	)?;
+	// no client certificate, so the server must reject this connection
+	let anon_wh = eh.add_tls_stream(&anon_stream, "localhost", Some("./src/resources/cert.pem"))?;
+	auth_wh.write(&[1])?;
+	anon_wh.write(&[2])?;
+	std::thread::sleep(std::time::Duration::from_millis(1000));
+
+	let client_reads = client_reads.lock().unwrap();
+	assert_eq!(
+		client_reads.get(&auth_wh.get_connection_id()),
+		Some(&vec![1])
+	);
+	assert_eq!(client_reads.get(&anon_wh.get_connection_id()), None);
+
+	let client_cert = load_certs("./src/resources/client_cert.pem")?;
+	let server_peer_certificates = server_peer_certificates.lock().unwrap();
+	assert_eq!(
+		*server_peer_certificates,
+		vec![Some(vec![client_cert[0].0.clone()])]
+	);
+
+	let server_cert = load_certs("./src/resources/cert.pem")?;
+	assert_eq!(
+		auth_wh.peer_certificates()?,
+		Some(vec![server_cert[0].0.clone()])
+	);
+	Ok(())
+}
+
+#[test]
+fn test_alpn() -> Result<(), Error> {
+	use std::net::TcpListener;
+	use std::net::TcpStream;
+	use std::sync::Mutex;
+
+	let listener = TcpListener::bind("127.0.0.1:9494")?;
+	let http_stream = TcpStream::connect("127.0.0.1:9494")?;
+	let none_stream = TcpStream::connect("127.0.0.1:9494")?;
+	let unknown_stream = TcpStream::connect("127.0.0.1:9494")?;
+	let mut eh = EventHandler::new(EventHandlerConfig::default());
+	let client_reads = Arc::new(Mutex::new(HashMap::new()));
+	let client_reads_clone = client_reads.clone();
+
+	// echo the negotiated protocol
+	eh.set_on_read(|_, _, wh| {
+		match wh.alpn_protocol()? {
+			Some(protocol) => wh.write(protocol.as_bytes())?,
+			None => wh.write(b"none")?,
+		}
+		Ok(())
+	})?;
+	eh.set_on_accept(|_, _| Ok(()))?;
+	eh.set_on_close(|_| Ok(()))?;
+	eh.set_on_client_read(move |buf, len, wh| {
+		let mut client_reads = client_reads_clone.lock().unwrap();
+		client_reads
+			.entry(wh.get_connection_id())
+			.or_insert(vec![])
+			.extend_from_slice(&buf[0..len]);
+		Ok(())
+	})?;
+
+	eh.start()?;
+	eh.add_tcp_listener_with_tls(
+		&listener,
+		Some(TlsConfig {
+			certificates_file: "./src/resources/cert.pem".to_string(),
+			private_key_file: "./src/resources/key.pem".to_string(),
+			alpn_protocols: vec!["h2".to_string(), "http/1.1".to_string()],
+			..TlsConfig::default()
+		}),
+	)?;
+
+	let tls_client_config = |alpn_protocols: Vec<&str>| TlsClientConfig {
+		trusted_certificate: Some("./src/resources/cert.pem".to_string()),
+		alpn_protocols: alpn_protocols.iter().map(|p| p.to_string()).collect(),
+		..TlsClientConfig::default()
+	};
+	let http_wh = eh.add_tls_stream_with_config(
+		&http_stream,
+		"localhost",
+		tls_client_config(vec!["foo", "http/1.1"]),
+	)?;
+	let none_wh =
+		eh.add_tls_stream_with_config(&none_stream, "localhost", tls_client_config(vec![]))?;
+	// no protocol in common, so the server must reject this connection
+	let unknown_wh = eh.add_tls_stream_with_config(
+		&unknown_stream,
+		"localhost",
+		tls_client_config(vec!["foo"]),
+	)?;
+	http_wh.write(&[1])?;
+	none_wh.write(&[2])?;
+	unknown_wh.write(&[3])?;
+	std::thread::sleep(std::time::Duration::from_millis(1000));
+
+	let client_reads = client_reads.lock().unwrap();
+	assert_eq!(
+		client_reads.get(&http_wh.get_connection_id()),
+		Some(&b"http/1.1".to_vec())
+	);
+	assert_eq!(
+		client_reads.get(&none_wh.get_connection_id()),
+		Some(&b"none".to_vec())
+	);
+	assert_eq!(client_reads.get(&unknown_wh.get_connection_id()), None);
+	assert_eq!(http_wh.alpn_protocol()?, Some("http/1.1".to_string()));
+	assert_eq!(none_wh.alpn_protocol()?, None);
+	Ok(())
+}
+
+#[test]
+fn test_stop_graceful() -> Result<(), Error> {
+	use std::io::Read;
+	use std::io::Write;
+	use std::net::TcpListener;
+	use std::net::TcpStream;
+	use std::sync::Mutex;
+
+	let listener = TcpListener::bind("127.0.0.1:9495")?;
+	let mut reader_stream = TcpStream::connect("127.0.0.1:9495")?;
+	let mut stalled_stream = TcpStream::connect("127.0.0.1:9495")?;
+	let mut eh = EventHandler::new(EventHandlerConfig::default());
+	let accepted = Arc::new(Mutex::new(0));
+	let accepted_clone = accepted.clone();
+	let closed = Arc::new(Mutex::new(HashSet::new()));
+	let closed_clone = closed.clone();
+	let stalled_id = Arc::new(Mutex::new(None));
+	let stalled_id_clone = stalled_id.clone();
+
+	eh.set_on_read(move |buf, len, wh| {
+		match buf[0..len] == [1] {
+			true => wh.write(&[2; 1000])?,
+			false => {
+				// the client never reads this, so it can't be flushed
+				*stalled_id_clone.lock().unwrap() = Some(wh.get_connection_id());
+				wh.write(&vec![3; 50 * 1024 * 1024])?;
+			}
+		}
+		Ok(())
+	})?;
+	eh.set_on_accept(move |_, _| {
+		*accepted_clone.lock().unwrap() += 1;
+		Ok(())
+	})?;
+	eh.set_on_close(move |connection_id| {
+		closed_clone.lock().unwrap().insert(connection_id);
+		Ok(())
+	})?;
+	eh.set_on_client_read(|_, _, _| Ok(()))?;
+
+	eh.start()?;
+	eh.add_tcp_listener(&listener)?;
+	stalled_stream.write_all(&[0])?;
+	loop {
+		if stalled_id.lock().unwrap().is_some() {
+			break;
+		}
+		std::thread::sleep(std::time::Duration::from_millis(10));
+	}
+	reader_stream.write_all(&[1])?;
+	std::thread::sleep(std::time::Duration::from_millis(100));
+
+	// the queued data is still written and then the connection is closed by the EventHandler
+	let reader = std::thread::spawn(move || {
+		let mut data = vec![];
+		reader_stream
+			.set_read_timeout(Some(std::time::Duration::from_millis(5000)))
+			.unwrap();
+		reader_stream.read_to_end(&mut data).unwrap();
+		data
+	});
+
+	let summary = eh.stop_graceful(std::time::Duration::from_millis(1000))?;
+	assert_eq!(reader.join().unwrap(), vec![2; 1000]);
+
+	let stalled_id = stalled_id.lock().unwrap().unwrap();
+	assert_eq!(summary.force_closed, vec![stalled_id]);
+	assert!(summary.unsent_bytes > 0);
+	assert_eq!(closed.lock().unwrap().len(), 2);
+	assert!(closed.lock().unwrap().contains(&stalled_id));
+
+	// no more connections are accepted
+	let _stream = TcpStream::connect("127.0.0.1:9495")?;
+	std::thread::sleep(std::time::Duration::from_millis(100));
+	assert_eq!(*accepted.lock().unwrap(), 2);
+	Ok(())
+}
+
+#[test]
+fn test_stop_graceful_failed_on_close() -> Result<(), Error> {
+	use std::io::Read;
+	use std::net::TcpListener;
+	use std::net::TcpStream;
+	use std::sync::Mutex;
+
+	let listener = TcpListener::bind("127.0.0.1:0")?;
+	let addr = listener.local_addr()?;
+	let mut eh = EventHandler::new(EventHandlerConfig::default());
+	let accepted = Arc::new(Mutex::new(vec![]));
+	let accepted_clone = accepted.clone();
+	let closed = Arc::new(Mutex::new(vec![]));
+	let closed_clone = closed.clone();
+
+	eh.set_on_read(|_, _, _| Ok(()))?;
+	eh.set_on_accept(move |connection_id, _| {
+		accepted_clone.lock().unwrap().push(connection_id);
+		Ok(())
+	})?;
+	// the listener thread exits after the first error
+	eh.set_on_close(move |connection_id| {
+		closed_clone.lock().unwrap().push(connection_id);
+		Err(ErrorKind::ApplicationError("on_close failed".to_string()).into())
+	})?;
+	eh.set_on_client_read(|_, _, _| Ok(()))?;
+
+	eh.start()?;
+	eh.add_tcp_listener(&listener)?;
+	let closed_stream = TcpStream::connect(addr)?;
+	let mut open_stream = TcpStream::connect(addr)?;
+	while accepted.lock().unwrap().len() < 2 {
+		std::thread::sleep(std::time::Duration::from_millis(10));
+	}
+	drop(closed_stream);
+	while closed.lock().unwrap().is_empty() {
+		std::thread::sleep(std::time::Duration::from_millis(10));
+	}
+	std::thread::sleep(std::time::Duration::from_millis(100));
+
+	// stop_graceful returns once the deadline passes rather than waiting for the listener thread
+	let (tx, rx) = sync_channel(1);
+	std::thread::spawn(move || {
+		let _ = tx.send(eh.stop_graceful(std::time::Duration::from_millis(500)));
+	});
+	let summary = rx
+		.recv_timeout(std::time::Duration::from_secs(10))
+		.unwrap()?;
+
+	// the open connection was closed without executing on_close
+	let closed = closed.lock().unwrap();
+	assert_eq!(closed.len(), 1);
+	assert_eq!(summary.force_closed.len(), 1);
+	assert_ne!(summary.force_closed[0], closed[0]);
+	assert!(accepted.lock().unwrap().contains(&summary.force_closed[0]));
+	assert_eq!(summary.unsent_bytes, 0);
+	open_stream.set_read_timeout(Some(std::time::Duration::from_secs(10)))?;
+	let mut buf = vec![];
+	assert_eq!(open_stream.read_to_end(&mut buf)?, 0);
+	Ok(())
+}
+
+#[test]
+fn test_attachment() -> Result<(), Error> {
+	use std::io::Read;
+	use std::io::Write;
+	use std::net::TcpListener;
+	use std::net::TcpStream;
+	use std::sync::Mutex;
+
+	struct Session {
+		reads: Mutex<u8>,
+		dropped: Arc<Mutex<bool>>,
+	}
+
+	impl Drop for Session {
+		fn drop(&mut self) {
+			*self.dropped.lock().unwrap() = true;
+		}
+	}
+
+	let listener = TcpListener::bind("127.0.0.1:9496")?;
+	let mut stream = TcpStream::connect("127.0.0.1:9496")?;
+	let mut eh = EventHandler::new(EventHandlerConfig::default());
+	let dropped = Arc::new(Mutex::new(false));
+	let dropped_clone = dropped.clone();
+	let closed = Arc::new(Mutex::new(false));
+	let closed_clone = closed.clone();
+
+	eh.set_on_read(|_, _, wh| {
+		// wrong type
+		assert!(wh.attachment::<String>()?.is_none());
+		let session = wh.attachment::<Session>()?.unwrap();
+		let mut reads = session.reads.lock().unwrap();
+		*reads += 1;
+		wh.write(&[*reads])?;
+		Ok(())
+	})?;
+	eh.set_on_accept(move |_, wh| {
+		wh.set_attachment(Session {
+			reads: Mutex::new(0),
+			dropped: dropped_clone.clone(),
+		})?;
+		Ok(())
+	})?;
+	eh.set_on_close(move |_| {
+		*closed_clone.lock().unwrap() = true;
+		Ok(())
+	})?;
+	eh.set_on_client_read(|_, _, _| Ok(()))?;
+
+	eh.start()?;
+	eh.add_tcp_listener(&listener)?;
+
+	let mut buf = [0u8; 1];
+	stream.write_all(&[0])?;
+	stream.read_exact(&mut buf)?;
+	assert_eq!(buf, [1]);
+	stream.write_all(&[0])?;
+	stream.read_exact(&mut buf)?;
+	assert_eq!(buf, [2]);
+	assert!(!*dropped.lock().unwrap());
+
+	// the attachment is dropped when the connection closes, right after on_close
+	drop(stream);
+	let start = Instant::now();
+	while !*closed.lock().unwrap() || !*dropped.lock().unwrap() {
+		assert!(start.elapsed() < Duration::from_secs(10));
+		std::thread::sleep(Duration::from_millis(10));
+	}
+	Ok(())
+}
+
+#[test]
+fn test_connection_handler() -> Result<(), Error> {
+	use std::io::Read;
+	use std::io::Write;
+	use std::net::TcpListener;
+	use std::net::TcpStream;
+	use std::net::UdpSocket;
+	use std::sync::Mutex;
+
+	struct Echo {
+		accepted: Mutex<Vec<u128>>,
+		closed: Mutex<Vec<u128>>,
+		connected: Mutex<Vec<u128>>,
+	}
+
+	impl ConnectionHandler for Echo {
+		fn on_accept(&self, connection_id: u128, _wh: WriteHandle) -> Result<(), Error> {
+			self.accepted.lock().unwrap().push(connection_id);
+			Ok(())
+		}
+
+		fn on_read(&self, buf: &[u8], len: usize, wh: WriteHandle) -> Result<(), Error> {
+			wh.write(&buf[0..len])
+		}
+
+		fn on_close(&self, connection_id: u128) -> Result<(), Error> {
+			self.closed.lock().unwrap().push(connection_id);
+			Ok(())
+		}
+
+		fn on_datagram(
+			&self,
+			buf: &[u8],
+			len: usize,
+			peer: SocketAddr,
+			dh: DatagramHandle,
+		) -> Result<(), Error> {
+			dh.send_to(&buf[0..len], peer)?;
+			Ok(())
+		}
+
+		fn on_connect(
+			&self,
+			connection_id: u128,
+			wh: Result<WriteHandle, Error>,
+		) -> Result<(), Error> {
+			wh?;
+			self.connected.lock().unwrap().push(connection_id);
+			Ok(())
+		}
+	}
+
+	let handler = Arc::new(Echo {
+		accepted: Mutex::new(vec![]),
+		closed: Mutex::new(vec![]),
+		connected: Mutex::new(vec![]),
+	});
+	let listener = TcpListener::bind("127.0.0.1:9497")?;
+	let mut stream = TcpStream::connect("127.0.0.1:9497")?;
+	let mut eh = EventHandler::with_handler(EventHandlerConfig::default(), handler.clone())?;
+	eh.start()?;
+	eh.add_tcp_listener(&listener)?;
+
+	let mut buf = [0u8; 3];
+	stream.write_all(&[1, 2, 3])?;
+	stream.read_exact(&mut buf)?;
+	assert_eq!(buf, [1, 2, 3]);
+
+	drop(stream);
+	loop {
+		if !handler.closed.lock().unwrap().is_empty() {
+			break;
+		}
+		std::thread::sleep(std::time::Duration::from_millis(10));
+	}
+	assert_eq!(
+		*handler.accepted.lock().unwrap(),
+		*handler.closed.lock().unwrap()
+	);
+
+	let server = UdpSocket::bind("127.0.0.1:0")?;
+	let client = UdpSocket::bind("127.0.0.1:0")?;
+	client.set_read_timeout(Some(std::time::Duration::from_millis(5000)))?;
+	eh.add_udp_socket(&server)?;
+	client.send_to(&[4, 5, 6], server.local_addr()?)?;
+	let mut dbuf = [0u8; 100];
+	let (len, _) = client.recv_from(&mut dbuf)?;
+	assert_eq!(&dbuf[0..len], [4, 5, 6]);
+
+	#[cfg(unix)]
+	{
+		let connection_id = eh.connect(
+			listener.local_addr()?,
+			std::time::Duration::from_millis(5000),
+		)?;
+		loop {
+			if !handler.connected.lock().unwrap().is_empty() {
+				break;
+			}
+			std::thread::sleep(std::time::Duration::from_millis(10));
+		}
+		assert_eq!(*handler.connected.lock().unwrap(), vec![connection_id]);
 	}
+	Ok(())
+}
 
-	#[cfg(target_os = "windows")]
-	fn remove_handle(
-		selector: SelectorHandle,
-		connection_handle: ConnectionHandle,
-		filter_set: &mut HashSet<ConnectionHandle>,
-	) -> Result<(), Error> {
-		filter_set.remove(&connection_handle);
-		let data = epoll_data_t {
-			fd: connection_handle.try_into().unwrap_or(0),
-		};
-		let mut event = epoll_event {
-			events: EPOLLIN | EPOLLOUT | EPOLLRDHUP,
-			data,
-		};
+#[test]
+fn test_write_owned() -> Result<(), Error> {
+	use std::io::Read;
+	use std::io::Write;
+	use std::net::TcpListener;
+	use std::net::TcpStream;
 
-		let res = unsafe {
-			epoll_ctl(
-				selector,
-				EPOLL_CTL_DEL.try_into().unwrap(),
-				connection_handle.try_into().unwrap_or(0),
-				&mut event,
-			)
-		};
+	let listener = TcpListener::bind("127.0.0.1:9498")?;
+	let mut stream = TcpStream::connect("127.0.0.1:9498")?;
+	let mut eh = EventHandler::new(EventHandlerConfig {
+		buffer_pool_size: 4,
+		..EventHandlerConfig::default()
+	});
 
-		if res != 0 {
-			mainlogerror!(
-				"Error epoll_ctl4: {}, fd={}, delete",
-				errno(),
-				connection_handle
-			);
-		}
+	eh.set_on_read(|buf, _, wh| {
+		let round = buf[0];
+		wh.write(&[round; 100_000])?;
+		wh.write_owned(vec![round + 1; 1_000_000])?;
+		wh.write_owned(format!("abc{}", round))?;
 		Ok(())
-	}
+	})?;
+	eh.set_on_accept(|_, _| Ok(()))?;
+	eh.set_on_close(|_| Ok(()))?;
+	eh.set_on_client_read(|_, _, _| Ok(()))?;
 
-	#[cfg(target_os = "linux")]
-	fn remove_handle(
-		selector: SelectorHandle,
-		connection_handle: ConnectionHandle,
-		filter_set: &mut HashSet<ConnectionHandle>,
-	) -> Result<(), Error> {
-		filter_set.remove(&connection_handle);
-		let interest = EpollFlags::empty();
-
-		let mut event = EpollEvent::new(interest, connection_handle.try_into().unwrap_or(0));
-		let res = epoll_ctl(
-			selector,
-			EpollOp::EpollCtlDel,
-			connection_handle,
-			&mut event,
-		);
-		match res {
-			Ok(_) => {}
-			Err(e) => mainlogerror!("Error epoll_ctl4: {}, fd={}, delete", e, connection_handle),
-		}
+	eh.start()?;
+	eh.add_tcp_listener(&listener)?;
 
-		Ok(())
+	// the later rounds are copied into the buffers recycled by the earlier ones
+	for round in 1..4u8 {
+		stream.write_all(&[round])?;
+		let mut buf = vec![0u8; 1_100_004];
+		stream.read_exact(&mut buf)?;
+		assert_eq!(buf[0..100_000], [round; 100_000]);
+		assert_eq!(buf[100_000..1_100_000], vec![round + 1; 1_000_000][..]);
+		assert_eq!(&buf[1_100_000..], format!("abc{}", round).as_bytes());
 	}
+	Ok(())
+}
 
-	#[cfg(any(target_os = "macos", dragonfly, freebsd, netbsd, openbsd))]
-	fn remove_handle(
-		selector: SelectorHandle,
-		connection_handle: ConnectionHandle,
-		_filter_set: &mut HashSet<ConnectionHandle>,
-	) -> Result<(), Error> {
-		let mut kevs = vec![];
-		kevs.push(kevent::new(
-			connection_handle as uintptr_t,
-			EventFilter::EVFILT_READ,
-			EventFlag::EV_DELETE,
-			FilterFlag::empty(),
-		));
+#[test]
+fn test_write_vectored() -> Result<(), Error> {
+	use std::io::Read;
+	use std::io::Write;
+	use std::net::TcpListener;
+	use std::net::TcpStream;
 
-		let mut ret_kevs = vec![];
-		let ret_count = unsafe {
-			kevent(
-				selector,
-				kevs.as_ptr(),
-				kevs.len() as i32,
-				ret_kevs.as_mut_ptr(),
-				0,
-				&duration_to_timespec(std::time::Duration::from_millis(0)),
-			)
-		};
+	let listener = TcpListener::bind("127.0.0.1:9499")?;
+	let mut stream = TcpStream::connect("127.0.0.1:9499")?;
+	let mut eh = EventHandler::new(EventHandlerConfig::default());
 
-		if ret_count < 0 {
-			mainlogerror!(
-				"Error in kevent (remove handle): kevs={:?}, error={}",
-				kevs,
-				errno()
-			);
-		}
+	eh.set_on_read(|_, _, wh| {
+		let header = b"header";
+		let body = vec![7u8; 50_000];
+		let trailer = b"trailer";
+		wh.write_vectored(&[
+			IoSlice::new(header),
+			IoSlice::new(&body),
+			IoSlice::new(&[]),
+			IoSlice::new(trailer),
+		])?;
+		wh.write(b"end")?;
 		Ok(())
-	}
+	})?;
+	eh.set_on_accept(|_, _| Ok(()))?;
+	eh.set_on_close(|_| Ok(()))?;
+	eh.set_on_client_read(|_, _, _| Ok(()))?;
 
-	#[cfg(target_os = "windows")]
-	fn get_events(
-		selector: SelectorHandle,
-		input_events: Vec<GenericEvent>,
-		output_events: &mut Vec<GenericEvent>,
-		filter_set: &mut HashSet<ConnectionHandle>,
-		_wakeup: bool,
-	) -> Result<usize, Error> {
-		for evt in input_events {
-			if evt.etype == GenericEventType::AddReadET
-				|| evt.etype == GenericEventType::AddReadET
-				|| evt.etype == GenericEventType::DelWrite
-				|| evt.etype == GenericEventType::AddReadLT
-			{
-				let op = if filter_set.remove(&evt.fd) {
-					EPOLL_CTL_MOD
-				} else {
-					EPOLL_CTL_ADD
-				};
-				filter_set.insert(evt.fd);
-				let data = epoll_data_t {
-					fd: evt.fd.try_into().unwrap_or(0),
-				};
-				let mut event = epoll_event {
-					events: EPOLLIN | EPOLLRDHUP,
-					data,
-				};
-				let res = unsafe {
-					epoll_ctl(
-						selector,
-						op.try_into().unwrap_or(0),
-						evt.fd as usize,
-						&mut event,
-					)
-				};
-				if res != 0 {
-					// normal occurance, just means the socket is already closed
-					// must remove from filter set for next request
-					filter_set.remove(&evt.fd);
-				}
-			} else if evt.etype == GenericEventType::AddWriteET {
-				let op = if filter_set.remove(&evt.fd) {
-					EPOLL_CTL_MOD
-				} else {
-					EPOLL_CTL_ADD
-				};
-				filter_set.insert(evt.fd);
-				let data = epoll_data_t {
-					fd: evt.fd.try_into().unwrap_or(0),
-				};
-				let mut event = epoll_event {
-					events: EPOLLIN | EPOLLOUT | EPOLLRDHUP,
-					data,
-				};
-				let res = unsafe {
-					epoll_ctl(
-						selector,
-						op.try_into().unwrap_or(0),
-						evt.fd.try_into().unwrap_or(0),
-						&mut event,
-					)
-				};
-				if res != 0 {
-					filter_set.remove(&evt.fd);
-					mainlogerror!(
-						"epoll_ctl (write) resulted in an unexpected error: {}, fd={}, op={}, epoll_ctl_add={}",
-						errno().to_string(), evt.fd, op, EPOLL_CTL_ADD,
-					);
-				}
-			} else if evt.etype == GenericEventType::DelRead {
-				filter_set.remove(&evt.fd);
-				let data = epoll_data_t {
-					fd: evt.fd.try_into().unwrap_or(0),
-				};
-				let mut event = epoll_event {
-					events: 0, // not used for del
-					data,
-				};
+	eh.start()?;
+	eh.add_tcp_listener(&listener)?;
 
-				let res = unsafe {
-					epoll_ctl(
-						selector,
-						EPOLL_CTL_DEL.try_into().unwrap_or(0),
-						evt.fd.try_into().unwrap_or(0),
-						&mut event,
-					)
-				};
+	stream.write_all(&[0])?;
+	let mut buf = vec![0u8; 50_016];
+	stream.read_exact(&mut buf)?;
+	assert_eq!(&buf[0..6], b"header");
+	assert_eq!(buf[6..50_006], vec![7u8; 50_000][..]);
+	assert_eq!(&buf[50_006..50_013], b"trailer");
+	assert_eq!(&buf[50_013..], b"end");
+	Ok(())
+}
 
-				if res != 0 {
-					mainlogerror!(
-						"epoll_ctl (del) resulted in unexpected error: {}",
-						errno().to_string(),
-					);
-				}
-			} else {
-				return Err(
-					ErrorKind::InternalError(format!("unexpected etype: {:?}", evt.etype)).into(),
-				);
-			}
-		}
-		let mut events: [epoll_event; MAX_EVENTS as usize] =
-			unsafe { std::mem::MaybeUninit::uninit().assume_init() };
-		let results = unsafe { epoll_wait(selector, events.as_mut_ptr(), MAX_EVENTS, 3000) };
-		let mut ret_count_adjusted = 0;
-
-		if results > 0 {
-			for i in 0..results {
-				if !(events[i as usize].events & EPOLLOUT == 0) {
-					ret_count_adjusted += 1;
-					output_events.push(GenericEvent::new(
-						unsafe { events[i as usize].data.fd } as ConnectionHandle,
-						GenericEventType::AddWriteET,
-					));
-				}
-				if !(events[i as usize].events & EPOLLIN == 0) {
-					ret_count_adjusted += 1;
-					output_events.push(GenericEvent::new(
-						unsafe { events[i as usize].data.fd } as ConnectionHandle,
-						GenericEventType::AddReadET,
-					));
-				}
-				if events[i as usize].events & (EPOLLIN | EPOLLOUT) == 0 {
-					let fd = unsafe { events[i as usize].data.fd };
-					let data = epoll_data_t {
-						fd: fd.try_into().unwrap_or(0),
-					};
-					let mut event = epoll_event {
-						events: 0, // not used for del
-						data,
-					};
-					let res = unsafe {
-						epoll_ctl(
-							selector,
-							EPOLL_CTL_DEL.try_into().unwrap_or(0),
-							fd.try_into().unwrap_or(0),
-							&mut event,
-						)
-					};
+#[test]
+fn test_send_file() -> Result<(), Error> {
+	use std::io::Read;
+	use std::io::Write;
+	use std::net::TcpListener;
+	use std::net::TcpStream;
 
-					if res != 0 {
-						mainlogerror!(
-							"Unexpected error with EPOLLHUP. res = {}, err={}",
-							res,
-							errno().to_string(),
-						);
-					}
-				}
-			}
-		}
+	let path = std::env::temp_dir().join(format!("nioruntime_evh_{}.dat", std::process::id()));
+	let content: Vec<u8> = (0..1_000_000).map(|i| (i % 251) as u8).collect();
+	std::fs::write(&path, &content)?;
 
-		Ok(ret_count_adjusted)
-	}
+	let listener = TcpListener::bind("127.0.0.1:9500")?;
+	let mut stream = TcpStream::connect("127.0.0.1:9500")?;
+	let mut eh = EventHandler::new(EventHandlerConfig::default());
 
-	#[cfg(target_os = "linux")]
-	fn get_events(
-		selector: SelectorHandle,
-		input_events: Vec<GenericEvent>,
-		output_events: &mut Vec<GenericEvent>,
-		filter_set: &mut HashSet<ConnectionHandle>,
-		wakeup: bool,
-	) -> Result<usize, Error> {
-		let epollfd = selector;
-		for evt in input_events {
-			let mut interest = EpollFlags::empty();
+	let file_path = path.clone();
+	eh.set_on_read(move |_, _, wh| {
+		wh.write(b"start")?;
+		wh.send_file(File::open(&file_path)?, 100, 900_000)?;
+		wh.write(b"end")?;
+		// a region past the end of the file closes the connection once it is reached
+		wh.send_file(File::open(&file_path)?, 999_990, 20)?;
+		Ok(())
+	})?;
+	eh.set_on_accept(|_, _| Ok(()))?;
+	eh.set_on_close(|_| Ok(()))?;
+	eh.set_on_client_read(|_, _, _| Ok(()))?;
 
-			if evt.etype == GenericEventType::AddReadLT {
-				let fd = evt.fd;
-				interest |= EpollFlags::EPOLLIN;
-				interest |= EpollFlags::EPOLLRDHUP;
+	eh.start()?;
+	eh.add_tcp_listener(&listener)?;
 
-				let op = if filter_set.remove(&fd) {
-					EpollOp::EpollCtlMod
-				} else {
-					EpollOp::EpollCtlAdd
-				};
-				filter_set.insert(fd);
+	stream.write_all(&[0])?;
+	let mut buf = vec![];
+	stream.read_to_end(&mut buf)?;
+	std::fs::remove_file(&path)?;
+	assert_eq!(buf.len(), 900_018);
+	assert_eq!(&buf[0..5], b"start");
+	assert_eq!(buf[5..900_005], content[100..900_100]);
+	assert_eq!(&buf[900_005..900_008], b"end");
+	assert_eq!(buf[900_008..], content[999_990..]);
+	Ok(())
+}
 
-				let mut event = EpollEvent::new(interest, evt.fd.try_into().unwrap_or(0));
-				let res = epoll_ctl(epollfd, op, evt.fd, &mut event);
-				match res {
-					Ok(_) => {}
-					Err(e) => mainlogerror!("Error epoll_ctl1: {}, fd={}, op={:?}", e, fd, op),
-				}
-			} else if evt.etype == GenericEventType::AddReadET {
-				let fd = evt.fd;
-				interest |= EpollFlags::EPOLLIN;
-				interest |= EpollFlags::EPOLLET;
-				interest |= EpollFlags::EPOLLRDHUP;
+#[test]
+fn test_send_file_tls() -> Result<(), Error> {
+	use std::io::Read;
+	use std::io::Write;
+	use std::net::TcpListener;
+	use std::net::TcpStream;
 
-				let op = if filter_set.remove(&fd) {
-					EpollOp::EpollCtlMod
-				} else {
-					EpollOp::EpollCtlAdd
-				};
-				filter_set.insert(fd);
+	let path = std::env::temp_dir().join(format!("nioruntime_evh_tls_{}.dat", std::process::id()));
+	let content: Vec<u8> = (0..1_000_000).map(|i| (i % 251) as u8).collect();
+	std::fs::write(&path, &content)?;
 
-				let mut event = EpollEvent::new(interest, evt.fd.try_into().unwrap_or(0));
-				let res = epoll_ctl(epollfd, op, evt.fd, &mut event);
-				match res {
-					Ok(_) => {}
-					Err(e) => mainlogerror!("Error epoll_ctl2: {}, fd={}, op={:?}", e, fd, op),
-				}
-			} else if evt.etype == GenericEventType::AddWriteET {
-				let fd = evt.fd;
-				interest |= EpollFlags::EPOLLOUT;
-				interest |= EpollFlags::EPOLLIN;
-				interest |= EpollFlags::EPOLLRDHUP;
-				interest |= EpollFlags::EPOLLET;
+	let listener = TcpListener::bind("127.0.0.1:9501")?;
+	let mut eh = EventHandler::new(EventHandlerConfig {
+		tls_config: Some(TlsConfig {
+			certificates_file: "./src/resources/cert.pem".to_string(),
+			private_key_file: "./src/resources/key.pem".to_string(),
+			..TlsConfig::default()
+		}),
+		..EventHandlerConfig::default()
+	});
 
-				let op = if filter_set.remove(&fd) {
-					EpollOp::EpollCtlMod
-				} else {
-					EpollOp::EpollCtlAdd
-				};
-				filter_set.insert(fd);
+	// the file is encrypted in chunks by the write loop, in order with the other writes
+	let file_path = path.clone();
+	eh.set_on_read(move |_, _, wh| {
+		wh.write(b"start")?;
+		wh.send_file(File::open(&file_path)?, 100, 900_000)?;
+		wh.write(b"end")?;
+		wh.send_file(File::open(&file_path)?, 999_990, 20)?;
+		Ok(())
+	})?;
+	eh.set_on_accept(|_, _| Ok(()))?;
+	eh.set_on_close(|_| Ok(()))?;
+	eh.set_on_client_read(|_, _, _| Ok(()))?;
 
-				let mut event = EpollEvent::new(interest, evt.fd.try_into().unwrap_or(0));
-				let res = epoll_ctl(epollfd, op, evt.fd, &mut event);
-				match res {
-					Ok(_) => {}
-					Err(e) => mainlogerror!("Error epoll_ctl3: {}, fd={}, op={:?}", e, fd, op),
-				}
-			} else {
-				return Err(
-					ErrorKind::InternalError(format!("unexpected etype: {:?}", evt.etype)).into(),
-				);
-			}
-		}
+	eh.start()?;
+	eh.add_tcp_listener(&listener)?;
 
-		let empty_event = EpollEvent::new(EpollFlags::empty(), 0);
-		let mut events = [empty_event; MAX_EVENTS as usize];
-		let results = epoll_wait(
-			epollfd,
-			&mut events,
-			match wakeup {
-				true => 0,
-				false => 3000,
-			},
-		);
-
-		let mut ret_count_adjusted = output_events.len();
-
-		match results {
-			Ok(results) => {
-				if results > 0 {
-					for i in 0..results {
-						if !(events[i].events() & EpollFlags::EPOLLOUT).is_empty() {
-							ret_count_adjusted += 1;
-							output_events.push(GenericEvent::new(
-								events[i].data() as ConnectionHandle,
-								GenericEventType::AddWriteET,
-							));
-						}
-						if !(events[i].events() & EpollFlags::EPOLLIN).is_empty() {
-							ret_count_adjusted += 1;
-							output_events.push(GenericEvent::new(
-								events[i].data() as ConnectionHandle,
-								GenericEventType::AddReadET,
-							));
-						}
+	let config = make_config(&TlsClientConfig {
+		trusted_certificate: Some("./src/resources/cert.pem".to_string()),
+		..TlsClientConfig::default()
+	})?;
+	let tls_conn = ClientConnection::new(config, "localhost".try_into().unwrap()).unwrap();
+	let mut stream = rustls::StreamOwned::new(tls_conn, TcpStream::connect("127.0.0.1:9501")?);
+	stream.write_all(&[0])?;
+	let mut buf = vec![0u8; 900_018];
+	stream.read_exact(&mut buf)?;
+	std::fs::remove_file(&path)?;
+	assert_eq!(&buf[0..5], b"start");
+	assert_eq!(buf[5..900_005], content[100..900_100]);
+	assert_eq!(&buf[900_005..900_008], b"end");
+	assert_eq!(buf[900_008..], content[999_990..]);
+	// the connection is closed once the end of the file is reached
+	assert!(!matches!(stream.read(&mut [0u8; 1]), Ok(n) if n > 0));
+	Ok(())
+}
+
+#[test]
+fn test_read_config() -> Result<(), Error> {
+	use std::io::Read;
+	use std::io::Write;
+	use std::net::TcpListener;
+	use std::net::TcpStream;
+	use std::sync::Mutex;
+
+	for (port, trigger_mode) in [(9501, TriggerMode::Edge), (9502, TriggerMode::Level)] {
+		let listener = TcpListener::bind(format!("127.0.0.1:{}", port))?;
+		let mut busy = TcpStream::connect(format!("127.0.0.1:{}", port))?;
+		let mut quiet = TcpStream::connect(format!("127.0.0.1:{}", port))?;
+		let mut eh = EventHandler::new(EventHandlerConfig {
+			thread_count: 1,
+			read_buffer_size: 100,
+			max_events: 1,
+			read_trigger_mode: trigger_mode,
+			max_read_per_poll: Some(1_000),
+			..EventHandlerConfig::default()
+		});
+
+		let received = Arc::new(Mutex::new(vec![]));
+		let received_clone = received.clone();
+		eh.set_on_read(move |buf, len, wh| {
+			assert!(len <= 100);
+			match buf[0..len].iter().position(|b| *b == b'q') {
+				Some(_) => wh.write(b"q")?,
+				None => {
+					let mut received = received_clone.lock().unwrap();
+					received.extend_from_slice(&buf[0..len]);
+					if received.len() == 1_000_000 {
+						wh.write(b"done")?;
 					}
 				}
 			}
-			Err(e) => {
-				mainlogerror!("Error with epoll wait = {}", e.to_string());
-			}
-		}
-		Ok(ret_count_adjusted)
+			Ok(())
+		})?;
+		eh.set_on_accept(|_, _| Ok(()))?;
+		eh.set_on_close(|_| Ok(()))?;
+		eh.set_on_client_read(|_, _, _| Ok(()))?;
+
+		eh.start()?;
+		eh.add_tcp_listener(&listener)?;
+
+		let content: Vec<u8> = (0..1_000_000).map(|i| (i % 100) as u8).collect();
+		let content_clone = content.clone();
+		let writer = std::thread::spawn(move || busy.write_all(&content_clone).map(|_| busy));
+
+		// the quiet connection is served while the busy one is being read
+		quiet.write_all(b"q")?;
+		let mut buf = [0u8; 1];
+		quiet.read_exact(&mut buf)?;
+		assert_eq!(&buf, b"q");
+
+		let mut busy = writer.join().unwrap()?;
+		let mut buf = [0u8; 4];
+		busy.read_exact(&mut buf)?;
+		assert_eq!(&buf, b"done");
+		assert_eq!(*received.lock().unwrap(), content);
 	}
+	Ok(())
+}
 
-	#[cfg(any(target_os = "macos", dragonfly, freebsd, netbsd, openbsd))]
-	fn get_events(
-		selector: SelectorHandle,
-		input_events: Vec<GenericEvent>,
-		output_events: &mut Vec<GenericEvent>,
-		_filter_set: &HashSet<ConnectionHandle>,
-		wakeup: bool,
-	) -> Result<usize, Error> {
-		let queue = selector;
-		let mut kevs = vec![];
-		for ev in input_events {
-			kevs.push(ev.to_kev());
-		}
-
-		let mut ret_kevs = vec![];
-		for _ in 0..MAX_EVENTS {
-			ret_kevs.push(kevent::new(
-				0,
-				EventFilter::EVFILT_SYSCOUNT,
-				EventFlag::empty(),
-				FilterFlag::empty(),
-			));
-		}
-		let ret_count = unsafe {
-			kevent(
-				queue,
-				kevs.as_ptr(),
-				kevs.len() as i32,
-				ret_kevs.as_mut_ptr(),
-				MAX_EVENTS,
-				&duration_to_timespec(std::time::Duration::from_millis(match wakeup {
-					true => 1,
-					false => 3000,
-				})),
-			)
-		};
-
-		if ret_count < 0 {
-			mainlogerror!("Error in kevent: kevs={:?}, error={}", kevs, errno());
-		}
+#[cfg(unix)]
+#[test]
+fn test_mock_selector() -> Result<(), Error> {
+	use crate::selector::MockNetwork;
+	use std::sync::Mutex;
 
-		let mut ret_count_adjusted = output_events.len();
-		for i in 0..ret_count {
-			let kev = ret_kevs[i as usize];
-			if !kev.flags.contains(EventFlag::EV_DELETE) {
-				if kev.filter == EVFILT_WRITE {
-					ret_count_adjusted += 1;
-					output_events.push(GenericEvent::new(
-						kev.ident.try_into().unwrap_or(0),
-						GenericEventType::AddWriteET,
-					));
-				}
-				if kev.filter == EVFILT_READ {
-					ret_count_adjusted += 1;
-					output_events.push(GenericEvent::new(
-						kev.ident.try_into().unwrap_or(0),
-						GenericEventType::AddReadLT,
-					));
-				}
-			}
-		}
+	let network = MockNetwork::new();
+	let mut eh = EventHandler::new(EventHandlerConfig {
+		thread_count: 1,
+		..EventHandlerConfig::default()
+	});
 
-		Ok(ret_count_adjusted)
+	let accepted = Arc::new(Mutex::new(vec![]));
+	let accepted_clone = accepted.clone();
+	let closed = Arc::new(Mutex::new(vec![]));
+	let closed_clone = closed.clone();
+	eh.set_on_read(|buf, len, wh| {
+		wh.write(&buf[0..len])?;
+		Ok(())
+	})?;
+	eh.set_on_accept(move |connection_id, _| {
+		accepted_clone.lock().unwrap().push(connection_id);
+		Ok(())
+	})?;
+	eh.set_on_close(move |connection_id| {
+		closed_clone.lock().unwrap().push(connection_id);
+		Ok(())
+	})?;
+	eh.set_on_client_read(|_, _, _| Ok(()))?;
+	eh.start_with_selectors(vec![network.selector(), network.selector()])?;
+
+	let listener = network.socket()?;
+	eh.add(listener, ActionType::AddListener, None, 0)?;
+	let handle = network.connect(listener)?;
+	network.push_data(handle, b"hello")?;
+	network.push_data(handle, b" world")?;
+
+	let start = Instant::now();
+	while network.written(handle)? != b"hello world" {
+		assert!(start.elapsed() < Duration::from_secs(10));
+		std::thread::sleep(Duration::from_millis(1));
 	}
-}
 
-#[derive(Clone, Debug)]
-enum ConnectionType {
-	Outbound,
-	Inbound,
-	Listener,
-}
+	network.push_eof(handle)?;
+	while !network.is_closed(handle)? {
+		assert!(start.elapsed() < Duration::from_secs(10));
+		std::thread::sleep(Duration::from_millis(1));
+	}
+	assert_eq!(accepted.lock().unwrap().len(), 1);
+	assert_eq!(*accepted.lock().unwrap(), *closed.lock().unwrap());
 
-#[derive(Clone, Debug)]
-struct ConnectionInfo {
-	handle: ConnectionHandle,
-	connection_id: u128,
-	ctype: ConnectionType,
-	sender: Option<SyncSender<()>>,
-	tls_server: Option<Arc<RwLock<ServerConnection>>>,
-	tls_client: Option<Arc<RwLock<ClientConnection>>>,
+	eh.stop()?;
+	Ok(())
 }
 
-struct GuardedData {
-	nconns: Vec<ConnectionInfo>,
-	cconns: Vec<ConnectionInfo>,
-	aconns: Vec<ConnectionInfo>,
-	write_queue: Vec<WriteBuffer>,
-	wakeup_tx: ConnectionHandle,
-	wakeup_rx: ConnectionHandle,
-	wakeup_scheduled: bool,
-	stop: bool,
-	callback_state: Arc<RwLock<State>>,
-}
+#[cfg(unix)]
+#[test]
+fn test_mock_selector_partial_writes() -> Result<(), Error> {
+	use crate::selector::MockNetwork;
+	use std::sync::atomic::{AtomicBool, Ordering};
 
-impl GuardedData {
-	fn wakeup(&mut self) -> Result<(), Error> {
-		if !self.wakeup_scheduled {
-			let res = write_bytes(self.wakeup_tx, &mut [0u8; 1])?;
-			if res <= 0 {
-				log_multi!(
-					ERROR,
-					MAIN_LOG,
-					"Error writing to wakup tx {}, error={}, wakeup={}",
-					res,
-					errno().to_string(),
-					self.wakeup_tx,
-				);
-			}
-			self.wakeup_scheduled = true;
-		}
+	let network = MockNetwork::new();
+	let mut eh = EventHandler::new(EventHandlerConfig {
+		thread_count: 1,
+		..EventHandlerConfig::default()
+	});
+	let closed = Arc::new(AtomicBool::new(false));
+	let closed_clone = closed.clone();
+	eh.set_on_read(|_, _, _| Ok(()))?;
+	eh.set_on_accept(|_, _| Ok(()))?;
+	eh.set_on_close(move |_| {
+		closed_clone.store(true, Ordering::SeqCst);
 		Ok(())
+	})?;
+	eh.set_on_client_read(|_, _, _| Ok(()))?;
+	eh.start_with_selectors(vec![network.selector(), network.selector()])?;
+
+	let handle = network.socket()?;
+	network.script_writes(handle, &[Some(3), None, Some(2), None])?;
+	let wh = eh.add_client_stream(handle)?;
+	wh.write(b"0123456789")?;
+
+	let start = Instant::now();
+	while network.written(handle)? != b"0123456789" {
+		assert!(start.elapsed() < Duration::from_secs(10));
+		std::thread::sleep(Duration::from_millis(1));
 	}
-}
-
-#[derive(Debug, Clone)]
-pub(crate) struct WriteBuffer {
-	len: u16,
-	offset: u16,
-	buffer: [u8; BUFFER_SIZE],
-	close: bool,
-	connection_id: u128,
-}
+	// each EAGAIN waits for the next write event and the rest is written in one call
+	assert_eq!(network.writes(handle)?, vec![3, -1, 2, -1, 5]);
+
+	// a close request is processed after the queued data
+	network.script_writes(handle, &[Some(1), None])?;
+	wh.write(b"abc")?;
+	wh.close()?;
+	while !closed.load(Ordering::SeqCst) {
+		assert!(start.elapsed() < Duration::from_secs(10));
+		std::thread::sleep(Duration::from_millis(1));
+	}
+	assert_eq!(network.written(handle)?, b"0123456789abc");
+	// the stream was added by the user so it is not closed by the EventHandler
+	assert!(!network.is_closed(handle)?);
 
-struct Callbacks<F, G, H, K> {
-	on_read: Option<Pin<Box<F>>>,
-	on_accept: Option<Pin<Box<G>>>,
-	on_close: Option<Pin<Box<H>>>,
-	on_client_read: Option<Pin<Box<K>>>,
+	eh.stop()?;
+	Ok(())
 }
 
-#[derive(Debug, Clone)]
-pub(crate) struct StreamWriteBuffer {
-	_fd: ConnectionHandle,
-	_write_buffer: WriteBuffer,
-}
+#[cfg(unix)]
+#[test]
+fn test_mock_selector_half_close() -> Result<(), Error> {
+	use crate::selector::MockNetwork;
+	use std::sync::atomic::{AtomicBool, Ordering};
 
-#[derive(Debug, Clone, PartialEq)]
-enum ActionType {
-	AddStream,
-	AddListener,
-	AddTlsStream,
-}
+	let network = MockNetwork::new();
+	let mut eh = EventHandler::new(EventHandlerConfig {
+		thread_count: 1,
+		..EventHandlerConfig::default()
+	});
+	let closed = Arc::new(AtomicBool::new(false));
+	let closed_clone = closed.clone();
+	eh.set_on_read(|buf, len, wh| {
+		wh.write(&buf[0..len])?;
+		Ok(())
+	})?;
+	eh.set_on_read_eof(|wh| {
+		wh.write(b" world")?;
+		wh.shutdown_write()?;
+		Ok(())
+	})?;
+	eh.set_on_accept(|_, _| Ok(()))?;
+	eh.set_on_close(move |_| {
+		closed_clone.store(true, Ordering::SeqCst);
+		Ok(())
+	})?;
+	eh.set_on_client_read(|_, _, _| Ok(()))?;
+	eh.start_with_selectors(vec![network.selector(), network.selector()])?;
+
+	let listener = network.socket()?;
+	eh.add(listener, ActionType::AddListener, None, 0)?;
+	let handle = network.connect(listener)?;
+	network.push_data(handle, b"hello")?;
+	network.push_eof(handle)?;
+
+	// the response is written before the write side is shut down, then the connection is closed
+	// since both sides are shut down
+	let start = Instant::now();
+	while !closed.load(Ordering::SeqCst) {
+		assert!(start.elapsed() < Duration::from_secs(10));
+		std::thread::sleep(Duration::from_millis(1));
+	}
+	assert_eq!(network.written(handle)?, b"hello world");
+	assert!(network.is_write_shutdown(handle)?);
+	assert!(network.is_closed(handle)?);
 
-#[derive(Debug, PartialEq, Clone, Eq, Hash)]
-enum GenericEventType {
-	AddReadET,
-	AddReadLT,
-	AddWriteET,
-	#[cfg(windows)]
-	DelRead,
-	#[cfg(windows)]
-	DelWrite,
+	eh.stop()?;
+	Ok(())
 }
 
-#[derive(Debug, Clone, Hash, Eq, PartialEq)]
-struct GenericEvent {
-	fd: ConnectionHandle,
-	etype: GenericEventType,
-}
+#[cfg(unix)]
+#[test]
+fn test_connection_handler_read_eof() -> Result<(), Error> {
+	use crate::selector::MockNetwork;
+	use std::sync::atomic::{AtomicBool, Ordering};
 
-impl GenericEvent {
-	fn new(fd: ConnectionHandle, etype: GenericEventType) -> Self {
-		GenericEvent { fd, etype }
+	struct HalfClose {
+		closed: AtomicBool,
 	}
 
-	#[cfg(any(target_os = "macos", dragonfly, freebsd, netbsd, openbsd))]
-	fn to_kev(&self) -> kevent {
-		kevent::new(
-			self.fd as uintptr_t,
-			match &self.etype {
-				GenericEventType::AddReadET => EventFilter::EVFILT_READ,
-				GenericEventType::AddReadLT => EventFilter::EVFILT_READ,
-				//GenericEventType::DelRead => EventFilter::EVFILT_READ,
-				GenericEventType::AddWriteET => EventFilter::EVFILT_WRITE,
-				//GenericEventType::DelWrite => EventFilter::EVFILT_WRITE,
-			},
-			match &self.etype {
-				GenericEventType::AddReadET => EventFlag::EV_ADD | EventFlag::EV_CLEAR,
-				GenericEventType::AddReadLT => EventFlag::EV_ADD,
-				//GenericEventType::DelRead => EventFlag::EV_DELETE,
-				GenericEventType::AddWriteET => EventFlag::EV_ADD | EventFlag::EV_CLEAR,
-				//GenericEventType::DelWrite => EventFlag::EV_DELETE,
-			},
-			FilterFlag::empty(),
-		)
-	}
-}
+	impl ConnectionHandler for HalfClose {
+		fn on_read(&self, buf: &[u8], len: usize, wh: WriteHandle) -> Result<(), Error> {
+			wh.write(&buf[0..len])
+		}
 
-fn do_read_bytes(handle: ConnectionHandle, buf: &mut [u8]) -> Result<i32, Error> {
-	#[cfg(unix)]
-	let res = {
-		let cbuf: *mut c_void = buf as *mut _ as *mut c_void;
-		unsafe { read(handle, cbuf, buf.len()) }
-	};
-	#[cfg(target_os = "windows")]
-	let res = {
-		let cbuf: *mut i8 = buf as *mut _ as *mut i8;
-		unsafe {
-			ws2_32::recv(
-				handle.try_into().unwrap_or(0),
-				cbuf,
-				buf.len().try_into().unwrap_or(0),
-				0,
-			)
+		fn on_read_eof(&self, wh: WriteHandle) -> Result<bool, Error> {
+			wh.write(b" world")?;
+			wh.shutdown_write()?;
+			Ok(true)
 		}
-	};
 
-	Ok(res.try_into().unwrap_or(-1))
-}
+		fn on_close(&self, _connection_id: u128) -> Result<(), Error> {
+			self.closed.store(true, Ordering::SeqCst);
+			Ok(())
+		}
+	}
 
-fn write_data(
-	handle: ConnectionHandle,
-	buf: &mut [u8],
-	global_lock: &Arc<RwLock<bool>>,
-) -> Result<isize, Error> {
-	let _lock = nioruntime_util::lockr!(global_lock)?;
-	write_bytes(handle, buf)
-}
+	let network = MockNetwork::new();
+	let handler = Arc::new(HalfClose {
+		closed: AtomicBool::new(false),
+	});
+	let mut eh = EventHandler::with_handler(
+		EventHandlerConfig {
+			thread_count: 1,
+			..EventHandlerConfig::default()
+		},
+		handler.clone(),
+	)?;
+	eh.start_with_selectors(vec![network.selector(), network.selector()])?;
+
+	let listener = network.socket()?;
+	eh.add(listener, ActionType::AddListener, None, 0)?;
+	let handle = network.connect(listener)?;
+	network.push_data(handle, b"hello")?;
+	network.push_eof(handle)?;
+
+	// since the end of the stream was handled, the response is written before the connection
+	// is closed
+	let start = Instant::now();
+	while !handler.closed.load(Ordering::SeqCst) {
+		assert!(start.elapsed() < Duration::from_secs(10));
+		std::thread::sleep(Duration::from_millis(1));
+	}
+	assert_eq!(network.written(handle)?, b"hello world");
+	assert!(network.is_write_shutdown(handle)?);
+	assert!(network.is_closed(handle)?);
 
-fn write_bytes(handle: ConnectionHandle, buf: &mut [u8]) -> Result<isize, Error> {
-	#[cfg(unix)]
-	let len = {
-		let cbuf: *const c_void = buf as *const _ as *const c_void;
-		unsafe { write(handle, cbuf, buf.len().into()) }
-	};
-	#[cfg(target_os = "windows")]
-	let len = {
-		let cbuf: *mut i8 = buf as *mut _ as *mut i8;
-		unsafe {
-			ws2_32::send(
-				handle.try_into().unwrap_or(0),
-				cbuf,
-				(buf.len()).try_into().unwrap_or(0),
-				0,
-			)
-		}
-	};
-	Ok(len.try_into().unwrap_or(0))
+	eh.stop()?;
+	Ok(())
 }
 
 #[cfg(unix)]
-type SelectorHandle = i32;
-#[cfg(target_os = "windows")]
-type SelectorHandle = *mut c_void;
-
 #[test]
-fn test_echo() -> Result<(), Error> {
-	use std::net::TcpListener;
-	use std::net::TcpStream;
+fn test_connection_limits() -> Result<(), Error> {
+	use crate::selector::MockNetwork;
 	use std::sync::Mutex;
 
-	let x = Arc::new(Mutex::new(0));
-	let x_clone = x.clone();
-
-	let listener = TcpListener::bind("127.0.0.1:9981")?;
-	let stream = TcpStream::connect("127.0.0.1:9981")?;
-	let mut eh = EventHandler::new(EventHandlerConfig::default());
+	let network = MockNetwork::new();
+	let mut eh = EventHandler::new(EventHandlerConfig {
+		thread_count: 1,
+		max_connections: Some(2),
+		max_connections_per_ip: Some(1),
+		..EventHandlerConfig::default()
+	});
 
-	// echo
-	eh.set_on_read(|buf, len, wh| {
-		info!("server received: {} bytes", len);
-		let _ = wh.write(&buf[0..len]);
+	let accepted = Arc::new(Mutex::new(0));
+	let accepted_clone = accepted.clone();
+	let rejected = Arc::new(Mutex::new(vec![]));
+	let rejected_clone = rejected.clone();
+	eh.set_on_read(|_, _, _| Ok(()))?;
+	eh.set_on_accept(move |_, _| {
+		*accepted_clone.lock().unwrap() += 1;
 		Ok(())
 	})?;
-
-	eh.set_on_accept(|_, _| Ok(()))?;
 	eh.set_on_close(|_| Ok(()))?;
-	let client_buf = Arc::new(Mutex::new(vec![]));
-	eh.set_on_client_read(move |buf, len, _wh| {
-		info!("client received: {} bytes", len);
-		let mut client_buf = client_buf.lock().unwrap();
-		for i in 0..len {
-			client_buf.push(buf[i]);
-		}
-		let len = client_buf.len();
-		if len == 5 {
-			assert_eq!(len, 5);
-			assert_eq!(client_buf[0], 1);
-			assert_eq!(client_buf[1], 2);
-			assert_eq!(client_buf[2], 3);
-			assert_eq!(client_buf[3], 4);
-			assert_eq!(client_buf[4], 5);
-			let mut x = x.lock().unwrap();
-			(*x) += 5;
-		}
+	eh.set_on_client_read(|_, _, _| Ok(()))?;
+	eh.set_on_reject(move |peer_addr, reason| {
+		rejected_clone.lock().unwrap().push((peer_addr, reason));
 		Ok(())
 	})?;
-	eh.start()?;
+	eh.start_with_selectors(vec![network.selector(), network.selector()])?;
+
+	let listener = network.socket()?;
+	eh.add(listener, ActionType::AddListener, None, 0)?;
+	let addrs: Vec<SocketAddr> = vec![
+		"10.0.0.1:1000".parse().unwrap(),
+		"10.0.0.1:1001".parse().unwrap(),
+		"10.0.0.2:1000".parse().unwrap(),
+		"10.0.0.3:1000".parse().unwrap(),
+	];
+	let mut handles = vec![];
+	for addr in &addrs {
+		handles.push(network.connect_from(listener, *addr)?);
+	}
 
-	eh.add_tcp_listener(&listener)?;
-	let wh = eh.add_tcp_stream(&stream)?;
-	// TODO: would be nice to eliminate this sleep
-	wh.write(&[1, 2, 3, 4, 5])?;
-	loop {
-		{
-			let x = x_clone.lock().unwrap();
-			if *x != 0 {
-				assert_eq!(*x, 5);
-				break;
-			}
-		}
-		std::thread::sleep(std::time::Duration::from_millis(10));
+	let start = Instant::now();
+	while rejected.lock().unwrap().len() < 2 || *accepted.lock().unwrap() < 2 {
+		assert!(start.elapsed() < Duration::from_secs(10));
+		std::thread::sleep(Duration::from_millis(1));
+	}
+	assert_eq!(
+		*rejected.lock().unwrap(),
+		vec![
+			(Some(addrs[1]), RejectReason::MaxConnectionsPerIp),
+			(Some(addrs[3]), RejectReason::MaxConnections),
+		]
+	);
+	assert!(!network.is_closed(handles[0])?);
+	assert!(network.is_closed(handles[1])?);
+	assert!(!network.is_closed(handles[2])?);
+	assert!(network.is_closed(handles[3])?);
+
+	// once a connection closes, its peer may connect again
+	network.push_eof(handles[0])?;
+	while !network.is_closed(handles[0])? {
+		assert!(start.elapsed() < Duration::from_secs(10));
+		std::thread::sleep(Duration::from_millis(1));
+	}
+	network.connect_from(listener, addrs[1])?;
+	while *accepted.lock().unwrap() < 3 {
+		assert!(start.elapsed() < Duration::from_secs(10));
+		std::thread::sleep(Duration::from_millis(1));
 	}
+	assert_eq!(rejected.lock().unwrap().len(), 2);
+
+	eh.stop()?;
 	Ok(())
 }
 
+#[cfg(unix)]
 #[test]
-fn test_close() -> Result<(), Error> {
-	use std::io::Read;
-	use std::io::Write;
-	use std::net::TcpListener;
-	use std::net::TcpStream;
+fn test_accept_rate() -> Result<(), Error> {
+	use crate::selector::MockNetwork;
 	use std::sync::Mutex;
 
-	let listener = TcpListener::bind("127.0.0.1:9982")?;
-	let mut stream = TcpStream::connect("127.0.0.1:9982")?;
-	let mut eh = EventHandler::new(EventHandlerConfig::default());
+	let network = MockNetwork::new();
+	let mut eh = EventHandler::new(EventHandlerConfig {
+		thread_count: 1,
+		max_accept_rate: Some(2),
+		max_connections_per_ip: Some(1),
+		..EventHandlerConfig::default()
+	});
 
-	let read_buf = Arc::new(Mutex::new(vec![]));
-	eh.set_on_read(move |buf, len, wh| {
-		let mut read_buf = read_buf.lock().unwrap();
-		for i in 0..len {
-			read_buf.push(buf[i]);
-		}
+	// the time of each accept
+	let accepted = Arc::new(Mutex::new(vec![]));
+	let accepted_clone = accepted.clone();
+	eh.set_on_read(|_, _, _| Ok(()))?;
+	eh.set_on_accept(move |_, _| {
+		accepted_clone.lock().unwrap().push(Instant::now());
+		Ok(())
+	})?;
+	eh.set_on_close(|_| Ok(()))?;
+	eh.set_on_client_read(|_, _, _| Ok(()))?;
+	eh.set_on_reject(|_, _| Ok(()))?;
+	let start = Instant::now();
+	eh.start_with_selectors(vec![network.selector(), network.selector()])?;
+
+	let listener = network.socket()?;
+	eh.add(listener, ActionType::AddListener, None, 0)?;
+	let mut handles = vec![network.connect_from(listener, "10.0.0.1:1000".parse().unwrap())?];
+	let rejected = network.connect_from(listener, "10.0.0.1:1001".parse().unwrap())?;
+	for i in 2..4 {
+		let addr = format!("10.0.0.{}:1000", i).parse().unwrap();
+		handles.push(network.connect_from(listener, addr)?);
+	}
 
-		if read_buf.len() < 5 {
-			return Ok(());
-		}
-		info!("read = {:?}", read_buf);
+	// the rejected connection doesn't count towards the accept rate. Compare the accept times
+	// rather than the time since start, the listener may be delayed before its first accept.
+	while accepted.lock().unwrap().len() < 2 {
+		assert!(start.elapsed() < Duration::from_secs(10));
+		std::thread::sleep(Duration::from_millis(1));
+	}
+	{
+		let accepted = accepted.lock().unwrap();
+		assert!(accepted[1].duration_since(accepted[0]) < Duration::from_secs(1));
+	}
+	assert!(network.is_closed(rejected)?);
+	// the third connection waits in the backlog until the next second
+	std::thread::sleep(Duration::from_millis(100));
+	assert_eq!(accepted.lock().unwrap().len(), 2);
+	while accepted.lock().unwrap().len() < 3 {
+		assert!(start.elapsed() < Duration::from_secs(10));
+		std::thread::sleep(Duration::from_millis(1));
+	}
+	assert!(start.elapsed() >= Duration::from_secs(1));
+	for handle in handles {
+		assert!(!network.is_closed(handle)?);
+	}
 
-		let len = read_buf.len();
+	eh.stop()?;
+	Ok(())
+}
 
-		for i in 0..len {
-			if read_buf[i] == 7 {
-				wh.close()?;
-				return Ok(());
-			}
+#[cfg(unix)]
+#[test]
+fn test_metrics() -> Result<(), Error> {
+	use crate::selector::MockNetwork;
+
+	let network = MockNetwork::new();
+	let mut eh = EventHandler::new(EventHandlerConfig {
+		thread_count: 1,
+		..EventHandlerConfig::default()
+	});
+	eh.set_on_read(|buf, len, wh| {
+		std::thread::sleep(Duration::from_millis(2));
+		wh.write(&buf[0..len])?;
+		Ok(())
+	})?;
+	eh.set_on_accept(|_, _| Ok(()))?;
+	eh.set_on_close(|_| Ok(()))?;
+	eh.set_on_client_read(|_, _, _| Ok(()))?;
+	eh.start_with_selectors(vec![network.selector(), network.selector()])?;
+
+	let listener = network.socket()?;
+	eh.add(listener, ActionType::AddListener, None, 0)?;
+	let handle = network.connect(listener)?;
+	network.push_data(handle, b"hello world")?;
+
+	let start = Instant::now();
+	while network.written(handle)? != b"hello world" || eh.metrics()?[1].bytes_written != 11 {
+		assert!(start.elapsed() < Duration::from_secs(10));
+		std::thread::sleep(Duration::from_millis(1));
+	}
+
+	let metrics = eh.metrics()?;
+	assert_eq!(metrics.len(), 2);
+	assert_eq!(metrics[0].accepts, 1);
+	assert_eq!(metrics[0].on_accept_latency.count, 1);
+	assert_eq!(metrics[1].accepts, 1);
+	assert_eq!(metrics[1].active_connections, 1);
+	assert_eq!(metrics[1].bytes_read, 11);
+	assert!(metrics[1].read_eagain > 0);
+	assert_eq!(metrics[1].on_read_latency.count, 1);
+	assert!(metrics[1].on_read_latency.max >= Duration::from_millis(2));
+	assert!(metrics[1].on_read_latency.quantile(0.5).unwrap() >= Duration::from_millis(2));
+	assert_eq!(metrics[1].write_queue_bytes, 0);
+	assert!(metrics[1].wakeups > 0);
+	assert!(metrics[1].poll_iterations > 0);
+	assert!(metrics[1].since_last_poll.is_some());
+
+	network.push_eof(handle)?;
+	loop {
+		let metrics = eh.metrics()?;
+		if metrics[0].closes == 1 && metrics[1].active_connections == 0 {
+			assert_eq!(metrics[1].closes, 1);
+			assert_eq!(metrics[0].on_close_latency.count, 1);
+			break;
 		}
-		for i in 0..len {
-			if read_buf[i] == 8 {
-				wh.write(&[0])?;
-				wh.close()?;
-				read_buf.clear();
+		assert!(start.elapsed() < Duration::from_secs(10));
+		std::thread::sleep(Duration::from_millis(1));
+	}
+
+	eh.stop()?;
+	Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn test_placement_strategies() -> Result<(), Error> {
+	use crate::selector::MockNetwork;
+
+	fn wait_for(
+		metrics: &dyn Fn() -> Result<Vec<ThreadMetrics>, Error>,
+		f: &dyn Fn(&[ThreadMetrics]) -> bool,
+	) -> Result<(), Error> {
+		let start = Instant::now();
+		loop {
+			if f(&metrics()?) {
 				return Ok(());
 			}
+			assert!(start.elapsed() < Duration::from_secs(10));
+			std::thread::sleep(Duration::from_millis(1));
 		}
-		wh.write(&[1])?;
-		read_buf.clear();
-		Ok(())
-	})?;
+	}
 
+	// least connections: a closed connection frees its thread for the next one
+	let network = MockNetwork::new();
+	let mut eh = EventHandler::new(EventHandlerConfig {
+		thread_count: 2,
+		placement_strategy: PlacementStrategy::LeastConnections,
+		..EventHandlerConfig::default()
+	});
+	eh.set_on_read(|_, _, _| Ok(()))?;
 	eh.set_on_accept(|_, _| Ok(()))?;
 	eh.set_on_close(|_| Ok(()))?;
-	eh.set_on_client_read(move |_buf, _len, _wh| Ok(()))?;
+	eh.set_on_client_read(|_, _, _| Ok(()))?;
+	eh.start_with_selectors(vec![
+		network.selector(),
+		network.selector(),
+		network.selector(),
+	])?;
+
+	let listener = network.socket()?;
+	eh.add(listener, ActionType::AddListener, None, 0)?;
+	let first = network.connect(listener)?;
+	wait_for(&|| eh.metrics(), &|m| m[1].active_connections == 1)?;
+	network.connect(listener)?;
+	wait_for(&|| eh.metrics(), &|m| m[2].active_connections == 1)?;
+	network.push_eof(first)?;
+	wait_for(&|| eh.metrics(), &|m| {
+		m[0].closes == 1 && m[1].active_connections == 0
+	})?;
+	network.connect(listener)?;
+	wait_for(&|| eh.metrics(), &|m| m[1].active_connections == 1)?;
+	let metrics = eh.metrics()?;
+	assert_eq!(metrics[1].accepts, 2);
+	assert_eq!(metrics[2].accepts, 1);
+	eh.stop()?;
 
-	eh.start()?;
-	eh.add_tcp_listener(&listener)?;
-	stream.write(&[1, 2, 3, 4, 5])?;
-	let mut buf = [0u8; 1000];
-	let len = stream.read(&mut buf)?;
-	assert_eq!(len, 1);
-	assert_eq!(buf[0], 1);
-	stream.write(&[8, 8, 8, 8, 8])?;
-	let len = stream.read(&mut buf)?;
-	assert_eq!(len, 1);
-	assert_eq!(buf[0], 0);
-	let len = stream.read(&mut buf)?;
-	assert_eq!(len, 0); // means connection closed
-	let mut stream2 = TcpStream::connect("127.0.0.1:9982")?;
-	stream2.write(&[7, 7, 7, 7, 7])?;
-	let len = stream2.read(&mut buf)?;
-	assert_eq!(len, 0); // means connection closed
+	// peer address hash: all connections from a host go to the same thread
+	let network = MockNetwork::new();
+	let mut eh = EventHandler::new(EventHandlerConfig {
+		thread_count: 2,
+		placement_strategy: PlacementStrategy::PeerAddressHash,
+		..EventHandlerConfig::default()
+	});
+	eh.set_on_read(|_, _, _| Ok(()))?;
+	eh.set_on_accept(|_, _| Ok(()))?;
+	eh.set_on_close(|_| Ok(()))?;
+	eh.set_on_client_read(|_, _, _| Ok(()))?;
+	eh.start_with_selectors(vec![
+		network.selector(),
+		network.selector(),
+		network.selector(),
+	])?;
+
+	let listener = network.socket()?;
+	eh.add(listener, ActionType::AddListener, None, 0)?;
+	for port in 1000..1004 {
+		network.connect_from(listener, format!("10.0.0.1:{}", port).parse().unwrap())?;
+	}
+	wait_for(&|| eh.metrics(), &|m| {
+		m[0].accepts == 4 && m[1].accepts + m[2].accepts == 4
+	})?;
+	let metrics = eh.metrics()?;
+	assert!(metrics[1].accepts == 4 || metrics[2].accepts == 4);
+	eh.stop()?;
 
 	Ok(())
 }
 
-#[test]
-fn test_client() -> Result<(), Error> {
-	use std::io::Write;
-	use std::net::TcpListener;
-	use std::net::TcpStream;
-
-	let listener = TcpListener::bind("127.0.0.1:9983")?;
-	let mut stream = TcpStream::connect("127.0.0.1:9983")?;
-	let mut eh = EventHandler::new(EventHandlerConfig::default());
+#[cfg(unix)]
+#[test]
+fn test_migrate() -> Result<(), Error> {
+	use crate::selector::MockNetwork;
+	use std::sync::atomic::{AtomicBool, Ordering};
+	use std::sync::Mutex;
 
-	// echo
+	let network = MockNetwork::new();
+	let mut eh = EventHandler::new(EventHandlerConfig {
+		thread_count: 2,
+		..EventHandlerConfig::default()
+	});
+	let accepted = Arc::new(Mutex::new(None));
+	let accepted_clone = accepted.clone();
+	let closed = Arc::new(AtomicBool::new(false));
+	let closed_clone = closed.clone();
 	eh.set_on_read(|buf, len, wh| {
-		match len {
-			// just close the connection with no response
-			7 => {
-				let _ = wh.close();
-			}
-			// close if len == 5, otherwise keep open
-			_ => {
-				let _ = wh.write(&buf[0..len])?;
-				if len == 5 {
-					wh.close()?;
-				}
-			}
-		}
+		wh.write(&buf[0..len])?;
 		Ok(())
 	})?;
-
-	eh.set_on_accept(|_, _| Ok(()))?;
-	eh.set_on_close(|_| Ok(()))?;
-	eh.set_on_client_read(move |buf, len, _wh| {
-		info!("client_read={:?}", &buf[0..len]);
-		assert_eq!(&buf[0..len], [1, 2, 3, 4, 5, 6]);
+	eh.set_on_accept(move |connection_id, wh| {
+		*accepted_clone.lock().unwrap() = Some((connection_id, wh));
+		Ok(())
+	})?;
+	eh.set_on_close(move |_| {
+		closed_clone.store(true, Ordering::SeqCst);
 		Ok(())
 	})?;
+	eh.set_on_client_read(|_, _, _| Ok(()))?;
+	eh.start_with_selectors(vec![
+		network.selector(),
+		network.selector(),
+		network.selector(),
+	])?;
+
+	let listener = network.socket()?;
+	eh.add(listener, ActionType::AddListener, None, 0)?;
+	let handle = network.connect(listener)?;
+	network.push_data(handle, b"a")?;
+
+	let start = Instant::now();
+	while network.written(handle)? != b"a" {
+		assert!(start.elapsed() < Duration::from_secs(10));
+		std::thread::sleep(Duration::from_millis(1));
+	}
 
-	eh.start()?;
-	eh.add_tcp_listener(&listener)?;
-	eh.add_tcp_stream(&stream)?;
-	std::thread::sleep(std::time::Duration::from_millis(1000));
-	stream.write(&[1, 2, 3, 4, 5, 6])?;
-	std::thread::sleep(std::time::Duration::from_millis(1000));
+	let (connection_id, wh) = accepted.lock().unwrap().clone().unwrap();
+	let metrics = eh.metrics()?;
+	let (from, to) = match metrics[1].active_connections {
+		1 => (1, 2),
+		_ => (2, 1),
+	};
+	assert!(eh.migrate(connection_id, 0).is_err());
+	assert!(eh.migrate(connection_id, 3).is_err());
+	eh.migrate(connection_id, to)?;
+	loop {
+		let metrics = eh.metrics()?;
+		if metrics[from].active_connections == 0 && metrics[to].active_connections == 1 {
+			break;
+		}
+		assert!(start.elapsed() < Duration::from_secs(10));
+		std::thread::sleep(Duration::from_millis(1));
+	}
+
+	// reads are handled by the new thread and the old write handle still works
+	network.push_data(handle, b"b")?;
+	while network.written(handle)? != b"ab" {
+		assert!(start.elapsed() < Duration::from_secs(10));
+		std::thread::sleep(Duration::from_millis(1));
+	}
+	wh.write(b"c")?;
+	while network.written(handle)? != b"abc" {
+		assert!(start.elapsed() < Duration::from_secs(10));
+		std::thread::sleep(Duration::from_millis(1));
+	}
+	assert_eq!(eh.metrics()?[to].bytes_written, 2);
+
+	wh.close()?;
+	while !closed.load(Ordering::SeqCst) {
+		assert!(start.elapsed() < Duration::from_secs(10));
+		std::thread::sleep(Duration::from_millis(1));
+	}
+
+	eh.stop()?;
 	Ok(())
 }
 
 #[test]
-fn test_stop() -> Result<(), Error> {
-	use std::io::Write;
-	use std::net::TcpListener;
-	use std::net::TcpStream;
+#[cfg(target_os = "linux")]
+fn test_migrate_io_uring() -> Result<(), Error> {
 	use std::sync::Mutex;
 
-	let listener = TcpListener::bind("127.0.0.1:9984")?;
-	let mut stream = TcpStream::connect("127.0.0.1:9984")?;
-	let mut eh = EventHandler::new(EventHandlerConfig::default());
-	let x = Arc::new(Mutex::new(0));
-	let xclone = x.clone();
-
-	// echo
-	eh.set_on_read(move |_, _, _| {
-		let mut x = xclone.lock().unwrap();
-		*x += 1;
+	let mut eh = EventHandler::new(EventHandlerConfig {
+		thread_count: 2,
+		backend: Backend::IoUring,
+		..EventHandlerConfig::default()
+	});
+	let accepted = Arc::new(Mutex::new(None));
+	let accepted_clone = accepted.clone();
+	eh.set_on_read(|buf, len, wh| {
+		wh.write(&buf[0..len])?;
+		Ok(())
+	})?;
+	eh.set_on_accept(move |connection_id, wh| {
+		*accepted_clone.lock().unwrap() = Some((connection_id, wh));
 		Ok(())
 	})?;
-
-	eh.set_on_accept(|_, _| Ok(()))?;
 	eh.set_on_close(|_| Ok(()))?;
-	eh.set_on_client_read(move |_, _, _| Ok(()))?;
-
+	eh.set_on_client_read(|_, _, _| Ok(()))?;
 	eh.start()?;
+
+	let listener = TcpListener::bind("127.0.0.1:0")?;
 	eh.add_tcp_listener(&listener)?;
-	eh.add_tcp_stream(&stream)?;
-	stream.write(&[1])?;
+	let mut stream = TcpStream::connect(listener.local_addr()?)?;
+	stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+	stream.write_all(b"a")?;
+	let mut response = [0u8; 1];
+	stream.read_exact(&mut response)?;
+	assert_eq!(&response, b"a");
+
+	// the client doesn't read, so the ring is still sending the data when the connection moves
+	let (connection_id, wh) = accepted.lock().unwrap().clone().unwrap();
+	let data: Vec<u8> = (0..32_000_000).map(|i| (i % 251) as u8).collect();
+	wh.write(&data)?;
+	std::thread::sleep(Duration::from_millis(100));
+	let (from, to) = match eh.metrics()?[1].active_connections {
+		1 => (1, 2),
+		_ => (2, 1),
+	};
+	eh.migrate(connection_id, to)?;
+	let start = Instant::now();
 	loop {
-		{
-			let x = x.lock().unwrap();
-			if *x == 1 {
-				break;
-			}
+		let metrics = eh.metrics()?;
+		if metrics[from].active_connections == 0 && metrics[to].active_connections == 1 {
+			break;
 		}
-		std::thread::sleep(std::time::Duration::from_millis(10));
+		assert!(start.elapsed() < Duration::from_secs(10));
+		std::thread::sleep(Duration::from_millis(1));
 	}
+
+	// nothing is lost or reordered and the new thread echoes the reads
+	stream.write_all(b"b")?;
+	let mut response = vec![0u8; data.len() + 1];
+	stream.read_exact(&mut response)?;
+	assert!(response[..data.len()] == data[..]);
+	assert_eq!(response[data.len()], b'b');
+
 	eh.stop()?;
-	std::thread::sleep(std::time::Duration::from_millis(3000));
-	stream.write(&[1])?;
-	std::thread::sleep(std::time::Duration::from_millis(3000));
-	let x = x.lock().unwrap();
-	assert_eq!(*x, 1);
 	Ok(())
 }
 
+#[cfg(unix)]
 #[test]
-fn test_large_messages() -> Result<(), Error> {
-	use std::net::TcpListener;
-	use std::net::TcpStream;
+fn test_reuse_port_listeners() -> Result<(), Error> {
+	use crate::selector::MockNetwork;
 	use std::sync::Mutex;
 
-	let listener = TcpListener::bind("127.0.0.1:9933")?;
-	let stream = TcpStream::connect("127.0.0.1:9933")?;
-	let mut eh = EventHandler::new(EventHandlerConfig::default());
+	let network = MockNetwork::new();
+	let mut eh = EventHandler::new(EventHandlerConfig {
+		thread_count: 2,
+		..EventHandlerConfig::default()
+	});
 
-	eh.set_on_read(move |buf, len, wh| {
-		match len {
-			// just close the connection with no response
-			7 => {
-				let _ = wh.close();
-			}
-			// close if len == 5, otherwise keep open
-			_ => {
-				let _ = wh.write(&buf[0..len])?;
-				if len == 5 {
-					wh.close()?;
-				}
-			}
-		}
+	let accepted = Arc::new(Mutex::new(vec![]));
+	let accepted_clone = accepted.clone();
+	let closed = Arc::new(Mutex::new(vec![]));
+	let closed_clone = closed.clone();
+	eh.set_on_read(|buf, len, wh| {
+		wh.write(&buf[0..len])?;
+		Ok(())
+	})?;
+	eh.set_on_accept(move |connection_id, _| {
+		accepted_clone.lock().unwrap().push(connection_id);
+		Ok(())
+	})?;
+	eh.set_on_close(move |connection_id| {
+		closed_clone.lock().unwrap().push(connection_id);
 		Ok(())
 	})?;
+	eh.set_on_client_read(|_, _, _| Ok(()))?;
+	eh.start_with_selectors(vec![
+		network.selector(),
+		network.selector(),
+		network.selector(),
+	])?;
+
+	// one listener per r/w thread, as done by add_tcp_listener_reuse_port
+	let mut listeners = vec![];
+	for i in 1..3 {
+		let listener = network.socket()?;
+		eh.add(listener, ActionType::AddReusePortListener, None, i)?;
+		listeners.push(listener);
+	}
 
-	let mut msgbuf = vec![];
-	for i in 0..3_000_000 {
-		msgbuf.push((i % 123) as u8);
+	let mut handles = vec![];
+	for listener in &listeners {
+		let handle = network.connect(*listener)?;
+		network.push_data(handle, b"hello")?;
+		handles.push(handle);
 	}
-	msgbuf.push(128);
-	let cloned_msgbuf = msgbuf.clone();
 
+	let start = Instant::now();
+	for handle in &handles {
+		while network.written(*handle)? != b"hello" {
+			assert!(start.elapsed() < Duration::from_secs(10));
+			std::thread::sleep(Duration::from_millis(1));
+		}
+	}
+
+	// each connection was accepted by the thread that owns its listener
+	let metrics = eh.metrics()?;
+	assert_eq!(metrics[0].accepts, 0);
+	assert_eq!(metrics[1].accepts, 1);
+	assert_eq!(metrics[2].accepts, 1);
+	assert_eq!(metrics[1].active_connections, 1);
+	assert_eq!(metrics[2].active_connections, 1);
+	assert_eq!(metrics[1].on_accept_latency.count, 1);
+	assert_eq!(accepted.lock().unwrap().len(), 2);
+
+	for handle in &handles {
+		network.push_eof(*handle)?;
+	}
+	for handle in &handles {
+		while !network.is_closed(*handle)? || closed.lock().unwrap().len() < 2 {
+			assert!(start.elapsed() < Duration::from_secs(10));
+			std::thread::sleep(Duration::from_millis(1));
+		}
+	}
+	let mut accepted = accepted.lock().unwrap().clone();
+	let mut closed = closed.lock().unwrap().clone();
+	accepted.sort();
+	closed.sort();
+	assert_eq!(accepted, closed);
+	for listener in &listeners {
+		assert!(!network.is_closed(*listener)?);
+	}
+	eh.stop()?;
+
+	// the same with real sockets bound to a port picked by the os
+	let mut eh = EventHandler::new(EventHandlerConfig {
+		thread_count: 2,
+		..EventHandlerConfig::default()
+	});
+	eh.set_on_read(|buf, len, wh| {
+		wh.write(&buf[0..len])?;
+		Ok(())
+	})?;
 	eh.set_on_accept(|_, _| Ok(()))?;
 	eh.set_on_close(|_| Ok(()))?;
+	eh.set_on_client_read(|_, _, _| Ok(()))?;
+	eh.start()?;
+	let addr = eh.add_tcp_listener_reuse_port("127.0.0.1:0".parse().unwrap())?;
+	assert_ne!(addr.port(), 0);
+
+	for _ in 0..4 {
+		let mut stream = TcpStream::connect(addr)?;
+		stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+		stream.write_all(b"hello")?;
+		let mut buf = [0u8; 5];
+		stream.read_exact(&mut buf)?;
+		assert_eq!(&buf, b"hello");
+	}
+	let metrics = eh.metrics()?;
+	assert_eq!(metrics[0].accepts, 0);
+	assert_eq!(metrics[1].accepts + metrics[2].accepts, 4);
 
-	let client_accumulator = Arc::new(Mutex::new(vec![]));
-	let complete = Arc::new(Mutex::new(false));
-	let complete_clone = complete.clone();
+	eh.stop()?;
+	Ok(())
+}
 
-	eh.set_on_client_read(move |buf, len, _wh| {
-		let mut client_accumulator = client_accumulator.lock().unwrap();
-		for i in 0..len {
-			client_accumulator.push(buf[i]);
-			if buf[i] == 128 {
-				assert_eq!(client_accumulator.len(), cloned_msgbuf.len());
-				assert_eq!(*client_accumulator, cloned_msgbuf);
-				let mut complete = complete_clone.lock().unwrap();
-				*complete = true;
-			}
-		}
+#[cfg(unix)]
+#[test]
+fn test_socket_options() -> Result<(), Error> {
+	use std::sync::Mutex;
+
+	fn get_socket_option(handle: ConnectionHandle, level: c_int, name: c_int) -> c_int {
+		let mut value: c_int = 0;
+		let mut len = std::mem::size_of::<c_int>() as socklen_t;
+		let res = unsafe {
+			libc::getsockopt(
+				handle,
+				level,
+				name,
+				&mut value as *mut _ as *mut c_void,
+				&mut len,
+			)
+		};
+		assert_eq!(res, 0);
+		value
+	}
+
+	let mut eh = EventHandler::new(EventHandlerConfig {
+		thread_count: 1,
+		socket_options: SocketOptions {
+			tcp_nodelay: Some(true),
+			keepalive: Some(true),
+			keepalive_count: Some(4),
+			linger: Some(Duration::from_secs(1)),
+			tcp_fastopen: Some(16),
+			..SocketOptions::default()
+		},
+		..EventHandlerConfig::default()
+	});
+
+	// the options of the accepted connections
+	let accepted = Arc::new(Mutex::new(vec![]));
+	let accepted_clone = accepted.clone();
+	eh.set_on_read(|_, _, _| Ok(()))?;
+	eh.set_on_accept(move |_, wh| {
+		accepted_clone.lock().unwrap().push((
+			get_socket_option(wh.fd, libc::IPPROTO_TCP, libc::TCP_NODELAY),
+			get_socket_option(wh.fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE),
+			get_socket_option(wh.fd, libc::IPPROTO_TCP, libc::TCP_KEEPCNT),
+		));
 		Ok(())
 	})?;
-
+	eh.set_on_close(|_| Ok(()))?;
+	eh.set_on_client_read(|_, _, _| Ok(()))?;
 	eh.start()?;
+
+	let listener = TcpListener::bind("127.0.0.1:0")?;
 	eh.add_tcp_listener(&listener)?;
-	let wh = eh.add_tcp_stream(&stream)?;
-	wh.write(&msgbuf[0..msgbuf.len()])?;
-	loop {
-		{
-			let complete = complete.lock().unwrap();
-			if *complete {
-				break;
-			}
-		}
-		std::thread::sleep(std::time::Duration::from_millis(10));
+	#[cfg(target_os = "linux")]
+	assert_eq!(
+		get_socket_option(listener.as_raw_fd(), libc::IPPROTO_TCP, libc::TCP_FASTOPEN),
+		16
+	);
+
+	// the config applies to outbound streams
+	let stream = TcpStream::connect(listener.local_addr()?)?;
+	eh.add_tcp_stream(&stream)?;
+	let handle = stream.as_raw_fd();
+	assert_eq!(
+		get_socket_option(handle, libc::IPPROTO_TCP, libc::TCP_NODELAY),
+		1
+	);
+	assert_eq!(
+		get_socket_option(handle, libc::IPPROTO_TCP, libc::TCP_KEEPCNT),
+		4
+	);
+
+	let start = Instant::now();
+	while accepted.lock().unwrap().len() != 1 {
+		assert!(start.elapsed() < Duration::from_secs(10));
+		std::thread::sleep(Duration::from_millis(1));
 	}
+	assert_eq!(accepted.lock().unwrap()[0], (1, 1, 4));
+
+	// per-stream options replace the config
+	let stream2 = TcpStream::connect(listener.local_addr()?)?;
+	eh.add_tcp_stream_with_options(
+		&stream2,
+		&SocketOptions {
+			keepalive: Some(true),
+			keepalive_interval: Some(Duration::from_secs(7)),
+			..SocketOptions::default()
+		},
+	)?;
+	let handle = stream2.as_raw_fd();
+	assert_eq!(
+		get_socket_option(handle, libc::IPPROTO_TCP, libc::TCP_NODELAY),
+		0
+	);
+	assert_eq!(
+		get_socket_option(handle, libc::SOL_SOCKET, libc::SO_KEEPALIVE),
+		1
+	);
+	assert_eq!(
+		get_socket_option(handle, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL),
+		7
+	);
+
+	// sub-second durations are rounded up
+	let stream3 = TcpStream::connect(listener.local_addr()?)?;
+	eh.add_tls_stream_with_options(
+		&stream3,
+		"localhost",
+		TlsClientConfig::default(),
+		&SocketOptions {
+			keepalive: Some(true),
+			keepalive_idle: Some(Duration::from_millis(200)),
+			keepalive_interval: Some(Duration::from_millis(1500)),
+			..SocketOptions::default()
+		},
+	)?;
+	let handle = stream3.as_raw_fd();
+	#[cfg(target_os = "linux")]
+	assert_eq!(
+		get_socket_option(handle, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE),
+		1
+	);
+	assert_eq!(
+		get_socket_option(handle, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL),
+		2
+	);
+
+	eh.stop()?;
 	Ok(())
 }
 
 #[test]
-fn test_ssl() -> Result<(), Error> {
-	use std::net::TcpListener;
-	use std::net::TcpStream;
+fn test_half_close() -> Result<(), Error> {
+	half_close(Backend::Native)
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_half_close_io_uring() -> Result<(), Error> {
+	half_close(Backend::IoUring)
+}
+
+#[cfg(test)]
+fn half_close(backend: Backend) -> Result<(), Error> {
+	use std::sync::Mutex;
 
-	let listener = TcpListener::bind("127.0.0.1:9483")?;
-	let stream = TcpStream::connect("127.0.0.1:9483")?;
 	let mut eh = EventHandler::new(EventHandlerConfig {
-		tls_config: Some(TlsConfig {
-			certificates_file: "./src/resources/cert.pem".to_string(),
-			private_key_file: "./src/resources/key.pem".to_string(),
-		}),
+		thread_count: 1,
+		read_trigger_mode: TriggerMode::Level,
+		backend,
 		..EventHandlerConfig::default()
 	});
 
-	// echo
+	let eof = Arc::new(Mutex::new(None));
+	let eof_clone = eof.clone();
+	let closed = Arc::new(Mutex::new(0));
+	let closed_clone = closed.clone();
 	eh.set_on_read(|buf, len, wh| {
-		info!("server read {}", len);
-		match len {
-			// just close the connection with no response
-			7 => {
-				let _ = wh.close();
-			}
-			// close if len == 5, otherwise keep open
-			_ => {
-				let _ = wh.write(&buf[0..len])?;
-				if len == 5 {
-					wh.close()?;
-				}
-			}
-		}
+		wh.write(&buf[0..len])?;
+		Ok(())
+	})?;
+	eh.set_on_read_eof(move |wh| {
+		*eof_clone.lock().unwrap() = Some(wh);
 		Ok(())
 	})?;
-
 	eh.set_on_accept(|_, _| Ok(()))?;
-	eh.set_on_close(|_| Ok(()))?;
-	let complete = Arc::new(RwLock::new(false));
-	let complete_clone = complete.clone();
-	eh.set_on_client_read(move |buf, len, _wh| {
-		info!("client_read={:?}", &buf[0..len]);
-		assert_eq!(&buf[0..len], [1, 2, 3, 4, 5, 6]);
-		let mut complete = complete_clone.write().unwrap();
-		*complete = true;
+	eh.set_on_close(move |_| {
+		*closed_clone.lock().unwrap() += 1;
 		Ok(())
 	})?;
-
+	eh.set_on_client_read(|_, _, _| Ok(()))?;
 	eh.start()?;
+
+	let listener = TcpListener::bind("127.0.0.1:0")?;
 	eh.add_tcp_listener(&listener)?;
-	let wh = eh.add_tls_stream(&stream, "localhost", Some("./src/resources/cert.pem"))?;
-	wh.write(&[1, 2, 3, 4, 5, 6])?;
-	loop {
-		std::thread::sleep(std::time::Duration::from_millis(10));
-		let complete = complete.write().unwrap();
-		if *complete {
-			break;
-		}
+	let mut stream = TcpStream::connect(listener.local_addr()?)?;
+	stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+	stream.write_all(b"hello")?;
+	stream.shutdown(std::net::Shutdown::Write)?;
+
+	let start = Instant::now();
+	while eof.lock().unwrap().is_none() {
+		assert!(start.elapsed() < Duration::from_secs(10));
+		std::thread::sleep(Duration::from_millis(1));
+	}
+
+	// the half closed connection stays open without being reported again
+	let poll_iterations = eh.metrics()?[1].poll_iterations;
+	std::thread::sleep(Duration::from_millis(100));
+	assert!(eh.metrics()?[1].poll_iterations < poll_iterations + 10);
+	assert_eq!(*closed.lock().unwrap(), 0);
+
+	// the response is written before the write side is shut down, which closes the connection
+	let wh = eof.lock().unwrap().take().unwrap();
+	wh.write(b" world")?;
+	wh.shutdown_write()?;
+	let mut response = vec![];
+	stream.read_to_end(&mut response)?;
+	assert_eq!(response, b"hello world");
+
+	while *closed.lock().unwrap() != 1 {
+		assert!(start.elapsed() < Duration::from_secs(10));
+		std::thread::sleep(Duration::from_millis(1));
 	}
 
+	eh.stop()?;
 	Ok(())
 }
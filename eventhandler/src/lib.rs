@@ -12,18 +12,46 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-#[cfg(any(target_os = "macos", dragonfly, freebsd, netbsd, openbsd))]
+#[cfg(any(
+	target_os = "macos",
+	target_os = "dragonfly",
+	target_os = "freebsd",
+	target_os = "netbsd",
+	target_os = "openbsd"
+))]
 use libc::timespec;
-#[cfg(any(target_os = "macos", dragonfly, freebsd, netbsd, openbsd))]
+#[cfg(any(
+	target_os = "macos",
+	target_os = "dragonfly",
+	target_os = "freebsd",
+	target_os = "netbsd",
+	target_os = "openbsd"
+))]
 use std::time::Duration;
 
 mod eventhandler;
+mod metrics;
+mod selector;
+mod timer;
+#[cfg(target_os = "linux")]
+mod uring;
 
-pub use crate::eventhandler::{EventHandler, EventHandlerConfig, State, TlsConfig, WriteHandle};
+pub use crate::eventhandler::{
+	Backend, ClientCertificate, ConnectionHandler, DatagramHandle, EventHandler,
+	EventHandlerConfig, PlacementStrategy, RejectReason, ShutdownSummary, SniCertificate,
+	SocketOptions, State, TlsClientConfig, TlsConfig, TriggerMode, WriteHandle,
+};
+pub use crate::metrics::{LatencyHistogram, ThreadMetrics};
+pub use crate::timer::TimerHandle;
 
 // Some needed timespec code
 
-#[cfg(any(target_os = "macos", dragonfly, netbsd, openbsd))]
+#[cfg(any(
+	target_os = "macos",
+	target_os = "dragonfly",
+	target_os = "netbsd",
+	target_os = "openbsd"
+))]
 pub(crate) fn duration_to_timespec(d: Duration) -> timespec {
 	let tv_sec = d.as_secs() as i64;
 	let tv_nsec = d.subsec_nanos() as i64;
@@ -0,0 +1,265 @@
+// Copyright 2021 The BMW Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// The counters kept by each of the EventHandler's threads. The threads update them with relaxed
+// atomics so that EventHandler::metrics can take a snapshot without stopping them.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+const LATENCY_BUCKETS: usize = 24;
+
+/// A histogram of the time spent in one of the [`crate::EventHandler`]'s callbacks.
+///
+/// Bucket `i` counts the calls that took less than 2^i microseconds and that did not fit in
+/// bucket `i - 1`. The last bucket counts all slower calls.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LatencyHistogram {
+	/// The number of calls in each bucket.
+	pub buckets: Vec<u64>,
+	/// The number of calls.
+	pub count: u64,
+	/// The total time spent in the callback.
+	pub total: Duration,
+	/// The time taken by the slowest call.
+	pub max: Duration,
+}
+
+impl LatencyHistogram {
+	/// Returns the upper bound of bucket `i`, or None for the last bucket, which is unbounded.
+	pub fn bucket_bound(i: usize) -> Option<Duration> {
+		if i + 1 >= LATENCY_BUCKETS {
+			None
+		} else {
+			Some(Duration::from_micros(1 << i))
+		}
+	}
+
+	/// Returns an upper bound of the `q` quantile (between 0.0 and 1.0) of the latencies. Returns
+	/// None if no calls were recorded or if the quantile falls in the last bucket, in which case
+	/// [`LatencyHistogram::max`] is the best known bound.
+	pub fn quantile(&self, q: f64) -> Option<Duration> {
+		if self.count == 0 {
+			return None;
+		}
+		let target = ((self.count as f64) * q.clamp(0.0, 1.0)).ceil().max(1.0) as u64;
+		let mut seen = 0;
+		for (i, count) in self.buckets.iter().enumerate() {
+			seen += count;
+			if seen >= target {
+				return Self::bucket_bound(i).map(|bound| bound.min(self.max));
+			}
+		}
+		None
+	}
+}
+
+/// A snapshot of the counters of one of the [`crate::EventHandler`]'s threads. See
+/// [`crate::EventHandler::metrics`].
+///
+/// All counters except the gauges `active_connections`, `write_queue_bytes` and
+/// `write_queue_connections` are totals since the thread started.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ThreadMetrics {
+	/// The number of connections owned by the thread. Always 0 for the listener thread.
+	pub active_connections: u64,
+	/// For the listener thread, the number of connections accepted. For the r/w threads, the
+	/// number of accepted connections assigned to the thread, including the ones the thread
+	/// accepted itself on the listeners added with
+	/// [`crate::EventHandler::add_tcp_listener_reuse_port`].
+	pub accepts: u64,
+	/// The number of connections rejected because of the connection limits. For the r/w
+	/// threads, only the connections on the listeners added with
+	/// [`crate::EventHandler::add_tcp_listener_reuse_port`] are counted.
+	pub rejects: u64,
+	/// For the listener thread, the number of closed connections for which on_close was executed.
+	/// For the r/w threads, the number of the thread's connections that were closed.
+	pub closes: u64,
+	/// The number of bytes read and passed to on_read or on_client_read.
+	pub bytes_read: u64,
+	/// The number of bytes written to the thread's connections.
+	pub bytes_written: u64,
+	/// The number of bytes queued for writing on the thread's connections.
+	pub write_queue_bytes: u64,
+	/// The number of the thread's connections with queued data.
+	pub write_queue_connections: u64,
+	/// The number of times the thread was woken up by another thread.
+	pub wakeups: u64,
+	/// The number of times the thread polled its selector for events.
+	pub poll_iterations: u64,
+	/// The number of reads that returned EAGAIN.
+	pub read_eagain: u64,
+	/// The number of writes that returned EAGAIN.
+	pub write_eagain: u64,
+	/// The time since the thread last returned from polling its selector, or None if it has not
+	/// polled yet. A thread that stays busy for long after the wait timeout of its selector is
+	/// stuck in a callback.
+	pub since_last_poll: Option<Duration>,
+	/// The time spent in on_read and on_client_read.
+	pub on_read_latency: LatencyHistogram,
+	/// The time spent in on_accept.
+	pub on_accept_latency: LatencyHistogram,
+	/// The time spent in on_close.
+	pub on_close_latency: LatencyHistogram,
+	/// The time spent in on_writable.
+	pub on_writable_latency: LatencyHistogram,
+	/// The time spent in on_timeout.
+	pub on_timeout_latency: LatencyHistogram,
+}
+
+pub(crate) struct Histogram {
+	buckets: [AtomicU64; LATENCY_BUCKETS],
+	count: AtomicU64,
+	total_nanos: AtomicU64,
+	max_nanos: AtomicU64,
+}
+
+impl Histogram {
+	fn new() -> Self {
+		Histogram {
+			buckets: Default::default(),
+			count: AtomicU64::new(0),
+			total_nanos: AtomicU64::new(0),
+			max_nanos: AtomicU64::new(0),
+		}
+	}
+
+	// execute f and record the time it took
+	pub(crate) fn time<T, F: FnOnce() -> T>(&self, f: F) -> T {
+		let start = Instant::now();
+		let ret = f();
+		self.record(start.elapsed());
+		ret
+	}
+
+	fn record(&self, elapsed: Duration) {
+		let micros = elapsed.as_micros();
+		let mut i = 0;
+		while i + 1 < LATENCY_BUCKETS && micros >= 1 << i {
+			i += 1;
+		}
+		let nanos = elapsed.as_nanos() as u64;
+		self.buckets[i].fetch_add(1, Ordering::Relaxed);
+		self.count.fetch_add(1, Ordering::Relaxed);
+		self.total_nanos.fetch_add(nanos, Ordering::Relaxed);
+		self.max_nanos.fetch_max(nanos, Ordering::Relaxed);
+	}
+
+	fn snapshot(&self) -> LatencyHistogram {
+		LatencyHistogram {
+			buckets: self
+				.buckets
+				.iter()
+				.map(|bucket| bucket.load(Ordering::Relaxed))
+				.collect(),
+			count: self.count.load(Ordering::Relaxed),
+			total: Duration::from_nanos(self.total_nanos.load(Ordering::Relaxed)),
+			max: Duration::from_nanos(self.max_nanos.load(Ordering::Relaxed)),
+		}
+	}
+}
+
+pub(crate) struct Metrics {
+	pub(crate) active_connections: AtomicU64,
+	pub(crate) accepts: AtomicU64,
+	pub(crate) rejects: AtomicU64,
+	pub(crate) closes: AtomicU64,
+	pub(crate) bytes_read: AtomicU64,
+	pub(crate) bytes_written: AtomicU64,
+	pub(crate) wakeups: AtomicU64,
+	pub(crate) read_eagain: AtomicU64,
+	pub(crate) write_eagain: AtomicU64,
+	pub(crate) on_read: Histogram,
+	pub(crate) on_accept: Histogram,
+	pub(crate) on_close: Histogram,
+	pub(crate) on_writable: Histogram,
+	pub(crate) on_timeout: Histogram,
+	poll_iterations: AtomicU64,
+	created: Instant,
+	// nanoseconds between created and the end of the last poll, plus one. 0 if never polled.
+	last_poll: AtomicU64,
+}
+
+impl Metrics {
+	pub(crate) fn new() -> Self {
+		Metrics {
+			active_connections: AtomicU64::new(0),
+			accepts: AtomicU64::new(0),
+			rejects: AtomicU64::new(0),
+			closes: AtomicU64::new(0),
+			bytes_read: AtomicU64::new(0),
+			bytes_written: AtomicU64::new(0),
+			wakeups: AtomicU64::new(0),
+			read_eagain: AtomicU64::new(0),
+			write_eagain: AtomicU64::new(0),
+			on_read: Histogram::new(),
+			on_accept: Histogram::new(),
+			on_close: Histogram::new(),
+			on_writable: Histogram::new(),
+			on_timeout: Histogram::new(),
+			poll_iterations: AtomicU64::new(0),
+			created: Instant::now(),
+			last_poll: AtomicU64::new(0),
+		}
+	}
+
+	pub(crate) fn inc(counter: &AtomicU64, n: u64) {
+		counter.fetch_add(n, Ordering::Relaxed);
+	}
+
+	pub(crate) fn get(counter: &AtomicU64) -> u64 {
+		counter.load(Ordering::Relaxed)
+	}
+
+	pub(crate) fn set(gauge: &AtomicU64, n: u64) {
+		gauge.store(n, Ordering::Relaxed);
+	}
+
+	// called by the owning thread each time it returns from polling its selector
+	pub(crate) fn polled(&self) {
+		self.poll_iterations.fetch_add(1, Ordering::Relaxed);
+		let nanos = self.created.elapsed().as_nanos() as u64;
+		self.last_poll.store(nanos + 1, Ordering::Relaxed);
+	}
+
+	pub(crate) fn snapshot(&self) -> ThreadMetrics {
+		let since_last_poll = match self.last_poll.load(Ordering::Relaxed) {
+			0 => None,
+			last_poll => {
+				let last_poll = self.created + Duration::from_nanos(last_poll - 1);
+				Some(Instant::now().saturating_duration_since(last_poll))
+			}
+		};
+		ThreadMetrics {
+			active_connections: self.active_connections.load(Ordering::Relaxed),
+			accepts: self.accepts.load(Ordering::Relaxed),
+			rejects: self.rejects.load(Ordering::Relaxed),
+			closes: self.closes.load(Ordering::Relaxed),
+			bytes_read: self.bytes_read.load(Ordering::Relaxed),
+			bytes_written: self.bytes_written.load(Ordering::Relaxed),
+			write_queue_bytes: 0,
+			write_queue_connections: 0,
+			wakeups: self.wakeups.load(Ordering::Relaxed),
+			poll_iterations: self.poll_iterations.load(Ordering::Relaxed),
+			read_eagain: self.read_eagain.load(Ordering::Relaxed),
+			write_eagain: self.write_eagain.load(Ordering::Relaxed),
+			since_last_poll,
+			on_read_latency: self.on_read.snapshot(),
+			on_accept_latency: self.on_accept.snapshot(),
+			on_close_latency: self.on_close.snapshot(),
+			on_writable_latency: self.on_writable.snapshot(),
+			on_timeout_latency: self.on_timeout.snapshot(),
+		}
+	}
+}
@@ -0,0 +1,1380 @@
+// Copyright 2021 The BMW Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// The selectors used by the listener and the r/w threads to wait for events on their sockets.
+// Each platform has its own implementation (epoll, kqueue, wepoll and io_uring), all behind the
+// Selector trait. The socket i/o done by the threads also goes through the selector so that
+// tests can substitute the in-memory MockSelector for real networking.
+
+use crate::eventhandler::{
+	accept_handle, close_handle, read_handle, send_file_data, shutdown_write_handle,
+	write_vectored_data, Backend, ConnectionHandle, SocketOptions,
+};
+use crate::mainlogerror;
+#[cfg(not(target_os = "linux"))]
+use errno::errno;
+use nioruntime_err::{Error, ErrorKind};
+use nioruntime_log::*;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::IoSlice;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+// linux deps
+#[cfg(target_os = "linux")]
+use crate::eventhandler::accepted_peer_addr;
+#[cfg(target_os = "linux")]
+use crate::uring::{Readiness, Uring};
+#[cfg(target_os = "linux")]
+use nix::sys::epoll::{
+	epoll_create1, epoll_ctl, epoll_wait, EpollCreateFlags, EpollEvent, EpollFlags, EpollOp,
+};
+#[cfg(target_os = "linux")]
+use std::convert::TryInto;
+#[cfg(target_os = "linux")]
+use std::sync::RwLock;
+
+// macos/bsd deps
+#[cfg(any(
+	target_os = "macos",
+	target_os = "dragonfly",
+	target_os = "freebsd",
+	target_os = "netbsd",
+	target_os = "openbsd"
+))]
+use crate::duration_to_timespec;
+#[cfg(any(
+	target_os = "macos",
+	target_os = "dragonfly",
+	target_os = "freebsd",
+	target_os = "netbsd",
+	target_os = "openbsd"
+))]
+use kqueue_sys::EventFilter::{self, EVFILT_READ, EVFILT_WRITE};
+#[cfg(any(
+	target_os = "macos",
+	target_os = "dragonfly",
+	target_os = "freebsd",
+	target_os = "netbsd",
+	target_os = "openbsd"
+))]
+use kqueue_sys::{kevent, kqueue, EventFlag, FilterFlag};
+#[cfg(any(
+	target_os = "macos",
+	target_os = "dragonfly",
+	target_os = "freebsd",
+	target_os = "netbsd",
+	target_os = "openbsd"
+))]
+use libc::uintptr_t;
+#[cfg(any(
+	target_os = "macos",
+	target_os = "dragonfly",
+	target_os = "freebsd",
+	target_os = "netbsd",
+	target_os = "openbsd"
+))]
+use std::convert::TryInto;
+
+// unix deps
+#[cfg(unix)]
+use libc::close;
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
+
+// windows deps
+#[cfg(windows)]
+use libc::c_void;
+#[cfg(windows)]
+use std::convert::TryInto;
+#[cfg(windows)]
+use wepoll_sys::{
+	epoll_create, epoll_ctl, epoll_data_t, epoll_event, epoll_wait, EPOLLIN, EPOLLOUT, EPOLLRDHUP,
+	EPOLL_CTL_ADD, EPOLL_CTL_DEL, EPOLL_CTL_MOD,
+};
+
+info!();
+
+const MAX_WAIT_MILLIS: u64 = 3000;
+#[cfg(target_os = "linux")]
+const URING_ENTRIES: u32 = 1024;
+
+#[derive(Debug, PartialEq, Clone, Eq, Hash)]
+pub(crate) enum GenericEventType {
+	AddReadET,
+	AddReadLT,
+	AddWriteET,
+	// write interest without read interest, used once the end of the stream was read
+	AddWriteOnlyET,
+	#[cfg(windows)]
+	DelRead,
+	#[cfg(windows)]
+	DelWrite,
+}
+
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+pub(crate) struct GenericEvent {
+	pub(crate) fd: ConnectionHandle,
+	pub(crate) etype: GenericEventType,
+}
+
+impl GenericEvent {
+	pub(crate) fn new(fd: ConnectionHandle, etype: GenericEventType) -> Self {
+		GenericEvent { fd, etype }
+	}
+
+	#[cfg(any(
+		target_os = "macos",
+		target_os = "dragonfly",
+		target_os = "freebsd",
+		target_os = "netbsd",
+		target_os = "openbsd"
+	))]
+	fn to_kev(&self) -> kevent {
+		kevent::new(
+			self.fd as uintptr_t,
+			match &self.etype {
+				GenericEventType::AddReadET => EventFilter::EVFILT_READ,
+				GenericEventType::AddReadLT => EventFilter::EVFILT_READ,
+				//GenericEventType::DelRead => EventFilter::EVFILT_READ,
+				GenericEventType::AddWriteET => EventFilter::EVFILT_WRITE,
+				GenericEventType::AddWriteOnlyET => EventFilter::EVFILT_WRITE,
+				//GenericEventType::DelWrite => EventFilter::EVFILT_WRITE,
+			},
+			match &self.etype {
+				GenericEventType::AddReadET => EventFlag::EV_ADD | EventFlag::EV_CLEAR,
+				GenericEventType::AddReadLT => EventFlag::EV_ADD,
+				//GenericEventType::DelRead => EventFlag::EV_DELETE,
+				GenericEventType::AddWriteET => EventFlag::EV_ADD | EventFlag::EV_CLEAR,
+				GenericEventType::AddWriteOnlyET => EventFlag::EV_ADD | EventFlag::EV_CLEAR,
+				//GenericEventType::DelWrite => EventFlag::EV_DELETE,
+			},
+			FilterFlag::empty(),
+		)
+	}
+}
+
+/// Waits for events on the sockets registered with it and performs the socket i/o of the
+/// [`crate::EventHandler`]'s threads. Each thread owns one selector.
+pub(crate) trait Selector: Send + Sync {
+	/// Register the `input_events`, then wait for events and append them to `output_events`.
+	/// Returns the total number of events in `output_events`. The wait is skipped if `wakeup` is
+	/// set or `output_events` is not empty.
+	fn get_events(
+		&self,
+		input_events: Vec<GenericEvent>,
+		output_events: &mut Vec<GenericEvent>,
+		filter_set: &mut HashSet<ConnectionHandle>,
+		wakeup: bool,
+		max_wait: Option<Duration>,
+		max_events: usize,
+	) -> Result<usize, Error>;
+
+	/// Stop waiting for events on `connection_handle`.
+	fn remove_handle(
+		&self,
+		connection_handle: ConnectionHandle,
+		filter_set: &mut HashSet<ConnectionHandle>,
+	) -> Result<(), Error>;
+
+	/// Release the selector. Called by the owning thread when the [`crate::EventHandler`] stops.
+	fn close(&self) -> Result<(), Error>;
+
+	/// Accept a connection on `listener` and apply `socket_options` to it. Returns the
+	/// non-blocking handle of the new connection and the address of the peer, or None if no
+	/// connection could be accepted.
+	fn accept(
+		&self,
+		listener: ConnectionHandle,
+		socket_options: &SocketOptions,
+	) -> Result<Option<(ConnectionHandle, Option<SocketAddr>)>, Error> {
+		accept_handle(listener, socket_options)
+	}
+
+	/// Read from `handle` into `buf`. Returns the number of bytes read, 0 at the end of the stream
+	/// and a negative value with errno set on error.
+	fn read(&self, handle: ConnectionHandle, buf: &mut [u8]) -> Result<isize, Error> {
+		read_handle(handle, buf)
+	}
+
+	/// Write `bufs` to `handle`. Returns the number of bytes written and a negative value with
+	/// errno set on error.
+	fn write(&self, handle: ConnectionHandle, bufs: &[IoSlice]) -> Result<isize, Error> {
+		write_vectored_data(handle, bufs)
+	}
+
+	/// Close `handle`. Returns a non-zero value with errno set on error.
+	fn close_handle(&self, handle: ConnectionHandle) -> Result<i32, Error> {
+		close_handle(handle)
+	}
+
+	/// Shut down the write side of `handle`. Returns a non-zero value with errno set on error.
+	fn shutdown_write(&self, handle: ConnectionHandle) -> Result<i32, Error> {
+		shutdown_write_handle(handle)
+	}
+
+	/// Send up to `len` bytes of `file` starting at `position` to `handle`. Returns the number of
+	/// bytes sent, 0 at the end of the file and a negative value with errno set on error.
+	fn send_file(
+		&self,
+		handle: ConnectionHandle,
+		file: &File,
+		position: u64,
+		len: usize,
+	) -> Result<isize, Error> {
+		send_file_data(handle, file, position, len)
+	}
+
+	/// Stop waiting for events on `handle` because it is moved to another thread. Returns the
+	/// data the selector received for it that was not read yet and the data it was given to write
+	/// that was not sent yet.
+	fn detach(
+		&self,
+		handle: ConnectionHandle,
+		filter_set: &mut HashSet<ConnectionHandle>,
+	) -> Result<(Vec<u8>, Vec<u8>), Error> {
+		self.remove_handle(handle, filter_set)?;
+		Ok((vec![], vec![]))
+	}
+
+	/// Take over `handle` from the selector of another thread, with the data `received` for it
+	/// that was returned by [`Selector::detach`].
+	fn attach(&self, _handle: ConnectionHandle, _received: Vec<u8>) -> Result<(), Error> {
+		Ok(())
+	}
+
+	/// Returns true if write events are only reported once per registration, in which case a
+	/// connection that still has data to write must register for write events again.
+	fn one_shot_writes(&self) -> bool {
+		false
+	}
+}
+
+/// Create the selector used by one of the [`crate::EventHandler`]'s threads for `backend`.
+#[cfg(target_os = "linux")]
+pub(crate) fn create(backend: Backend) -> Result<Arc<dyn Selector>, Error> {
+	Ok(match backend {
+		Backend::Native => match epoll_create1(EpollCreateFlags::empty()) {
+			Ok(fd) => Arc::new(EpollSelector { fd }),
+			Err(e) => {
+				return Err(ErrorKind::SetupError(format!("epoll_create1 error: {}", e)).into())
+			}
+		},
+		Backend::IoUring => Arc::new(UringSelector {
+			uring: RwLock::new(Uring::new(URING_ENTRIES)?),
+		}),
+	})
+}
+
+/// Create the selector used by one of the [`crate::EventHandler`]'s threads for `backend`.
+#[cfg(any(
+	target_os = "macos",
+	target_os = "dragonfly",
+	target_os = "freebsd",
+	target_os = "netbsd",
+	target_os = "openbsd"
+))]
+pub(crate) fn create(backend: Backend) -> Result<Arc<dyn Selector>, Error> {
+	check_backend(backend)?;
+	Ok(Arc::new(KqueueSelector {
+		fd: unsafe { kqueue() },
+	}))
+}
+
+/// Create the selector used by one of the [`crate::EventHandler`]'s threads for `backend`.
+#[cfg(target_os = "windows")]
+pub(crate) fn create(backend: Backend) -> Result<Arc<dyn Selector>, Error> {
+	check_backend(backend)?;
+	Ok(Arc::new(WepollSelector {
+		handle: unsafe { epoll_create(1) } as usize,
+	}))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn check_backend(backend: Backend) -> Result<(), Error> {
+	match backend {
+		Backend::Native => Ok(()),
+		Backend::IoUring => Err(ErrorKind::SetupError(
+			"the io_uring backend is only available on linux".to_string(),
+		)
+		.into()),
+	}
+}
+
+fn wait_millis(wakeup: bool, max_wait: Option<Duration>) -> u64 {
+	if wakeup {
+		return 0;
+	}
+	match max_wait {
+		Some(max_wait) => {
+			let millis = max_wait.as_nanos().div_ceil(1_000_000) as u64;
+			if millis > MAX_WAIT_MILLIS {
+				MAX_WAIT_MILLIS
+			} else {
+				millis
+			}
+		}
+		None => MAX_WAIT_MILLIS,
+	}
+}
+
+#[cfg(target_os = "linux")]
+struct EpollSelector {
+	fd: RawFd,
+}
+
+#[cfg(target_os = "linux")]
+impl Selector for EpollSelector {
+	fn get_events(
+		&self,
+		input_events: Vec<GenericEvent>,
+		output_events: &mut Vec<GenericEvent>,
+		filter_set: &mut HashSet<ConnectionHandle>,
+		wakeup: bool,
+		max_wait: Option<Duration>,
+		max_events: usize,
+	) -> Result<usize, Error> {
+		let epollfd = self.fd;
+		for evt in input_events {
+			let mut interest = EpollFlags::empty();
+
+			if evt.etype == GenericEventType::AddReadLT {
+				let fd = evt.fd;
+				interest |= EpollFlags::EPOLLIN;
+				interest |= EpollFlags::EPOLLRDHUP;
+
+				let op = if filter_set.remove(&fd) {
+					EpollOp::EpollCtlMod
+				} else {
+					EpollOp::EpollCtlAdd
+				};
+				filter_set.insert(fd);
+
+				let mut event = EpollEvent::new(interest, evt.fd.try_into().unwrap_or(0));
+				let res = epoll_ctl(epollfd, op, evt.fd, &mut event);
+				match res {
+					Ok(_) => {}
+					Err(e) => mainlogerror!("Error epoll_ctl1: {}, fd={}, op={:?}", e, fd, op),
+				}
+			} else if evt.etype == GenericEventType::AddReadET {
+				let fd = evt.fd;
+				interest |= EpollFlags::EPOLLIN;
+				interest |= EpollFlags::EPOLLET;
+				interest |= EpollFlags::EPOLLRDHUP;
+
+				let op = if filter_set.remove(&fd) {
+					EpollOp::EpollCtlMod
+				} else {
+					EpollOp::EpollCtlAdd
+				};
+				filter_set.insert(fd);
+
+				let mut event = EpollEvent::new(interest, evt.fd.try_into().unwrap_or(0));
+				let res = epoll_ctl(epollfd, op, evt.fd, &mut event);
+				match res {
+					Ok(_) => {}
+					Err(e) => mainlogerror!("Error epoll_ctl2: {}, fd={}, op={:?}", e, fd, op),
+				}
+			} else if evt.etype == GenericEventType::AddWriteET {
+				let fd = evt.fd;
+				interest |= EpollFlags::EPOLLOUT;
+				interest |= EpollFlags::EPOLLIN;
+				interest |= EpollFlags::EPOLLRDHUP;
+				interest |= EpollFlags::EPOLLET;
+
+				let op = if filter_set.remove(&fd) {
+					EpollOp::EpollCtlMod
+				} else {
+					EpollOp::EpollCtlAdd
+				};
+				filter_set.insert(fd);
+
+				let mut event = EpollEvent::new(interest, evt.fd.try_into().unwrap_or(0));
+				let res = epoll_ctl(epollfd, op, evt.fd, &mut event);
+				match res {
+					Ok(_) => {}
+					Err(e) => mainlogerror!("Error epoll_ctl3: {}, fd={}, op={:?}", e, fd, op),
+				}
+			} else if evt.etype == GenericEventType::AddWriteOnlyET {
+				let fd = evt.fd;
+				interest |= EpollFlags::EPOLLOUT;
+				interest |= EpollFlags::EPOLLET;
+
+				let op = if filter_set.remove(&fd) {
+					EpollOp::EpollCtlMod
+				} else {
+					EpollOp::EpollCtlAdd
+				};
+				filter_set.insert(fd);
+
+				let mut event = EpollEvent::new(interest, evt.fd.try_into().unwrap_or(0));
+				let res = epoll_ctl(epollfd, op, evt.fd, &mut event);
+				match res {
+					Ok(_) => {}
+					Err(e) => mainlogerror!("Error epoll_ctl5: {}, fd={}, op={:?}", e, fd, op),
+				}
+			} else {
+				return Err(
+					ErrorKind::InternalError(format!("unexpected etype: {:?}", evt.etype)).into(),
+				);
+			}
+		}
+
+		let empty_event = EpollEvent::new(EpollFlags::empty(), 0);
+		let mut events = vec![empty_event; max_events];
+		let results = epoll_wait(
+			epollfd,
+			&mut events,
+			wait_millis(wakeup, max_wait).try_into().unwrap_or(0),
+		);
+
+		let mut ret_count_adjusted = output_events.len();
+
+		match results {
+			Ok(results) => {
+				if results > 0 {
+					for event in &events[..results] {
+						if !(event.events() & EpollFlags::EPOLLOUT).is_empty() {
+							ret_count_adjusted += 1;
+							output_events.push(GenericEvent::new(
+								event.data() as ConnectionHandle,
+								GenericEventType::AddWriteET,
+							));
+						}
+						if !(event.events() & EpollFlags::EPOLLIN).is_empty() {
+							ret_count_adjusted += 1;
+							output_events.push(GenericEvent::new(
+								event.data() as ConnectionHandle,
+								GenericEventType::AddReadET,
+							));
+						}
+					}
+				}
+			}
+			Err(e) => {
+				mainlogerror!("Error with epoll wait = {}", e.to_string());
+			}
+		}
+		Ok(ret_count_adjusted)
+	}
+
+	fn remove_handle(
+		&self,
+		connection_handle: ConnectionHandle,
+		filter_set: &mut HashSet<ConnectionHandle>,
+	) -> Result<(), Error> {
+		filter_set.remove(&connection_handle);
+		let interest = EpollFlags::empty();
+
+		let mut event = EpollEvent::new(interest, connection_handle.try_into().unwrap_or(0));
+		let res = epoll_ctl(self.fd, EpollOp::EpollCtlDel, connection_handle, &mut event);
+		match res {
+			Ok(_) => {}
+			Err(e) => mainlogerror!("Error epoll_ctl4: {}, fd={}, delete", e, connection_handle),
+		}
+
+		Ok(())
+	}
+
+	fn close(&self) -> Result<(), Error> {
+		let _ = unsafe { close(self.fd) };
+		Ok(())
+	}
+}
+
+#[cfg(target_os = "linux")]
+struct UringSelector {
+	uring: RwLock<Uring>,
+}
+
+#[cfg(target_os = "linux")]
+impl Selector for UringSelector {
+	fn get_events(
+		&self,
+		input_events: Vec<GenericEvent>,
+		output_events: &mut Vec<GenericEvent>,
+		filter_set: &mut HashSet<ConnectionHandle>,
+		wakeup: bool,
+		max_wait: Option<Duration>,
+		max_events: usize,
+	) -> Result<usize, Error> {
+		let mut uring = nioruntime_util::lockw!(self.uring)?;
+		for evt in input_events {
+			filter_set.insert(evt.fd);
+			match evt.etype {
+				GenericEventType::AddReadET | GenericEventType::AddReadLT => {
+					uring.add_read(evt.fd)?
+				}
+				GenericEventType::AddWriteET => uring.add_write(evt.fd)?,
+				GenericEventType::AddWriteOnlyET => uring.add_write_only(evt.fd)?,
+			}
+		}
+
+		let mut events = vec![];
+		uring.wait(
+			// don't wait if there are already events to process
+			wait_millis(wakeup || !output_events.is_empty(), max_wait),
+			max_events,
+			&mut events,
+		)?;
+		for (fd, readiness) in events {
+			output_events.push(GenericEvent::new(
+				fd,
+				match readiness {
+					Readiness::Read => GenericEventType::AddReadET,
+					Readiness::Write => GenericEventType::AddWriteET,
+				},
+			));
+		}
+		Ok(output_events.len())
+	}
+
+	fn remove_handle(
+		&self,
+		connection_handle: ConnectionHandle,
+		filter_set: &mut HashSet<ConnectionHandle>,
+	) -> Result<(), Error> {
+		filter_set.remove(&connection_handle);
+		nioruntime_util::lockw!(self.uring)?.remove(connection_handle)
+	}
+
+	fn close(&self) -> Result<(), Error> {
+		// the ring is released when the last reference to the selector is dropped
+		Ok(())
+	}
+
+	fn accept(
+		&self,
+		listener: ConnectionHandle,
+		socket_options: &SocketOptions,
+	) -> Result<Option<(ConnectionHandle, Option<SocketAddr>)>, Error> {
+		let accepted = nioruntime_util::lockw!(self.uring)?.accept(listener)?;
+		match accepted {
+			Some(Some((handle, storage))) => {
+				let peer_addr = accepted_peer_addr(handle, &storage, socket_options);
+				Ok(Some((handle, peer_addr)))
+			}
+			Some(None) => Ok(None),
+			// not accepted by the ring
+			None => accept_handle(listener, socket_options),
+		}
+	}
+
+	fn read(&self, handle: ConnectionHandle, buf: &mut [u8]) -> Result<isize, Error> {
+		match nioruntime_util::lockw!(self.uring)?.read(handle, buf)? {
+			Some(len) => Ok(len),
+			None => read_handle(handle, buf),
+		}
+	}
+
+	fn write(&self, handle: ConnectionHandle, bufs: &[IoSlice]) -> Result<isize, Error> {
+		match nioruntime_util::lockw!(self.uring)?.write(handle, bufs)? {
+			Some(len) => Ok(len),
+			None => write_vectored_data(handle, bufs),
+		}
+	}
+
+	fn close_handle(&self, handle: ConnectionHandle) -> Result<i32, Error> {
+		nioruntime_util::lockw!(self.uring)?.close(handle);
+		close_handle(handle)
+	}
+
+	fn shutdown_write(&self, handle: ConnectionHandle) -> Result<i32, Error> {
+		match nioruntime_util::lockw!(self.uring)?.shutdown_write(handle) {
+			true => Ok(0),
+			false => shutdown_write_handle(handle),
+		}
+	}
+
+	fn send_file(
+		&self,
+		handle: ConnectionHandle,
+		file: &File,
+		position: u64,
+		len: usize,
+	) -> Result<isize, Error> {
+		// the file is sent once the data written before it was
+		if nioruntime_util::lockr!(self.uring)?.is_sending(handle) {
+			errno::set_errno(errno::Errno(libc::EAGAIN));
+			return Ok(-1);
+		}
+		send_file_data(handle, file, position, len)
+	}
+
+	fn detach(
+		&self,
+		handle: ConnectionHandle,
+		filter_set: &mut HashSet<ConnectionHandle>,
+	) -> Result<(Vec<u8>, Vec<u8>), Error> {
+		filter_set.remove(&handle);
+		nioruntime_util::lockw!(self.uring)?.detach(handle)
+	}
+
+	fn attach(&self, handle: ConnectionHandle, received: Vec<u8>) -> Result<(), Error> {
+		nioruntime_util::lockw!(self.uring)?.attach(handle, received)
+	}
+
+	fn one_shot_writes(&self) -> bool {
+		true
+	}
+}
+
+#[cfg(any(
+	target_os = "macos",
+	target_os = "dragonfly",
+	target_os = "freebsd",
+	target_os = "netbsd",
+	target_os = "openbsd"
+))]
+struct KqueueSelector {
+	fd: RawFd,
+}
+
+#[cfg(any(
+	target_os = "macos",
+	target_os = "dragonfly",
+	target_os = "freebsd",
+	target_os = "netbsd",
+	target_os = "openbsd"
+))]
+impl Selector for KqueueSelector {
+	fn get_events(
+		&self,
+		input_events: Vec<GenericEvent>,
+		output_events: &mut Vec<GenericEvent>,
+		_filter_set: &mut HashSet<ConnectionHandle>,
+		wakeup: bool,
+		max_wait: Option<Duration>,
+		max_events: usize,
+	) -> Result<usize, Error> {
+		let queue = self.fd;
+		let mut kevs = vec![];
+		for ev in input_events {
+			kevs.push(ev.to_kev());
+		}
+
+		let mut ret_kevs = vec![];
+		for _ in 0..max_events {
+			ret_kevs.push(kevent::new(
+				0,
+				EventFilter::EVFILT_SYSCOUNT,
+				EventFlag::empty(),
+				FilterFlag::empty(),
+			));
+		}
+		let ret_count = unsafe {
+			kevent(
+				queue,
+				kevs.as_ptr(),
+				kevs.len() as i32,
+				ret_kevs.as_mut_ptr(),
+				max_events.try_into().unwrap_or(i32::MAX),
+				&duration_to_timespec(std::time::Duration::from_millis(match wakeup {
+					true => 1,
+					false => wait_millis(false, max_wait),
+				})),
+			)
+		};
+
+		if ret_count < 0 {
+			mainlogerror!("Error in kevent: kevs={:?}, error={}", kevs, errno());
+		}
+
+		let mut ret_count_adjusted = output_events.len();
+		for i in 0..ret_count {
+			let kev = ret_kevs[i as usize];
+			if !kev.flags.contains(EventFlag::EV_DELETE) {
+				if kev.filter == EVFILT_WRITE {
+					ret_count_adjusted += 1;
+					output_events.push(GenericEvent::new(
+						kev.ident.try_into().unwrap_or(0),
+						GenericEventType::AddWriteET,
+					));
+				}
+				if kev.filter == EVFILT_READ {
+					ret_count_adjusted += 1;
+					output_events.push(GenericEvent::new(
+						kev.ident.try_into().unwrap_or(0),
+						GenericEventType::AddReadLT,
+					));
+				}
+			}
+		}
+
+		Ok(ret_count_adjusted)
+	}
+
+	fn remove_handle(
+		&self,
+		connection_handle: ConnectionHandle,
+		_filter_set: &mut HashSet<ConnectionHandle>,
+	) -> Result<(), Error> {
+		let mut kevs = vec![];
+		kevs.push(kevent::new(
+			connection_handle as uintptr_t,
+			EventFilter::EVFILT_READ,
+			EventFlag::EV_DELETE,
+			FilterFlag::empty(),
+		));
+
+		let mut ret_kevs = vec![];
+		let ret_count = unsafe {
+			kevent(
+				self.fd,
+				kevs.as_ptr(),
+				kevs.len() as i32,
+				ret_kevs.as_mut_ptr(),
+				0,
+				&duration_to_timespec(std::time::Duration::from_millis(0)),
+			)
+		};
+
+		if ret_count < 0 {
+			mainlogerror!(
+				"Error in kevent (remove handle): kevs={:?}, error={}",
+				kevs,
+				errno()
+			);
+		}
+		Ok(())
+	}
+
+	fn close(&self) -> Result<(), Error> {
+		let _ = unsafe { close(self.fd) };
+		Ok(())
+	}
+}
+
+#[cfg(target_os = "windows")]
+struct WepollSelector {
+	// the wepoll handle, stored as usize so that the selector can be shared between threads
+	handle: usize,
+}
+
+#[cfg(target_os = "windows")]
+impl Selector for WepollSelector {
+	fn get_events(
+		&self,
+		input_events: Vec<GenericEvent>,
+		output_events: &mut Vec<GenericEvent>,
+		filter_set: &mut HashSet<ConnectionHandle>,
+		_wakeup: bool,
+		max_wait: Option<Duration>,
+		max_events: usize,
+	) -> Result<usize, Error> {
+		let selector = self.handle as *mut c_void;
+		for evt in input_events {
+			if evt.etype == GenericEventType::AddReadET
+				|| evt.etype == GenericEventType::AddReadET
+				|| evt.etype == GenericEventType::DelWrite
+				|| evt.etype == GenericEventType::AddReadLT
+			{
+				let op = if filter_set.remove(&evt.fd) {
+					EPOLL_CTL_MOD
+				} else {
+					EPOLL_CTL_ADD
+				};
+				filter_set.insert(evt.fd);
+				let data = epoll_data_t {
+					fd: evt.fd.try_into().unwrap_or(0),
+				};
+				let mut event = epoll_event {
+					events: EPOLLIN | EPOLLRDHUP,
+					data,
+				};
+				let res = unsafe {
+					epoll_ctl(
+						selector,
+						op.try_into().unwrap_or(0),
+						evt.fd as usize,
+						&mut event,
+					)
+				};
+				if res != 0 {
+					// normal occurance, just means the socket is already closed
+					// must remove from filter set for next request
+					filter_set.remove(&evt.fd);
+				}
+			} else if evt.etype == GenericEventType::AddWriteET
+				|| evt.etype == GenericEventType::AddWriteOnlyET
+			{
+				let op = if filter_set.remove(&evt.fd) {
+					EPOLL_CTL_MOD
+				} else {
+					EPOLL_CTL_ADD
+				};
+				filter_set.insert(evt.fd);
+				let data = epoll_data_t {
+					fd: evt.fd.try_into().unwrap_or(0),
+				};
+				let mut event = epoll_event {
+					events: match evt.etype {
+						GenericEventType::AddWriteOnlyET => EPOLLOUT,
+						_ => EPOLLIN | EPOLLOUT | EPOLLRDHUP,
+					},
+					data,
+				};
+				let res = unsafe {
+					epoll_ctl(
+						selector,
+						op.try_into().unwrap_or(0),
+						evt.fd.try_into().unwrap_or(0),
+						&mut event,
+					)
+				};
+				if res != 0 {
+					filter_set.remove(&evt.fd);
+					mainlogerror!(
+						"epoll_ctl (write) resulted in an unexpected error: {}, fd={}, op={}, epoll_ctl_add={}",
+						errno().to_string(), evt.fd, op, EPOLL_CTL_ADD,
+					);
+				}
+			} else if evt.etype == GenericEventType::DelRead {
+				filter_set.remove(&evt.fd);
+				let data = epoll_data_t {
+					fd: evt.fd.try_into().unwrap_or(0),
+				};
+				let mut event = epoll_event {
+					events: 0, // not used for del
+					data,
+				};
+
+				let res = unsafe {
+					epoll_ctl(
+						selector,
+						EPOLL_CTL_DEL.try_into().unwrap_or(0),
+						evt.fd.try_into().unwrap_or(0),
+						&mut event,
+					)
+				};
+
+				if res != 0 {
+					mainlogerror!(
+						"epoll_ctl (del) resulted in unexpected error: {}",
+						errno().to_string(),
+					);
+				}
+			} else {
+				return Err(
+					ErrorKind::InternalError(format!("unexpected etype: {:?}", evt.etype)).into(),
+				);
+			}
+		}
+		let mut events: Vec<epoll_event> = Vec::with_capacity(max_events);
+		let results = unsafe {
+			epoll_wait(
+				selector,
+				events.as_mut_ptr(),
+				max_events.try_into().unwrap_or(i32::MAX),
+				// don't wait if there are already events to process
+				wait_millis(!output_events.is_empty(), max_wait)
+					.try_into()
+					.unwrap_or(0),
+			)
+		};
+		let mut ret_count_adjusted = output_events.len();
+
+		if results > 0 {
+			unsafe { events.set_len(results as usize) };
+			for i in 0..results {
+				if !(events[i as usize].events & EPOLLOUT == 0) {
+					ret_count_adjusted += 1;
+					output_events.push(GenericEvent::new(
+						unsafe { events[i as usize].data.fd } as ConnectionHandle,
+						GenericEventType::AddWriteET,
+					));
+				}
+				if !(events[i as usize].events & EPOLLIN == 0) {
+					ret_count_adjusted += 1;
+					output_events.push(GenericEvent::new(
+						unsafe { events[i as usize].data.fd } as ConnectionHandle,
+						GenericEventType::AddReadET,
+					));
+				}
+				if events[i as usize].events & (EPOLLIN | EPOLLOUT) == 0 {
+					let fd = unsafe { events[i as usize].data.fd };
+					let data = epoll_data_t {
+						fd: fd.try_into().unwrap_or(0),
+					};
+					let mut event = epoll_event {
+						events: 0, // not used for del
+						data,
+					};
+					let res = unsafe {
+						epoll_ctl(
+							selector,
+							EPOLL_CTL_DEL.try_into().unwrap_or(0),
+							fd.try_into().unwrap_or(0),
+							&mut event,
+						)
+					};
+
+					if res != 0 {
+						mainlogerror!(
+							"Unexpected error with EPOLLHUP. res = {}, err={}",
+							res,
+							errno().to_string(),
+						);
+					}
+				}
+			}
+		}
+
+		Ok(ret_count_adjusted)
+	}
+
+	fn remove_handle(
+		&self,
+		connection_handle: ConnectionHandle,
+		filter_set: &mut HashSet<ConnectionHandle>,
+	) -> Result<(), Error> {
+		let selector = self.handle as *mut c_void;
+		filter_set.remove(&connection_handle);
+		let data = epoll_data_t {
+			fd: connection_handle.try_into().unwrap_or(0),
+		};
+		let mut event = epoll_event {
+			events: EPOLLIN | EPOLLOUT | EPOLLRDHUP,
+			data,
+		};
+
+		let res = unsafe {
+			epoll_ctl(
+				selector,
+				EPOLL_CTL_DEL.try_into().unwrap(),
+				connection_handle.try_into().unwrap_or(0),
+				&mut event,
+			)
+		};
+
+		if res != 0 {
+			mainlogerror!(
+				"Error epoll_ctl4: {}, fd={}, delete",
+				errno(),
+				connection_handle
+			);
+		}
+		Ok(())
+	}
+
+	fn close(&self) -> Result<(), Error> {
+		let _ = unsafe { ws2_32::closesocket((self.handle as u64).try_into().unwrap_or(0)) };
+		Ok(())
+	}
+}
+
+/// An in-memory network used to test the [`crate::EventHandler`] without real sockets. Sockets
+/// are identified by fake handles, the data read by the [`crate::EventHandler`] and the
+/// connections it accepts are queued by the test and everything it writes is recorded. Each
+/// socket can also be given a script of write results to simulate partial writes and EAGAIN.
+#[cfg(all(test, unix))]
+#[derive(Clone)]
+pub(crate) struct MockNetwork {
+	state: Arc<std::sync::RwLock<MockState>>,
+}
+
+#[cfg(all(test, unix))]
+struct MockState {
+	sockets: std::collections::BTreeMap<ConnectionHandle, MockSocket>,
+	next_handle: ConnectionHandle,
+	next_selector: usize,
+}
+
+#[cfg(all(test, unix))]
+#[derive(Default)]
+struct MockSocket {
+	// the data to be read by the EventHandler
+	inbound: std::collections::VecDeque<u8>,
+	eof: bool,
+	// the connections waiting to be accepted if the socket is a listener
+	backlog: std::collections::VecDeque<ConnectionHandle>,
+	peer_addr: Option<SocketAddr>,
+	// the data written by the EventHandler and the result of each write
+	written: Vec<u8>,
+	writes: Vec<isize>,
+	// Some(n) accepts at most n bytes, None fails with EAGAIN. Writes are complete once empty.
+	write_script: std::collections::VecDeque<Option<usize>>,
+	closed: bool,
+	// set by shutdown_write, later writes fail with EPIPE
+	write_shutdown: bool,
+	read_interest: bool,
+	write_interest: bool,
+	// the id of the selector the socket was last registered with. Only that selector reports
+	// its events.
+	selector: usize,
+}
+
+#[cfg(all(test, unix))]
+impl MockNetwork {
+	pub(crate) fn new() -> Self {
+		MockNetwork {
+			state: Arc::new(std::sync::RwLock::new(MockState {
+				sockets: std::collections::BTreeMap::new(),
+				// well above any real fd
+				next_handle: 1 << 30,
+				next_selector: 0,
+			})),
+		}
+	}
+
+	/// Returns a new selector on this network.
+	pub(crate) fn selector(&self) -> Arc<dyn Selector> {
+		let id = {
+			let mut state = self.state.write().unwrap();
+			state.next_selector += 1;
+			state.next_selector
+		};
+		Arc::new(MockSelector {
+			id,
+			network: self.clone(),
+			fds: std::sync::RwLock::new(HashSet::new()),
+		})
+	}
+
+	/// Create a socket, to be used as a listener or a stream.
+	pub(crate) fn socket(&self) -> Result<ConnectionHandle, Error> {
+		let mut state = nioruntime_util::lockw!(self.state)?;
+		let handle = state.next_handle;
+		state.next_handle += 1;
+		state.sockets.insert(handle, MockSocket::default());
+		Ok(handle)
+	}
+
+	/// Queue a new connection on `listener` and return its handle.
+	pub(crate) fn connect(&self, listener: ConnectionHandle) -> Result<ConnectionHandle, Error> {
+		let handle = self.socket()?;
+		self.with_socket(listener, |socket| socket.backlog.push_back(handle))?;
+		Ok(handle)
+	}
+
+	/// Queue a new connection from `peer_addr` on `listener` and return its handle.
+	pub(crate) fn connect_from(
+		&self,
+		listener: ConnectionHandle,
+		peer_addr: SocketAddr,
+	) -> Result<ConnectionHandle, Error> {
+		let handle = self.connect(listener)?;
+		self.with_socket(handle, |socket| socket.peer_addr = Some(peer_addr))?;
+		Ok(handle)
+	}
+
+	/// Queue `data` to be read on `handle`.
+	pub(crate) fn push_data(&self, handle: ConnectionHandle, data: &[u8]) -> Result<(), Error> {
+		self.with_socket(handle, |socket| socket.inbound.extend(data))
+	}
+
+	/// End the stream of `handle` once its queued data has been read.
+	pub(crate) fn push_eof(&self, handle: ConnectionHandle) -> Result<(), Error> {
+		self.with_socket(handle, |socket| socket.eof = true)
+	}
+
+	/// Set the results of the next writes on `handle`.
+	pub(crate) fn script_writes(
+		&self,
+		handle: ConnectionHandle,
+		script: &[Option<usize>],
+	) -> Result<(), Error> {
+		self.with_socket(handle, |socket| socket.write_script.extend(script))
+	}
+
+	/// Returns the data written on `handle`.
+	pub(crate) fn written(&self, handle: ConnectionHandle) -> Result<Vec<u8>, Error> {
+		self.with_socket(handle, |socket| socket.written.clone())
+	}
+
+	/// Returns the result of each write on `handle`, -1 for EAGAIN.
+	pub(crate) fn writes(&self, handle: ConnectionHandle) -> Result<Vec<isize>, Error> {
+		self.with_socket(handle, |socket| socket.writes.clone())
+	}
+
+	/// Returns true if `handle` was closed.
+	pub(crate) fn is_closed(&self, handle: ConnectionHandle) -> Result<bool, Error> {
+		self.with_socket(handle, |socket| socket.closed)
+	}
+
+	/// Returns true if the write side of `handle` was shut down.
+	pub(crate) fn is_write_shutdown(&self, handle: ConnectionHandle) -> Result<bool, Error> {
+		self.with_socket(handle, |socket| socket.write_shutdown)
+	}
+
+	fn with_socket<T, F>(&self, handle: ConnectionHandle, f: F) -> Result<T, Error>
+	where
+		F: FnOnce(&mut MockSocket) -> T,
+	{
+		let mut state = nioruntime_util::lockw!(self.state)?;
+		match state.sockets.get_mut(&handle) {
+			Some(socket) => Ok(f(socket)),
+			None => {
+				Err(ErrorKind::InternalError(format!("unknown mock socket: {}", handle)).into())
+			}
+		}
+	}
+
+	fn is_mock(&self, handle: ConnectionHandle) -> Result<bool, Error> {
+		let state = nioruntime_util::lockr!(self.state)?;
+		Ok(state.sockets.contains_key(&handle))
+	}
+}
+
+// Reports the events of the MockNetwork's sockets. Reads are level triggered and writes are
+// reported once per AddWriteET. Real fds, such as the wakeup pipes, are polled.
+#[cfg(all(test, unix))]
+struct MockSelector {
+	id: usize,
+	network: MockNetwork,
+	fds: std::sync::RwLock<HashSet<ConnectionHandle>>,
+}
+
+#[cfg(all(test, unix))]
+impl Selector for MockSelector {
+	fn get_events(
+		&self,
+		input_events: Vec<GenericEvent>,
+		output_events: &mut Vec<GenericEvent>,
+		filter_set: &mut HashSet<ConnectionHandle>,
+		wakeup: bool,
+		max_wait: Option<Duration>,
+		max_events: usize,
+	) -> Result<usize, Error> {
+		let mut fds = nioruntime_util::lockw!(self.fds)?;
+		{
+			let mut state = nioruntime_util::lockw!(self.network.state)?;
+			for evt in input_events {
+				filter_set.insert(evt.fd);
+				match state.sockets.get_mut(&evt.fd) {
+					Some(socket) => {
+						socket.selector = self.id;
+						match evt.etype {
+							GenericEventType::AddWriteET => {
+								socket.read_interest = true;
+								socket.write_interest = true;
+							}
+							GenericEventType::AddWriteOnlyET => socket.write_interest = true,
+							_ => socket.read_interest = true,
+						}
+					}
+					None => {
+						fds.insert(evt.fd);
+					}
+				}
+			}
+		}
+
+		let timeout =
+			Duration::from_millis(wait_millis(wakeup || !output_events.is_empty(), max_wait));
+		let start = std::time::Instant::now();
+		loop {
+			let mut count = 0;
+			{
+				let mut state = nioruntime_util::lockw!(self.network.state)?;
+				for (handle, socket) in state.sockets.iter_mut() {
+					if count >= max_events {
+						break;
+					}
+					if socket.closed || socket.selector != self.id {
+						continue;
+					}
+					if socket.write_interest {
+						socket.write_interest = false;
+						output_events
+							.push(GenericEvent::new(*handle, GenericEventType::AddWriteET));
+						count += 1;
+					}
+					if socket.read_interest
+						&& (!socket.inbound.is_empty() || socket.eof || !socket.backlog.is_empty())
+					{
+						output_events.push(GenericEvent::new(*handle, GenericEventType::AddReadET));
+						count += 1;
+					}
+				}
+			}
+
+			let remaining = timeout
+				.checked_sub(start.elapsed())
+				.unwrap_or(Duration::from_millis(0));
+			let wait = match count {
+				0 => std::cmp::min(remaining, Duration::from_millis(10)).as_millis(),
+				_ => 0,
+			};
+			let mut pollfds: Vec<libc::pollfd> = fds
+				.iter()
+				.map(|fd| libc::pollfd {
+					fd: *fd,
+					events: libc::POLLIN,
+					revents: 0,
+				})
+				.collect();
+			let res = unsafe {
+				libc::poll(
+					pollfds.as_mut_ptr(),
+					pollfds.len() as libc::nfds_t,
+					wait as i32,
+				)
+			};
+			if res > 0 {
+				for pollfd in pollfds {
+					if pollfd.revents != 0 {
+						output_events
+							.push(GenericEvent::new(pollfd.fd, GenericEventType::AddReadLT));
+						count += 1;
+					}
+				}
+			}
+
+			if count > 0 || remaining.as_millis() == 0 {
+				return Ok(output_events.len());
+			}
+		}
+	}
+
+	fn remove_handle(
+		&self,
+		connection_handle: ConnectionHandle,
+		filter_set: &mut HashSet<ConnectionHandle>,
+	) -> Result<(), Error> {
+		filter_set.remove(&connection_handle);
+		nioruntime_util::lockw!(self.fds)?.remove(&connection_handle);
+		let mut state = nioruntime_util::lockw!(self.network.state)?;
+		match state.sockets.get_mut(&connection_handle) {
+			Some(socket) if socket.selector == self.id => {
+				socket.read_interest = false;
+				socket.write_interest = false;
+			}
+			_ => {}
+		}
+		Ok(())
+	}
+
+	fn close(&self) -> Result<(), Error> {
+		Ok(())
+	}
+
+	fn accept(
+		&self,
+		listener: ConnectionHandle,
+		socket_options: &SocketOptions,
+	) -> Result<Option<(ConnectionHandle, Option<SocketAddr>)>, Error> {
+		if !self.network.is_mock(listener)? {
+			return accept_handle(listener, socket_options);
+		}
+		let handle = self
+			.network
+			.with_socket(listener, |socket| socket.backlog.pop_front())?;
+		match handle {
+			Some(handle) => {
+				let peer_addr = self
+					.network
+					.with_socket(handle, |socket| socket.peer_addr)?;
+				Ok(Some((handle, peer_addr)))
+			}
+			None => Ok(None),
+		}
+	}
+
+	fn read(&self, handle: ConnectionHandle, buf: &mut [u8]) -> Result<isize, Error> {
+		if !self.network.is_mock(handle)? {
+			return read_handle(handle, buf);
+		}
+		self.network.with_socket(handle, |socket| {
+			if socket.closed {
+				errno::set_errno(errno::Errno(libc::EBADF));
+				return -1;
+			}
+			let len = std::cmp::min(buf.len(), socket.inbound.len());
+			for (i, b) in socket.inbound.drain(..len).enumerate() {
+				buf[i] = b;
+			}
+			if len == 0 && !socket.eof {
+				errno::set_errno(errno::Errno(libc::EAGAIN));
+				return -1;
+			}
+			len as isize
+		})
+	}
+
+	fn write(&self, handle: ConnectionHandle, bufs: &[IoSlice]) -> Result<isize, Error> {
+		if !self.network.is_mock(handle)? {
+			return write_vectored_data(handle, bufs);
+		}
+		self.network.with_socket(handle, |socket| {
+			if socket.closed {
+				errno::set_errno(errno::Errno(libc::EBADF));
+				return -1;
+			}
+			if socket.write_shutdown {
+				errno::set_errno(errno::Errno(libc::EPIPE));
+				return -1;
+			}
+			let total: usize = bufs.iter().map(|buf| buf.len()).sum();
+			let len = match socket.write_script.pop_front() {
+				Some(Some(max)) => std::cmp::min(max, total),
+				Some(None) => {
+					socket.writes.push(-1);
+					errno::set_errno(errno::Errno(libc::EAGAIN));
+					return -1;
+				}
+				None => total,
+			};
+			let mut remaining = len;
+			for buf in bufs {
+				let amt = std::cmp::min(remaining, buf.len());
+				socket.written.extend_from_slice(&buf[..amt]);
+				remaining -= amt;
+			}
+			socket.writes.push(len as isize);
+			len as isize
+		})
+	}
+
+	fn close_handle(&self, handle: ConnectionHandle) -> Result<i32, Error> {
+		if !self.network.is_mock(handle)? {
+			return close_handle(handle);
+		}
+		self.network
+			.with_socket(handle, |socket| match socket.closed {
+				true => {
+					errno::set_errno(errno::Errno(libc::EBADF));
+					-1
+				}
+				false => {
+					socket.closed = true;
+					0
+				}
+			})
+	}
+
+	fn shutdown_write(&self, handle: ConnectionHandle) -> Result<i32, Error> {
+		if !self.network.is_mock(handle)? {
+			return shutdown_write_handle(handle);
+		}
+		self.network
+			.with_socket(handle, |socket| match socket.closed {
+				true => {
+					errno::set_errno(errno::Errno(libc::EBADF));
+					-1
+				}
+				false => {
+					socket.write_shutdown = true;
+					0
+				}
+			})
+	}
+
+	fn one_shot_writes(&self) -> bool {
+		// a write event is reported once per AddWriteET
+		true
+	}
+}
@@ -0,0 +1,191 @@
+// Copyright 2021 The BMW Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License
+
+use nioruntime_err::{Error, ErrorKind};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+const TICK_MILLIS: u64 = 10;
+const SLOT_COUNT: usize = 1024;
+
+/// A handle to a timer that was scheduled with [`crate::EventHandler::schedule`].
+///
+/// The handle may be used to cancel the timer before it fires. Cancelling a timer that
+/// has already fired has no effect.
+#[derive(Clone)]
+pub struct TimerHandle {
+	cancelled: Arc<RwLock<bool>>,
+}
+
+impl TimerHandle {
+	pub(crate) fn new() -> Self {
+		TimerHandle {
+			cancelled: Arc::new(RwLock::new(false)),
+		}
+	}
+
+	/// Cancel this timer. The associated callback will not be executed.
+	pub fn cancel(&self) -> Result<(), Error> {
+		let mut cancelled = nioruntime_util::lockw!(self.cancelled)?;
+		*cancelled = true;
+		Ok(())
+	}
+
+	/// Returns true if [`TimerHandle::cancel`] has been called on this timer.
+	pub fn is_cancelled(&self) -> Result<bool, Error> {
+		let cancelled = nioruntime_util::lockr!(self.cancelled)?;
+		Ok(*cancelled)
+	}
+}
+
+struct TimerEntry<T> {
+	deadline: Instant,
+	value: T,
+}
+
+/// A hashed timer wheel. Each slot covers one tick. Entries whose deadline is more than
+/// one rotation away stay in their slot until a later pass over it finds them expired.
+pub(crate) struct TimerWheel<T> {
+	slots: Vec<Vec<TimerEntry<T>>>,
+	current: usize,
+	last_tick: Instant,
+	tick: Duration,
+	count: usize,
+}
+
+impl<T> TimerWheel<T> {
+	pub(crate) fn new() -> Self {
+		let mut slots = vec![];
+		for _ in 0..SLOT_COUNT {
+			slots.push(vec![]);
+		}
+		TimerWheel {
+			slots,
+			current: 0,
+			last_tick: Instant::now(),
+			tick: Duration::from_millis(TICK_MILLIS),
+			count: 0,
+		}
+	}
+
+	/// Insert a value which will be returned by [`TimerWheel::expire`] once `deadline`
+	/// has passed.
+	pub(crate) fn insert(&mut self, deadline: Instant, value: T) {
+		let ticks = if deadline > self.last_tick {
+			let nanos = (deadline - self.last_tick).as_nanos();
+			let tick = self.tick.as_nanos();
+			nanos.div_ceil(tick) as usize
+		} else {
+			1
+		};
+		let slot = (self.current + ticks) % SLOT_COUNT;
+		self.slots[slot].push(TimerEntry { deadline, value });
+		self.count += 1;
+	}
+
+	/// Return all values whose deadline is at or before `now`.
+	pub(crate) fn expire(&mut self, now: Instant) -> Vec<T> {
+		let mut ret = vec![];
+		if now < self.last_tick {
+			return ret;
+		}
+		let elapsed = ((now - self.last_tick).as_nanos() / self.tick.as_nanos()) as usize;
+		if elapsed == 0 {
+			return ret;
+		}
+
+		let to_check = if elapsed >= SLOT_COUNT {
+			SLOT_COUNT
+		} else {
+			elapsed
+		};
+		for i in 1..to_check + 1 {
+			let slot = (self.current + i) % SLOT_COUNT;
+			self.expire_slot(slot, now, &mut ret);
+		}
+
+		self.current = (self.current + elapsed) % SLOT_COUNT;
+		self.last_tick += self.tick * elapsed as u32;
+		ret
+	}
+
+	/// Returns the amount of time until the next non-empty slot is reached or None
+	/// if there are no timers.
+	pub(crate) fn next_timeout(&self, now: Instant) -> Option<Duration> {
+		if self.count == 0 {
+			return None;
+		}
+		for i in 1..SLOT_COUNT + 1 {
+			let slot = (self.current + i) % SLOT_COUNT;
+			if !self.slots[slot].is_empty() {
+				let tick_time = self.last_tick + self.tick * i as u32;
+				return Some(if tick_time > now {
+					tick_time - now
+				} else {
+					Duration::from_millis(0)
+				});
+			}
+		}
+		None
+	}
+
+	/// Number of values currently held by this timer wheel.
+	pub(crate) fn len(&self) -> usize {
+		self.count
+	}
+
+	fn expire_slot(&mut self, slot: usize, now: Instant, ret: &mut Vec<T>) {
+		let entries = std::mem::take(&mut self.slots[slot]);
+		for entry in entries {
+			if entry.deadline <= now {
+				self.count -= 1;
+				ret.push(entry.value);
+			} else {
+				self.slots[slot].push(entry);
+			}
+		}
+	}
+}
+
+#[test]
+fn test_timer_wheel() -> Result<(), Error> {
+	let mut wheel = TimerWheel::new();
+	let start = Instant::now();
+	wheel.insert(start + Duration::from_millis(25), 1);
+	wheel.insert(start + Duration::from_millis(50), 2);
+	// more than one rotation away
+	wheel.insert(
+		start + Duration::from_millis(TICK_MILLIS * SLOT_COUNT as u64 + 30),
+		3,
+	);
+	assert_eq!(wheel.len(), 3);
+	assert!(wheel.next_timeout(start).unwrap() <= Duration::from_millis(30));
+
+	assert_eq!(
+		wheel.expire(start + Duration::from_millis(5)),
+		Vec::<i32>::new()
+	);
+	assert_eq!(wheel.expire(start + Duration::from_millis(40)), vec![1]);
+	assert_eq!(wheel.expire(start + Duration::from_millis(60)), vec![2]);
+	assert_eq!(wheel.len(), 1);
+
+	// a full rotation passes but the remaining timer is not yet due
+	let later = start + Duration::from_millis(TICK_MILLIS * SLOT_COUNT as u64 + 5);
+	assert_eq!(wheel.expire(later), Vec::<i32>::new());
+	let later = start + Duration::from_millis(TICK_MILLIS * SLOT_COUNT as u64 + 60);
+	assert_eq!(wheel.expire(later), vec![3]);
+	assert_eq!(wheel.len(), 0);
+	assert_eq!(wheel.next_timeout(later), None);
+	Ok(())
+}
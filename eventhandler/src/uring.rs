@@ -0,0 +1,1123 @@
+// Copyright 2021 The BMW Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// A minimal io_uring selector. The i/o of stream sockets is submitted to the ring: each stream
+// has a recv in progress into a buffer owned by the ring whose completion is reported as a read
+// event, the data passed to write is copied to a send buffer and sent by the ring, and listeners
+// have an accept in progress. Other handles, such as the wakeup pipes and datagram sockets, are
+// polled. Read events of streams and listeners are reported once per completion, while write
+// interest is one-shot and must be requested again, which matches the edge triggered writes of
+// the epoll selector.
+
+use errno::errno;
+use nioruntime_err::{Error, ErrorKind};
+use std::collections::{HashMap, VecDeque};
+use std::io::IoSlice;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x8000000;
+const IORING_OFF_SQES: libc::off_t = 0x10000000;
+const IORING_ENTER_GETEVENTS: u32 = 1;
+const IORING_OP_POLL_ADD: u8 = 6;
+const IORING_OP_POLL_REMOVE: u8 = 7;
+const IORING_OP_TIMEOUT: u8 = 11;
+const IORING_OP_ACCEPT: u8 = 13;
+const IORING_OP_ASYNC_CANCEL: u8 = 14;
+const IORING_OP_SEND: u8 = 26;
+const IORING_OP_RECV: u8 = 27;
+
+// user_data of the operations whose completions are not reported
+const IGNORED: u64 = u64::MAX;
+// the size of the buffer each stream receives into
+const RECV_BUFFER_SIZE: usize = 16 * 1024;
+// the most data copied to the send buffer of a stream by one write
+const MAX_SEND: usize = 256 * 1024;
+// the number of times the ring waits for its operations to be cancelled when it is dropped
+const DROP_ATTEMPTS: usize = 100;
+
+#[repr(C)]
+#[derive(Default)]
+struct SqringOffsets {
+	head: u32,
+	tail: u32,
+	ring_mask: u32,
+	ring_entries: u32,
+	flags: u32,
+	dropped: u32,
+	array: u32,
+	resv1: u32,
+	user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct CqringOffsets {
+	head: u32,
+	tail: u32,
+	ring_mask: u32,
+	ring_entries: u32,
+	overflow: u32,
+	cqes: u32,
+	flags: u32,
+	resv1: u32,
+	user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+	sq_entries: u32,
+	cq_entries: u32,
+	flags: u32,
+	sq_thread_cpu: u32,
+	sq_thread_idle: u32,
+	features: u32,
+	wq_fd: u32,
+	resv: [u32; 3],
+	sq_off: SqringOffsets,
+	cq_off: CqringOffsets,
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct Sqe {
+	opcode: u8,
+	flags: u8,
+	ioprio: u16,
+	fd: i32,
+	off: u64,
+	addr: u64,
+	len: u32,
+	op_flags: u32,
+	user_data: u64,
+	buf_index: u16,
+	personality: u16,
+	splice_fd_in: i32,
+	addr3: u64,
+	pad: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Cqe {
+	user_data: u64,
+	res: i32,
+	flags: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct KernelTimespec {
+	tv_sec: i64,
+	tv_nsec: i64,
+}
+
+/// The kind of readiness reported for a socket.
+pub(crate) enum Readiness {
+	Read,
+	Write,
+}
+
+// the operations submitted to the ring, encoded in their user_data
+#[derive(Clone, Copy, PartialEq)]
+enum Op {
+	PollRead,
+	PollWrite,
+	Recv,
+	Send,
+	Accept,
+}
+
+impl Op {
+	fn from_user_data(user_data: u64) -> Op {
+		match (user_data >> 32) & 0x7 {
+			0 => Op::PollRead,
+			1 => Op::PollWrite,
+			2 => Op::Recv,
+			3 => Op::Send,
+			_ => Op::Accept,
+		}
+	}
+}
+
+// how the i/o of a handle is done
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+	// connected or connecting stream sockets, read with recv and written with send
+	Stream,
+	// listening sockets, whose connections are accepted by the ring
+	Listener,
+	// anything else, such as pipes and datagram sockets, whose readiness is polled
+	Other,
+}
+
+// the address of the connection accepted by the accept in progress on a listener
+#[repr(C)]
+struct AcceptAddr {
+	storage: libc::sockaddr_storage,
+	len: libc::socklen_t,
+}
+
+impl Default for AcceptAddr {
+	fn default() -> Self {
+		AcceptAddr {
+			storage: unsafe { std::mem::zeroed() },
+			len: std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t,
+		}
+	}
+}
+
+// the buffers of the operations that were cancelled, kept until their completion
+enum Retired {
+	Buffer(Vec<u8>),
+	Address(Box<AcceptAddr>),
+}
+
+// the state of a handle registered with the ring
+struct Registration {
+	kind: Kind,
+	// false once the handle was removed. The registration is kept until its send completes.
+	registered: bool,
+	// set while the handle is detached, so that its operations are not submitted again
+	detaching: bool,
+	// the user_data of the poll, recv or accept in progress for reads
+	read: Option<u64>,
+	// the user_data of the poll in progress for writes
+	write: Option<u64>,
+	// the user_data of the send in progress
+	send: Option<u64>,
+	// a write event is reported once the send in progress completes
+	write_wanted: bool,
+	// the write side is shut down once the send in progress completes
+	shutdown_pending: bool,
+	// the data received by the last recv, of which `consumed` bytes were read
+	recv_buf: Vec<u8>,
+	received: usize,
+	consumed: usize,
+	eof: bool,
+	recv_error: Option<i32>,
+	// the data passed to write, of which `sent` bytes were sent
+	send_buf: Vec<u8>,
+	sent: usize,
+	send_error: Option<i32>,
+	// the connections accepted on a listener that were not returned by accept yet
+	accepted: VecDeque<(RawFd, libc::sockaddr_storage)>,
+	accept_addr: Box<AcceptAddr>,
+}
+
+impl Registration {
+	fn new(kind: Kind) -> Self {
+		Registration {
+			kind,
+			registered: true,
+			detaching: false,
+			read: None,
+			write: None,
+			send: None,
+			write_wanted: false,
+			shutdown_pending: false,
+			recv_buf: vec![],
+			received: 0,
+			consumed: 0,
+			eof: false,
+			recv_error: None,
+			send_buf: vec![],
+			sent: 0,
+			send_error: None,
+			accepted: VecDeque::new(),
+			accept_addr: Box::new(AcceptAddr::default()),
+		}
+	}
+
+	// true once nothing refers to the registration anymore
+	fn is_unused(&self) -> bool {
+		!self.registered
+			&& self.read.is_none()
+			&& self.write.is_none()
+			&& self.send.is_none()
+			&& self.accepted.is_empty()
+	}
+}
+
+pub(crate) struct Uring {
+	fd: RawFd,
+	sq_ring: (*mut libc::c_void, usize),
+	cq_ring: (*mut libc::c_void, usize),
+	sqes: (*mut libc::c_void, usize),
+	sq_head: *const AtomicU32,
+	sq_tail: *const AtomicU32,
+	sq_mask: u32,
+	sq_entries: u32,
+	sq_array: *mut u32,
+	cq_head: *const AtomicU32,
+	cq_tail: *const AtomicU32,
+	cq_mask: u32,
+	cqes: *const Cqe,
+	registrations: HashMap<RawFd, Registration>,
+	retired: HashMap<u64, Retired>,
+	// the events of the completions that were not reported yet
+	pending: VecDeque<(RawFd, Readiness)>,
+	// read by the kernel when a timeout is submitted, which may happen after wait returns if
+	// io_uring_enter fails, so it is owned by the ring
+	timeout: Box<KernelTimespec>,
+	next_seq: u32,
+	closing: bool,
+}
+
+// the ring is only accessed through the lock in the selector that owns it
+unsafe impl Send for Uring {}
+unsafe impl Sync for Uring {}
+
+impl Drop for Uring {
+	fn drop(&mut self) {
+		// the kernel may write to the buffers of the operations in progress until they complete,
+		// so they are cancelled and waited for before being freed
+		self.closing = true;
+		let mut in_flight = vec![];
+		for registration in self.registrations.values() {
+			for user_data in [registration.read, registration.send].iter().flatten() {
+				in_flight.push(*user_data);
+			}
+		}
+		for user_data in in_flight
+			.iter()
+			.chain(self.retired.keys())
+			.cloned()
+			.collect::<Vec<_>>()
+		{
+			let _ = self.cancel(user_data);
+		}
+		let mut attempts = 0;
+		while self.has_buffers_in_flight() && attempts < DROP_ATTEMPTS {
+			let _ = self.wait_millis(10);
+			let _ = self.reap();
+			attempts += 1;
+		}
+		for registration in self.registrations.values() {
+			for (fd, _) in &registration.accepted {
+				unsafe { libc::close(*fd) };
+			}
+		}
+
+		for (ptr, len) in &[self.sq_ring, self.cq_ring, self.sqes] {
+			if *ptr != libc::MAP_FAILED && !ptr.is_null() {
+				unsafe { libc::munmap(*ptr, *len) };
+			}
+		}
+		unsafe { libc::close(self.fd) };
+	}
+}
+
+impl Uring {
+	/// Create a new io_uring selector with room for `entries` submissions.
+	pub(crate) fn new(entries: u32) -> Result<Self, Error> {
+		let mut params = Params::default();
+		let fd = unsafe {
+			libc::syscall(
+				libc::SYS_io_uring_setup,
+				entries,
+				&mut params as *mut Params,
+			)
+		};
+		if fd < 0 {
+			return Err(
+				ErrorKind::SetupError(format!("io_uring_setup failed: {}", errno())).into(),
+			);
+		}
+
+		let mut uring = Uring {
+			fd: fd as RawFd,
+			sq_ring: (libc::MAP_FAILED, 0),
+			cq_ring: (libc::MAP_FAILED, 0),
+			sqes: (libc::MAP_FAILED, 0),
+			sq_head: std::ptr::null(),
+			sq_tail: std::ptr::null(),
+			sq_mask: 0,
+			sq_entries: params.sq_entries,
+			sq_array: std::ptr::null_mut(),
+			cq_head: std::ptr::null(),
+			cq_tail: std::ptr::null(),
+			cq_mask: 0,
+			cqes: std::ptr::null(),
+			registrations: HashMap::new(),
+			retired: HashMap::new(),
+			pending: VecDeque::new(),
+			timeout: Box::new(KernelTimespec::default()),
+			next_seq: 0,
+			closing: false,
+		};
+
+		let sq_len =
+			params.sq_off.array as usize + params.sq_entries as usize * std::mem::size_of::<u32>();
+		uring.sq_ring = (uring.mmap(sq_len, IORING_OFF_SQ_RING)?, sq_len);
+		let cq_len =
+			params.cq_off.cqes as usize + params.cq_entries as usize * std::mem::size_of::<Cqe>();
+		uring.cq_ring = (uring.mmap(cq_len, IORING_OFF_CQ_RING)?, cq_len);
+		let sqes_len = params.sq_entries as usize * std::mem::size_of::<Sqe>();
+		uring.sqes = (uring.mmap(sqes_len, IORING_OFF_SQES)?, sqes_len);
+
+		unsafe {
+			let sq = uring.sq_ring.0 as *mut u8;
+			uring.sq_head = sq.add(params.sq_off.head as usize) as *const AtomicU32;
+			uring.sq_tail = sq.add(params.sq_off.tail as usize) as *const AtomicU32;
+			uring.sq_mask = *(sq.add(params.sq_off.ring_mask as usize) as *const u32);
+			uring.sq_array = sq.add(params.sq_off.array as usize) as *mut u32;
+			let cq = uring.cq_ring.0 as *mut u8;
+			uring.cq_head = cq.add(params.cq_off.head as usize) as *const AtomicU32;
+			uring.cq_tail = cq.add(params.cq_off.tail as usize) as *const AtomicU32;
+			uring.cq_mask = *(cq.add(params.cq_off.ring_mask as usize) as *const u32);
+			uring.cqes = cq.add(params.cq_off.cqes as usize) as *const Cqe;
+		}
+
+		Ok(uring)
+	}
+
+	fn mmap(&self, len: usize, offset: libc::off_t) -> Result<*mut libc::c_void, Error> {
+		let ptr = unsafe {
+			libc::mmap(
+				std::ptr::null_mut(),
+				len,
+				libc::PROT_READ | libc::PROT_WRITE,
+				libc::MAP_SHARED | libc::MAP_POPULATE,
+				self.fd,
+				offset,
+			)
+		};
+		if ptr == libc::MAP_FAILED {
+			return Err(ErrorKind::SetupError(format!("io_uring mmap failed: {}", errno())).into());
+		}
+		Ok(ptr)
+	}
+
+	/// Request read events for `fd`. A stream starts receiving and a listener starts accepting.
+	pub(crate) fn add_read(&mut self, fd: RawFd) -> Result<(), Error> {
+		match self.register(fd) {
+			Kind::Stream => {
+				let buffered = match self.registrations.get(&fd) {
+					Some(registration) => registration.consumed < registration.received,
+					None => false,
+				};
+				match buffered {
+					// the data that was not read yet is reported again
+					true => self.pending.push_back((fd, Readiness::Read)),
+					false => self.arm_recv(fd)?,
+				}
+				Ok(())
+			}
+			Kind::Listener => {
+				let queued = match self.registrations.get(&fd) {
+					Some(registration) => registration.accepted.len(),
+					None => 0,
+				};
+				// registering the listener again reports the connections that were accepted
+				for _ in 0..queued {
+					self.pending.push_back((fd, Readiness::Read));
+				}
+				self.arm_accept(fd)
+			}
+			Kind::Other => {
+				let read_armed = match self.registrations.get(&fd) {
+					Some(registration) => registration.read.is_some(),
+					None => false,
+				};
+				match read_armed {
+					true => Ok(()),
+					false => self.arm_poll(fd, false),
+				}
+			}
+		}
+	}
+
+	/// Request a single write event for `fd`. Read events are requested too if they aren't
+	/// already.
+	pub(crate) fn add_write(&mut self, fd: RawFd) -> Result<(), Error> {
+		self.add_write_only(fd)?;
+		let read_armed = match self.registrations.get(&fd) {
+			Some(registration) => registration.read.is_some(),
+			None => false,
+		};
+		match read_armed {
+			true => Ok(()),
+			false => self.add_read(fd),
+		}
+	}
+
+	/// Request a single write event for `fd` without requesting read events. The event of a
+	/// stream whose send is in progress is reported once the send completes.
+	pub(crate) fn add_write_only(&mut self, fd: RawFd) -> Result<(), Error> {
+		self.register(fd);
+		match self.registrations.get_mut(&fd) {
+			Some(registration) if registration.send.is_some() => {
+				registration.write_wanted = true;
+				Ok(())
+			}
+			_ => self.arm_poll(fd, true),
+		}
+	}
+
+	/// Stop reporting events for `fd`. The operations in progress, other than sends, are
+	/// cancelled immediately, so that the ring doesn't keep the socket open once it is closed.
+	/// A send in progress completes and data that was received but not read is discarded.
+	pub(crate) fn remove(&mut self, fd: RawFd) -> Result<(), Error> {
+		let mut cancelled = vec![];
+		let unused = match self.registrations.get_mut(&fd) {
+			Some(registration) => {
+				registration.registered = false;
+				registration.write_wanted = false;
+				cancelled.extend(registration.write.take());
+				if let Some(user_data) = registration.read.take() {
+					cancelled.push(user_data);
+					match Op::from_user_data(user_data) {
+						Op::Recv => {
+							let buf = std::mem::take(&mut registration.recv_buf);
+							self.retired.insert(user_data, Retired::Buffer(buf));
+						}
+						Op::Accept => {
+							let addr = std::mem::take(&mut registration.accept_addr);
+							self.retired.insert(user_data, Retired::Address(addr));
+						}
+						_ => {}
+					}
+				}
+				registration.received = 0;
+				registration.consumed = 0;
+				registration.eof = false;
+				registration.recv_error = None;
+				// listeners are kept for the connections accepted before the accept is cancelled
+				registration.kind != Kind::Listener && registration.is_unused()
+			}
+			None => return Ok(()),
+		};
+		if unused {
+			self.registrations.remove(&fd);
+		}
+		for user_data in cancelled {
+			self.cancel(user_data)?;
+		}
+		self.enter(0, 0)
+	}
+
+	/// Stop reporting events for the stream `fd` because it is moved to another selector. The
+	/// operations in progress are cancelled and waited for. Returns the data that was received
+	/// but not read yet and the data that was passed to write but not sent.
+	pub(crate) fn detach(&mut self, fd: RawFd) -> Result<(Vec<u8>, Vec<u8>), Error> {
+		let in_flight = match self.registrations.get_mut(&fd) {
+			Some(registration) if registration.kind == Kind::Stream => {
+				registration.detaching = true;
+				[registration.read, registration.send]
+			}
+			_ => {
+				self.remove(fd)?;
+				return Ok((vec![], vec![]));
+			}
+		};
+		for user_data in in_flight.iter().flatten() {
+			self.cancel(*user_data)?;
+		}
+		// the other completions are reported by the next call to wait
+		loop {
+			let done = match self.registrations.get(&fd) {
+				Some(registration) => registration.read.is_none() && registration.send.is_none(),
+				None => true,
+			};
+			if done {
+				break;
+			}
+			self.enter(1, IORING_ENTER_GETEVENTS)?;
+			self.reap()?;
+		}
+
+		let (received, unsent) = match self.registrations.get_mut(&fd) {
+			Some(registration) => {
+				registration.detaching = false;
+				registration.shutdown_pending = false;
+				let received =
+					registration.recv_buf[registration.consumed..registration.received].to_vec();
+				let unsent = match registration.sent < registration.send_buf.len() {
+					true => registration.send_buf[registration.sent..].to_vec(),
+					false => vec![],
+				};
+				registration.send_buf.clear();
+				registration.sent = 0;
+				(received, unsent)
+			}
+			None => (vec![], vec![]),
+		};
+		self.remove(fd)?;
+		Ok((received, unsent))
+	}
+
+	/// Make `received`, data received for the stream `fd` by another selector, available to the
+	/// next reads. The read event is reported once `fd` is registered for reads.
+	pub(crate) fn attach(&mut self, fd: RawFd, received: Vec<u8>) -> Result<(), Error> {
+		if received.is_empty() {
+			return Ok(());
+		}
+		self.register(fd);
+		match self.registrations.get_mut(&fd) {
+			Some(registration) if registration.read.is_none() => {
+				registration.received = received.len();
+				registration.consumed = 0;
+				registration.recv_buf = received;
+			}
+			_ => {}
+		}
+		Ok(())
+	}
+
+	/// Read the data received for the stream `fd` into `buf`. Returns the number of bytes read,
+	/// 0 at the end of the stream and -1 with errno set on error or if no data was received yet,
+	/// in which case a read event is reported once there is. Returns None if `fd` is not a stream
+	/// registered with the ring.
+	pub(crate) fn read(&mut self, fd: RawFd, buf: &mut [u8]) -> Result<Option<isize>, Error> {
+		let registration = match self.registrations.get_mut(&fd) {
+			Some(registration) if registration.kind == Kind::Stream && registration.registered => {
+				registration
+			}
+			_ => return Ok(None),
+		};
+		if registration.consumed < registration.received {
+			let len = std::cmp::min(buf.len(), registration.received - registration.consumed);
+			let start = registration.consumed;
+			buf[..len].copy_from_slice(&registration.recv_buf[start..start + len]);
+			registration.consumed += len;
+			if registration.consumed == registration.received {
+				registration.consumed = 0;
+				registration.received = 0;
+				self.arm_recv(fd)?;
+			}
+			return Ok(Some(len as isize));
+		}
+		if registration.eof {
+			return Ok(Some(0));
+		}
+		match registration.recv_error.take() {
+			Some(e) => errno::set_errno(errno::Errno(e)),
+			None => {
+				self.arm_recv(fd)?;
+				errno::set_errno(errno::Errno(libc::EAGAIN));
+			}
+		}
+		Ok(Some(-1))
+	}
+
+	/// Copy as much of `bufs` as fits in the send buffer of the stream `fd` and send it. Returns
+	/// the number of bytes copied and -1 with errno set on error or while a send is in progress.
+	/// Returns None if `fd` is not a stream registered with the ring.
+	pub(crate) fn write(&mut self, fd: RawFd, bufs: &[IoSlice]) -> Result<Option<isize>, Error> {
+		let registration = match self.registrations.get_mut(&fd) {
+			Some(registration) if registration.kind == Kind::Stream => registration,
+			_ => return Ok(None),
+		};
+		if registration.send.is_some() {
+			errno::set_errno(errno::Errno(libc::EAGAIN));
+			return Ok(Some(-1));
+		}
+		if let Some(e) = registration.send_error.take() {
+			errno::set_errno(errno::Errno(e));
+			return Ok(Some(-1));
+		}
+		registration.send_buf.clear();
+		registration.sent = 0;
+		for buf in bufs {
+			let len = std::cmp::min(buf.len(), MAX_SEND - registration.send_buf.len());
+			registration.send_buf.extend_from_slice(&buf[..len]);
+			if registration.send_buf.len() == MAX_SEND {
+				break;
+			}
+		}
+		let len = registration.send_buf.len();
+		if len > 0 {
+			self.submit_send(fd)?;
+		}
+		Ok(Some(len as isize))
+	}
+
+	/// Returns true if a send is in progress for `fd`, in which case nothing else may be written
+	/// to it until the send completes.
+	pub(crate) fn is_sending(&self, fd: RawFd) -> bool {
+		match self.registrations.get(&fd) {
+			Some(registration) => registration.send.is_some(),
+			None => false,
+		}
+	}
+
+	/// Shut down the write side of `fd` once its send in progress completes. Returns false if
+	/// there is none, in which case the write side is to be shut down by the caller.
+	pub(crate) fn shutdown_write(&mut self, fd: RawFd) -> bool {
+		match self.registrations.get_mut(&fd) {
+			Some(registration) if registration.send.is_some() => {
+				registration.shutdown_pending = true;
+				true
+			}
+			_ => false,
+		}
+	}
+
+	/// Called before `fd` is closed, so that the handle isn't used once its number is reused.
+	pub(crate) fn close(&mut self, fd: RawFd) {
+		if let Some(registration) = self.registrations.get_mut(&fd) {
+			registration.shutdown_pending = false;
+		}
+	}
+
+	/// Returns a connection accepted on the listener `fd`, with the address of its peer, or
+	/// Some(None) if there is none yet. Returns None if `fd` is not a listener registered with
+	/// the ring.
+	pub(crate) fn accept(
+		&mut self,
+		fd: RawFd,
+	) -> Result<Option<Option<(RawFd, libc::sockaddr_storage)>>, Error> {
+		let accepted = match self.registrations.get_mut(&fd) {
+			Some(registration) if registration.kind == Kind::Listener => {
+				registration.accepted.pop_front()
+			}
+			_ => return Ok(None),
+		};
+		self.arm_accept(fd)?;
+		Ok(Some(accepted))
+	}
+
+	/// Submit the queued operations and wait up to `timeout_millis` for events. At most
+	/// `max_events` events are appended to `events`. Any others are reported by the next call.
+	pub(crate) fn wait(
+		&mut self,
+		timeout_millis: u64,
+		max_events: usize,
+		events: &mut Vec<(RawFd, Readiness)>,
+	) -> Result<(), Error> {
+		let ready = unsafe {
+			(*self.cq_tail)
+				.load(Ordering::Acquire)
+				.wrapping_sub((*self.cq_head).load(Ordering::Relaxed))
+		};
+		if timeout_millis > 0 && ready == 0 && self.pending.is_empty() {
+			self.wait_millis(timeout_millis)?;
+		} else {
+			self.enter(0, 0)?;
+		}
+
+		self.reap()?;
+		let count = std::cmp::min(max_events, self.pending.len());
+		events.extend(self.pending.drain(..count));
+
+		// submit the operations armed while handling the completions
+		self.enter(0, 0)
+	}
+
+	// submit a timeout and wait until it or another operation completes
+	fn wait_millis(&mut self, timeout_millis: u64) -> Result<(), Error> {
+		*self.timeout = KernelTimespec {
+			tv_sec: (timeout_millis / 1_000) as i64,
+			tv_nsec: ((timeout_millis % 1_000) * 1_000_000) as i64,
+		};
+		let addr = &*self.timeout as *const KernelTimespec as u64;
+		self.push(Sqe {
+			opcode: IORING_OP_TIMEOUT,
+			fd: -1,
+			addr,
+			len: 1,
+			// complete after one other completion
+			off: 1,
+			user_data: IGNORED,
+			..Sqe::default()
+		})?;
+		self.enter(1, IORING_ENTER_GETEVENTS)
+	}
+
+	// handle the completions in the completion queue
+	fn reap(&mut self) -> Result<(), Error> {
+		loop {
+			let cqe = unsafe {
+				let head = (*self.cq_head).load(Ordering::Relaxed);
+				if head == (*self.cq_tail).load(Ordering::Acquire) {
+					return Ok(());
+				}
+				let cqe = *self.cqes.add((head & self.cq_mask) as usize);
+				(*self.cq_head).store(head.wrapping_add(1), Ordering::Release);
+				cqe
+			};
+			self.complete(cqe)?;
+		}
+	}
+
+	// handle a completion, queuing the event it reports if any
+	fn complete(&mut self, cqe: Cqe) -> Result<(), Error> {
+		if cqe.user_data == IGNORED {
+			return Ok(());
+		}
+		let op = Op::from_user_data(cqe.user_data);
+		let fd = (cqe.user_data & 0xffff_ffff) as u32 as RawFd;
+		match self.retired.remove(&cqe.user_data) {
+			// a cancelled operation, whose buffer may now be freed
+			Some(Retired::Address(addr)) if cqe.res >= 0 => {
+				// the connection was accepted before the accept was cancelled, it is kept for
+				// when the listener is registered again
+				match self.registrations.get_mut(&fd) {
+					Some(registration) if registration.kind == Kind::Listener => {
+						registration.accepted.push_back((cqe.res, addr.storage));
+						if registration.registered {
+							self.pending.push_back((fd, Readiness::Read));
+						}
+					}
+					_ => unsafe {
+						libc::close(cqe.res);
+					},
+				}
+				return Ok(());
+			}
+			Some(Retired::Buffer(buf)) => {
+				// the buffer is reused if the stream was registered again
+				match self.registrations.get_mut(&fd) {
+					Some(registration) if registration.recv_buf.is_empty() => {
+						registration.recv_buf = buf;
+					}
+					_ => {}
+				}
+				return Ok(());
+			}
+			Some(Retired::Address(_)) => return Ok(()),
+			None => {}
+		}
+		let registration = match self.registrations.get_mut(&fd) {
+			Some(registration) => registration,
+			None => return Ok(()),
+		};
+		let armed = match op {
+			Op::PollWrite => &mut registration.write,
+			Op::Send => &mut registration.send,
+			_ => &mut registration.read,
+		};
+		if *armed != Some(cqe.user_data) {
+			// a cancelled operation
+			if op == Op::Accept && cqe.res >= 0 {
+				unsafe { libc::close(cqe.res) };
+			}
+			return Ok(());
+		}
+		*armed = None;
+
+		match op {
+			Op::PollRead => {
+				if cqe.res >= 0 {
+					self.pending.push_back((fd, Readiness::Read));
+					self.arm_poll(fd, false)?;
+				}
+			}
+			Op::PollWrite => {
+				if cqe.res >= 0 {
+					self.pending.push_back((fd, Readiness::Write));
+				}
+			}
+			Op::Recv => {
+				match cqe.res {
+					0 => registration.eof = true,
+					res if res > 0 => registration.received = res as usize,
+					res if res == -libc::ECANCELED => {}
+					res => registration.recv_error = Some(-res),
+				}
+				if !registration.detaching {
+					self.pending.push_back((fd, Readiness::Read));
+				}
+			}
+			Op::Send => {
+				if cqe.res > 0 {
+					registration.sent += cqe.res as usize;
+				} else if cqe.res < 0 && cqe.res != -libc::ECANCELED {
+					registration.send_error = Some(-cqe.res);
+				}
+				let remaining = registration.sent < registration.send_buf.len();
+				if remaining && registration.send_error.is_none() && !registration.detaching {
+					return self.submit_send(fd);
+				}
+				if !registration.detaching {
+					registration.send_buf.clear();
+					registration.sent = 0;
+				}
+				if registration.shutdown_pending {
+					registration.shutdown_pending = false;
+					unsafe { libc::shutdown(fd, libc::SHUT_WR) };
+				}
+				if registration.write_wanted {
+					registration.write_wanted = false;
+					self.pending.push_back((fd, Readiness::Write));
+				}
+				if registration.is_unused() {
+					self.registrations.remove(&fd);
+				}
+			}
+			Op::Accept => {
+				if cqe.res >= 0 {
+					let storage = registration.accept_addr.storage;
+					registration.accepted.push_back((cqe.res, storage));
+				}
+				// errors are reported too, accept submits a new one
+				self.pending.push_back((fd, Readiness::Read));
+			}
+		}
+		Ok(())
+	}
+
+	// returns the kind of fd, registering it if needed
+	fn register(&mut self, fd: RawFd) -> Kind {
+		match self.registrations.get_mut(&fd) {
+			Some(registration) if registration.registered => registration.kind,
+			Some(registration) => {
+				// the handle may have been closed and its number reused since it was removed
+				let kind = kind(fd);
+				if kind != registration.kind {
+					for (fd, _) in registration.accepted.drain(..) {
+						unsafe { libc::close(fd) };
+					}
+					registration.kind = kind;
+				}
+				registration.registered = true;
+				kind
+			}
+			None => {
+				let kind = kind(fd);
+				self.registrations.insert(fd, Registration::new(kind));
+				kind
+			}
+		}
+	}
+
+	fn user_data(&mut self, fd: RawFd, op: Op) -> u64 {
+		self.next_seq = self.next_seq.wrapping_add(1) & 0x0fff_ffff;
+		((self.next_seq as u64) << 35) | ((op as u64) << 32) | fd as u32 as u64
+	}
+
+	// submit a recv for the stream fd unless one is in progress or its data was not read yet
+	fn arm_recv(&mut self, fd: RawFd) -> Result<(), Error> {
+		if self.closing {
+			return Ok(());
+		}
+		let user_data = self.user_data(fd, Op::Recv);
+		let registration = match self.registrations.get_mut(&fd) {
+			Some(registration) => registration,
+			None => return Ok(()),
+		};
+		if registration.read.is_some()
+			|| registration.detaching
+			|| registration.consumed < registration.received
+			|| registration.eof
+			|| registration.recv_error.is_some()
+		{
+			return Ok(());
+		}
+		if registration.recv_buf.len() < RECV_BUFFER_SIZE {
+			registration.recv_buf.resize(RECV_BUFFER_SIZE, 0);
+		}
+		registration.read = Some(user_data);
+		let sqe = Sqe {
+			opcode: IORING_OP_RECV,
+			fd,
+			addr: registration.recv_buf.as_mut_ptr() as u64,
+			len: RECV_BUFFER_SIZE as u32,
+			user_data,
+			..Sqe::default()
+		};
+		self.push(sqe)
+	}
+
+	// submit the rest of the send buffer of the stream fd
+	fn submit_send(&mut self, fd: RawFd) -> Result<(), Error> {
+		if self.closing {
+			return Ok(());
+		}
+		let user_data = self.user_data(fd, Op::Send);
+		let registration = match self.registrations.get_mut(&fd) {
+			Some(registration) => registration,
+			None => return Ok(()),
+		};
+		registration.send = Some(user_data);
+		let remaining = &registration.send_buf[registration.sent..];
+		let sqe = Sqe {
+			opcode: IORING_OP_SEND,
+			fd,
+			addr: remaining.as_ptr() as u64,
+			len: remaining.len() as u32,
+			op_flags: libc::MSG_NOSIGNAL as u32,
+			user_data,
+			..Sqe::default()
+		};
+		self.push(sqe)
+	}
+
+	// submit an accept on the listener fd unless one is in progress or a connection is queued
+	fn arm_accept(&mut self, fd: RawFd) -> Result<(), Error> {
+		if self.closing {
+			return Ok(());
+		}
+		let user_data = self.user_data(fd, Op::Accept);
+		let registration = match self.registrations.get_mut(&fd) {
+			Some(registration) if registration.registered => registration,
+			_ => return Ok(()),
+		};
+		if registration.read.is_some() || !registration.accepted.is_empty() {
+			return Ok(());
+		}
+		registration.read = Some(user_data);
+		*registration.accept_addr = AcceptAddr::default();
+		let sqe = Sqe {
+			opcode: IORING_OP_ACCEPT,
+			fd,
+			addr: &registration.accept_addr.storage as *const libc::sockaddr_storage as u64,
+			off: &registration.accept_addr.len as *const libc::socklen_t as u64,
+			op_flags: libc::SOCK_NONBLOCK as u32,
+			user_data,
+			..Sqe::default()
+		};
+		self.push(sqe)
+	}
+
+	// submit a poll for fd, replacing the one already armed in the same direction
+	fn arm_poll(&mut self, fd: RawFd, write: bool) -> Result<(), Error> {
+		if self.closing {
+			return Ok(());
+		}
+		let user_data = match write {
+			true => self.user_data(fd, Op::PollWrite),
+			false => self.user_data(fd, Op::PollRead),
+		};
+		let registration = self
+			.registrations
+			.entry(fd)
+			.or_insert_with(|| Registration::new(Kind::Other));
+		let previous = match write {
+			true => registration.write.replace(user_data),
+			false => registration.read.replace(user_data),
+		};
+		if let Some(previous) = previous {
+			self.cancel(previous)?;
+		}
+
+		let events = match write {
+			true => libc::POLLOUT,
+			false => libc::POLLIN | libc::POLLRDHUP,
+		} as u16 as u32;
+		// the kernel swaps the halves of poll32_events on big endian
+		#[cfg(target_endian = "big")]
+		let events = events.rotate_left(16);
+		self.push(Sqe {
+			opcode: IORING_OP_POLL_ADD,
+			fd,
+			op_flags: events,
+			user_data,
+			..Sqe::default()
+		})
+	}
+
+	fn cancel(&mut self, user_data: u64) -> Result<(), Error> {
+		let opcode = match Op::from_user_data(user_data) {
+			Op::PollRead | Op::PollWrite => IORING_OP_POLL_REMOVE,
+			_ => IORING_OP_ASYNC_CANCEL,
+		};
+		self.push(Sqe {
+			opcode,
+			fd: -1,
+			addr: user_data,
+			user_data: IGNORED,
+			..Sqe::default()
+		})
+	}
+
+	fn has_buffers_in_flight(&self) -> bool {
+		!self.retired.is_empty()
+			|| self.registrations.values().any(|registration| {
+				registration.send.is_some()
+					|| match registration.read {
+						Some(user_data) => Op::from_user_data(user_data) != Op::PollRead,
+						None => false,
+					}
+			})
+	}
+
+	fn push(&mut self, sqe: Sqe) -> Result<(), Error> {
+		unsafe {
+			let tail = (*self.sq_tail).load(Ordering::Relaxed);
+			if tail.wrapping_sub((*self.sq_head).load(Ordering::Acquire)) == self.sq_entries {
+				// the submission queue is full
+				self.enter(0, 0)?;
+			}
+			let index = tail & self.sq_mask;
+			*(self.sqes.0 as *mut Sqe).add(index as usize) = sqe;
+			*self.sq_array.add(index as usize) = index;
+			(*self.sq_tail).store(tail.wrapping_add(1), Ordering::Release);
+		}
+		Ok(())
+	}
+
+	fn enter(&mut self, min_complete: u32, flags: u32) -> Result<(), Error> {
+		let to_submit = unsafe {
+			(*self.sq_tail)
+				.load(Ordering::Relaxed)
+				.wrapping_sub((*self.sq_head).load(Ordering::Acquire))
+		};
+		if to_submit == 0 && min_complete == 0 {
+			return Ok(());
+		}
+		let res = unsafe {
+			libc::syscall(
+				libc::SYS_io_uring_enter,
+				self.fd,
+				to_submit,
+				min_complete,
+				flags,
+				std::ptr::null::<libc::sigset_t>(),
+				0usize,
+			)
+		};
+		if res < 0 {
+			let e = errno();
+			match e.0 {
+				// interrupted or out of resources, the operations are submitted by the next call
+				libc::EINTR | libc::EAGAIN | libc::EBUSY => {}
+				_ => return Err(ErrorKind::IOError(format!("io_uring_enter failed: {}", e)).into()),
+			}
+		}
+		Ok(())
+	}
+}
+
+// returns how the i/o of fd is done
+fn kind(fd: RawFd) -> Kind {
+	let sockopt = |option| {
+		let mut value: libc::c_int = 0;
+		let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+		let res = unsafe {
+			libc::getsockopt(
+				fd,
+				libc::SOL_SOCKET,
+				option,
+				&mut value as *mut libc::c_int as *mut libc::c_void,
+				&mut len,
+			)
+		};
+		match res {
+			0 => Some(value),
+			_ => None,
+		}
+	};
+	match sockopt(libc::SO_TYPE) {
+		Some(libc::SOCK_STREAM) => match sockopt(libc::SO_ACCEPTCONN) {
+			Some(0) => Kind::Stream,
+			Some(_) => Kind::Listener,
+			None => Kind::Other,
+		},
+		_ => Kind::Other,
+	}
+}
@@ -39,9 +39,12 @@ use std::collections::HashSet;
 use std::convert::TryInto;
 use std::fs::metadata;
 use std::fs::File;
+use std::io::IoSlice;
 use std::io::Read;
 use std::io::Write;
 use std::net::TcpListener;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
 use std::path::Path;
 use std::path::PathBuf;
 use std::sync::{Arc, RwLock, RwLockWriteGuard};
@@ -87,7 +90,6 @@ const STATS_LOG: &str = "statslog";
 const HEADER: &str =
 	"-------------------------------------------------------------------------------------------------------------------------------";
 const VERSION: &'static str = env!("CARGO_PKG_VERSION");
-const MAX_CHUNK_SIZE: u64 = 10 * 1024 * 1024;
 const HTTP_GET: &[u8] = &['G' as u8, 'E' as u8, 'T' as u8];
 const HTTP_POST: &[u8] = &['P' as u8, 'O' as u8, 'S' as u8, 'T' as u8];
 const END_HEADERS: &[u8] = &['\r' as u8, '\n' as u8, '\r' as u8, '\n' as u8];
@@ -258,6 +260,9 @@ pub struct HttpConfig {
 	pub host: String,
 	/// The port to bind to. The default is 8080.
 	pub port: u16,
+	/// An optional unix domain socket path to listen on in addition to the tcp port.
+	/// The default is None, which is disabled.
+	pub unix_socket_path: Option<String>,
 	/// The root directory of the http daemon. By default ~/.niohttpd is used.
 	pub root_dir: String,
 	/// The name of the server. The default is `"NIORuntime Httpd <version>"`
@@ -329,6 +334,7 @@ impl Default for HttpConfig {
 		HttpConfig {
 			host: "0.0.0.0".to_string(),
 			port: 8080,
+			unix_socket_path: None,
 			root_dir: "~/.niohttpd".to_string(),
 			server_name: format!("NIORuntime Httpd {}", VERSION),
 			request_log_params: vec![
@@ -493,6 +499,8 @@ pub struct HttpServer {
 	/// The config of this [`HttpServer`].
 	pub config: HttpConfig,
 	listener: Option<TcpListener>,
+	#[cfg(unix)]
+	unix_listener: Option<UnixListener>,
 	onion_address: Option<String>,
 	onion_secret: Option<ExpandedSecretKey>,
 	onion_bytes: Option<[u8; 64]>,
@@ -536,6 +544,8 @@ impl HttpServer {
 		HttpServer {
 			config: cloned_config,
 			listener: None,
+			#[cfg(unix)]
+			unix_listener: None,
 			onion_address: None,
 			onion_secret: None,
 			onion_bytes: None,
@@ -896,6 +906,17 @@ impl HttpServer {
 		eh.start()?;
 		eh.add_tcp_listener(&listener)?;
 		self.listener = Some(listener);
+		#[cfg(unix)]
+		match self.config.unix_socket_path.as_ref() {
+			Some(path) => {
+				// remove a stale socket file from a previous run
+				let _ = std::fs::remove_file(path);
+				let unix_listener = UnixListener::bind(path)?;
+				eh.add_unix_listener(&unix_listener)?;
+				self.unix_listener = Some(unix_listener);
+			}
+			None => {}
+		}
 		self.http_context = Some(http_context_clone3);
 
 		std::thread::spawn(move || loop {
@@ -1077,6 +1098,16 @@ impl HttpServer {
 			None => {}
 		}
 		self.listener = None;
+		#[cfg(unix)]
+		{
+			self.unix_listener = None;
+			match self.config.unix_socket_path.as_ref() {
+				Some(path) => {
+					let _ = std::fs::remove_file(path);
+				}
+				None => {}
+			}
+		}
 		Ok(())
 	}
 
@@ -1950,9 +1981,11 @@ impl HttpServer {
 					);
 					let byte_msg = byte_msg.as_bytes();
 					let msg = format!("{:X}\r\n", byte_msg.len());
-					let _ = conn_data.wh.write(msg.as_bytes());
-					let _ = conn_data.wh.write(&byte_msg);
-					let _ = conn_data.wh.write("\r\n0\r\n\r\n".as_bytes());
+					let _ = conn_data.wh.write_vectored(&[
+						IoSlice::new(msg.as_bytes()),
+						IoSlice::new(&byte_msg),
+						IoSlice::new("\r\n0\r\n\r\n".as_bytes()),
+					]);
 				} else {
 					let res = conn_data
 						.wh
@@ -2648,7 +2681,7 @@ impl HttpServer {
 		let mut path = Self::get_path(config, uri)?;
 		let mut is_404 = false;
 		let mut found_404_content = true;
-		let mut flen = match metadata(path.clone()) {
+		let flen = match metadata(path.clone()) {
 			Ok(md) => {
 				if md.is_dir() {
 					path = format!("{}/www{}/index.html", config.root_dir, uri);
@@ -2684,78 +2717,29 @@ impl HttpServer {
 		let file = File::open(path.clone());
 
 		match file {
-			Ok(mut file) => {
-				let buflen = if flen > MAX_CHUNK_SIZE {
-					MAX_CHUNK_SIZE
+			Ok(file) if file.metadata().map(|md| md.is_file()).unwrap_or(false) => {
+				Self::write_headers(
+					wh,
+					config,
+					!is_404,
+					found_404_content,
+					keep_alive,
+					vec![],
+					None,
+				)?;
+				// the file is sent from the event loop without reading it into memory
+				if keep_alive {
+					let msg_len_bytes = format!("{:X}\r\n", flen);
+					wh.write(msg_len_bytes.as_bytes())?;
+					wh.send_file(file, 0, flen)?;
+					wh.write("\r\n0\r\n\r\n".as_bytes())?;
 				} else {
-					flen
-				};
-
-				let mut buf = vec![0; buflen as usize];
-				let mut first_loop = true;
-				loop {
-					let res = file.read(&mut buf);
-					match res {
-						Ok(amt) => {
-							if first_loop {
-								Self::write_headers(
-									wh,
-									config,
-									!is_404,
-									found_404_content,
-									keep_alive,
-									vec![],
-									None,
-								)?;
-							}
-							if keep_alive {
-								let msg_len_bytes = format!("{:X}\r\n", amt);
-								let msg_len_bytes = msg_len_bytes.as_bytes();
-								wh.write(msg_len_bytes)?;
-								wh.write(&buf[0..amt])?;
-								if flen <= amt.try_into().unwrap_or(0) {
-									wh.write("\r\n0\r\n\r\n".as_bytes())?;
-									if !keep_alive {
-										wh.close()?;
-									}
-								} else {
-									wh.write("\r\n".as_bytes())?;
-								}
-							} else {
-								if flen <= amt.try_into().unwrap_or(0) {
-									wh.write(&buf[0..amt])?;
-									if !keep_alive {
-										wh.close()?;
-									}
-								} else {
-									wh.write(&buf[0..amt])?;
-								}
-							}
-							flen -= amt.try_into().unwrap_or(0);
-						}
-						Err(_) => {
-							// directory
-							Self::write_headers(
-								wh,
-								config,
-								false,
-								found_404_content,
-								keep_alive,
-								vec![],
-								None,
-							)?;
-							break;
-						}
-					}
-
-					if flen <= 0 {
-						break;
-					}
-					first_loop = false;
+					wh.send_file(file, 0, flen)?;
+					wh.close()?;
 				}
 			}
-			Err(_) => {
-				// file not found
+			_ => {
+				// file not found or a directory
 				Self::write_headers(
 					wh,
 					config,
@@ -27,7 +27,6 @@ use nioruntime_http::HttpConfig;
 use nioruntime_http::HttpServer;
 use nioruntime_log::*;
 use rand::Rng;
-use std::collections::HashMap;
 use std::io::Cursor;
 use std::io::Read;
 use std::io::Write;
@@ -241,6 +240,7 @@ fn real_main() -> Result<(), Error> {
 		true => Some(TlsConfig {
 			certificates_file: args.value_of("certs").unwrap().to_string(),
 			private_key_file: args.value_of("private_key").unwrap().to_string(),
+			..Default::default()
 		}),
 		false => None,
 	};
@@ -361,20 +361,11 @@ fn real_main() -> Result<(), Error> {
 		let mut eh = EventHandler::new(EventHandlerConfig {
 			thread_count: 6,
 			tls_config,
+			..Default::default()
 		});
 
-		let buffers: Arc<Mutex<HashMap<u128, Arc<Mutex<Buffer>>>>> =
-			Arc::new(Mutex::new(HashMap::new()));
-		let buffers_clone = buffers.clone();
-		let buffers_clone2 = buffers.clone();
 		eh.set_on_read(move |buf, len, wh| {
-			let held_buf;
-			{
-				let mut buffers = buffers_clone2.lock().unwrap();
-				let held_buf2 = buffers.get_mut(&wh.get_connection_id()).unwrap();
-				held_buf = held_buf2.clone()
-			}
-
+			let held_buf = wh.attachment::<Mutex<Buffer>>()?.unwrap();
 			let mut held_buf = held_buf.lock().unwrap();
 			let hbuf_len = (*held_buf).len;
 			copy(
@@ -419,19 +410,12 @@ fn real_main() -> Result<(), Error> {
 			wh.write(&buf.to_vec()[0..len])?;
 			Ok(())
 		})?;
-		eh.set_on_accept(move |connection_id, _wh| {
-			let mut buffers = buffers.lock().unwrap();
-
-			buffers.insert(connection_id, Arc::new(Mutex::new(Buffer::new())));
-
+		eh.set_on_accept(move |_connection_id, wh| {
+			wh.set_attachment(Mutex::new(Buffer::new()))?;
 			Ok(())
 		})?;
 
-		eh.set_on_close(move |connection_id| {
-			let mut buffers = buffers_clone.lock().unwrap();
-			buffers.remove(&connection_id);
-			Ok(())
-		})?;
+		eh.set_on_close(move |_connection_id| Ok(()))?;
 		eh.start()?;
 		eh.add_tcp_listener(&listener)?;
 		std::thread::park();